@@ -1,11 +1,12 @@
 /// This module contains blocks and a ledger (a list of those blocks where each element contains a hash of the previous one)
-/// used as the commit log of a key value store: each block contains a (possibly empty) list of write (set) commands of key values.
+/// used as the commit log of a key value store: each block contains a (possibly empty) list of write commands of key values.
+use std::collections::HashMap;
 use std::fmt::Display;
 
 use anyhow::{bail, Result};
 use itertools::Itertools;
 
-use lib::command::ClientCommand;
+use lib::command::{ClientCommand, KeyValues};
 use log::{debug, warn};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
@@ -128,23 +129,30 @@ impl Ledger {
     }
 
     /// Viewing the ledger as the commit log of key/value commands, return the current value
-    /// of the given key.
+    /// of the given key. The value is computed by replaying, from the oldest block, every command
+    /// in the ledger; rejected commands (e.g. a failed compare-and-swap) are skipped, and so are the
+    /// session requests older than one already replayed for the same client.
     pub fn get(&self, key: &str) -> Option<String> {
-        for block in self.blocks.iter().rev() {
+        let mut state = KeyValues::new();
+        let mut sessions = HashMap::new();
+        for block in &self.blocks {
             for (_, cmd) in &block.data {
-                if let ClientCommand::Set {
-                    key: block_key,
-                    value,
+                if let ClientCommand::Session {
+                    client, sequence, ..
                 } = cmd
                 {
-                    if block_key == key {
-                        return Some(value.clone());
+                    let last = sessions.entry(*client).or_insert(0);
+                    if *sequence <= *last {
+                        continue;
                     }
+                    *last = *sequence;
                 }
+                // a rejected command leaves the state untouched
+                let _ = cmd.execute(&mut state);
             }
         }
 
-        None
+        state.remove(key).flatten()
     }
 
     /// Returns true if there's a transaction with the given id commited in some block of this ledger.
@@ -187,6 +195,35 @@ impl Ledger {
         Ok(new_ledger)
     }
 
+    /// Return a new ledger that is the current one followed by the given number of blocks, the first
+    /// of them including the given transactions, as a byzantine node skipping the proof of work would
+    /// forge it: their hashes match their contents but don't meet the difficulty target.
+    pub fn forge(&self, miner_id: &str, transactions: Vec<Transaction>, blocks: usize) -> Self {
+        let mut new_ledger = self.clone();
+        let mut data = transactions;
+        for _ in 0..blocks {
+            let previous_block = new_ledger.blocks.last().unwrap();
+            let mut block = Block {
+                height: previous_block.height + 1,
+                miner_id: miner_id.to_string(),
+                previous_hash: previous_block.hash.clone(),
+                hash: "not known yet".to_string(),
+                data: std::mem::take(&mut data),
+                nonce: 0,
+            };
+            // a hash that happens to meet the target would be a valid proof of work
+            loop {
+                block.hash = block.calculate_hash();
+                if !is_below_difficulty_target(&block.hash).unwrap() {
+                    break;
+                }
+                block.nonce += 1;
+            }
+            new_ledger.blocks.push(block);
+        }
+        new_ledger
+    }
+
     const MINER_LOG_EVERY: u64 = 100000;
 
     /// Produce a block that extends the given one and includes the given list of transactions as its
@@ -457,4 +494,107 @@ mod tests {
         assert!(ledger.contains("tx2"));
         assert_eq!("another", &ledger.get("key").unwrap());
     }
+
+    #[tokio::test]
+    async fn replay_write_commands() {
+        let ledger = Ledger::new();
+        let genesis = ledger.blocks.first().unwrap().clone();
+
+        let transactions = vec![
+            (
+                "tx1".to_string(),
+                ClientCommand::Increment {
+                    key: "counter".to_string(),
+                    by: 2,
+                },
+            ),
+            (
+                "tx2".to_string(),
+                ClientCommand::Set {
+                    key: "key".to_string(),
+                    value: "value".to_string(),
+                },
+            ),
+            // rejected, the current value is 2
+            (
+                "tx3".to_string(),
+                ClientCommand::CompareAndSwap {
+                    key: "counter".to_string(),
+                    expected: "1".to_string(),
+                    new: "10".to_string(),
+                },
+            ),
+            (
+                "tx4".to_string(),
+                ClientCommand::Increment {
+                    key: "counter".to_string(),
+                    by: 3,
+                },
+            ),
+        ];
+        let block = Ledger::mine_block("127.0.0.1:6100", genesis, transactions).await;
+        let ledger = ledger.extend(block.clone()).unwrap();
+        assert_eq!("5", &ledger.get("counter").unwrap());
+        assert_eq!("value", &ledger.get("key").unwrap());
+
+        let transactions = vec![
+            (
+                "tx5".to_string(),
+                ClientCommand::Delete {
+                    key: "key".to_string(),
+                },
+            ),
+            (
+                "tx6".to_string(),
+                ClientCommand::CompareAndSwap {
+                    key: "counter".to_string(),
+                    expected: "5".to_string(),
+                    new: "10".to_string(),
+                },
+            ),
+        ];
+        let block = Ledger::mine_block("127.0.0.1:6100", block, transactions).await;
+        let ledger = ledger.extend(block.clone()).unwrap();
+        assert_eq!("10", &ledger.get("counter").unwrap());
+        assert!(ledger.get("key").is_none());
+
+        // a batch is committed as a single transaction and is applied all-or-nothing
+        let transactions = vec![(
+            "tx7".to_string(),
+            ClientCommand::Batch {
+                commands: vec![
+                    ClientCommand::Set {
+                        key: "key".to_string(),
+                        value: "batched".to_string(),
+                    },
+                    ClientCommand::CompareAndSwap {
+                        key: "counter".to_string(),
+                        expected: "5".to_string(),
+                        new: "0".to_string(),
+                    },
+                ],
+            },
+        )];
+        let block = Ledger::mine_block("127.0.0.1:6100", block, transactions).await;
+        let ledger = ledger.extend(block.clone()).unwrap();
+        assert_eq!("10", &ledger.get("counter").unwrap());
+        assert!(ledger.get("key").is_none());
+
+        // a session request committed twice, e.g. retried through another miner, is only applied once
+        let request = ClientCommand::Session {
+            client: 1,
+            sequence: 1,
+            command: Box::new(ClientCommand::Increment {
+                key: "counter".to_string(),
+                by: 1,
+            }),
+        };
+        let transactions = vec![
+            ("1/1".to_string(), request.clone()),
+            ("tx8".to_string(), request),
+        ];
+        let block = Ledger::mine_block("127.0.0.1:6100", block, transactions).await;
+        let ledger = ledger.extend(block).unwrap();
+        assert_eq!("11", &ledger.get("counter").unwrap());
+    }
 }
@@ -1,4 +1,4 @@
-use crate::node::Node;
+use crate::node::{Byzantine, Node};
 /// This module is a binary that listens for TCP connections, runs a node and forwards to it incoming client and peer messages.
 use clap::Parser;
 use lib::network::Receiver;
@@ -23,6 +23,9 @@ struct Cli {
     /// if running as a replica, this is the address of the primary
     #[clap(long, value_parser, value_name = "ADDR")]
     seed: Option<SocketAddr>,
+    /// Run the node with a faulty behavior, to test how the rest of the network copes with it.
+    #[clap(long, value_enum, value_name = "BEHAVIOR")]
+    byzantine: Option<Byzantine>,
 }
 
 #[tokio::main(flavor = "multi_thread")]
@@ -39,16 +42,18 @@ async fn main() {
     let network_address = SocketAddr::new(cli.address, cli.client_port);
     let client_address = SocketAddr::new(cli.address, cli.network_port);
 
-    let (_, network_handle, _) = spawn_node_tasks(network_address, client_address, cli.seed).await;
+    let mut node = Node::new(network_address, cli.seed);
+    node.byzantine = cli.byzantine;
+    let (_, network_handle, _) = spawn_node(network_address, client_address, node).await;
 
     network_handle.await.unwrap();
 }
 
 /// Spawn the client and network receivers and a blockchain node that listents to messages from both.
-async fn spawn_node_tasks(
+async fn spawn_node(
     network_address: SocketAddr,
     client_address: SocketAddr,
-    seed: Option<SocketAddr>,
+    mut node: Node,
 ) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
     // listen for peer network tcp connections
     let (network_tcp_receiver, network_channel_receiver) = Receiver::new(network_address);
@@ -63,7 +68,6 @@ async fn spawn_node_tasks(
     });
 
     // run a task to manage the blockchain node state, listening for messages from client and network
-    let mut node = Node::new(network_address, seed);
     let node_handle = tokio::spawn(async move {
         node.run(network_channel_receiver, client_channel_receiver)
             .await;
@@ -76,6 +80,9 @@ async fn spawn_node_tasks(
 mod tests {
     use super::*;
     use lib::command::ClientCommand;
+    use lib::history::History;
+    use lib::linearizability;
+    use lib::network::simulator::{SimulatedNetwork, SimulatorConfig};
     use tokio_retry::strategy::FixedInterval;
     use tokio_retry::Retry;
 
@@ -86,6 +93,16 @@ mod tests {
         simple_logger::SimpleLogger::new().env().init().unwrap();
     }
 
+    /// Spawn an honest node with the given seed, and the receivers it listens to.
+    async fn spawn_node_tasks(
+        network_address: SocketAddr,
+        client_address: SocketAddr,
+        seed: Option<SocketAddr>,
+    ) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
+        let node = Node::new(network_address, seed);
+        spawn_node(network_address, client_address, node).await
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     #[serial_test::serial]
     async fn single_node() {
@@ -265,6 +282,119 @@ mod tests {
         assert_eventually_equals(client_address3, "k1", "v3").await;
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial_test::serial]
+    async fn simulated_network() {
+        // miners keep the runtime busy so time can't be paused and fast-forwarded like in the tests
+        // of other protocols, and nodes only ask for the state of their seed once when starting, so
+        // the network delays and reorders messages but doesn't drop them
+        let network = SimulatedNetwork::new(SimulatorConfig {
+            reorder: true,
+            ..Default::default()
+        });
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 9121 + i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 9124 + i).parse().unwrap())
+            .collect();
+
+        for (i, (network_address, client_address)) in
+            network_addresses.iter().zip(&client_addresses).enumerate()
+        {
+            let seed = (i > 0).then_some(network_addresses[0]);
+            let mut node = Node::new(*network_address, seed);
+            node.sender = Box::new(network.sender(*network_address));
+            let (_, network_channel) = network.listen(*network_address);
+            let (_, client_channel) = network.listen(*client_address);
+            tokio::spawn(async move {
+                node.run(network_channel, client_channel).await;
+            });
+        }
+
+        let mut client = network.client();
+        ClientCommand::Set {
+            key: "k1".to_string(),
+            value: "v1".to_string(),
+        }
+        .send_with(&mut client, client_addresses[1])
+        .await
+        .unwrap();
+
+        // the longest chain eventually includes the value despite reordered messages
+        for client_address in client_addresses {
+            let retries = FixedInterval::from_millis(100).take(200);
+            Retry::spawn(retries, || {
+                let mut client = client.clone();
+                async move {
+                    let command = ClientCommand::Get {
+                        key: "k1".to_string(),
+                    };
+                    match command.send_with(&mut client, client_address).await {
+                        Ok(Some(value)) if value == "v1" => Ok(()),
+                        _ => Err(()),
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("value was never mined, seed {}", network.seed()));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial_test::serial]
+    async fn linearizable_history() {
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 9127 + i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 9130 + i).parse().unwrap())
+            .collect();
+        for (i, (network_address, client_address)) in
+            network_addresses.iter().zip(&client_addresses).enumerate()
+        {
+            let seed = (i > 0).then_some(network_addresses[0]);
+            spawn_node_tasks(*network_address, *client_address, seed).await;
+        }
+
+        // writes are answered once they are added to the mempool, and each client reads from a
+        // different node, whose chain may be behind the others or later replaced by a longer one
+        let history = History::with_unacknowledged_writes();
+        history
+            .run_clients(3, 6, &["k1", "k2"], |client| client_addresses[client])
+            .await;
+
+        // the chain is only eventually consistent, so violations are reported instead of failing
+        match linearizability::check(&history.operations()) {
+            Ok(()) => log::info!("history is linearizable"),
+            Err(violations) => {
+                for violation in violations {
+                    log::warn!("{violation}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[serial_test::serial]
+    async fn single_node_linearizable_history() {
+        // a single node only ever extends its chain, unlike several nodes whose chains may be replaced
+        // by a longer one, so its history has to be linearizable
+        let network_address: SocketAddr = "127.0.0.1:9133".parse().unwrap();
+        let client_address: SocketAddr = "127.0.0.1:9134".parse().unwrap();
+        spawn_node_tasks(network_address, client_address, None).await;
+
+        // writes are answered once they are added to the mempool, so their outcome is unknown
+        let history = History::with_unacknowledged_writes();
+        history
+            .run_clients(3, 6, &["k1", "k2"], |_| client_address)
+            .await;
+
+        if let Err(violations) = linearizability::check(&history.operations()) {
+            panic!("{}", violations[0]);
+        }
+    }
+
     /// Send Get commands to the given address with delayed retries to give it time for a transaction
     /// to propagate. Fails if the expected value isn't read after 20 seconds.
     async fn assert_eventually_equals(address: SocketAddr, key: &str, value: &str) {
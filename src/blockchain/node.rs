@@ -2,8 +2,9 @@
 /// a ledger of key/value store transactions, as well of the network messages supported between nodes.
 use anyhow::Result;
 use bytes::Bytes;
+use clap::ValueEnum;
 use core::fmt;
-use lib::network::SimpleSender;
+use lib::network::{MessageSender, SimpleSender};
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
@@ -45,7 +46,7 @@ pub struct Node {
     peers: HashSet<SocketAddr>,
 
     /// Network sender to communicate with peers, for example for broadcasting messages.
-    sender: SimpleSender,
+    pub sender: Box<dyn MessageSender>,
 
     /// The pool of pending transactions. The miner task will draw from this pool to include in blocks.
     mempool: HashMap<TransactionId, ClientCommand>,
@@ -62,8 +63,25 @@ pub struct Node {
 
     /// A copy of the sender end of the mining channel, held to pass to each new miner task.
     miner_sender: Sender<Block>,
+
+    /// The faulty behavior of this node, if any, to test how the rest of the network copes with it.
+    pub byzantine: Option<Byzantine>,
+}
+
+/// A faulty behavior a node can be started with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Byzantine {
+    /// Keep the blocks mined or received to itself, never sharing its ledger with the peers.
+    WithholdBlocks,
+    /// Share a ledger extended with blocks that skip the proof of work, committing writes that
+    /// conflict with the ones in its mempool.
+    ForgeLedger,
 }
 
+/// The number of blocks a byzantine node forges on top of its ledger, so it's longer than the ones
+/// of its peers.
+const FORGED_BLOCKS: usize = 10;
+
 use ClientCommand::*;
 use Message::*;
 
@@ -82,12 +100,13 @@ impl Node {
         Self {
             address,
             peers,
-            sender: SimpleSender::new(),
+            sender: Box::new(SimpleSender::new()),
             mempool: HashMap::new(),
             ledger: Ledger::new(),
             miner_task: tokio::spawn(async {}), // noop default
             miner_sender,
             miner_receiver,
+            byzantine: None,
         }
     }
 
@@ -119,8 +138,12 @@ impl Node {
                 Some((command, reply_sender)) = client_receiver.recv() => {
                     info!("Received client message {}", command);
 
-                    // for now generating the uuid here, should we let the client do it?
-                    let txid = uuid::Uuid::new_v4().to_string();
+                    // the requests of a client session are identified by the client, so retries are
+                    // deduplicated; the id of other commands is generated here
+                    let txid = match &command {
+                        Session { client, sequence, .. } => format!("{client}/{sequence}"),
+                        _ => uuid::Uuid::new_v4().to_string(),
+                    };
                     let message = Command(txid, command);
                     let result = self.handle_message(message.clone()).await.map_err(|e|e.to_string());
 
@@ -146,28 +169,35 @@ impl Node {
     /// and peers, updates the local state and broadcasts updates.
     async fn handle_message(&mut self, message: Message) -> Result<Option<String>> {
         match message {
-            // When a client read request is received, just read the local ledger and send a response
-            Command(_, Get { key }) => Ok(self.ledger.get(&key)),
+            // When a client read request is received, just read the local ledger and send a response.
+            // There's no leader to read from, so every consistency level reads the local ledger too
+            Command(_, Get { key } | Read { key, .. }) => Ok(self.ledger.get(&key)),
 
             // When a client write request is received, it needs to be added to the local mempool (so it's included
             // in future blocks mined in this node) and broadcast to the network (so all the nodes eventually know about
             // the transaction and any winning chain includes it).
-            Command(txid, Set { value, key }) => {
+            Command(txid, cmd) => {
+                // just for consistency return the value, although it's not committed
+                let reply = match &cmd {
+                    Set { value, .. } => Some(value.clone()),
+                    Session { command, .. } => match command.as_ref() {
+                        Set { value, .. } => Some(value.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+
                 if self.mempool.contains_key(&txid) || self.ledger.contains(&txid) {
                     debug!("skipping already seen transaction {}", txid);
-                    Ok(None)
+                    // a retried client request gets the same reply as the first time
+                    Ok(reply)
                 } else {
-                    let cmd = Set {
-                        key: key.clone(),
-                        value: value.clone(),
-                    };
                     self.mempool.insert(txid.clone(), cmd.clone());
 
                     let message = Command(txid, cmd);
                     self.broadcast(message).await;
 
-                    // just for consistency return the value, although it's not committed
-                    Ok(Some(value))
+                    Ok(reply)
                 }
             }
 
@@ -176,13 +206,7 @@ impl Node {
                 // save the peer if later user
                 self.peers.insert(reply_to);
 
-                let response = State {
-                    from: self.address,
-                    ledger: self.ledger.clone(),
-                    peers: self.peers.clone(),
-                };
-
-                if let Some(data) = serialize(&response) {
+                if let Some(data) = self.state().as_ref().and_then(serialize) {
                     self.sender.send(reply_to, data).await;
                 }
                 Ok(None)
@@ -228,12 +252,31 @@ impl Node {
         // so we abort it and restart mining based on the latest ledger
         self.restart_miner();
 
-        let message = State {
+        if let Some(message) = self.state() {
+            self.broadcast(message).await;
+        }
+    }
+
+    /// The state message this node shares with its peers, which a byzantine node may withhold or forge.
+    fn state(&self) -> Option<Message> {
+        let ledger = match self.byzantine {
+            None => self.ledger.clone(),
+            Some(Byzantine::WithholdBlocks) => return None,
+            Some(Byzantine::ForgeLedger) => {
+                let transactions = self
+                    .mempool
+                    .iter()
+                    .map(|(txid, cmd)| (txid.clone(), cmd.conflicting()))
+                    .collect();
+                self.ledger
+                    .forge(&self.address.to_string(), transactions, FORGED_BLOCKS)
+            }
+        };
+        Some(State {
             from: self.address,
-            ledger: self.ledger.clone(),
+            ledger,
             peers: self.peers.clone(),
-        };
-        self.broadcast(message).await;
+        })
     }
 
     /// Abort the currently running miner task and start a new one based on the latest ledger and mempool.
@@ -295,6 +338,8 @@ fn serialize<T: Serialize + fmt::Debug>(message: &T) -> Option<Bytes> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use lib::network::simulator::{SimulatedNetwork, SimulatorConfig};
+    use tokio::time::Duration;
 
     #[tokio::test(flavor = "multi_thread")]
     async fn transactions() {
@@ -417,4 +462,60 @@ mod tests {
             node1.ledger.blocks.last().unwrap().miner_id
         );
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn byzantine_ledgers() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let address1: SocketAddr = "127.0.0.1:9133".parse().unwrap();
+        let address2: SocketAddr = "127.0.0.1:9134".parse().unwrap();
+        let (_, mut channel1) = network.listen::<Message, String>(address1);
+        let mut node1 = Node::new(address1, None);
+
+        // a node withholding its blocks doesn't share the ledger they extend, not even when asked
+        let mut node2 = Node::new(address2, Some(address1));
+        node2.sender = Box::new(network.sender(address2));
+        node2.byzantine = Some(Byzantine::WithholdBlocks);
+        node2.restart_miner();
+        let block = node2.miner_receiver.recv().await.unwrap();
+        let new_ledger = node2.ledger.extend(block).unwrap();
+        node2.update_ledger(new_ledger).await;
+        let get_state = GetState { reply_to: address1 };
+        node2.handle_message(get_state.clone()).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_millis(500), channel1.recv()).await;
+        assert!(received.is_err());
+        assert_eq!(2, node2.ledger.blocks.len());
+
+        // a node forging a longer ledger with a conflicting write
+        node2.byzantine = Some(Byzantine::ForgeLedger);
+        let tx1 = Command(
+            "tx1".to_string(),
+            ClientCommand::Set {
+                key: "key".to_string(),
+                value: "value".to_string(),
+            },
+        );
+        node2.handle_message(tx1.clone()).await.unwrap();
+        node2.handle_message(get_state).await.unwrap();
+        let mut messages = Vec::new();
+        for _ in 0..2 {
+            let (message, reply_sender) = channel1.recv().await.unwrap();
+            let _ = reply_sender.send("ACK".to_string());
+            messages.push(message);
+        }
+        let forged = messages
+            .iter()
+            .find(|message| matches!(message, State { .. }))
+            .unwrap()
+            .clone();
+        if let State { ledger, .. } = &forged {
+            assert_eq!(2 + FORGED_BLOCKS, ledger.blocks.len());
+            assert_eq!(Some("value'".to_string()), ledger.get("key"));
+        }
+
+        // is caught by the proof of work check, and the honest node keeps its ledger
+        node1.handle_message(tx1).await.unwrap();
+        node1.handle_message(forged).await.unwrap();
+        assert_eq!(1, node1.ledger.blocks.len());
+        assert!(node1.mempool.contains_key("tx1"));
+    }
 }
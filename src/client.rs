@@ -1,6 +1,7 @@
 use anyhow::Result;
-use clap::Parser;
-use lib::command;
+use clap::{Parser, ValueEnum};
+use lib::cluster::ClusterClient;
+use lib::command::{self, ClientCommand, Consistency};
 use log::{error, info};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
@@ -20,6 +21,33 @@ struct Cli {
     /// The network address of the node where to send txs.
     #[clap(short, long, value_parser, value_name = "INT", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
     address: IpAddr,
+
+    /// The client addresses of every node of the cluster. The command is sent to the leader, found
+    /// through the redirects of the other nodes, instead of the node given by `--address` and `--port`.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "ADDR",
+        use_value_delimiter = true,
+        value_delimiter = ','
+    )]
+    nodes: Vec<SocketAddr>,
+
+    /// How up to date the value of a `get` must be. By default the node that gets it reads its local state.
+    #[clap(long, value_enum)]
+    consistency: Option<ReadConsistency>,
+
+    /// How many milliseconds behind the leader the node serving a `stale` read can be.
+    #[clap(long, value_name = "MS", default_value_t = 1000)]
+    max_staleness: u64,
+}
+
+/// The consistency levels of `lib::command::Consistency`, as passed in the command line.
+#[derive(Clone, Copy, ValueEnum)]
+enum ReadConsistency {
+    Linearizable,
+    Lease,
+    Stale,
 }
 
 #[tokio::main]
@@ -29,9 +57,26 @@ async fn main() -> Result<()> {
 
     simple_logger::SimpleLogger::new().env().init()?;
 
-    // using a reliable sender to get a response back
-    let address = SocketAddr::new(cli.address, cli.port);
-    match cli.command.send_to(address).await {
+    // a single node may still redirect the commands it can't serve to the leader it knows of
+    let nodes = if cli.nodes.is_empty() {
+        vec![SocketAddr::new(cli.address, cli.port)]
+    } else {
+        cli.nodes
+    };
+    let command = match (cli.command, cli.consistency) {
+        (ClientCommand::Get { key }, Some(level)) => ClientCommand::Read {
+            key,
+            consistency: match level {
+                ReadConsistency::Linearizable => Consistency::Linearizable,
+                ReadConsistency::Lease => Consistency::Lease,
+                ReadConsistency::Stale => Consistency::Stale {
+                    max_staleness_ms: cli.max_staleness,
+                },
+            },
+        },
+        (command, _) => command,
+    };
+    match ClusterClient::new(nodes).send(command).await {
         Ok(Some(value)) => info!("{}", value),
         Ok(None) => info!("null"),
         Err(error) => error!("ERROR {}", error),
@@ -0,0 +1,287 @@
+/// A client of a replicated key/value store that knows every node of the cluster, instead of a single
+/// address. It sends the commands to the node it believes is the leader, learns which one that is
+/// from the redirects of the other nodes (for the nodes that redirect their clients), and moves on to the next node with an increasing backoff when
+/// one doesn't reply in time. Writes are sent as requests of a client session, so the nodes can tell
+/// a retry from a new command and apply each write exactly once, even across failovers.
+use crate::command::{ClientCommand, CommandResult};
+use crate::network::{ReliableSender, RequestSender};
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::time::{sleep, timeout, Duration};
+
+/// The reply of a node to a client command: the result of the command, or, for a command only the leader
+/// can serve, the client address of the leader if the node knows it. The `Ok` and `Err` variants are
+/// serialized like those of a `CommandResult`, so clients read the replies of the nodes that answer with a
+/// `CommandResult` the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientReply {
+    Ok(Option<String>),
+    Err(String),
+    Redirect(Option<SocketAddr>),
+}
+
+impl ClientReply {
+    /// The result of the command for a client that doesn't follow redirects, to which a redirect is an
+    /// error naming the leader.
+    pub fn into_result(self) -> CommandResult {
+        match self {
+            ClientReply::Ok(value) => Ok(value),
+            ClientReply::Err(error) => Err(error),
+            ClientReply::Redirect(leader) => Err(NotLeader(leader).to_string()),
+        }
+    }
+}
+
+impl From<CommandResult> for ClientReply {
+    fn from(result: CommandResult) -> Self {
+        match result {
+            Ok(value) => ClientReply::Ok(value),
+            Err(error) => ClientReply::Err(error),
+        }
+    }
+}
+
+impl From<Result<Option<String>>> for ClientReply {
+    /// A `NotLeader` error becomes a redirect, and any other error is sent as is.
+    fn from(result: Result<Option<String>>) -> Self {
+        match result {
+            Ok(value) => ClientReply::Ok(value),
+            Err(error) => match error.downcast_ref::<NotLeader>() {
+                Some(NotLeader(leader)) => ClientReply::Redirect(*leader),
+                None => ClientReply::Err(error.to_string()),
+            },
+        }
+    }
+}
+
+/// The error of a node asked to run a command only the leader can serve, with the client address of the
+/// leader if the node knows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotLeader(pub Option<SocketAddr>);
+
+impl fmt::Display for NotLeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(leader) => write!(f, "not the leader, try {leader}"),
+            None => write!(f, "not the leader"),
+        }
+    }
+}
+
+impl std::error::Error for NotLeader {}
+
+pub struct ClusterClient {
+    /// The client addresses of the nodes, including the leaders learned from redirects.
+    nodes: Vec<SocketAddr>,
+    /// The index of the node the commands are sent to.
+    leader: usize,
+    sender: Box<dyn RequestSender>,
+    /// The random id of the session of this client.
+    client: u64,
+    /// The sequence number of the last write sent in the session.
+    sequence: u64,
+    /// How long to wait for the reply of a node before trying the next one.
+    pub timeout: Duration,
+    /// The number of times a command is sent before giving up.
+    pub max_attempts: usize,
+    /// The wait before the first retry, doubled after each one up to `max_backoff`.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ClusterClient {
+    pub fn new(nodes: Vec<SocketAddr>) -> Self {
+        Self::with_sender(nodes, Box::new(ReliableSender::new()))
+    }
+
+    /// A client sending the commands through the given sender, e.g. one of a simulated network.
+    pub fn with_sender(nodes: Vec<SocketAddr>, sender: Box<dyn RequestSender>) -> Self {
+        assert!(!nodes.is_empty(), "a cluster needs at least one node");
+        Self {
+            nodes,
+            leader: 0,
+            sender,
+            client: rand::random(),
+            sequence: 0,
+            timeout: Duration::from_secs(1),
+            max_attempts: 10,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+
+    /// The node the next command will be sent to.
+    pub fn leader(&self) -> SocketAddr {
+        self.nodes[self.leader]
+    }
+
+    /// Send the command to the leader and return its response, following redirects and retrying on the
+    /// next node when one doesn't reply. Every retry of a write is sent with the same sequence number.
+    pub async fn send(&mut self, command: ClientCommand) -> Result<Option<String>> {
+        let command = if command.is_write() {
+            self.sequence += 1;
+            ClientCommand::Session {
+                client: self.client,
+                sequence: self.sequence,
+                command: Box::new(command),
+            }
+        } else {
+            command
+        };
+        let data: Bytes = bincode::serialize(&command)?.into();
+        let mut backoff = self.backoff;
+
+        for _ in 0..self.max_attempts {
+            let address = self.leader();
+            let reply_handler = self.sender.send(address, data.clone()).await;
+            match timeout(self.timeout, reply_handler).await {
+                Ok(Ok(reply)) => match bincode::deserialize(&reply)? {
+                    ClientReply::Redirect(Some(leader)) => {
+                        info!("{} redirected the command to {}", address, leader);
+                        self.follow(leader);
+                        // the named leader is tried right away
+                        continue;
+                    }
+                    // the node doesn't know the leader either, e.g. during a view change
+                    ClientReply::Redirect(None) => info!("{} is not the leader", address),
+                    reply => return reply.into_result().map_err(|e| anyhow!(e)),
+                },
+                _ => warn!("{} didn't reply, trying the next node", address),
+            }
+
+            self.leader = (self.leader + 1) % self.nodes.len();
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+        bail!("no leader replied after {} attempts", self.max_attempts)
+    }
+
+    /// Send the next commands to the given leader, which is added to the nodes if it wasn't known.
+    fn follow(&mut self, leader: SocketAddr) {
+        self.leader = match self.nodes.iter().position(|node| *node == leader) {
+            Some(index) => index,
+            None => {
+                self.nodes.push(leader);
+                self.nodes.len() - 1
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::simulator::{SimulatedNetwork, SimulatorConfig};
+
+    /// Run a fake node that answers every command with the given reply.
+    fn reply_with(network: &SimulatedNetwork, address: SocketAddr, reply: ClientReply) {
+        let (_, mut channel) = network.listen::<ClientCommand, ClientReply>(address);
+        tokio::spawn(async move {
+            while let Some((_, reply_sender)) = channel.recv().await {
+                let _ = reply_sender.send(reply.clone());
+            }
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follow_redirects() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let nodes: Vec<SocketAddr> = (0..4)
+            .map(|i| format!("127.0.0.1:{}", 7700 + i).parse().unwrap())
+            .collect();
+        let leader: SocketAddr = "127.0.0.1:7704".parse().unwrap();
+
+        // the first node never replies, the second doesn't know the leader and the third names it,
+        // though it's not one of the nodes the client started with
+        let (_, _silent) = network.listen::<ClientCommand, CommandResult>(nodes[0]);
+        reply_with(&network, nodes[1], ClientReply::Redirect(None));
+        reply_with(&network, nodes[2], ClientReply::Redirect(Some(leader)));
+        reply_with(&network, leader, ClientReply::Ok(Some("v1".to_string())));
+
+        let mut client = ClusterClient::with_sender(nodes.clone(), Box::new(network.client()));
+        let command = ClientCommand::Set {
+            key: "k1".to_string(),
+            value: "v1".to_string(),
+        };
+        let reply = client.send(command.clone()).await.unwrap();
+        assert_eq!(Some("v1".to_string()), reply);
+        assert_eq!(leader, client.leader());
+
+        // other errors are returned to the caller without retrying
+        reply_with(&network, nodes[3], ClientReply::Err("rejected".to_string()));
+        let mut client = ClusterClient::with_sender(vec![nodes[3]], Box::new(network.client()));
+        let reply = client.send(command.clone()).await;
+        assert_eq!("rejected", reply.unwrap_err().to_string());
+
+        // and the client gives up when no node replies
+        let mut client = ClusterClient::with_sender(vec![nodes[0]], Box::new(network.client()));
+        client.max_attempts = 3;
+        assert!(client.send(command).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_reuse_sequence() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let nodes: Vec<SocketAddr> = (5..7)
+            .map(|i| format!("127.0.0.1:{}", 7700 + i).parse().unwrap())
+            .collect();
+
+        // the first node applies the write but its reply is lost, so the client retries on the second
+        let (_, mut lost) = network.listen::<ClientCommand, CommandResult>(nodes[0]);
+        let (_, mut replied) = network.listen::<ClientCommand, CommandResult>(nodes[1]);
+        let (received_sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some((command, reply_sender)) = replied.recv().await {
+                let _ = received_sender.send(command);
+                let _ = reply_sender.send(Ok(None));
+            }
+        });
+
+        let mut client = ClusterClient::with_sender(nodes, Box::new(network.client()));
+        let set = ClientCommand::Set {
+            key: "k1".to_string(),
+            value: "v1".to_string(),
+        };
+        client.send(set.clone()).await.unwrap();
+        let (first, _) = lost.recv().await.unwrap();
+        assert!(matches!(first, ClientCommand::Session { sequence: 1, .. }));
+        assert_eq!(first, received.recv().await.unwrap());
+
+        // the next write starts a new request of the same session, and reads are sent as they are
+        client.send(set).await.unwrap();
+        let second = received.recv().await.unwrap();
+        assert!(matches!(second, ClientCommand::Session { sequence: 2, .. }));
+        let get = ClientCommand::Get {
+            key: "k1".to_string(),
+        };
+        client.send(get.clone()).await.unwrap();
+        assert_eq!(get, received.recv().await.unwrap());
+    }
+
+    #[test]
+    fn replies_read_as_results() {
+        // clients that expect a plain result read the replies of the nodes that may redirect them
+        let result: CommandResult = Ok(Some("v1".to_string()));
+        let reply = bincode::serialize(&ClientReply::from(result.clone())).unwrap();
+        assert_eq!(bincode::serialize(&result).unwrap(), reply);
+        let result: CommandResult = Err("rejected".to_string());
+        let reply = bincode::serialize(&ClientReply::from(result.clone())).unwrap();
+        assert_eq!(
+            result,
+            bincode::deserialize::<CommandResult>(&reply).unwrap()
+        );
+
+        // a redirect is an error naming the leader for them
+        let leader: SocketAddr = "127.0.0.1:6100".parse().unwrap();
+        let reply = ClientReply::from(Err(anyhow!(NotLeader(Some(leader)))));
+        assert_eq!(ClientReply::Redirect(Some(leader)), reply);
+        assert_eq!(
+            Err("not the leader, try 127.0.0.1:6100".to_string()),
+            reply.into_result()
+        );
+    }
+}
@@ -1,6 +1,9 @@
-use crate::network::ReliableSender;
+use crate::cluster::ClientReply;
+use crate::network::{ReliableSender, RequestSender};
+use crate::store::Store;
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 
 use clap::Parser;
@@ -9,26 +12,423 @@ use std::net::SocketAddr;
 
 pub type CommandResult = Result<Option<String>, String>;
 
+/// A view of the key/value state commands are executed against: maps each key to its current
+/// value, where `None` means the key is missing.
+pub type KeyValues = BTreeMap<String, Option<String>>;
+
+/// Store keys to write (`Some`) or delete (`None`) in a single batch.
+pub type Changes = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// The prefix of the store keys where the last request of each client session and its reply are kept,
+/// followed by the big-endian client id. Client keys are valid UTF-8, where the 0xff byte never appears.
+pub const SESSION_PREFIX: &[u8] = b"\xffsession/";
+
+/// The prefix of the errors of the commands the key/value state rejects, e.g. a failed compare-and-swap.
+/// Unlike the errors of the nodes or the network, they mean the command certainly didn't change the store.
+const REJECTED_PREFIX: &str = "rejected: ";
+
 #[derive(Debug, Serialize, Deserialize, Parser, Clone, PartialEq, Eq)]
 #[clap()]
 pub enum ClientCommand {
     // user-generated commands
-    Set { key: String, value: String },
-    Get { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Delete {
+        key: String,
+    },
+    /// Set the key to `new` only if its current value is `expected`.
+    #[clap(alias = "cas")]
+    CompareAndSwap {
+        key: String,
+        expected: String,
+        new: String,
+    },
+    /// Add `by` to the integer value of the key. Missing keys count as zero.
+    #[clap(alias = "incr")]
+    Increment {
+        key: String,
+        #[clap(allow_negative_numbers = true, default_value_t = 1)]
+        by: i64,
+    },
+    /// Run the given commands as a single atomic unit: either all of them are applied or none is.
+    /// From the command line each command is passed as a quoted string, e.g. `batch "set a 1" "delete b"`,
+    /// where values with spaces are quoted again, e.g. `batch "set greeting 'hello world'"`.
+    Batch {
+        #[clap(value_parser = parse_batch_command, required = true)]
+        commands: Vec<ClientCommand>,
+    },
+    /// A command sent as the request with the given sequence number of a client session. Nodes that
+    /// apply it to a store run it once, and answer the retries of the last request of the client with
+    /// the reply they cached, so a retried write isn't applied twice.
+    #[clap(skip)]
+    Session {
+        client: u64,
+        sequence: u64,
+        command: Box<ClientCommand>,
+    },
+    /// A `Get` that states how up to date its value must be. Nodes that don't distinguish consistency
+    /// levels serve it as a `Get`.
+    #[clap(skip)]
+    Read {
+        key: String,
+        consistency: Consistency,
+    },
+}
+
+/// How up to date the value returned by a `Read` must be.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Consistency {
+    /// Ordered by the leader along with the writes, so it observes every write acknowledged before it.
+    Linearizable,
+    /// Served by the leader from its local state while it holds a lease, during which no other node
+    /// can take over, assuming bounded message delays. Without a lease it's served as linearizable.
+    Lease,
+    /// Served by any node from its local state, as long as it's at most the given milliseconds behind
+    /// the leader.
+    Stale { max_staleness_ms: u64 },
 }
 
+use ClientCommand::*;
+
 impl ClientCommand {
     /// Send this command over to a server at the given address and return the response.
     pub async fn send_to(self, address: SocketAddr) -> Result<Option<String>> {
-        let mut sender = ReliableSender::new();
+        self.send_with(&mut ReliableSender::new(), address).await
+    }
 
+    /// Send this command over to a server at the given address through the given sender, e.g. one
+    /// of a simulated network, and return the response.
+    pub async fn send_with(
+        self,
+        sender: &mut dyn RequestSender,
+        address: SocketAddr,
+    ) -> Result<Option<String>> {
         let message: Bytes = bincode::serialize(&(self))?.into();
         let reply_handler = sender.send(address, message).await;
 
+        // the nodes that redirect their clients name the leader in the error
         let response = reply_handler.await?;
-        let response: CommandResult = bincode::deserialize(&response)?;
-        response.map_err(|e| anyhow!(e))
+        let response: ClientReply = bincode::deserialize(&response)?;
+        response.into_result().map_err(|e| anyhow!(e))
+    }
+
+    /// The keys this command reads or writes.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Batch { commands } => {
+                let mut keys: Vec<&str> = commands.iter().flat_map(|c| c.keys()).collect();
+                keys.sort_unstable();
+                keys.dedup();
+                keys
+            }
+            Session { command, .. } => command.keys(),
+            command => command.key().into_iter().collect(),
+        }
+    }
+
+    /// The key of a single-key command, `None` for batches.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Set { key, .. }
+            | Get { key }
+            | Delete { key }
+            | CompareAndSwap { key, .. }
+            | Increment { key, .. }
+            | Read { key, .. } => Some(key),
+            Session { command, .. } => command.key(),
+            Batch { .. } => None,
+        }
+    }
+
+    /// Returns true if applying this command may change the value of some key.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Get { .. } | Read { .. } => false,
+            Batch { commands } => commands.iter().any(|c| c.is_write()),
+            Session { command, .. } => command.is_write(),
+            _ => true,
+        }
+    }
+
+    /// A write that conflicts with this command, setting its first key to a different value. Byzantine
+    /// nodes use it to equivocate.
+    pub fn conflicting(&self) -> ClientCommand {
+        match self {
+            Set { key, value } => Set {
+                key: key.clone(),
+                value: format!("{value}'"),
+            },
+            command => Set {
+                key: command.keys().first().unwrap_or(&"").to_string(),
+                value: "byzantine".to_string(),
+            },
+        }
+    }
+
+    /// Compute the value the key of a single-key command should hold after running it, given its
+    /// current value. An error means the command is rejected and the key is left untouched.
+    fn evaluate(&self, current: Option<String>) -> CommandResult {
+        match self {
+            Get { .. } | Read { .. } => Ok(current),
+            Set { value, .. } => Ok(Some(value.clone())),
+            Delete { .. } => Ok(None),
+            CompareAndSwap { key, expected, new } => {
+                if current.as_ref() == Some(expected) {
+                    Ok(Some(new.clone()))
+                } else {
+                    Err(rejected(format!(
+                        "compare-and-swap failed for key {key}: expected {expected:?}, found {current:?}"
+                    )))
+                }
+            }
+            Increment { key, by } => {
+                let current = match current {
+                    Some(value) => value.parse::<i64>().map_err(|_| {
+                        rejected(format!("value of key {key} is not an integer: {value:?}"))
+                    })?,
+                    None => 0,
+                };
+                current
+                    .checked_add(*by)
+                    .map(|value| Some(value.to_string()))
+                    .ok_or_else(|| rejected(format!("increment of key {key} overflows")))
+            }
+            Batch { .. } => Err("batch commands span several keys".to_string()),
+            Session { command, .. } => command.evaluate(current),
+        }
+    }
+
+    /// Run this command against the given key/value state, updating it in place, and return its result.
+    /// This is a pure function so every replica that executes the same sequence of commands ends up
+    /// in the same state. An error means the command is rejected and the state is left untouched;
+    /// for batches this happens if any of the inner commands is rejected.
+    pub fn execute(&self, state: &mut KeyValues) -> CommandResult {
+        match self {
+            Batch { commands } => {
+                let mut scratch = state.clone();
+                let mut results = Vec::new();
+                for command in commands {
+                    results.push(command.execute(&mut scratch)?);
+                }
+                *state = scratch;
+                Ok(Some(format_batch_results(results)))
+            }
+            Session { command, .. } => command.execute(state),
+            command => {
+                let key = command.key().unwrap_or_default().to_string();
+                let current = state.get(&key).cloned().flatten();
+                let value = command.evaluate(current)?;
+                if command.is_write() {
+                    state.insert(key, value.clone());
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    /// Run this command against the given store and return its result.
+    /// All the writes of the command are applied to the store in a single atomic batch.
+    /// The outer error is reserved for store failures, while the inner `CommandResult` error
+    /// means the command was deterministically rejected (e.g. a failed compare-and-swap), which
+    /// replicas should treat as a no-op rather than a fault.
+    /// Commands sent in a session are only run once: the reply to the last request of each client is
+    /// written along with the changes of the command, and returned again if the request is retried.
+    pub async fn apply(&self, store: &Store) -> Result<CommandResult> {
+        self.apply_with(store, Vec::new()).await
+    }
+
+    /// Run this command against the given store like `apply`, writing the given keys (e.g. the index
+    /// of the last applied command) in the same batch as the changes of the command.
+    pub async fn apply_with(&self, store: &Store, mut writes: Changes) -> Result<CommandResult> {
+        let (result, changes) = self.mutations(store).await?;
+        writes.extend(changes);
+        if !writes.is_empty() {
+            store.write_batch(writes).await?;
+        }
+        Ok(result)
+    }
+
+    /// Run this command against the given store without writing to it, and return its result along with
+    /// the changes that apply it, to be written in a single batch. For session requests these include the
+    /// reply of the request, and a request that was already run gets its reply again with no changes.
+    pub async fn mutations(&self, store: &Store) -> Result<(CommandResult, Changes)> {
+        let Session {
+            client,
+            sequence,
+            command,
+        } = self
+        else {
+            return self.changes(store).await;
+        };
+        if let Some(reply) = self.session_reply(store).await? {
+            return Ok((reply, Vec::new()));
+        }
+
+        let (result, mut changes) = command.changes(store).await?;
+        changes.push((
+            session_key(*client),
+            Some(bincode::serialize(&(*sequence, &result))?),
+        ));
+        Ok((result, changes))
+    }
+
+    /// If this is a session request that was already answered, its reply according to the given store.
+    /// Nodes that log or replicate the commands they run check it first, so a retry isn't logged again.
+    pub async fn session_reply(&self, store: &Store) -> Result<Option<CommandResult>> {
+        let Session {
+            client, sequence, ..
+        } = self
+        else {
+            return Ok(None);
+        };
+        match store.read(session_key(*client)).await? {
+            Some(entry) => {
+                let (last, reply): (u64, CommandResult) = bincode::deserialize(&entry)?;
+                Ok(answered(*client, *sequence, last, reply))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Run this command against the keys it reads from the given store, and return its result along
+    /// with the keys it changes.
+    async fn changes(&self, store: &Store) -> Result<(CommandResult, Changes)> {
+        let mut state = KeyValues::new();
+        for key in self.keys() {
+            let value = match store.read(key.into()).await? {
+                Some(value) => Some(String::from_utf8(value)?),
+                None => None,
+            };
+            state.insert(key.to_string(), value);
+        }
+
+        let previous = state.clone();
+        let result = self.execute(&mut state);
+
+        let changes: Vec<_> = state
+            .into_iter()
+            .filter(|(key, value)| previous.get(key) != Some(value))
+            .map(|(key, value)| (key.into_bytes(), value.map(String::into_bytes)))
+            .collect();
+
+        Ok((result, changes))
+    }
+}
+
+/// The last request of each client session and its reply, for the nodes that run commands against
+/// an in-memory state instead of a store.
+#[derive(Debug, Clone, Default)]
+pub struct Sessions(HashMap<u64, (u64, CommandResult)>);
+
+impl Sessions {
+    /// The reply to the given command if it's a session request that was already run: the cached
+    /// reply of the last request of its client, or an error for an older one.
+    pub fn reply(&self, command: &ClientCommand) -> Option<CommandResult> {
+        let Session {
+            client, sequence, ..
+        } = command
+        else {
+            return None;
+        };
+        let (last, reply) = self.0.get(client)?;
+        answered(*client, *sequence, *last, reply.clone())
+    }
+
+    /// Run the command against the given state like `ClientCommand::execute`, except for session
+    /// requests that were already run, which get their reply again without changing the state.
+    pub fn execute(&mut self, command: &ClientCommand, state: &mut KeyValues) -> CommandResult {
+        if let Some(reply) = self.reply(command) {
+            return reply;
+        }
+        let result = command.execute(state);
+        if let Session {
+            client, sequence, ..
+        } = command
+        {
+            self.0.insert(*client, (*sequence, result.clone()));
+        }
+        result
+    }
+}
+
+/// The reply to request `sequence` of a client whose last request was `last`, answered with `reply`,
+/// if the request was already answered.
+fn answered(client: u64, sequence: u64, last: u64, reply: CommandResult) -> Option<CommandResult> {
+    if sequence == last {
+        Some(reply)
+    } else if sequence < last {
+        Some(Err(format!(
+            "request {sequence} of client {client} was already answered"
+        )))
+    } else {
+        None
+    }
+}
+
+/// The error of a command the key/value state rejects for the given reason.
+fn rejected(reason: String) -> String {
+    format!("{REJECTED_PREFIX}{reason}")
+}
+
+/// Whether the given error a client got for a command means the key/value state rejected it, so it didn't
+/// take effect, rather than a node or network failure after which it may have.
+pub fn is_rejection(error: &str) -> bool {
+    error.starts_with(REJECTED_PREFIX)
+}
+
+/// The store key of the session of the given client.
+fn session_key(client: u64) -> Vec<u8> {
+    [SESSION_PREFIX, &client.to_be_bytes()].concat()
+}
+
+/// Format the results of the commands of a batch as the single reply of the batch, e.g. "[1, null]".
+pub fn format_batch_results(results: Vec<Option<String>>) -> String {
+    let results: Vec<String> = results
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| "null".to_string()))
+        .collect();
+    format!("[{}]", results.join(", "))
+}
+
+/// Parse a single command of a batch from its command line representation, e.g. "set key value".
+fn parse_batch_command(command: &str) -> Result<ClientCommand, String> {
+    let args = std::iter::once("batch".to_string()).chain(split_words(command)?);
+    ClientCommand::try_parse_from(args).map_err(|e| e.to_string())
+}
+
+/// Split a command line into its whitespace separated words like a shell does: single or double quotes
+/// group the words in between, and a backslash escapes the next character.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (_, '\\') => {
+                let escaped = chars.next().ok_or("trailing backslash")?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
     }
+    words.extend(word);
+    Ok(words)
 }
 
 impl fmt::Display for ClientCommand {
@@ -36,3 +436,279 @@ impl fmt::Display for ClientCommand {
         write!(f, "{self:?}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn evaluate_commands() {
+        let set = Set {
+            key: "k".to_string(),
+            value: "v".to_string(),
+        };
+        assert_eq!(Ok(Some("v".to_string())), set.evaluate(None));
+
+        let delete = Delete {
+            key: "k".to_string(),
+        };
+        assert_eq!(Ok(None), delete.evaluate(Some("v".to_string())));
+
+        let cas = CompareAndSwap {
+            key: "k".to_string(),
+            expected: "v".to_string(),
+            new: "v2".to_string(),
+        };
+        assert_eq!(
+            Ok(Some("v2".to_string())),
+            cas.evaluate(Some("v".to_string()))
+        );
+        assert!(cas.evaluate(Some("other".to_string())).is_err());
+        assert!(cas.evaluate(None).is_err());
+
+        let increment = Increment {
+            key: "k".to_string(),
+            by: 5,
+        };
+        assert_eq!(Ok(Some("5".to_string())), increment.evaluate(None));
+        assert_eq!(
+            Ok(Some("2".to_string())),
+            increment.evaluate(Some("-3".to_string()))
+        );
+        assert!(increment.evaluate(Some("v".to_string())).is_err());
+        assert!(increment.evaluate(Some(i64::MAX.to_string())).is_err());
+    }
+
+    #[test]
+    fn execute_batch() {
+        let mut state = KeyValues::new();
+        state.insert("a".to_string(), Some("10".to_string()));
+
+        let transfer = Batch {
+            commands: vec![
+                CompareAndSwap {
+                    key: "a".to_string(),
+                    expected: "10".to_string(),
+                    new: "7".to_string(),
+                },
+                Increment {
+                    key: "b".to_string(),
+                    by: 3,
+                },
+                Get {
+                    key: "c".to_string(),
+                },
+            ],
+        };
+        assert_eq!(
+            Ok(Some("[7, 3, null]".to_string())),
+            transfer.execute(&mut state)
+        );
+        assert_eq!(Some(&Some("7".to_string())), state.get("a"));
+        assert_eq!(Some(&Some("3".to_string())), state.get("b"));
+        assert!(!state.contains_key("c"));
+
+        // running it again fails on the compare-and-swap, so b is not incremented either
+        assert!(transfer.execute(&mut state).is_err());
+        assert_eq!(Some(&Some("7".to_string())), state.get("a"));
+        assert_eq!(Some(&Some("3".to_string())), state.get("b"));
+    }
+
+    #[test]
+    fn parse_batch() {
+        let command = ClientCommand::try_parse_from([
+            "client",
+            "batch",
+            "set a 1",
+            "increment b -1",
+            "delete c",
+        ])
+        .unwrap();
+        let expected = Batch {
+            commands: vec![
+                Set {
+                    key: "a".to_string(),
+                    value: "1".to_string(),
+                },
+                Increment {
+                    key: "b".to_string(),
+                    by: -1,
+                },
+                Delete {
+                    key: "c".to_string(),
+                },
+            ],
+        };
+        assert_eq!(expected, command);
+
+        // quoted values can have spaces
+        let command = parse_batch_command(r#"set greeting 'hello "world"'"#).unwrap();
+        let expected = Set {
+            key: "greeting".to_string(),
+            value: "hello \"world\"".to_string(),
+        };
+        assert_eq!(expected, command);
+        let command = parse_batch_command(r"set empty '' ").unwrap();
+        let expected = Set {
+            key: "empty".to_string(),
+            value: String::new(),
+        };
+        assert_eq!(expected, command);
+        let command = parse_batch_command(r"set path a\ b").unwrap();
+        let expected = Set {
+            key: "path".to_string(),
+            value: "a b".to_string(),
+        };
+        assert_eq!(expected, command);
+        assert!(parse_batch_command("set greeting 'hello").is_err());
+    }
+
+    #[tokio::test]
+    async fn apply_commands() {
+        let path = ".db_test_apply_commands";
+        let _ = fs::remove_dir_all(path);
+        let store = Store::new(path).unwrap();
+        let key = "k".to_string();
+
+        let result = Increment {
+            key: key.clone(),
+            by: 2,
+        }
+        .apply(&store)
+        .await
+        .unwrap();
+        assert_eq!(Ok(Some("2".to_string())), result);
+
+        // a rejected command doesn't change the store
+        let result = CompareAndSwap {
+            key: key.clone(),
+            expected: "3".to_string(),
+            new: "10".to_string(),
+        }
+        .apply(&store)
+        .await
+        .unwrap();
+        assert!(result.is_err());
+
+        let result = Get { key: key.clone() }.apply(&store).await.unwrap();
+        assert_eq!(Ok(Some("2".to_string())), result);
+
+        let result = Delete { key: key.clone() }.apply(&store).await.unwrap();
+        assert_eq!(Ok(None), result);
+
+        let result = Get { key: key.clone() }.apply(&store).await.unwrap();
+        assert_eq!(Ok(None), result);
+
+        // a rejected batch doesn't write any of its keys
+        let result = Batch {
+            commands: vec![
+                Set {
+                    key: "other".to_string(),
+                    value: "v".to_string(),
+                },
+                Increment {
+                    key: key.clone(),
+                    by: i64::MAX,
+                },
+                Increment {
+                    key: key.clone(),
+                    by: 1,
+                },
+            ],
+        }
+        .apply(&store)
+        .await
+        .unwrap();
+        assert!(result.is_err());
+
+        let result = Get { key }.apply(&store).await.unwrap();
+        assert_eq!(Ok(None), result);
+        let result = Get {
+            key: "other".to_string(),
+        }
+        .apply(&store)
+        .await
+        .unwrap();
+        assert_eq!(Ok(None), result);
+    }
+
+    #[tokio::test]
+    async fn apply_session_commands() {
+        let path = ".db_test_apply_session_commands";
+        let _ = fs::remove_dir_all(path);
+        let store = Store::new(path).unwrap();
+        let request = |sequence| Session {
+            client: 1,
+            sequence,
+            command: Box::new(Increment {
+                key: "k".to_string(),
+                by: 1,
+            }),
+        };
+
+        assert_eq!(
+            Ok(Some("1".to_string())),
+            request(1).apply(&store).await.unwrap()
+        );
+
+        // a retry gets the cached reply without incrementing again
+        assert_eq!(
+            Ok(Some("1".to_string())),
+            request(1).apply(&store).await.unwrap()
+        );
+        assert_eq!(
+            Ok(Some("2".to_string())),
+            request(2).apply(&store).await.unwrap()
+        );
+
+        // the replies to older requests are no longer cached
+        assert!(request(1).apply(&store).await.unwrap().is_err());
+
+        // sessions of other clients are independent
+        let other = Session {
+            client: 2,
+            sequence: 1,
+            command: Box::new(Get {
+                key: "k".to_string(),
+            }),
+        };
+        assert_eq!(
+            Ok(Some("2".to_string())),
+            other.apply(&store).await.unwrap()
+        );
+    }
+
+    #[test]
+    fn execute_session_commands() {
+        let mut state = KeyValues::new();
+        let mut sessions = Sessions::default();
+        let request = |sequence| Session {
+            client: 1,
+            sequence,
+            command: Box::new(Increment {
+                key: "k".to_string(),
+                by: 1,
+            }),
+        };
+
+        assert_eq!(None, sessions.reply(&request(1)));
+        assert_eq!(
+            Ok(Some("1".to_string())),
+            sessions.execute(&request(1), &mut state)
+        );
+
+        // a retry gets the cached reply without incrementing again
+        assert_eq!(Some(Ok(Some("1".to_string()))), sessions.reply(&request(1)));
+        assert_eq!(
+            Ok(Some("1".to_string())),
+            sessions.execute(&request(1), &mut state)
+        );
+        assert_eq!(
+            Ok(Some("2".to_string())),
+            sessions.execute(&request(2), &mut state)
+        );
+        assert!(sessions.execute(&request(1), &mut state).is_err());
+        assert_eq!(Some("2".to_string()), state["k"]);
+    }
+}
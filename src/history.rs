@@ -0,0 +1,162 @@
+/// A recorder of the operations that clients send to a key/value store, with the times when they were
+/// invoked and completed, to check afterwards if the store behaved as a linearizable one.
+use crate::command::{is_rejection, ClientCommand};
+use crate::network::{ReliableSender, RequestSender};
+use anyhow::Result;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+/// What a client learned about the result of an operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The store answered the operation with the given value.
+    Ok(Option<String>),
+    /// The store rejected the operation with the given error, e.g. a failed compare-and-swap, so it
+    /// didn't take effect.
+    Failed(String),
+    /// The client doesn't know the result: the operation failed with the given error, which could be a
+    /// timeout or a network error, or the store answered before applying it. The operation may or may
+    /// not have taken effect.
+    Unknown(String),
+}
+
+/// An operation of a client history.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub client: usize,
+    pub command: ClientCommand,
+    pub invoked: Instant,
+    pub completed: Instant,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "client {}: {} -> {:?}",
+            self.client, self.command, self.outcome
+        )
+    }
+}
+
+/// The history of the operations sent by a set of clients, cheap to clone and shared by all of them.
+#[derive(Clone)]
+pub struct History {
+    operations: Arc<Mutex<Vec<Operation>>>,
+    /// If false, the replies to writes are not their results, so their outcome is unknown.
+    acknowledged_writes: bool,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            operations: Arc::default(),
+            acknowledged_writes: true,
+        }
+    }
+
+    /// A history of a store that replies to writes before applying them, e.g. once they are proposed
+    /// or added to a mempool. Their replies don't tell the clients the result of the writes, so it's
+    /// recorded as unknown.
+    pub fn with_unacknowledged_writes() -> Self {
+        Self {
+            operations: Arc::default(),
+            acknowledged_writes: false,
+        }
+    }
+
+    /// Send the command of the given client to a server at the given address, like
+    /// `ClientCommand::send_to`, and record it.
+    pub async fn send_to(
+        &self,
+        client: usize,
+        command: ClientCommand,
+        address: SocketAddr,
+    ) -> Result<Option<String>> {
+        self.send_with(client, command, &mut ReliableSender::new(), address)
+            .await
+    }
+
+    /// Send the command of the given client through the given sender, like `ClientCommand::send_with`,
+    /// and record it.
+    pub async fn send_with(
+        &self,
+        client: usize,
+        command: ClientCommand,
+        sender: &mut dyn RequestSender,
+        address: SocketAddr,
+    ) -> Result<Option<String>> {
+        let invoked = Instant::now();
+        let result = command.clone().send_with(sender, address).await;
+        let completed = Instant::now();
+
+        let outcome = match &result {
+            Ok(value) if self.acknowledged_writes || !command.is_write() => {
+                Outcome::Ok(value.clone())
+            }
+            Ok(_) => Outcome::Unknown("write not acknowledged".to_string()),
+            Err(error) if is_rejection(&error.to_string()) => Outcome::Failed(error.to_string()),
+            Err(error) => Outcome::Unknown(error.to_string()),
+        };
+        self.operations.lock().unwrap().push(Operation {
+            client,
+            command,
+            invoked,
+            completed,
+            outcome,
+        });
+        result
+    }
+
+    /// Run the given number of clients concurrently, each sending the given number of commands
+    /// that alternate between setting one of the keys to a value unique to the history and getting
+    /// another one. Each client sends its commands to the address returned by `target` for it, and
+    /// waits for the reply to each one before sending the next.
+    pub async fn run_clients(
+        &self,
+        clients: usize,
+        operations: usize,
+        keys: &[&str],
+        target: impl Fn(usize) -> SocketAddr,
+    ) {
+        let handles: Vec<_> = (0..clients)
+            .map(|client| {
+                let history = self.clone();
+                let address = target(client);
+                let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+                tokio::spawn(async move {
+                    for i in 0..operations {
+                        let key = keys[(client + i) % keys.len()].clone();
+                        let command = if i % 2 == 0 {
+                            ClientCommand::Set {
+                                key,
+                                value: format!("{client}-{i}"),
+                            }
+                        } else {
+                            ClientCommand::Get { key }
+                        };
+                        let _ = history.send_to(client, command, address).await;
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+
+    /// The operations recorded so far, in the order they completed.
+    pub fn operations(&self) -> Vec<Operation> {
+        self.operations.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,287 @@
+/// This module contains the blocks proposed by HotStuff leaders, the quorum certificates that justify
+/// them, and the ledger of committed blocks used as the commit log of the key/value store.
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use anyhow::{bail, Result};
+use lib::command::{ClientCommand, CommandResult, KeyValues, Sessions};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub type TransactionId = String;
+pub type Transaction = (TransactionId, ClientCommand);
+
+/// A proof that a quorum of nodes voted for the block with the given hash in the given view.
+/// Votes are not signed, so the certificate just lists the nodes that voted: a byzantine node can
+/// forge one by naming any quorum of peers, and the protocol only tolerates crashed nodes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct QuorumCertificate {
+    pub view: u128,
+    pub hash: String,
+    pub voters: BTreeSet<SocketAddr>,
+}
+
+impl QuorumCertificate {
+    /// The certificate of the genesis block, which every node accepts without votes.
+    pub fn genesis() -> Self {
+        Self {
+            view: 0,
+            hash: Block::genesis().hash,
+            voters: BTreeSet::new(),
+        }
+    }
+
+    /// Returns true if the certificate has the given quorum of votes from known nodes. Since votes are
+    /// not signed, this doesn't check that the listed nodes actually voted.
+    pub fn is_valid(&self, peers: &[SocketAddr], quorum: usize) -> bool {
+        *self == Self::genesis()
+            || (self.voters.len() >= quorum && self.voters.iter().all(|v| peers.contains(v)))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// The view this block was proposed in.
+    pub view: u128,
+    pub proposer: SocketAddr,
+    pub height: u64,
+    pub hash: String,
+    pub previous_hash: String,
+    /// The certificate of the parent block.
+    pub justify: QuorumCertificate,
+    pub data: Vec<Transaction>,
+}
+
+impl Block {
+    /// Create a block proposed in the given view that extends the block certified by the given
+    /// certificate.
+    pub fn new(
+        view: u128,
+        proposer: SocketAddr,
+        parent: &Block,
+        justify: QuorumCertificate,
+        transactions: Vec<Transaction>,
+    ) -> Self {
+        let mut block = Self {
+            view,
+            proposer,
+            height: parent.height + 1,
+            hash: "not known yet".to_string(),
+            previous_hash: parent.hash.clone(),
+            justify,
+            data: transactions,
+        };
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    /// Generate a hex string of a Sha256 hash for the attributes in this block.
+    /// The hash field itself doesn't affect the result.
+    pub fn calculate_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.view.to_string());
+        hasher.update(self.proposer.to_string());
+        hasher.update(self.height.to_string());
+        hasher.update(&self.previous_hash);
+        hasher.update(self.justify.view.to_string());
+        hasher.update(&self.justify.hash);
+        for voter in &self.justify.voters {
+            hasher.update(voter.to_string());
+        }
+        for (txid, cmd) in &self.data {
+            hasher.update(txid);
+            hasher.update(cmd.to_string());
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    /// Create a genesis block, which is expected to be the first block of any valid ledger.
+    /// It's considered certified and committed by every node from the start.
+    pub fn genesis() -> Self {
+        let mut block = Self {
+            view: 0,
+            proposer: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            height: 0,
+            hash: "temporary".to_string(),
+            previous_hash: "genesis".to_string(),
+            justify: QuorumCertificate {
+                view: 0,
+                hash: "genesis".to_string(),
+                voters: BTreeSet::new(),
+            },
+            data: vec![],
+        };
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    /// Returns if this is a valid block: if its hash attribute matches the result of hashing the block data.
+    pub fn is_valid(&self) -> bool {
+        if self.calculate_hash() != self.hash {
+            warn!("block has invalid hash {}", self.hash);
+            return false;
+        }
+        true
+    }
+
+    /// Returns true if the given block is the parent of this one.
+    pub fn extends(&self, other: &Block) -> bool {
+        if self.previous_hash != other.hash {
+            warn!(
+                "block has wrong previous hash {}, expected {}",
+                self.previous_hash, other.hash
+            );
+            return false;
+        }
+        if self.height != other.height + 1 || self.view <= other.view {
+            warn!(
+                "block at height {} view {} can't extend block at height {} view {}",
+                self.height, self.view, other.height, other.view
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// The chain of committed blocks. Viewed as the commit log of the key/value store, the store state
+/// is the result of replaying, from the oldest block, every command in the ledger; the state is kept
+/// up to date as blocks are appended so reads don't need to replay the whole chain.
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    pub blocks: Vec<Block>,
+    state: KeyValues,
+    /// The last request of each client session run by the ledger, so retries are only run once.
+    sessions: Sessions,
+}
+
+impl Ledger {
+    /// Creates a new ledger with a genesis block in it.
+    pub fn new() -> Self {
+        Self {
+            blocks: vec![Block::genesis()],
+            state: KeyValues::new(),
+            sessions: Sessions::default(),
+        }
+    }
+
+    /// The latest committed block.
+    pub fn tip(&self) -> &Block {
+        self.blocks.last().unwrap()
+    }
+
+    /// Return the current value of the given key.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.get(key).cloned().flatten()
+    }
+
+    /// The reply to the given command if it's a session request the ledger already ran, so a retry is
+    /// answered without ordering it again.
+    pub fn reply(&self, command: &ClientCommand) -> Option<CommandResult> {
+        self.sessions.reply(command)
+    }
+
+    /// Returns true if there's a transaction with the given id commited in some block of this ledger.
+    pub fn contains(&self, txid: &str) -> bool {
+        self.blocks.iter().rev().any(|block| {
+            block
+                .data
+                .iter()
+                .any(|(stored_txid, _)| stored_txid == txid)
+        })
+    }
+
+    /// Append the given committed block to the ledger and run its commands, returning the result of
+    /// each of them. Rejected commands (e.g. a failed compare-and-swap) leave the state untouched, and
+    /// so do session requests that were already run, which get their first reply again.
+    /// Fails if the block is an invalid extension of this ledger.
+    pub fn extend(&mut self, block: Block) -> Result<Vec<(TransactionId, CommandResult)>> {
+        if !block.is_valid() || !block.extends(self.tip()) {
+            bail!("block {:?} is not a valid extension of the ledger", block);
+        }
+
+        let results = block
+            .data
+            .iter()
+            .map(|(txid, cmd)| (txid.clone(), self.sessions.execute(cmd, &mut self.state)))
+            .collect();
+        self.blocks.push(block);
+        Ok(results)
+    }
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for Ledger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Ledger {{ length: {}, latest: {:?}  }}",
+            self.blocks.len(),
+            self.tip()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    #[test]
+    fn certificates_and_ledger() {
+        let peers: Vec<SocketAddr> = (6200..6204).map(address).collect();
+        assert!(QuorumCertificate::genesis().is_valid(&peers, 3));
+
+        let mut ledger = Ledger::new();
+        let block = Block::new(
+            1,
+            peers[1],
+            ledger.tip(),
+            QuorumCertificate::genesis(),
+            vec![(
+                "tx1".to_string(),
+                ClientCommand::Set {
+                    key: "a".to_string(),
+                    value: "1".to_string(),
+                },
+            )],
+        );
+
+        let mut qc = QuorumCertificate {
+            view: 1,
+            hash: block.hash.clone(),
+            voters: peers[..2].iter().cloned().collect(),
+        };
+        assert!(!qc.is_valid(&peers, 3));
+        qc.voters.insert(address(6300));
+        assert!(!qc.is_valid(&peers, 3));
+        qc.voters.remove(&address(6300));
+        qc.voters.insert(peers[3]);
+        assert!(qc.is_valid(&peers, 3));
+
+        let results = ledger.extend(block.clone()).unwrap();
+        assert_eq!(
+            vec![("tx1".to_string(), Ok(Some("1".to_string())))],
+            results
+        );
+        assert_eq!(Some("1".to_string()), ledger.get("a"));
+        assert!(ledger.contains("tx1"));
+
+        // blocks must extend the tip in a later view
+        assert!(ledger.extend(block.clone()).is_err());
+        let stale = Block::new(1, peers[2], &block, qc.clone(), vec![]);
+        assert!(ledger.extend(stale).is_err());
+        let next = Block::new(2, peers[2], &block, qc, vec![]);
+        assert!(ledger.extend(next).is_ok());
+    }
+}
@@ -0,0 +1,657 @@
+/// This module contains the definition of a chained HotStuff node and the network messages supported
+/// between nodes. Each view has a leader that proposes a block extending the block with the highest
+/// quorum certificate it knows of. The votes for a block are aggregated into a certificate that the
+/// leader of the next view includes in its proposal, so every block carries the certificate of its parent. A node locks on the grandparent of a certified block (two-chain), and commits
+/// the great-grandparent once it heads a three-chain of blocks from consecutive views.
+use crate::ledger::{Block, Ledger, QuorumCertificate, Transaction, TransactionId};
+use bytes::Bytes;
+use core::fmt;
+use lib::command::{ClientCommand, CommandResult};
+use lib::network::{MessageSender, SimpleSender};
+use log::{debug, error, info, warn};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+use tokio::time::{interval, Duration, Instant};
+
+/// How often the node checks its pacemaker timers.
+const TICK: Duration = Duration::from_millis(10);
+
+/// How long a leader waits before proposing once it's ready to, so the mempool can fill up
+/// instead of producing empty blocks back to back.
+const PROPOSE_DELAY: Duration = Duration::from_millis(50);
+
+/// The maximum number of times the view timeout is doubled after consecutive failed views.
+const MAX_BACKOFF: u32 = 6;
+
+/// The types of messages supported by this implementation's state machine.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Message {
+    /// A client transaction received by a peer, to be included in a future proposal.
+    Command(TransactionId, ClientCommand),
+    /// A block proposed by the leader of its view.
+    Propose(Block),
+    /// A vote for the block with the given hash.
+    Vote {
+        from: SocketAddr,
+        view: u128,
+        hash: String,
+    },
+    /// Sent to the leader of a view when the previous one timed out, with the highest certificate
+    /// known by the sender.
+    NewView {
+        from: SocketAddr,
+        view: u128,
+        high_qc: QuorumCertificate,
+    },
+    /// A request for a block this node is missing.
+    Fetch { from: SocketAddr, hash: String },
+    /// The response to a `Fetch` request.
+    Fetched(Block),
+}
+
+pub struct Node {
+    pub address: SocketAddr,
+
+    /// All the nodes of the network, including this one, sorted so every node agrees on the
+    /// leader of each view.
+    peers: Vec<SocketAddr>,
+
+    pub sender: Box<dyn MessageSender>,
+
+    /// The pacemaker state: the current view, when it times out, the base view timeout and the
+    /// number of consecutive views that timed out, which doubles the timeout each time.
+    view: u128,
+    view_deadline: Instant,
+    timeout: Duration,
+    failed_views: u32,
+
+    /// When this node, as the leader of the current view, should propose.
+    propose_at: Option<Instant>,
+    /// The last view this node proposed in.
+    proposed_view: u128,
+
+    /// The last view this node voted in, to vote at most once per view.
+    voted_view: u128,
+
+    /// The certificate of the block this node is locked on: it only votes for blocks that extend it,
+    /// unless they carry a higher certificate.
+    locked_qc: QuorumCertificate,
+
+    /// The highest certificate known by this node, which its proposals extend.
+    high_qc: QuorumCertificate,
+
+    /// The known blocks that are not committed yet, by hash.
+    blocks: HashMap<String, Block>,
+
+    /// The votes received for each view and block hash.
+    votes: HashMap<(u128, String), BTreeSet<SocketAddr>>,
+
+    /// The nodes that sent a `NewView` for each view this node is the leader of.
+    new_views: HashMap<u128, HashSet<SocketAddr>>,
+
+    /// A block that should be committed but has missing ancestors that were requested to other nodes.
+    commit_target: Option<String>,
+
+    /// The pool of pending transactions, to be included in this node's proposals.
+    mempool: BTreeMap<TransactionId, ClientCommand>,
+
+    /// The reply channels of the client commands received by this node, answered when the
+    /// command is committed.
+    pending: HashMap<TransactionId, oneshot::Sender<CommandResult>>,
+
+    /// The chain of committed blocks.
+    ledger: Ledger,
+
+    /// The source of the ids of the client commands.
+    pub rng: SmallRng,
+}
+
+use Message::*;
+
+impl Node {
+    pub fn new(mut peers: Vec<SocketAddr>, address: SocketAddr, timeout_ms: u64) -> Self {
+        if !peers.contains(&address) {
+            peers.push(address);
+        }
+        peers.sort();
+
+        let timeout = Duration::from_millis(timeout_ms);
+        Self {
+            address,
+            peers,
+            sender: Box::new(SimpleSender::new()),
+            view: 1,
+            view_deadline: Instant::now() + timeout,
+            timeout,
+            failed_views: 0,
+            propose_at: None,
+            proposed_view: 0,
+            voted_view: 0,
+            locked_qc: QuorumCertificate::genesis(),
+            high_qc: QuorumCertificate::genesis(),
+            blocks: HashMap::new(),
+            votes: HashMap::new(),
+            new_views: HashMap::new(),
+            commit_target: None,
+            mempool: BTreeMap::new(),
+            pending: HashMap::new(),
+            ledger: Ledger::new(),
+            rng: SmallRng::from_entropy(),
+        }
+    }
+
+    /// Runs the node to process network messages incoming in the given receiver.
+    pub async fn run(
+        &mut self,
+        mut network_receiver: Receiver<(Message, oneshot::Sender<String>)>,
+        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<CommandResult>)>,
+    ) {
+        // the genesis certificate is known by everyone, so the first leader doesn't need to wait for it
+        if self.leader(self.view) == self.address {
+            self.propose_at = Some(Instant::now() + PROPOSE_DELAY);
+        }
+
+        let mut timer = interval(TICK);
+        loop {
+            tokio::select! {
+                biased;
+                _ = timer.tick() => self.check_timers().await,
+                Some((command, reply_sender)) = client_receiver.recv() => {
+                    info!("{}: Received client message {}", self.address, command);
+                    self.handle_client_command(command, reply_sender).await;
+                }
+                Some((message, reply_sender)) = network_receiver.recv() => {
+                    debug!("{}: Received network message {}", self.address, message);
+                    if let Err(error) = reply_sender.send("ACK".to_string()) {
+                        error!("failed to send message {:?} response {:?}", message, error);
+                    }
+                    self.handle_message(message).await;
+                }
+                else => {
+                    error!("node channels are closed");
+                }
+            }
+        }
+    }
+
+    /// Reads are served from the committed ledger. Writes are shared with the rest of the nodes so
+    /// any leader can propose them, and are answered once their block is committed.
+    async fn handle_client_command(
+        &mut self,
+        command: ClientCommand,
+        reply_sender: oneshot::Sender<CommandResult>,
+    ) {
+        if let ClientCommand::Get { key } = &command {
+            if let Err(error) = reply_sender.send(Ok(self.ledger.get(key))) {
+                error!("failed to send client response {:?}", error);
+            }
+            return;
+        }
+        // a retry of a request the ledger already ran gets its first reply
+        if let Some(reply) = self.ledger.reply(&command) {
+            if let Err(error) = reply_sender.send(reply) {
+                error!("failed to send client response {:?}", error);
+            }
+            return;
+        }
+
+        // the requests of a client session are identified by the client, so retries are deduplicated;
+        // the id of other commands is drawn here
+        let txid = match &command {
+            ClientCommand::Session {
+                client, sequence, ..
+            } => format!("{client}/{sequence}"),
+            _ => uuid::Builder::from_random_bytes(self.rng.gen())
+                .into_uuid()
+                .to_string(),
+        };
+        self.mempool.insert(txid.clone(), command.clone());
+        self.pending.insert(txid.clone(), reply_sender);
+        self.broadcast(Command(txid, command)).await;
+    }
+
+    async fn handle_message(&mut self, message: Message) {
+        match message {
+            Command(txid, command) => {
+                if !self.ledger.contains(&txid) {
+                    self.mempool.insert(txid, command);
+                }
+            }
+            Propose(block) => self.handle_proposal(block).await,
+            Vote { from, view, hash } => self.handle_vote(from, view, hash),
+            NewView {
+                from,
+                view,
+                high_qc,
+            } => self.handle_new_view(from, view, high_qc),
+            Fetch { from, hash } => {
+                let block = self.block(&hash).cloned().or_else(|| {
+                    self.ledger
+                        .blocks
+                        .iter()
+                        .rev()
+                        .find(|block| block.hash == hash)
+                        .cloned()
+                });
+                if let Some(block) = block {
+                    self.send(from, Fetched(block)).await;
+                }
+            }
+            Fetched(block) => {
+                if block.is_valid() && block.height > self.ledger.tip().height {
+                    self.blocks.insert(block.hash.clone(), block);
+                    if let Some(target) = self.commit_target.take() {
+                        self.commit(&target).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The pacemaker: move to the next view if the current one timed out, and propose if this node
+    /// is the leader and it's time to.
+    async fn check_timers(&mut self) {
+        let now = Instant::now();
+        if now >= self.view_deadline {
+            warn!("{}: View {} timed out", self.address, self.view);
+            self.failed_views = (self.failed_views + 1).min(MAX_BACKOFF);
+            self.enter_view(self.view + 1);
+
+            let new_view = NewView {
+                from: self.address,
+                view: self.view,
+                high_qc: self.high_qc.clone(),
+            };
+            let leader = self.leader(self.view);
+            self.send(leader, new_view).await;
+        }
+
+        if self.propose_at.is_some_and(|at| now >= at) {
+            self.propose_at = None;
+            self.propose().await;
+        }
+    }
+
+    fn enter_view(&mut self, view: u128) {
+        if view <= self.view {
+            return;
+        }
+        self.view = view;
+        self.view_deadline = Instant::now() + self.timeout * 2u32.pow(self.failed_views);
+        self.propose_at = None;
+    }
+
+    /// Propose a block extending the block of the highest certificate, with the pending transactions
+    /// that are not already in one of its uncommitted ancestors.
+    async fn propose(&mut self) {
+        if self.proposed_view >= self.view || self.leader(self.view) != self.address {
+            return;
+        }
+        let parent = match self.block(&self.high_qc.hash) {
+            Some(parent) => parent.clone(),
+            None => {
+                // the votes for a block may arrive before the block itself, wait for it
+                debug!("{}: missing certified block to propose", self.address);
+                self.propose_at = Some(Instant::now() + PROPOSE_DELAY);
+                return;
+            }
+        };
+        self.proposed_view = self.view;
+
+        let included: HashSet<&TransactionId> = self
+            .chain(&parent.hash)
+            .iter()
+            .flat_map(|block| block.data.iter().map(|(txid, _)| txid))
+            .collect();
+        let transactions: Vec<Transaction> = self
+            .mempool
+            .iter()
+            .filter(|(txid, _)| !included.contains(txid))
+            .map(|(txid, command)| (txid.clone(), command.clone()))
+            .collect();
+
+        let block = Block::new(
+            self.view,
+            self.address,
+            &parent,
+            self.high_qc.clone(),
+            transactions,
+        );
+        debug!(
+            "{}: Proposing block {} at height {} for view {}",
+            self.address, block.hash, block.height, self.view
+        );
+        self.broadcast(Propose(block.clone())).await;
+        self.handle_proposal(block).await;
+    }
+
+    /// Process the certificate carried by the proposal, then vote for it if it's safe.
+    async fn handle_proposal(&mut self, block: Block) {
+        if !block.is_valid()
+            || block.proposer != self.leader(block.view)
+            || block.view < self.view
+            || block.justify.hash != block.previous_hash
+            || !block.justify.is_valid(&self.peers, self.quorum())
+        {
+            warn!("{}: ignoring invalid proposal {}", self.address, block.hash);
+            return;
+        }
+        match self.block(&block.previous_hash) {
+            Some(parent) if block.extends(parent) => {}
+            Some(_) => return,
+            None => {
+                // can't vote without checking the parent, but keep the block so the proposals that
+                // extend it can be checked, and fetch the parent for later commits
+                let fetch = self.fetch(&block.previous_hash);
+                self.blocks.insert(block.hash.clone(), block.clone());
+                self.send(block.proposer, fetch).await;
+                self.enter_view(block.view + 1);
+                return;
+            }
+        }
+
+        self.blocks.insert(block.hash.clone(), block.clone());
+        self.process_certificate(&block.justify).await;
+
+        // the liveness rule lets the node vote for a block that doesn't extend its lock if it carries a
+        // certificate newer than the lock, which means a quorum of nodes moved past it
+        let safe =
+            self.extends(&block, &self.locked_qc.hash) || block.justify.view > self.locked_qc.view;
+        if block.view > self.voted_view && safe {
+            self.voted_view = block.view;
+            self.failed_views = 0;
+
+            // the votes are sent to every node rather than just to the next leader: otherwise, when the
+            // next leader is down, the certificate is lost and with round robin leaders a crashed node
+            // could prevent three consecutive views from ever being certified
+            let vote = Vote {
+                from: self.address,
+                view: block.view,
+                hash: block.hash.clone(),
+            };
+            self.broadcast(vote).await;
+            self.handle_vote(self.address, block.view, block.hash.clone());
+        }
+        self.enter_view(block.view + 1);
+    }
+
+    /// Update the highest certificate, the lock and the committed chain with a new certificate.
+    async fn process_certificate(&mut self, qc: &QuorumCertificate) {
+        self.update_high_qc(qc);
+
+        // the block certified by the given certificate (b2), its parent (b1) and grandparent (b0)
+        let b2 = match self.block(&qc.hash) {
+            Some(block) => block.clone(),
+            None => return,
+        };
+        let b1 = match self.block(&b2.justify.hash) {
+            Some(block) => block.clone(),
+            None => return,
+        };
+        if b2.justify.view > self.locked_qc.view {
+            debug!("{}: Locked on block {}", self.address, b1.hash);
+            self.locked_qc = b2.justify.clone();
+        }
+
+        let b0 = match self.block(&b1.justify.hash) {
+            Some(block) => block.clone(),
+            None => return,
+        };
+        if b2.view == b1.view + 1 && b1.view == b0.view + 1 {
+            self.commit(&b0.hash).await;
+        }
+    }
+
+    /// Count the vote and form a certificate once there's a quorum. If this node is the leader of the
+    /// next view, it can then propose.
+    fn handle_vote(&mut self, from: SocketAddr, view: u128, hash: String) {
+        if !self.peers.contains(&from) {
+            warn!("{}: ignoring vote from {}", self.address, from);
+            return;
+        }
+        let quorum = self.quorum();
+        let votes = self.votes.entry((view, hash.clone())).or_default();
+        votes.insert(from);
+        if votes.len() == quorum {
+            let qc = QuorumCertificate {
+                view,
+                hash,
+                voters: votes.clone(),
+            };
+            debug!("{}: Formed certificate for view {}", self.address, view);
+            self.votes.retain(|(vote_view, _), _| *vote_view > view);
+            self.update_high_qc(&qc);
+            self.enter_view(view + 1);
+            self.schedule_proposal(view + 1);
+        }
+    }
+
+    /// As the leader of the given view, wait for a quorum of nodes to move to it, then propose.
+    fn handle_new_view(&mut self, from: SocketAddr, view: u128, high_qc: QuorumCertificate) {
+        if !self.peers.contains(&from)
+            || self.leader(view) != self.address
+            || !high_qc.is_valid(&self.peers, self.quorum())
+        {
+            warn!("{}: ignoring new view from {}", self.address, from);
+            return;
+        }
+        self.update_high_qc(&high_qc);
+        let quorum = self.quorum();
+        let senders = self.new_views.entry(view).or_default();
+        senders.insert(from);
+        if senders.len() == quorum {
+            self.new_views.retain(|new_view, _| *new_view > view);
+            self.enter_view(view);
+            self.schedule_proposal(view);
+        }
+    }
+
+    fn schedule_proposal(&mut self, view: u128) {
+        if self.view == view && self.proposed_view < view && self.leader(view) == self.address {
+            self.propose_at = Some(Instant::now() + PROPOSE_DELAY);
+        }
+    }
+
+    fn update_high_qc(&mut self, qc: &QuorumCertificate) {
+        if qc.view > self.high_qc.view {
+            self.high_qc = qc.clone();
+        }
+    }
+
+    /// Append to the ledger the chain of blocks ending in the given one, answer the client commands
+    /// included in them and forget the blocks that can no longer be committed.
+    async fn commit(&mut self, hash: &str) {
+        let mut chain: Vec<Block> = self.chain(hash).into_iter().cloned().collect();
+        chain.reverse();
+
+        // the chain has to reach the last committed block, otherwise some blocks are missing
+        let first = chain.first().map(|block| block.previous_hash.clone());
+        if let Some(missing) = first.filter(|first| *first != self.ledger.tip().hash) {
+            warn!("{}: missing block {} to commit", self.address, missing);
+            self.commit_target = Some(hash.to_string());
+            let fetch = self.fetch(&missing);
+            self.broadcast(fetch).await;
+            return;
+        }
+
+        for block in chain {
+            info!(
+                "{}: Committed block {} at height {}",
+                self.address, block.hash, block.height
+            );
+            let results = match self.ledger.extend(block) {
+                Ok(results) => results,
+                Err(error) => {
+                    error!("{}: {}", self.address, error);
+                    return;
+                }
+            };
+
+            for (txid, result) in results {
+                self.mempool.remove(&txid);
+                if let Some(reply_sender) = self.pending.remove(&txid) {
+                    if let Err(error) = reply_sender.send(result) {
+                        error!("failed to send client response {:?}", error);
+                    }
+                }
+            }
+        }
+
+        let height = self.ledger.tip().height;
+        self.blocks.retain(|_, block| block.height > height);
+    }
+
+    /// The block with the given hash, if it's the latest committed block or a known pending one.
+    fn block(&self, hash: &str) -> Option<&Block> {
+        if self.ledger.tip().hash == hash {
+            Some(self.ledger.tip())
+        } else {
+            self.blocks.get(hash)
+        }
+    }
+
+    /// The known pending blocks from the given one back to the latest committed block, excluding the latter.
+    fn chain(&self, hash: &str) -> Vec<&Block> {
+        let mut chain = Vec::new();
+        let mut current = self.blocks.get(hash);
+        while let Some(block) = current {
+            chain.push(block);
+            current = self.blocks.get(&block.previous_hash);
+        }
+        chain
+    }
+
+    /// Returns true if the given block is the block with the given hash or one of its descendants.
+    fn extends(&self, block: &Block, hash: &str) -> bool {
+        let chain = self.chain(&block.hash);
+        block.hash == hash
+            || chain.iter().any(|ancestor| ancestor.hash == hash)
+            || chain.last().map(|first| first.previous_hash == hash) == Some(true)
+    }
+
+    fn fetch(&self, hash: &str) -> Message {
+        Fetch {
+            from: self.address,
+            hash: hash.to_string(),
+        }
+    }
+
+    /// The leader of each view is picked in a round robin fashion.
+    fn leader(&self, view: u128) -> SocketAddr {
+        self.peers[(view % self.peers.len() as u128) as usize]
+    }
+
+    /// The number of votes needed for a certificate: all the nodes but the faulty ones, out of a
+    /// network of 3f + 1 nodes.
+    fn quorum(&self) -> usize {
+        self.peers.len() - (self.peers.len() - 1) / 3
+    }
+
+    /// Send a message to the given node, handling it locally if it's this node.
+    async fn send(&mut self, to: SocketAddr, message: Message) {
+        if to == self.address {
+            if let NewView {
+                from,
+                view,
+                high_qc,
+            } = message
+            {
+                self.handle_new_view(from, view, high_qc);
+            }
+        } else {
+            let message: Bytes = bincode::serialize(&message).unwrap().into();
+            self.sender.send(to, message).await;
+        }
+    }
+
+    /// Send a message to every other node.
+    async fn broadcast(&mut self, message: Message) {
+        let others: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|peer| **peer != self.address)
+            .cloned()
+            .collect();
+        let message: Bytes = bincode::serialize(&message).unwrap().into();
+        self.sender.broadcast(others, message).await;
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command(txid, command) => write!(f, "Command({txid}, {command})"),
+            Propose(block) => write!(f, "Propose({}, view {})", block.hash, block.view),
+            Vote { from, view, hash } => write!(f, "Vote({view}, {hash}, {from})"),
+            NewView { from, view, .. } => write!(f, "NewView({view}, {from})"),
+            Fetch { from, hash } => write!(f, "Fetch({hash}, {from})"),
+            Fetched(block) => write!(f, "Fetched({})", block.hash),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    fn certificate(block: &Block, peers: &[SocketAddr]) -> QuorumCertificate {
+        QuorumCertificate {
+            view: block.view,
+            hash: block.hash.clone(),
+            voters: peers[..3].iter().cloned().collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn three_chain_commit() {
+        let peers: Vec<SocketAddr> = (7690..7694).map(address).collect();
+        let mut node = Node::new(peers.clone(), peers[0], 1000);
+
+        let command = ClientCommand::Set {
+            key: "k".to_string(),
+            value: "v".to_string(),
+        };
+        let b1 = Block::new(
+            1,
+            peers[1],
+            &Block::genesis(),
+            QuorumCertificate::genesis(),
+            vec![("tx1".to_string(), command)],
+        );
+        let b2 = Block::new(2, peers[2], &b1, certificate(&b1, &peers), vec![]);
+        let b3 = Block::new(3, peers[3], &b2, certificate(&b2, &peers), vec![]);
+        let b4 = Block::new(4, peers[0], &b3, certificate(&b3, &peers), vec![]);
+
+        node.handle_proposal(b1.clone()).await;
+        assert_eq!(1, node.voted_view);
+        assert_eq!(2, node.view);
+
+        node.handle_proposal(b2).await;
+        assert_eq!(certificate(&b1, &peers), node.high_qc);
+
+        // b3 certifies b2, so the node locks on b1
+        node.handle_proposal(b3).await;
+        assert_eq!(certificate(&b1, &peers), node.locked_qc);
+        assert_eq!(0, node.ledger.tip().height);
+
+        // b4 certifies b3, heading a three-chain of consecutive views that commits b1
+        node.handle_proposal(b4).await;
+        assert_eq!(b1, *node.ledger.tip());
+        assert_eq!(Some("v".to_string()), node.ledger.get("k"));
+        assert_eq!(4, node.voted_view);
+
+        // a fork of the locked block with an older certificate is not safe to vote for
+        let fork = Block::new(5, peers[1], &b1, certificate(&b1, &peers), vec![]);
+        node.handle_proposal(fork).await;
+        assert_eq!(4, node.voted_view);
+    }
+}
@@ -1,6 +1,10 @@
+pub mod cluster;
 pub mod command;
+pub mod history;
+pub mod linearizability;
 pub mod network;
 pub mod store;
+pub mod testing;
 
 use anyhow::Result;
 use tokio::sync::mpsc;
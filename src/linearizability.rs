@@ -0,0 +1,334 @@
+/// A linearizability checker for client histories of the key/value store, in the style of Knossos and
+/// Porcupine. A history is linearizable if every operation can be assigned a point between its invocation
+/// and its completion, such that running the operations in the order of those points against a single
+/// key/value store gives the results the clients got.
+///
+/// The checker looks for that order with a depth-first search over the operations that can go next (the
+/// ones invoked before any of the pending operations completed), caching the pairs of linearized operations
+/// and store state it already explored. Since operations on different keys don't affect each other, the
+/// history is first split in independent parts by key and each part is checked on its own.
+///
+/// Operations with an unknown outcome may take effect at any point after their invocation, or never, while
+/// failed ones must be rejected at some point of their interval.
+use crate::command::{ClientCommand, KeyValues};
+use crate::history::{Operation, Outcome};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A part of a history, with the operations on a set of keys, that is not linearizable.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub keys: Vec<String>,
+    pub operations: Vec<Operation>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "operations on keys {:?} are not linearizable:",
+            self.keys
+        )?;
+        let start = self.operations.iter().map(|op| op.invoked).min();
+        for operation in &self.operations {
+            let start = start.unwrap_or(operation.invoked);
+            writeln!(
+                f,
+                "  [{:?}, {:?}] {}",
+                operation.invoked - start,
+                operation.completed - start,
+                operation
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Check if the given history is linearizable, starting from an empty store, and return the parts of it
+/// that are not.
+pub fn check(operations: &[Operation]) -> Result<(), Vec<Violation>> {
+    let violations: Vec<Violation> = partition(operations)
+        .into_iter()
+        .filter(|(_, operations)| !Search::new(operations).run())
+        .map(|(keys, mut operations)| {
+            operations.sort_by_key(|operation| operation.invoked);
+            Violation { keys, operations }
+        })
+        .collect();
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+/// Split the operations in groups that don't share any key. Reads with an unknown outcome are left out
+/// since they don't tell anything about the state of the store.
+fn partition(operations: &[Operation]) -> Vec<(Vec<String>, Vec<Operation>)> {
+    let operations: Vec<&Operation> = operations
+        .iter()
+        .filter(|op| op.command.is_write() || !matches!(op.outcome, Outcome::Unknown(_)))
+        .collect();
+
+    // union-find over the keys, joining the keys used by the same operation
+    let mut parents: HashMap<&str, &str> = HashMap::new();
+    fn root<'a>(parents: &mut HashMap<&'a str, &'a str>, key: &'a str) -> &'a str {
+        let parent = *parents.entry(key).or_insert(key);
+        if parent == key {
+            return key;
+        }
+        let root = root(parents, parent);
+        parents.insert(key, root);
+        root
+    }
+    for operation in &operations {
+        let keys = operation.command.keys();
+        for pair in keys.windows(2) {
+            let (first, second) = (root(&mut parents, pair[0]), root(&mut parents, pair[1]));
+            parents.insert(first, second);
+        }
+        if let Some(key) = keys.first() {
+            root(&mut parents, key);
+        }
+    }
+
+    let mut groups: HashMap<&str, (Vec<String>, Vec<Operation>)> = HashMap::new();
+    let keys: Vec<&str> = parents.keys().copied().collect();
+    for key in keys {
+        let root = root(&mut parents, key);
+        groups.entry(root).or_default().0.push(key.to_string());
+    }
+    for operation in operations {
+        if let Some(key) = operation.command.keys().first() {
+            let root = root(&mut parents, key);
+            groups.entry(root).or_default().1.push(operation.clone());
+        }
+    }
+
+    let mut groups: Vec<_> = groups.into_values().collect();
+    for (keys, _) in &mut groups {
+        keys.sort();
+    }
+    groups.sort_by(|(a, _), (b, _)| a.cmp(b));
+    groups
+}
+
+struct Search<'a> {
+    operations: &'a [Operation],
+    linearized: Vec<bool>,
+    /// The combinations of linearized operations and resulting store state already explored.
+    explored: HashSet<(Vec<bool>, KeyValues)>,
+}
+
+impl<'a> Search<'a> {
+    fn new(operations: &'a [Operation]) -> Self {
+        Self {
+            operations,
+            linearized: vec![false; operations.len()],
+            explored: HashSet::new(),
+        }
+    }
+
+    fn run(&mut self) -> bool {
+        self.search(&KeyValues::new())
+    }
+
+    fn search(&mut self, state: &KeyValues) -> bool {
+        let pending: Vec<usize> = (0..self.operations.len())
+            .filter(|i| !self.linearized[*i])
+            .collect();
+
+        // every operation with a known outcome must be linearized, the rest may never take effect
+        let deadline = pending
+            .iter()
+            .map(|i| &self.operations[*i])
+            .filter(|op| !matches!(op.outcome, Outcome::Unknown(_)))
+            .map(|op| op.completed)
+            .min();
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return true,
+        };
+
+        // an operation can go next if no pending one completed before it was invoked
+        for i in pending {
+            let operation = &self.operations[i];
+            if operation.invoked > deadline {
+                continue;
+            }
+
+            let mut next = state.clone();
+            if !matches_outcome(&operation.command, &mut next, &operation.outcome) {
+                continue;
+            }
+
+            self.linearized[i] = true;
+            if self
+                .explored
+                .insert((self.linearized.clone(), next.clone()))
+                && self.search(&next)
+            {
+                return true;
+            }
+            self.linearized[i] = false;
+        }
+        false
+    }
+}
+
+/// Run the command against the state and return true if its result is consistent with the outcome
+/// the client got.
+fn matches_outcome(command: &ClientCommand, state: &mut KeyValues, outcome: &Outcome) -> bool {
+    let result = command.execute(state);
+    match outcome {
+        Outcome::Ok(value) => result.as_ref() == Ok(value),
+        // a rejected command leaves the state untouched
+        Outcome::Failed(_) => result.is_err(),
+        Outcome::Unknown(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{Duration, Instant};
+
+    /// Build an operation of the given client, invoked and completed at the given milliseconds
+    /// after the start.
+    fn operation(
+        start: Instant,
+        client: usize,
+        command: ClientCommand,
+        (invoked, completed): (u64, u64),
+        outcome: Outcome,
+    ) -> Operation {
+        Operation {
+            client,
+            command,
+            invoked: start + Duration::from_millis(invoked),
+            completed: start + Duration::from_millis(completed),
+            outcome,
+        }
+    }
+
+    fn set(key: &str, value: &str) -> ClientCommand {
+        ClientCommand::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn get(key: &str) -> ClientCommand {
+        ClientCommand::Get {
+            key: key.to_string(),
+        }
+    }
+
+    fn ok(value: &str) -> Outcome {
+        Outcome::Ok(Some(value.to_string()))
+    }
+
+    #[test]
+    fn check_histories() {
+        let start = Instant::now();
+
+        // a write can't be undone by one that completed before it started
+        let history = vec![
+            operation(start, 0, set("k", "1"), (0, 4), ok("1")),
+            operation(start, 1, set("k", "2"), (5, 15), ok("2")),
+            operation(start, 2, get("k"), (6, 8), ok("2")),
+            operation(start, 2, get("k"), (16, 20), ok("1")),
+            operation(start, 0, get("x"), (0, 20), Outcome::Ok(None)),
+        ];
+        assert!(check(&history).is_err());
+
+        // concurrent writes can be ordered either way, as long as reads agree on the order
+        let history = vec![
+            operation(start, 0, set("k", "1"), (0, 10), ok("1")),
+            operation(start, 1, set("k", "2"), (5, 15), ok("2")),
+            operation(start, 2, get("k"), (6, 8), ok("2")),
+            operation(start, 2, get("k"), (16, 20), ok("1")),
+            operation(start, 0, get("x"), (0, 20), Outcome::Ok(None)),
+        ];
+        assert!(check(&history).is_ok());
+
+        // a read can't return a value older than one written before it was invoked
+        let history = vec![
+            operation(start, 0, set("k", "1"), (0, 5), ok("1")),
+            operation(start, 0, set("k", "2"), (6, 10), ok("2")),
+            operation(start, 1, get("k"), (11, 12), ok("1")),
+            operation(start, 1, set("x", "1"), (0, 20), ok("1")),
+        ];
+        let violations = check(&history).unwrap_err();
+        assert_eq!(1, violations.len());
+        assert_eq!(vec!["k".to_string()], violations[0].keys);
+        assert_eq!(3, violations[0].operations.len());
+
+        // a write with an unknown outcome may take effect later, or never
+        let history = vec![
+            operation(
+                start,
+                0,
+                set("k", "1"),
+                (0, 5),
+                Outcome::Unknown("timeout".into()),
+            ),
+            operation(start, 1, get("k"), (6, 7), Outcome::Ok(None)),
+            operation(start, 1, get("k"), (8, 9), ok("1")),
+        ];
+        assert!(check(&history).is_ok());
+
+        let history = vec![
+            operation(
+                start,
+                0,
+                set("k", "1"),
+                (0, 5),
+                Outcome::Unknown("timeout".into()),
+            ),
+            operation(start, 1, get("k"), (6, 7), ok("1")),
+            operation(start, 1, get("k"), (8, 9), Outcome::Ok(None)),
+        ];
+        assert!(check(&history).is_err());
+
+        // a rejected compare-and-swap doesn't take effect, and is only rejected if the value differs
+        let cas = ClientCommand::CompareAndSwap {
+            key: "k".to_string(),
+            expected: "1".to_string(),
+            new: "2".to_string(),
+        };
+        let failed = || Outcome::Failed("compare-and-swap failed".into());
+        let history = vec![
+            operation(start, 0, set("k", "1"), (0, 5), ok("1")),
+            operation(start, 1, cas.clone(), (6, 7), failed()),
+            operation(start, 1, get("k"), (8, 9), ok("2")),
+        ];
+        assert!(check(&history).is_err());
+
+        let history = vec![
+            operation(start, 0, set("k", "1"), (0, 5), ok("1")),
+            operation(start, 1, cas.clone(), (6, 7), failed()),
+        ];
+        assert!(check(&history).is_err());
+
+        let history = vec![
+            operation(start, 0, set("k", "1"), (0, 5), ok("1")),
+            operation(start, 1, cas, (3, 7), failed()),
+            operation(start, 1, get("k"), (8, 9), ok("1")),
+        ];
+        assert!(check(&history).is_ok());
+
+        // batches join the keys they use
+        let batch = ClientCommand::Batch {
+            commands: vec![set("a", "1"), set("b", "1")],
+        };
+        let history = vec![
+            operation(start, 0, batch, (0, 5), ok("[1, 1]")),
+            operation(start, 1, get("a"), (6, 7), ok("1")),
+            operation(start, 1, get("b"), (8, 9), Outcome::Ok(None)),
+        ];
+        let violations = check(&history).unwrap_err();
+        assert_eq!(vec!["a".to_string(), "b".to_string()], violations[0].keys);
+    }
+}
@@ -0,0 +1,136 @@
+/// Signed Lock votes and the quorum certificates aggregated from them. A certificate proves that a
+/// quorum of nodes locked a command-view, so backups only commit the commands it certifies, and nodes
+/// justify the highest lock they know of with it during a view change.
+use crate::command_ext::CommandView;
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// A command-view with the Lock votes of a quorum of nodes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct QuorumCertificate {
+    pub command_view: CommandView,
+    pub votes: BTreeMap<SocketAddr, Signature>,
+}
+
+impl QuorumCertificate {
+    /// Returns true if the certificate has the given quorum of valid votes from known nodes.
+    pub fn is_valid(&self, public_keys: &HashMap<SocketAddr, PublicKey>, quorum: usize) -> bool {
+        self.votes.len() >= quorum
+            && self.votes.iter().all(|(voter, signature)| {
+                is_valid_vote(public_keys, voter, &self.command_view, signature)
+            })
+    }
+}
+
+/// Sign a Lock vote for the given command-view.
+pub fn sign_vote(keypair: &Keypair, command_view: &CommandView) -> Signature {
+    keypair.sign(&vote_message(command_view))
+}
+
+/// Returns true if the signature is a Lock vote of the given known node for the command-view.
+pub fn is_valid_vote(
+    public_keys: &HashMap<SocketAddr, PublicKey>,
+    voter: &SocketAddr,
+    command_view: &CommandView,
+    signature: &Signature,
+) -> bool {
+    public_keys.get(voter).is_some_and(|public_key| {
+        public_key
+            .verify(&vote_message(command_view), signature)
+            .is_ok()
+    })
+}
+
+fn vote_message(command_view: &CommandView) -> Vec<u8> {
+    bincode::serialize(command_view).expect("failed to serialize command-view")
+}
+
+pub fn generate_keypair() -> Keypair {
+    Keypair::generate(&mut OsRng)
+}
+
+/// Read the keypair whose secret key is stored in hex in the given file, creating the file with a new
+/// secret key if it doesn't exist.
+pub fn read_keypair(path: &Path) -> Result<Keypair> {
+    if !path.exists() {
+        let keypair = generate_keypair();
+        fs::write(path, hex::encode(keypair.secret.as_bytes()))?;
+        return Ok(keypair);
+    }
+
+    let secret = SecretKey::from_bytes(&hex::decode(fs::read_to_string(path)?.trim())?)
+        .map_err(|e| anyhow!("invalid secret key in {}: {}", path.display(), e))?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+/// Parse a public key encoded in hex.
+pub fn parse_public_key(key: &str) -> Result<PublicKey> {
+    PublicKey::from_bytes(&hex::decode(key)?).map_err(|e| anyhow!("invalid public key: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::command::ClientCommand;
+
+    #[test]
+    fn validate_certificates() {
+        let voters: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10060 + i).parse().unwrap())
+            .collect();
+        let keypairs: Vec<Keypair> = voters.iter().map(|_| generate_keypair()).collect();
+        let public_keys: HashMap<SocketAddr, PublicKey> = voters
+            .iter()
+            .copied()
+            .zip(keypairs.iter().map(|keypair| keypair.public))
+            .collect();
+        let command_view = CommandView {
+            command: ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            },
+            view: 0,
+            sequence: 1,
+        };
+
+        let mut certificate = QuorumCertificate {
+            command_view: command_view.clone(),
+            votes: voters
+                .iter()
+                .zip(&keypairs)
+                .take(2)
+                .map(|(voter, keypair)| (*voter, sign_vote(keypair, &command_view)))
+                .collect(),
+        };
+        assert!(certificate.is_valid(&public_keys, 2));
+
+        // not enough votes
+        assert!(!certificate.is_valid(&public_keys, 3));
+
+        // the votes are for another command
+        let mut other = certificate.clone();
+        other.command_view.sequence = 2;
+        assert!(!other.is_valid(&public_keys, 2));
+
+        // a node signs in the name of another one
+        certificate
+            .votes
+            .insert(voters[2], sign_vote(&keypairs[0], &command_view));
+        assert!(!certificate.is_valid(&public_keys, 2));
+
+        // a vote from an unknown node
+        certificate.votes.remove(&voters[2]);
+        let unknown: SocketAddr = "127.0.0.1:10063".parse().unwrap();
+        certificate
+            .votes
+            .insert(unknown, sign_vote(&generate_keypair(), &command_view));
+        assert!(!certificate.is_valid(&public_keys, 2));
+    }
+}
@@ -1,6 +1,8 @@
 use std::fmt;
 use std::net::SocketAddr;
 
+use crate::certificate::QuorumCertificate;
+use ed25519_dalek::Signature;
 use lib::command::ClientCommand;
 use serde::{Deserialize, Serialize};
 
@@ -8,13 +10,16 @@ use serde::{Deserialize, Serialize};
 pub enum NetworkCommand {
     Propose {
         command_view: CommandView,
+        /// Where clients reach the primary, for the backups to redirect them there.
+        client_address: Option<SocketAddr>,
     },
     Lock {
         socket_addr: SocketAddr,
         command_view: CommandView,
+        signature: Signature,
     },
     Commit {
-        command_view: CommandView,
+        certificate: QuorumCertificate,
     },
 
     // view change
@@ -26,7 +31,11 @@ pub enum NetworkCommand {
     ViewChange {
         socket_addr: SocketAddr,
         new_view: u128,
-        highest_lock: CommandView,
+        highest_lock: Option<QuorumCertificate>,
+        /// The command-views the sender locked and didn't apply, which the new primary proposes again.
+        locks: Vec<CommandView>,
+        /// Where clients reach the sender, which the backups redirect them to if it's the new primary.
+        client_address: Option<SocketAddr>,
     },
     Forward {
         command: ClientCommand,
@@ -37,17 +46,7 @@ pub enum NetworkCommand {
 pub struct CommandView {
     pub command: ClientCommand, // lock_value
     pub view: u128,             // lock_view
-}
-
-impl CommandView {
-    pub fn new() -> CommandView {
-        CommandView {
-            command: ClientCommand::Get {
-                key: "-".to_string(),
-            },
-            view: 0,
-        }
-    }
+    pub sequence: u64,          // position of the command in the log
 }
 
 impl fmt::Display for NetworkCommand {
@@ -1,10 +1,20 @@
-use crate::node::{Node, State};
+use crate::node::{Byzantine, Node, State};
 use clap::Parser;
-use lib::{command::ClientCommand, network::Receiver};
+use ed25519_dalek::PublicKey;
+use lib::{
+    command::ClientCommand,
+    network::{
+        faults::{self, Faults},
+        Receiver,
+    },
+};
 use log::{info, warn};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::task::JoinHandle;
 
+mod certificate;
 mod command_ext;
 mod node;
 
@@ -34,6 +44,40 @@ struct Cli {
     /// If view-change mechanism is enabled, you can set the delta time (in ms)
     #[clap(short, long, value_parser, value_name = "UINT")]
     view_change_delta_ms: Option<u16>,
+    /// The file with the secret key the node signs its votes with, in hex. A new key is generated and
+    /// saved there if it doesn't exist. Defaults to `.key_<port>`.
+    #[clap(long, value_parser, value_name = "FILE")]
+    key: Option<PathBuf>,
+    /// The public keys of the peers, in hex and in the same order as `--peers`.
+    #[clap(
+        long,
+        value_parser = certificate::parse_public_key,
+        value_name = "HEX",
+        use_value_delimiter = true,
+        value_delimiter = ' '
+    )]
+    peer_keys: Vec<PublicKey>,
+    /// Print the public key of the node and exit.
+    #[clap(long)]
+    public_key: bool,
+    /// The maximum number of commands the primary proposes before the first of them is committed.
+    #[clap(long, value_parser, value_name = "UINT", default_value_t = 1)]
+    pipeline_depth: usize,
+    /// Make certificates need a quorum that tolerates byzantine nodes (n > 3f) instead of crashed
+    /// ones (n > 2f).
+    #[clap(long)]
+    byzantine_quorums: bool,
+    /// Run the node with a faulty behavior, to test the safety checks of the other nodes.
+    #[clap(long, value_enum, value_name = "BEHAVIOR")]
+    byzantine: Option<Byzantine>,
+    /// As a backup, reply to client writes with the address of the primary instead of forwarding them to it,
+    /// so the clients get the result of their writes.
+    #[clap(long)]
+    redirect: bool,
+    /// A script of faults to inject in the messages this node sends, with one fault per line preceded by
+    /// the milliseconds since the node started when it's applied, e.g. `1000 crash 127.0.0.1:6200`.
+    #[clap(long, value_parser, value_name = "FILE")]
+    faults: Option<PathBuf>,
 
     /// The key/value store command to execute.
     #[clap(subcommand)]
@@ -60,24 +104,60 @@ async fn main() {
         return send_command(client_address, cmd).await;
     }
 
-    let node = Node::new(
-        cli.peers,
-        &format!(".db_{}", network_address.port()),
-        network_address,
-        cli.view_change_delta_ms,
-    );
+    let key_path = cli
+        .key
+        .unwrap_or_else(|| PathBuf::from(format!(".key_{}", network_address.port())));
+    let keypair = certificate::read_keypair(&key_path).unwrap();
+    if cli.public_key {
+        println!("{}", hex::encode(keypair.public.as_bytes()));
+        return;
+    }
+    if cli.peer_keys.len() != cli.peers.len() {
+        warn!("Not every peer has a public key, their votes will be rejected");
+    }
 
-    info!(
-        "Node: Running on {}. Primary = {}...",
-        node.socket_address,
-        matches!(node.get_state(), State::Primary)
-    );
+    let faults = Faults::new();
+    if let Some(path) = &cli.faults {
+        let script = faults::load_script(path).unwrap();
+        faults.run_script(script);
+    }
 
-    let (_, network_handle, _) = spawn_node_tasks(network_address, client_address, node).await;
-    network_handle.await.unwrap();
+    // the node is built again from its store each time the faults script restarts it
+    let keypair = Arc::new(keypair);
+    let node_faults = faults.clone();
+    faults.spawn(network_address, move || {
+        let mut node = Node::new(
+            cli.peers.clone(),
+            &format!(".db_{}", network_address.port()),
+            network_address,
+            cli.view_change_delta_ms,
+        );
+        node.pipeline_depth = cli.pipeline_depth;
+        node.byzantine_quorums = cli.byzantine_quorums;
+        node.byzantine = cli.byzantine;
+        node.redirect = cli.redirect;
+        node.client_address = Some(client_address);
+        node.keypair = keypair.clone();
+        node.public_keys
+            .extend(cli.peers.iter().copied().zip(cli.peer_keys.iter().copied()));
+        node.sender = Box::new(node_faults.sender(network_address, node.sender.clone()));
+
+        info!(
+            "Node: Running on {}. Primary = {}...",
+            node.socket_address,
+            matches!(node.get_state(), State::Primary)
+        );
+
+        let (node_handle, network_handle, client_handle) =
+            spawn_node_tasks(network_address, client_address, node);
+        vec![node_handle, network_handle, client_handle]
+    });
+
+    // keep running while the node is crashed
+    std::future::pending::<()>().await;
 }
 
-async fn spawn_node_tasks(
+fn spawn_node_tasks(
     network_address: SocketAddr,
     client_address: SocketAddr,
     mut node: Node,
@@ -115,9 +195,19 @@ async fn send_command(socket_addr: SocketAddr, command: ClientCommand) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use lib::command::ClientCommand;
+    use crate::certificate::QuorumCertificate;
+    use crate::command_ext::{CommandView, NetworkCommand};
+    use ed25519_dalek::{Keypair, SecretKey};
+    use futures::future::join_all;
+    use lib::cluster::{ClientReply, ClusterClient, NotLeader};
+    use lib::command::{ClientCommand, Consistency};
+    use lib::history::History;
+    use lib::linearizability;
+    use lib::network::simulator::{SimulatedNetwork, SimulatorConfig};
 
+    use std::collections::HashMap;
     use std::fs;
+    use tokio::sync::oneshot;
     use tokio::time::{sleep, Duration};
 
     #[ctor::ctor]
@@ -131,6 +221,24 @@ mod tests {
         format!(".db_test/{suffix}")
     }
 
+    /// The keypair of a test node, derived from its address so that every node knows the public keys
+    /// of the others.
+    fn keypair(address: SocketAddr) -> Keypair {
+        let mut secret = [0; 32];
+        secret[..2].copy_from_slice(&address.port().to_be_bytes());
+        let secret = SecretKey::from_bytes(&secret).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    fn set_keys(node: &mut Node, peers: &[SocketAddr]) {
+        node.keypair = Arc::new(keypair(node.socket_address));
+        node.public_keys = peers
+            .iter()
+            .map(|peer| (*peer, keypair(*peer).public))
+            .collect::<HashMap<_, _>>();
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_only_primary_server() {
         let network_address_primary: SocketAddr = "127.0.0.1:6380".parse().unwrap();
@@ -145,7 +253,7 @@ mod tests {
             Some(100),
         );
 
-        spawn_node_tasks(network_address_primary, client_address_primary, primary).await;
+        spawn_node_tasks(network_address_primary, client_address_primary, primary);
 
         sleep(Duration::from_millis(10)).await;
 
@@ -188,22 +296,25 @@ mod tests {
         let network_address_replica: SocketAddr = "127.0.0.1:6580".parse().unwrap();
         let client_address_replica: SocketAddr = "127.0.0.1:6581".parse().unwrap();
 
-        let backup = node::Node::new(
-            vec![network_address_primary, network_address_replica],
+        let peers = vec![network_address_primary, network_address_replica];
+        let mut backup = node::Node::new(
+            peers.clone(),
             &db_path("backup2"),
             network_address_replica,
             Some(100),
         );
+        set_keys(&mut backup, &peers);
 
-        let primary = node::Node::new(
-            vec![network_address_primary, network_address_replica],
+        let mut primary = node::Node::new(
+            peers.clone(),
             &db_path("primary2"),
             network_address_primary,
             Some(100),
         );
+        set_keys(&mut primary, &peers);
 
-        spawn_node_tasks(network_address_primary, client_address_primary, primary).await;
-        spawn_node_tasks(network_address_replica, client_address_replica, backup).await;
+        spawn_node_tasks(network_address_primary, client_address_primary, primary);
+        spawn_node_tasks(network_address_replica, client_address_replica, backup);
 
         sleep(Duration::from_millis(10)).await;
 
@@ -288,6 +399,962 @@ mod tests {
         }
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_simulated_network() {
+        // a lost lock or commit message is never resent, so the network only delays messages
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10010 + 2 * i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10011 + 2 * i).parse().unwrap())
+            .collect();
+
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let mut node = node::Node::new(
+                network_addresses.clone(),
+                &db_path(&format!("simulated{}", network_address.port())),
+                *network_address,
+                Some(100),
+            );
+            set_keys(&mut node, &network_addresses);
+            node.sender = Box::new(network.sender(*network_address));
+            let (_, network_channel) = network.listen(*network_address);
+            let (_, client_channel) = network.listen(*client_address);
+            tokio::spawn(async move { node.run(network_channel, client_channel).await });
+        }
+
+        let mut client = network.client();
+        ClientCommand::Set {
+            key: "k1".to_string(),
+            value: "v1".to_string(),
+        }
+        .send_with(&mut client, client_addresses[0])
+        .await
+        .unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        // every replica commits the value
+        for client_address in &client_addresses {
+            let reply = ClientCommand::Get {
+                key: "k1".to_string(),
+            }
+            .send_with(&mut client, *client_address)
+            .await
+            .unwrap();
+            assert_eq!(Some("v1".to_string()), reply, "seed {}", network.seed());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipelined_commands() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10050 + 2 * i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10051 + 2 * i).parse().unwrap())
+            .collect();
+
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let mut node = node::Node::new(
+                network_addresses.clone(),
+                &db_path(&format!("pipelined{}", network_address.port())),
+                *network_address,
+                None,
+            );
+            set_keys(&mut node, &network_addresses);
+            node.pipeline_depth = 4;
+            node.sender = Box::new(network.sender(*network_address));
+            let (_, network_channel) = network.listen(*network_address);
+            let (_, client_channel) = network.listen(*client_address);
+            tokio::spawn(async move { node.run(network_channel, client_channel).await });
+        }
+
+        // concurrent clients get several commands in flight at the same time, and the ones that
+        // don't fit in the pipeline wait for the first ones to commit
+        let commands = (0..10).flat_map(|i| {
+            [
+                ClientCommand::Set {
+                    key: "k1".to_string(),
+                    value: i.to_string(),
+                },
+                ClientCommand::Increment {
+                    key: "counter".to_string(),
+                    by: 1,
+                },
+            ]
+        });
+        let sends = commands.map(|command| {
+            let mut client = network.client();
+            let address = client_addresses[0];
+            async move { command.send_with(&mut client, address).await }
+        });
+        for result in join_all(sends).await {
+            result.unwrap();
+        }
+
+        sleep(Duration::from_millis(500)).await;
+
+        // every replica applies the commands in the same order as the primary
+        let mut client = network.client();
+        let mut replies = Vec::new();
+        for client_address in &client_addresses {
+            let mut values = Vec::new();
+            for key in ["k1", "counter"] {
+                let reply = ClientCommand::Get {
+                    key: key.to_string(),
+                }
+                .send_with(&mut client, *client_address)
+                .await
+                .unwrap();
+                values.push(reply);
+            }
+            replies.push(values);
+        }
+        assert_eq!(Some("10".to_string()), replies[0][1]);
+        assert!(
+            replies.iter().all(|values| *values == replies[0]),
+            "seed {}",
+            network.seed()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_consistency_levels() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10110 + 2 * i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10111 + 2 * i).parse().unwrap())
+            .collect();
+
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let mut node = node::Node::new(
+                network_addresses.clone(),
+                &db_path(&format!("reads{}", network_address.port())),
+                *network_address,
+                Some(100),
+            );
+            set_keys(&mut node, &network_addresses);
+            node.client_address = Some(*client_address);
+            node.sender = Box::new(network.sender(*network_address));
+            let (_, network_channel) = network.listen(*network_address);
+            let (_, client_channel) = network.listen(*client_address);
+            tokio::spawn(async move { node.run(network_channel, client_channel).await });
+        }
+
+        let read = |address, consistency| {
+            let command = ClientCommand::Read {
+                key: "k1".to_string(),
+                consistency,
+            };
+            let mut client = network.client();
+            async move { command.send_with(&mut client, address).await }
+        };
+        ClientCommand::Set {
+            key: "k1".to_string(),
+            value: "v1".to_string(),
+        }
+        .send_with(&mut network.client(), client_addresses[0])
+        .await
+        .unwrap();
+
+        // the primary replies to the write before committing it, but a linearizable read is ordered
+        // after it, and the quorum it took gives the primary a lease
+        for consistency in [Consistency::Linearizable, Consistency::Lease] {
+            let reply = read(client_addresses[0], consistency).await.unwrap();
+            assert_eq!(Some("v1".to_string()), reply, "seed {}", network.seed());
+        }
+
+        // backups redirect reads that need the primary to the address it proposed the write with, and
+        // serve stale ones once they applied the commits
+        sleep(Duration::from_millis(100)).await;
+        let reply = read(client_addresses[1], Consistency::Lease).await;
+        assert_eq!(
+            NotLeader(Some(client_addresses[0])).to_string(),
+            reply.unwrap_err().to_string()
+        );
+        let stale = Consistency::Stale {
+            max_staleness_ms: 300,
+        };
+        let reply = read(client_addresses[1], stale).await.unwrap();
+        assert_eq!(Some("v1".to_string()), reply, "seed {}", network.seed());
+
+        // without new commits the backups can't tell how stale they are, and the lease of the primary
+        // expires, so a lease read is ordered with the writes again
+        sleep(Duration::from_millis(400)).await;
+        assert!(read(client_addresses[1], stale).await.is_err());
+        let reply = read(client_addresses[0], Consistency::Lease).await.unwrap();
+        assert_eq!(Some("v1".to_string()), reply, "seed {}", network.seed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crashed_primary() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10020 + 2 * i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10021 + 2 * i).parse().unwrap())
+            .collect();
+
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let (network, node_faults) = (network.clone(), faults.clone());
+            let (network_address, client_address) = (*network_address, *client_address);
+            let peers = network_addresses.clone();
+            faults.spawn(network_address, move || {
+                let mut node = node::Node::new(
+                    peers.clone(),
+                    &db_path(&format!("crashed{}", network_address.port())),
+                    network_address,
+                    Some(100),
+                );
+                set_keys(&mut node, &peers);
+                node.client_address = Some(client_address);
+                node.redirect = true;
+                let sender = network.sender(network_address);
+                node.sender = Box::new(node_faults.sender(network_address, sender));
+                let (network_handle, network_channel) = network.listen(network_address);
+                let (client_handle, client_channel) = network.listen(client_address);
+                let node_handle =
+                    tokio::spawn(async move { node.run(network_channel, client_channel).await });
+                vec![node_handle, network_handle, client_handle]
+            });
+        }
+
+        // the primary of the first view stops answering
+        faults.crash(network_addresses[0]);
+
+        // the backups redirect the writes, until they blame the crashed primary and move to a view
+        // where one of them is the primary
+        let mut client = network.client();
+        let mut cluster =
+            ClusterClient::with_sender(client_addresses[1..].to_vec(), Box::new(network.client()));
+        let mut committed = false;
+        for _ in 0..50 {
+            let command = ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            };
+            let _ = cluster.send(command).await;
+            sleep(Duration::from_millis(100)).await;
+
+            let reply = ClientCommand::Get {
+                key: "k1".to_string(),
+            }
+            .send_with(&mut client, client_addresses[2])
+            .await
+            .unwrap();
+            if reply == Some("v1".to_string()) {
+                committed = true;
+                break;
+            }
+        }
+        assert!(
+            committed,
+            "value was never committed, seed {}",
+            network.seed()
+        );
+
+        // the crashed node doesn't answer, and once restarted it's built from its store, which
+        // missed the commit
+        let get = ClientCommand::Get {
+            key: "k1".to_string(),
+        };
+        assert!(get
+            .clone()
+            .send_with(&mut client, client_addresses[0])
+            .await
+            .is_err());
+        faults.restart(network_addresses[0]);
+        sleep(Duration::from_millis(50)).await;
+        let reply = get
+            .send_with(&mut client, client_addresses[0])
+            .await
+            .unwrap();
+        assert!(reply.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_view_change_keeps_committed_command() {
+        // with a fixed latency, the first primary gets the lock of a backup before its commit is dropped
+        let network = SimulatedNetwork::new(SimulatorConfig {
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(10),
+            ..SimulatorConfig::default()
+        });
+        let faults = Faults::new();
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10120 + 2 * i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10121 + 2 * i).parse().unwrap())
+            .collect();
+
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let (network, node_faults) = (network.clone(), faults.clone());
+            let (network_address, client_address) = (*network_address, *client_address);
+            let peers = network_addresses.clone();
+            faults.spawn(network_address, move || {
+                let mut node = node::Node::new(
+                    peers.clone(),
+                    &db_path(&format!("committed{}", network_address.port())),
+                    network_address,
+                    Some(100),
+                );
+                set_keys(&mut node, &peers);
+                node.client_address = Some(client_address);
+                node.redirect = true;
+                let sender = network.sender(network_address);
+                node.sender = Box::new(node_faults.sender(network_address, sender));
+                let (network_handle, network_channel) = network.listen(network_address);
+                let (client_handle, client_channel) = network.listen(client_address);
+                let node_handle =
+                    tokio::spawn(async move { node.run(network_channel, client_channel).await });
+                vec![node_handle, network_handle, client_handle]
+            });
+        }
+
+        // the primary of the next view never hears from the first one, and the other backup gets its
+        // proposal but not its commit
+        let (first, other) = (network_addresses[0], network_addresses[2]);
+        faults.drop_link(first, network_addresses[1]);
+        let mut client = network.client();
+        ClientCommand::Set {
+            key: "k1".to_string(),
+            value: "v1".to_string(),
+        }
+        .send_with(&mut client, client_addresses[0])
+        .await
+        .unwrap();
+        faults.drop_link(first, other);
+        sleep(Duration::from_millis(50)).await;
+
+        // the first primary committed the command on the quorum of itself and the other backup
+        let get = |key: &str| ClientCommand::Get {
+            key: key.to_string(),
+        };
+        let reply = get("k1")
+            .send_with(&mut client, client_addresses[0])
+            .await
+            .unwrap();
+        assert_eq!(Some("v1".to_string()), reply);
+        faults.crash(first);
+
+        // the next primary proposes the locked command again before the new one, so it keeps its
+        // sequence number
+        let mut cluster =
+            ClusterClient::with_sender(client_addresses[1..].to_vec(), Box::new(network.client()));
+        let mut committed = false;
+        for _ in 0..50 {
+            let command = ClientCommand::Set {
+                key: "k2".to_string(),
+                value: "v2".to_string(),
+            };
+            let _ = cluster.send(command).await;
+            sleep(Duration::from_millis(100)).await;
+
+            let reply = get("k2")
+                .send_with(&mut client, client_addresses[2])
+                .await
+                .unwrap();
+            if reply == Some("v2".to_string()) {
+                committed = true;
+                break;
+            }
+        }
+        assert!(
+            committed,
+            "value was never committed, seed {}",
+            network.seed()
+        );
+        for client_address in &client_addresses[1..] {
+            let reply = get("k1")
+                .send_with(&mut client, *client_address)
+                .await
+                .unwrap();
+            assert_eq!(Some("v1".to_string()), reply, "seed {}", network.seed());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_linearizable_history() {
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10030 + 2 * i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10031 + 2 * i).parse().unwrap())
+            .collect();
+
+        // without view changes the first node stays the primary
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let mut node = node::Node::new(
+                network_addresses.clone(),
+                &db_path(&format!("linearizable{}", network_address.port())),
+                *network_address,
+                None,
+            );
+            set_keys(&mut node, &network_addresses);
+            spawn_node_tasks(*network_address, *client_address, node);
+        }
+
+        sleep(Duration::from_millis(10)).await;
+
+        // the primary replies to writes before they are committed, so their outcome is unknown,
+        // but reads at the primary should still agree on a single order of the committed writes.
+        // Writes with unknown outcomes can be linearized at any later point, which makes checking
+        // exponential on their number, so the history is kept short
+        let history = History::with_unacknowledged_writes();
+        history
+            .run_clients(4, 10, &["k1", "k2"], |_| client_addresses[0])
+            .await;
+
+        if let Err(violations) = linearizability::check(&history.operations()) {
+            panic!("{}", violations[0]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart_keeps_lock() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10040 + 2 * i).parse().unwrap())
+            .collect();
+        let address = network_addresses[1];
+        let path = db_path("restart");
+        let start_backup = || {
+            let mut node = node::Node::new(network_addresses.clone(), &path, address, None);
+            node.sender = Box::new(network.sender(address));
+            set_keys(&mut node, &network_addresses);
+            node
+        };
+
+        // the backup locks a proposal and sends its Lock message, then crashes
+        let mut backup = start_backup();
+        let command_view = CommandView {
+            command: ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            },
+            view: 0,
+            sequence: 1,
+        };
+        backup
+            .handle_network_msg(NetworkCommand::Propose {
+                command_view: command_view.clone(),
+                client_address: None,
+            })
+            .await
+            .unwrap();
+
+        // a view change doesn't release the lock either
+        backup
+            .handle_network_msg(NetworkCommand::ViewChange {
+                socket_addr: network_addresses[2],
+                new_view: 2,
+                highest_lock: None,
+                locks: Vec::new(),
+                client_address: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(Some(&command_view), backup.locks.get(&1));
+        drop(backup);
+        // let the store task close the database
+        sleep(Duration::from_millis(10)).await;
+
+        // once restarted it still holds the lock, and can commit the command when the primary asks it to
+        let mut backup = start_backup();
+        backup.restore().await.unwrap();
+        assert_eq!(Some(&command_view), backup.locks.get(&1));
+        assert_eq!(0, backup.committed);
+        assert_eq!(2, backup.current_view);
+
+        let certificate = QuorumCertificate {
+            command_view: command_view.clone(),
+            votes: network_addresses[..2]
+                .iter()
+                .map(|voter| {
+                    (
+                        *voter,
+                        certificate::sign_vote(&keypair(*voter), &command_view),
+                    )
+                })
+                .collect(),
+        };
+        backup
+            .handle_network_msg(NetworkCommand::Commit { certificate })
+            .await
+            .unwrap();
+        drop(backup);
+        sleep(Duration::from_millis(10)).await;
+
+        // after another restart it knows the command was applied, holds no lock, and still has the
+        // certificate of the last commit
+        let mut backup = start_backup();
+        backup.restore().await.unwrap();
+        assert_eq!(1, backup.committed);
+        assert!(backup.locks.is_empty());
+        assert_eq!(
+            Some(&command_view),
+            backup
+                .highest_certificate
+                .as_ref()
+                .map(|certificate| &certificate.command_view)
+        );
+        let reply = ClientCommand::Get {
+            key: "k1".to_string(),
+        }
+        .apply(&backup.store)
+        .await
+        .unwrap();
+        assert_eq!(Ok(Some("v1".to_string())), reply);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forged_votes_rejected() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10070 + 2 * i).parse().unwrap())
+            .collect();
+        let mut primary = node::Node::new(
+            network_addresses.clone(),
+            &db_path("forged_primary"),
+            network_addresses[0],
+            None,
+        );
+        primary.sender = Box::new(network.sender(network_addresses[0]));
+        set_keys(&mut primary, &network_addresses);
+        let mut backup = node::Node::new(
+            network_addresses.clone(),
+            &db_path("forged_backup"),
+            network_addresses[1],
+            None,
+        );
+        backup.sender = Box::new(network.sender(network_addresses[1]));
+        set_keys(&mut backup, &network_addresses);
+
+        primary
+            .handle_client_msg(ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            })
+            .await
+            .unwrap();
+        let command_view = primary.locks[&1].clone();
+        backup
+            .handle_network_msg(NetworkCommand::Propose {
+                command_view: command_view.clone(),
+                client_address: None,
+            })
+            .await
+            .unwrap();
+
+        // the third node votes in the name of the backup
+        let forged = certificate::sign_vote(&keypair(network_addresses[2]), &command_view);
+        let lock = NetworkCommand::Lock {
+            socket_addr: network_addresses[1],
+            command_view: command_view.clone(),
+            signature: forged,
+        };
+        assert!(primary.handle_network_msg(lock).await.is_err());
+        assert_eq!(0, primary.committed);
+
+        // a commit certified by the primary alone is not enough
+        let certificate = QuorumCertificate {
+            command_view: command_view.clone(),
+            votes: primary.lock_responses[&1].clone(),
+        };
+        let commit = NetworkCommand::Commit { certificate };
+        assert!(backup.handle_network_msg(commit).await.is_err());
+        assert_eq!(0, backup.committed);
+
+        // the genuine vote of the backup commits the command
+        let lock = NetworkCommand::Lock {
+            socket_addr: network_addresses[1],
+            signature: certificate::sign_vote(&keypair(network_addresses[1]), &command_view),
+            command_view,
+        };
+        primary.handle_network_msg(lock).await.unwrap();
+        assert_eq!(1, primary.committed);
+        let certificate = primary.highest_certificate.clone().unwrap();
+        backup
+            .handle_network_msg(NetworkCommand::Commit { certificate })
+            .await
+            .unwrap();
+        assert_eq!(1, backup.committed);
+    }
+
+    /// Run a cluster of the given nodes whose primary equivocates on a write, and return the value of
+    /// the written key on each backup.
+    async fn run_equivocating_primary(
+        first_port: u16,
+        size: u16,
+        byzantine_quorums: bool,
+    ) -> Vec<Option<String>> {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let network_addresses: Vec<SocketAddr> = (0..size)
+            .map(|i| format!("127.0.0.1:{}", first_port + 2 * i).parse().unwrap())
+            .collect();
+        let client_addresses: Vec<SocketAddr> = (0..size)
+            .map(|i| {
+                format!("127.0.0.1:{}", first_port + 1 + 2 * i)
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let mut node = node::Node::new(
+                network_addresses.clone(),
+                &db_path(&format!("equivocate{}", network_address.port())),
+                *network_address,
+                None,
+            );
+            set_keys(&mut node, &network_addresses);
+            node.byzantine_quorums = byzantine_quorums;
+            if *network_address == network_addresses[0] {
+                node.byzantine = Some(Byzantine::Equivocate);
+            }
+            node.sender = Box::new(network.sender(*network_address));
+            let (_, network_channel) = network.listen(*network_address);
+            let (_, client_channel) = network.listen(*client_address);
+            tokio::spawn(async move { node.run(network_channel, client_channel).await });
+        }
+
+        let mut client = network.client();
+        ClientCommand::Set {
+            key: "k1".to_string(),
+            value: "v1".to_string(),
+        }
+        .send_with(&mut client, client_addresses[0])
+        .await
+        .unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        let mut values = Vec::new();
+        for client_address in &client_addresses[1..] {
+            let reply = ClientCommand::Get {
+                key: "k1".to_string(),
+            }
+            .send_with(&mut client, *client_address)
+            .await
+            .unwrap();
+            values.push(reply);
+        }
+        values
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_equivocation_breaks_crash_quorums() {
+        // each half of the backups and the primary make up a majority, so both commands commit
+        let values = run_equivocating_primary(10080, 5, false).await;
+        assert_eq!(
+            vec![
+                Some("v1".to_string()),
+                Some("v1".to_string()),
+                Some("v1'".to_string()),
+                Some("v1'".to_string())
+            ],
+            values
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_equivocation_caught_by_byzantine_quorums() {
+        // neither half of the backups makes up a quorum with the primary, so the honest nodes never
+        // apply conflicting commands, though the write doesn't commit either
+        let values = run_equivocating_primary(10090, 5, true).await;
+        assert_eq!(vec![None; 4], values);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_ordered_after_reproposed_locks() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let peers: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10130 + 2 * i).parse().unwrap())
+            .collect();
+        let mut primary = node::Node::new(peers.clone(), &db_path("reproposed"), peers[1], None);
+        primary.sender = Box::new(network.sender(peers[1]));
+        set_keys(&mut primary, &peers);
+
+        // a backup reports the lock of a write from the previous view, which the primary of the next
+        // view proposes again once a quorum moved to it
+        let write = CommandView {
+            command: ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            },
+            view: 0,
+            sequence: 1,
+        };
+        let view_change = |socket_addr, locks| NetworkCommand::ViewChange {
+            socket_addr,
+            new_view: 1,
+            highest_lock: None,
+            locks,
+            client_address: None,
+        };
+        primary
+            .handle_network_msg(view_change(peers[0], vec![write]))
+            .await
+            .unwrap();
+        assert!(primary.recovering);
+
+        // a read ordered meanwhile is proposed after the write, and gets its own result
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let read = ClientCommand::Read {
+            key: "k2".to_string(),
+            consistency: Consistency::Linearizable,
+        };
+        primary.proccess_client_msg(read, reply_sender).await;
+        primary
+            .handle_network_msg(view_change(peers[2], Vec::new()))
+            .await
+            .unwrap();
+        assert!(!primary.recovering);
+
+        // committing the write frees the pipeline for the read
+        for sequence in 1..=2 {
+            let command_view = primary.locks[&sequence].clone();
+            let lock = NetworkCommand::Lock {
+                socket_addr: peers[0],
+                signature: certificate::sign_vote(&keypair(peers[0]), &command_view),
+                command_view,
+            };
+            primary.handle_network_msg(lock).await.unwrap();
+        }
+        assert_eq!(2, primary.committed);
+        assert_eq!(ClientReply::Ok(None), reply_receiver.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reproposed_locks_fill_gaps() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let peers: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10140 + 2 * i).parse().unwrap())
+            .collect();
+        let mut primary = node::Node::new(peers.clone(), &db_path("gaps"), peers[1], None);
+        primary.sender = Box::new(network.sender(peers[1]));
+        set_keys(&mut primary, &peers);
+
+        // with pipelined commands only the second write of the previous view was locked by a backup
+        let write = CommandView {
+            command: ClientCommand::Set {
+                key: "k2".to_string(),
+                value: "v2".to_string(),
+            },
+            view: 0,
+            sequence: 2,
+        };
+        for (socket_addr, locks) in [(peers[0], vec![write.clone()]), (peers[2], Vec::new())] {
+            primary
+                .handle_network_msg(NetworkCommand::ViewChange {
+                    socket_addr,
+                    new_view: 1,
+                    highest_lock: None,
+                    locks,
+                    client_address: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        // the missing sequence number is proposed as a no-op, so the write can be applied after it
+        let empty = ClientCommand::Batch {
+            commands: Vec::new(),
+        };
+        assert_eq!(empty, primary.locks[&1].command);
+        assert_eq!(write.command, primary.locks[&2].command);
+        assert_eq!(3, primary.next_sequence);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_commit_without_proposal() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let peers: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10150 + 2 * i).parse().unwrap())
+            .collect();
+        let start_node = |index: usize| {
+            let mut node = node::Node::new(
+                peers.clone(),
+                &db_path(&format!("without_proposal{}", peers[index].port())),
+                peers[index],
+                None,
+            );
+            node.sender = Box::new(network.sender(peers[index]));
+            set_keys(&mut node, &peers);
+            node
+        };
+        let mut primary = start_node(0);
+        let mut backup = start_node(2);
+
+        primary
+            .handle_client_msg(ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            })
+            .await
+            .unwrap();
+        let command_view = primary.locks[&1].clone();
+        let lock = NetworkCommand::Lock {
+            socket_addr: peers[1],
+            signature: certificate::sign_vote(&keypair(peers[1]), &command_view),
+            command_view,
+        };
+        primary.handle_network_msg(lock).await.unwrap();
+        assert_eq!(1, primary.committed);
+
+        // the backup missed the proposal, but a quorum certified the command
+        let certificate = primary.highest_certificate.clone().unwrap();
+        backup
+            .handle_network_msg(NetworkCommand::Commit { certificate })
+            .await
+            .unwrap();
+        assert_eq!(1, backup.committed);
+        let value = backup.store.read("k1".into()).await.unwrap();
+        assert_eq!(Some("v1".into()), value);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lock_restarts_blame_timer() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let peers: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10160 + 2 * i).parse().unwrap())
+            .collect();
+        let mut backup =
+            node::Node::new(peers.clone(), &db_path("blame_timer"), peers[1], Some(50));
+        backup.sender = Box::new(network.sender(peers[1]));
+        set_keys(&mut backup, &peers);
+
+        // the lease of the primary starts once the backups locked, so their timer can't expire before it
+        sleep(Duration::from_millis(300)).await;
+        let command_view = CommandView {
+            command: ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            },
+            view: 0,
+            sequence: 1,
+        };
+        backup
+            .handle_network_msg(NetworkCommand::Propose {
+                command_view,
+                client_address: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(Duration::ZERO, backup.timer_start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_conflicting_messages_rejected() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let network_addresses: Vec<SocketAddr> = (0..3)
+            .map(|i| format!("127.0.0.1:{}", 10100 + 2 * i).parse().unwrap())
+            .collect();
+        let start_node = |index: usize, byzantine: Option<Byzantine>| {
+            let address = network_addresses[index];
+            let mut node = node::Node::new(
+                network_addresses.clone(),
+                &db_path(&format!("conflicting{}", address.port())),
+                address,
+                None,
+            );
+            node.sender = Box::new(network.sender(address));
+            node.byzantine = byzantine;
+            set_keys(&mut node, &network_addresses);
+            node
+        };
+        let mut primary = start_node(0, Some(Byzantine::ConflictingCommits));
+        let mut backup = start_node(1, Some(Byzantine::ConflictingLocks));
+        let mut honest_backup = start_node(2, None);
+        let (_, mut primary_channel) = network.listen(network_addresses[0]);
+        let (_, mut backup_channel) = network.listen(network_addresses[1]);
+
+        primary
+            .handle_client_msg(ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            })
+            .await
+            .unwrap();
+        let command_view = primary.locks[&1].clone();
+
+        // a second proposal for the same sequence number in the same view is not voted for
+        let conflicting = CommandView {
+            command: command_view.command.conflicting(),
+            ..command_view.clone()
+        };
+        honest_backup
+            .handle_network_msg(NetworkCommand::Propose {
+                command_view: command_view.clone(),
+                client_address: None,
+            })
+            .await
+            .unwrap();
+        let proposal = NetworkCommand::Propose {
+            command_view: conflicting.clone(),
+            client_address: None,
+        };
+        assert!(honest_backup.handle_network_msg(proposal).await.is_err());
+        assert_eq!(Some(&command_view), honest_backup.locks.get(&1));
+
+        // the primary doesn't count a vote for a command it didn't propose
+        backup
+            .handle_network_msg(NetworkCommand::Propose {
+                command_view: command_view.clone(),
+                client_address: None,
+            })
+            .await
+            .unwrap();
+        // the vote of the honest backup may arrive first
+        let lock = loop {
+            let (message, reply_sender): (NetworkCommand, oneshot::Sender<String>) =
+                primary_channel.recv().await.unwrap();
+            let _ = reply_sender.send("ACK".to_string());
+            if let NetworkCommand::Lock {
+                socket_addr,
+                command_view,
+                ..
+            } = &message
+            {
+                if *socket_addr == network_addresses[1] {
+                    assert_eq!(conflicting, *command_view);
+                    break message;
+                }
+            }
+        };
+        primary.handle_network_msg(lock).await.unwrap();
+        assert_eq!(0, primary.committed);
+
+        // the honest vote commits the command, and the Commit the primary sends for a conflicting
+        // command is only certified by itself
+        let lock = NetworkCommand::Lock {
+            socket_addr: network_addresses[2],
+            signature: certificate::sign_vote(&keypair(network_addresses[2]), &command_view),
+            command_view: command_view.clone(),
+        };
+        primary.handle_network_msg(lock).await.unwrap();
+        assert_eq!(1, primary.committed);
+        let commit = loop {
+            let (message, reply_sender): (NetworkCommand, oneshot::Sender<String>) =
+                backup_channel.recv().await.unwrap();
+            let _ = reply_sender.send("ACK".to_string());
+            if let NetworkCommand::Commit { certificate } = &message {
+                assert_eq!(conflicting, certificate.command_view);
+                break message;
+            }
+        };
+        assert!(backup.handle_network_msg(commit).await.is_err());
+        assert_eq!(0, backup.committed);
+    }
+
     // in order for the `move` not to change Node's memory location, this function takes a Box<Node> instead of a <Node>
     async fn spawn_node_tasks_test(
         network_address: SocketAddr,
@@ -1,42 +1,121 @@
+use crate::certificate::{self, QuorumCertificate};
 use crate::command_ext::{CommandView, NetworkCommand};
 /// This module contains an implementation nodes that can run in primary or backup mode.
 /// Every Set command to a primary node will be broadcasted reliably for the backup nodes to replicate it.
 /// We plan to add backup promotion in case of primary failure.
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
+use clap::ValueEnum;
+use ed25519_dalek::{Keypair, PublicKey, Signature};
 use lib::{
-    command::{ClientCommand, CommandResult},
-    network::SimpleSender,
+    cluster::ClientReply,
+    command::{ClientCommand, CommandResult, Consistency},
+    network::{MessageSender, SimpleSender},
     store::Store,
 };
 use log::{error, info};
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     net::SocketAddr,
-    time::{self, Duration, Instant},
+    sync::Arc,
 };
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::oneshot::{self, Sender};
+use tokio::time::{self, Duration, Instant};
+
+/// The key under which the current view is persisted, prefixed with a byte that can't start a UTF-8
+/// client key.
+const VIEW_KEY: &[u8] = b"\xffview";
+/// The prefix of the keys under which the locked command-views are persisted, followed by their
+/// sequence number.
+const LOCK_PREFIX: &[u8] = b"\xfflock/";
+/// The key under which the sequence number of the last applied command is persisted.
+const COMMITTED_KEY: &[u8] = b"\xffcommitted";
+/// The key under which the certificate of the highest lock is persisted.
+const CERTIFICATE_KEY: &[u8] = b"\xffcertificate";
 
-#[derive(Clone)]
 /// A message handler that just forwards key/value store requests from clients to an internal rocksdb store.
 pub struct Node {
     pub socket_address: SocketAddr,
+    /// Where clients reach this node, which it tells the backups as primary.
+    pub client_address: Option<SocketAddr>,
+    /// Where clients reach the primary of the current view, if it told this node.
+    pub primary_client_address: Option<SocketAddr>,
+    /// Whether as backup this node redirects client writes to the primary instead of forwarding them to it.
+    pub redirect: bool,
     pub store: Store,
     pub peers: Vec<SocketAddr>,
-    pub sender: SimpleSender,
+    pub sender: Box<dyn MessageSender>,
 
     pub view_change_delta_ms: Option<u16>,
     pub timer_start: time::Instant,
 
+    /// The keypair this node signs its Lock votes with.
+    pub keypair: Arc<Keypair>,
+    /// The public keys of the peers, to verify their votes.
+    pub public_keys: HashMap<SocketAddr, PublicKey>,
+    /// The certificate of the last command committed, which justifies the highest lock during a view change.
+    pub highest_certificate: Option<QuorumCertificate>,
+
+    // the view, the locks and the highest certificate are persisted in the store, so that a restarted
+    // node doesn't lock or vote for a command conflicting with the one it locked before. Locks are
+    // kept across view changes, and only removed once their command is applied
     pub current_view: u128,
-    pub command_view_lock: CommandView,
+    /// The command-views locked and not applied yet, by sequence number.
+    pub locks: BTreeMap<u64, CommandView>,
+    /// The sequence number of the last command applied to the store.
+    pub committed: u64,
+    /// The committed command-views waiting for the ones before them to be applied.
+    pub commits: BTreeMap<u64, CommandView>,
+
+    /// The sequence number of the next command proposed as primary.
+    pub next_sequence: u64,
+    /// Whether the node became primary of the current view, and waits for the ViewChange of a quorum
+    /// before proposing new commands.
+    pub recovering: bool,
+    /// The nodes whose ViewChange to the current view the primary got.
+    pub view_changes: HashSet<SocketAddr>,
+    /// The highest-view lock those nodes reported for each sequence number not applied yet. Since any
+    /// command committed in a previous view was locked by a quorum, one of them is among these.
+    pub reported_locks: BTreeMap<u64, CommandView>,
+    /// The maximum number of proposed commands that are not applied yet. Each of them collects its
+    /// own lock quorum, but they are applied strictly in sequence order.
+    pub pipeline_depth: usize,
+    /// The client commands waiting for a slot in the pipeline, with the reply sender of the reads,
+    /// which are answered once applied.
+    pub queued: VecDeque<(ClientCommand, Option<Sender<ClientReply>>)>,
+    /// The replies to the reads ordered along with the writes, by the sequence number they are applied at.
+    pub reads: HashMap<u64, Sender<ClientReply>>,
+    /// The last time a quorum locked a command this node proposed as primary of the current view, which
+    /// its lease is counted from.
+    pub quorum_at: Option<Instant>,
+    /// The last time the node applied every command it got a Commit for.
+    pub synced_at: Option<Instant>,
 
-    // the amount of peers which responded with "Lock"
-    // note: if we were to create a QC to store in the blockchain,
-    // we would need to store signatures from peers here
-    pub lock_responses: HashSet<SocketAddr>,
+    // the signed "Lock" votes of the peers for each command in flight, which make up its
+    // certificate once they reach a quorum
+    pub lock_responses: HashMap<u64, BTreeMap<SocketAddr, Signature>>,
     pub blame_messages: HashSet<SocketAddr>,
+
+    /// The faulty behavior of this node, if any, to test which faults the protocol tolerates.
+    pub byzantine: Option<Byzantine>,
+    /// Whether certificates need a quorum that tolerates byzantine nodes (n > 3f) instead of
+    /// crashed ones (n > 2f).
+    pub byzantine_quorums: bool,
+    // the conflicting command-views an equivocating primary proposed, and the votes it got for them
+    pub equivocations: HashMap<u64, (CommandView, BTreeMap<SocketAddr, Signature>)>,
+}
+
+/// A faulty behavior a node can be started with, to test the safety checks of the protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Byzantine {
+    /// As primary, propose a command to half of the backups and a conflicting one for the same
+    /// sequence number to the other half, and commit both.
+    Equivocate,
+    /// As backup, send Lock votes for a command conflicting with the proposed one.
+    ConflictingLocks,
+    /// As primary, send Commit messages for a command conflicting with the one the quorum locked.
+    ConflictingCommits,
 }
 
 /// The state of a node viewed as a state-machine.
@@ -55,15 +134,38 @@ impl Node {
         address: SocketAddr,
         view_change_delta_ms: Option<u16>,
     ) -> Self {
+        // until the public keys of the peers are set, the node only knows its own
+        let keypair = certificate::generate_keypair();
+        let public_keys = HashMap::from([(address, keypair.public)]);
         Self {
             store: Store::new(db_path).unwrap(),
+            keypair: Arc::new(keypair),
+            public_keys,
+            highest_certificate: None,
             peers,
-            sender: SimpleSender::new(),
+            sender: Box::new(SimpleSender::new()),
             current_view: 0,
-            command_view_lock: CommandView::new(),
-            lock_responses: HashSet::new(),
+            locks: BTreeMap::new(),
+            committed: 0,
+            commits: BTreeMap::new(),
+            next_sequence: 1,
+            recovering: false,
+            view_changes: HashSet::new(),
+            reported_locks: BTreeMap::new(),
+            pipeline_depth: 1,
+            queued: VecDeque::new(),
+            reads: HashMap::new(),
+            quorum_at: None,
+            synced_at: None,
+            lock_responses: HashMap::new(),
             blame_messages: HashSet::new(),
+            byzantine: None,
+            byzantine_quorums: false,
+            equivocations: HashMap::new(),
             socket_address: address,
+            client_address: None,
+            primary_client_address: None,
+            redirect: false,
             view_change_delta_ms,
             timer_start: Instant::now(),
         }
@@ -92,8 +194,15 @@ impl Node {
     pub async fn run(
         &mut self,
         mut network_receiver: Receiver<(NetworkCommand, oneshot::Sender<String>)>,
-        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<CommandResult>)>,
+        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<ClientReply>)>,
     ) {
+        if let Err(error) = self.restore().await {
+            error!(
+                "{}: failed to restore the view and locks: {}",
+                self.socket_address, error
+            );
+        }
+
         if self.view_change_delta_ms.is_none() {
             loop {
                 tokio::select! {
@@ -104,7 +213,9 @@ impl Node {
                     Some((command, reply_sender)) = network_receiver.recv() => {
                         info!("Received network message {}", command);
                         reply_sender.send("ACK".to_string()).unwrap();
-                        self.handle_network_msg(command.clone()).await.unwrap();
+                        if let Err(error) = self.handle_network_msg(command.clone()).await {
+                            error!("{}: failed to handle message: {}", self.socket_address, error);
+                        }
                     }
                 }
             }
@@ -118,7 +229,9 @@ impl Node {
                     Some((command, reply_sender)) = network_receiver.recv() => {
                         info!("Received network message {}", command);
                         reply_sender.send("ACK".to_string()).unwrap();
-                        self.handle_network_msg(command.clone()).await.unwrap();
+                        if let Err(error) = self.handle_network_msg(command.clone()).await {
+                            error!("{}: failed to handle message: {}", self.socket_address, error);
+                        }
                     }
                     _ = self.check_timer() => ()
                 }
@@ -129,14 +242,38 @@ impl Node {
     pub async fn proccess_client_msg(
         &mut self,
         command: ClientCommand,
-        reply_sender: Sender<Result<Option<String>, String>>,
+        reply_sender: Sender<ClientReply>,
     ) {
-        let result = self
-            .handle_client_msg(command.clone())
-            .await
-            .map_err(|e| e.to_string());
+        let reply = match (self.get_state(), &command) {
+            (_, ClientCommand::Read { consistency, .. }) if self.serves_locally(*consistency) => {
+                self.handle_client_command(command.clone())
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|result| result)
+                    .into()
+            }
+            // backups learn where clients reach the primary from its proposals and view changes
+            (Backup, ClientCommand::Read { .. }) => {
+                ClientReply::Redirect(self.primary_client_address)
+            }
+            (Backup, command) if self.redirect && command.is_write() => {
+                ClientReply::Redirect(self.primary_client_address)
+            }
+            // the other reads are proposed like writes, and answered once applied
+            (Primary, ClientCommand::Read { .. }) => {
+                if let Err(error) = self.order(command, Some(reply_sender)).await {
+                    error!("{}: failed to order read: {}", self.socket_address, error);
+                }
+                return;
+            }
+            _ => self
+                .handle_client_msg(command.clone())
+                .await
+                .map_err(|e| e.to_string())
+                .into(),
+        };
 
-        if let Err(error) = reply_sender.send(result) {
+        if let Err(error) = reply_sender.send(reply) {
             error!("failed to send message {:?} response {:?}", command, error);
         };
     }
@@ -145,30 +282,12 @@ impl Node {
         let state = self.get_state();
 
         match (state, message) {
-            (_, cmd @ ClientCommand::Get { key: _ }) => self.handle_client_command(cmd).await,
+            (_, cmd @ ClientCommand::Get { key: _ }) => self
+                .handle_client_command(cmd)
+                .await?
+                .map_err(|e| anyhow!(e)),
             (Primary, client_comand) => {
-                // we advance the view according to the primary and propose it
-                let command_view = CommandView {
-                    command: client_comand,
-                    view: self.current_view + 1,
-                };
-
-                // since we are primary, we lock the command view
-
-                self.lock_command_view(&command_view);
-                self.handle_lock_message(self.socket_address, command_view.clone())
-                    .await
-                    .unwrap();
-
-                let command = NetworkCommand::Propose {
-                    command_view: command_view.clone(),
-                };
-
-                self.timer_start = Instant::now(); // for blame/view-change
-
-                info!("Received command, broadcasting Propose");
-                self.broadcast_to_others(command).await;
-
+                self.order(client_comand, None).await?;
                 Ok(None)
             }
             (Backup, client_command) => {
@@ -192,21 +311,66 @@ impl Node {
                 NetworkCommand::Lock {
                     socket_addr,
                     command_view,
+                    signature,
                 },
-            ) => self.handle_lock_message(socket_addr, command_view).await,
+            ) => {
+                self.handle_lock_message(socket_addr, command_view, signature)
+                    .await?;
+                // committing frees slots in the pipeline
+                self.propose_queued().await?;
+                Ok(None)
+            }
 
             // a command has been proposed and we can lock it before sending a Lock message
             // for now this happens in the Primary as well, but that functionality could be piggy-backed in the section where we receive the command
-            (_, NetworkCommand::Propose { command_view }) => {
-                // TODO: You should only lock if view number is expected?
-                self.lock_command_view(&command_view);
+            (
+                _,
+                NetworkCommand::Propose {
+                    command_view,
+                    client_address,
+                },
+            ) => {
+                if command_view.view < self.current_view || command_view.sequence <= self.committed
+                {
+                    info!(
+                        "{}: Received a proposal from an old view or for an applied command, discarding",
+                        self.socket_address
+                    );
+                    return Ok(None);
+                }
+                // an honest primary proposes a single command for each sequence number in a view, so
+                // voting for a second one could let a byzantine primary certify both
+                if let Some(locked) = self.locks.get(&command_view.sequence) {
+                    if locked.view == command_view.view && *locked != command_view {
+                        return Err(anyhow!(
+                            "Received a proposal conflicting with the locked {:?}",
+                            locked
+                        ));
+                    }
+                }
+                if command_view.view == self.current_view {
+                    self.primary_client_address = client_address;
+                }
+                // the lock is persisted before sending the Lock message, since the vote has to survive a restart
+                self.lock_command_view(&command_view).await?;
+                // the lease of the primary counts from a quorum of locks, so a vote restarts the blame timer
+                self.timer_start = Instant::now();
                 info!(
                     "{}: View-command locked, sending out Lock message",
                     self.socket_address
                 );
 
+                let command_view = if self.byzantine == Some(Byzantine::ConflictingLocks) {
+                    CommandView {
+                        command: command_view.command.conflicting(),
+                        ..command_view
+                    }
+                } else {
+                    command_view
+                };
                 let lock_command = NetworkCommand::Lock {
                     socket_addr: self.socket_address,
+                    signature: certificate::sign_vote(&self.keypair, &command_view),
                     command_view,
                 };
 
@@ -218,30 +382,44 @@ impl Node {
             (Primary, NetworkCommand::Commit { .. }) => Ok(None),
 
             // the backup gets a Commit message after we reach quorum, so we can go ahead and commit
-            (Backup, NetworkCommand::Commit { command_view }) => {
+            (Backup, NetworkCommand::Commit { certificate }) => {
+                if !certificate.is_valid(&self.public_keys, self.quorum()) {
+                    return Err(anyhow!(
+                        "Received Commit with an invalid certificate for {:?}",
+                        certificate.command_view
+                    ));
+                }
                 info!("about to try commit as a response to Commit message");
 
-                match self.try_commit(command_view).await {
-                    Ok(result) => {
-                        info!(
-                            "{}: Committed command, response was {:?}",
-                            self.socket_address,
-                            result.unwrap()
-                        );
-                        Ok(None)
-                    }
-                    _ => Err(anyhow!("Error committing command")),
+                // a quorum locked the command, so it's committed even if this node missed the proposal
+                match self.commit_certified(certificate).await {
+                    Ok(()) => Ok(None),
+                    Err(error) => Err(anyhow!("Error committing command: {}", error)),
                 }
             }
             // View change moves the view/primary after enough blames were emitted
             (
                 _,
                 NetworkCommand::ViewChange {
-                    socket_addr: _,
+                    socket_addr,
                     new_view,
-                    highest_lock: _,
+                    highest_lock,
+                    locks,
+                    client_address,
                 },
             ) => {
+                if let Some(certificate) = highest_lock {
+                    if !certificate.is_valid(&self.public_keys, self.quorum()) {
+                        return Err(anyhow!(
+                            "Received ViewChange from {} with an invalid certificate",
+                            socket_addr
+                        ));
+                    }
+                    self.commit_certified(certificate).await?;
+                }
+                // the new primary tells where clients reach it
+                let primary_client_address =
+                    client_address.filter(|_| socket_addr == *self.get_primary(new_view));
                 if new_view > self.current_view {
                     info!(
                         "{}: View-change performed, primary is {}",
@@ -249,7 +427,35 @@ impl Node {
                         self.get_primary(new_view)
                     );
                     self.timer_start = Instant::now();
-                    self.trigger_view_change(new_view);
+                    self.trigger_view_change(new_view, primary_client_address)
+                        .await?;
+                    // the commands queued by the previous primary go to the new one
+                    for (command, reply_sender) in std::mem::take(&mut self.queued) {
+                        match (self.get_state(), reply_sender) {
+                            (Primary, reply_sender) => self.order(command, reply_sender).await?,
+                            (Backup, Some(reply_sender)) => {
+                                let _ = reply_sender
+                                    .send(ClientReply::Redirect(self.primary_client_address));
+                            }
+                            (Backup, None) => {
+                                self.handle_client_msg(command).await?;
+                            }
+                        }
+                    }
+                }
+                if new_view == self.current_view && primary_client_address.is_some() {
+                    self.primary_client_address = primary_client_address;
+                }
+                if new_view == self.current_view && self.recovering {
+                    self.view_changes.insert(socket_addr);
+                    for command_view in locks {
+                        if command_view.sequence > self.committed {
+                            keep_highest_lock(&mut self.reported_locks, command_view);
+                        }
+                    }
+                    if self.view_changes.len() >= self.quorum() {
+                        self.repropose_locks().await?;
+                    }
                 }
                 Ok(None)
             }
@@ -274,55 +480,344 @@ impl Node {
         }
     }
 
+    /// The number of commands proposed as primary that are not applied yet.
+    fn in_flight(&self) -> usize {
+        self.next_sequence.saturating_sub(self.committed + 1) as usize
+    }
+
+    /// Propose the command if there is a free slot in the pipeline, or queue it otherwise. A read is
+    /// answered through the given sender once it's applied.
+    async fn order(
+        &mut self,
+        command: ClientCommand,
+        reply_sender: Option<Sender<ClientReply>>,
+    ) -> Result<()> {
+        if !self.recovering && self.in_flight() < self.pipeline_depth {
+            self.propose(command, reply_sender).await
+        } else {
+            info!(
+                "{}: Pipeline is full, queueing command",
+                self.socket_address
+            );
+            self.queued.push_back((command, reply_sender));
+            Ok(())
+        }
+    }
+
+    /// Assign the next sequence number to the command, lock it and broadcast its proposal. The reply
+    /// sender of a read waits for the command applied at that sequence number.
+    async fn propose(
+        &mut self,
+        command: ClientCommand,
+        reply_sender: Option<Sender<ClientReply>>,
+    ) -> Result<()> {
+        let command_view = CommandView {
+            command,
+            view: self.current_view,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        if let Some(reply_sender) = reply_sender {
+            self.reads.insert(command_view.sequence, reply_sender);
+        }
+        self.propose_command_view(command_view).await
+    }
+
+    /// Propose again, in the current view, the highest-view lock reported for each sequence number
+    /// not applied yet, so that a command committed in a previous view keeps its sequence number.
+    /// The sequence numbers below the highest lock that nobody reported are filled with no-ops, since
+    /// the commands after them can only be applied in order. The new commands are proposed after them.
+    async fn repropose_locks(&mut self) -> Result<()> {
+        self.recovering = false;
+        let mut reported_locks = std::mem::take(&mut self.reported_locks);
+        for command_view in self.locks.values() {
+            keep_highest_lock(&mut reported_locks, command_view.clone());
+        }
+        let highest = reported_locks.keys().next_back().copied().unwrap_or(0);
+        for sequence in self.committed + 1..highest {
+            reported_locks
+                .entry(sequence)
+                .or_insert_with(|| CommandView {
+                    // an empty batch doesn't change the store
+                    command: ClientCommand::Batch {
+                        commands: Vec::new(),
+                    },
+                    view: self.current_view,
+                    sequence,
+                });
+        }
+        for (sequence, command_view) in reported_locks {
+            if sequence <= self.committed {
+                continue;
+            }
+            info!(
+                "{}: Proposing again the lock {:?}",
+                self.socket_address, command_view
+            );
+            self.next_sequence = self.next_sequence.max(sequence + 1);
+            self.propose_command_view(CommandView {
+                view: self.current_view,
+                ..command_view
+            })
+            .await?;
+        }
+        self.propose_queued().await
+    }
+
+    /// Lock the command-view and broadcast its proposal.
+    async fn propose_command_view(&mut self, command_view: CommandView) -> Result<()> {
+        // since we are primary, we lock the command view
+        self.lock_command_view(&command_view).await?;
+        let signature = certificate::sign_vote(&self.keypair, &command_view);
+        self.handle_lock_message(self.socket_address, command_view.clone(), signature)
+            .await?;
+
+        self.timer_start = Instant::now(); // for blame/view-change
+
+        if self.byzantine == Some(Byzantine::Equivocate) {
+            self.equivocate(command_view).await;
+            return Ok(());
+        }
+
+        let command = NetworkCommand::Propose {
+            command_view,
+            client_address: self.client_address,
+        };
+
+        info!("Received command, broadcasting Propose");
+        self.broadcast_to_others(command).await;
+        Ok(())
+    }
+
+    /// Propose the command-view to half of the backups and a conflicting one for the same sequence
+    /// number to the other half, voting for both.
+    async fn equivocate(&mut self, command_view: CommandView) {
+        let conflicting = CommandView {
+            command: command_view.command.conflicting(),
+            ..command_view.clone()
+        };
+        let others: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .copied()
+            .filter(|x| *x != self.socket_address)
+            .collect();
+        let (first_half, second_half) = others.split_at(others.len() / 2);
+
+        info!(
+            "{}: Equivocating, proposing {:?} to {:?} and {:?} to {:?}",
+            self.socket_address, command_view, first_half, conflicting, second_half
+        );
+        self.send_to(
+            first_half.to_vec(),
+            NetworkCommand::Propose {
+                command_view,
+                client_address: self.client_address,
+            },
+        )
+        .await;
+        self.send_to(
+            second_half.to_vec(),
+            NetworkCommand::Propose {
+                command_view: conflicting.clone(),
+                client_address: self.client_address,
+            },
+        )
+        .await;
+
+        let signature = certificate::sign_vote(&self.keypair, &conflicting);
+        let votes = BTreeMap::from([(self.socket_address, signature)]);
+        self.equivocations
+            .insert(conflicting.sequence, (conflicting, votes));
+    }
+
+    /// Count a vote for the conflicting command-view of an equivocation, and commit it on the
+    /// backups that locked it once it has a quorum.
+    async fn handle_equivocated_lock(
+        &mut self,
+        socket_addr: SocketAddr,
+        signature: Signature,
+        sequence: u64,
+    ) {
+        let quorum_count = self.quorum();
+        let Some((command_view, votes)) = self.equivocations.get_mut(&sequence) else {
+            return;
+        };
+        votes.insert(socket_addr, signature);
+        if votes.len() == quorum_count {
+            let certificate = QuorumCertificate {
+                command_view: command_view.clone(),
+                votes: votes.clone(),
+            };
+            let voters = self.other_voters(&certificate);
+            self.send_to(voters, NetworkCommand::Commit { certificate })
+                .await;
+        }
+    }
+
+    /// The nodes other than this one that voted for the command of the certificate.
+    fn other_voters(&self, certificate: &QuorumCertificate) -> Vec<SocketAddr> {
+        certificate
+            .votes
+            .keys()
+            .copied()
+            .filter(|voter| *voter != self.socket_address)
+            .collect()
+    }
+
+    /// Propose the queued commands while there are free slots in the pipeline.
+    async fn propose_queued(&mut self) -> Result<()> {
+        while self.in_flight() < self.pipeline_depth {
+            match self.queued.pop_front() {
+                Some((command, reply_sender)) => self.propose(command, reply_sender).await?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
     async fn handle_lock_message(
         &mut self,
         socket_addr: SocketAddr,
         command_view: CommandView,
+        signature: Signature,
     ) -> Result<Option<String>> {
-        if command_view.view <= self.current_view {
-            info!("Received command with an old, previously committed view, discarding");
+        let sequence = command_view.sequence;
+        if !certificate::is_valid_vote(&self.public_keys, &socket_addr, &command_view, &signature) {
+            Err(anyhow!(
+                "Received lock with an invalid signature from {}",
+                socket_addr
+            ))
+        } else if self
+            .equivocations
+            .get(&sequence)
+            .is_some_and(|(conflicting, _)| *conflicting == command_view)
+        {
+            self.handle_equivocated_lock(socket_addr, signature, sequence)
+                .await;
+            Ok(None)
+        } else if sequence <= self.committed || self.locks.get(&sequence) != Some(&command_view) {
+            info!("Received lock for an applied command or one we didn't propose, discarding");
             Ok(None)
         } else {
-            let _ = self.lock_responses.insert(socket_addr);
-            let response_count = self.lock_responses.len();
-
-            // the literature defines quorum as a function of the adversarial threshold we want to support
-            // n > f, n > 2f, or n > 3f are the alternatives; this uses n > 2f model
-            let quorum_count = ((self.peers.len()) / 2) + 1;
+            let quorum_count = self.quorum();
+            let responses = self.lock_responses.entry(sequence).or_default();
+            responses.insert(socket_addr, signature);
+            let response_count = responses.len();
 
             info!(
                 "Received lock, did we get quorum? {} responses so far vs expected quorum of {} ",
                 response_count, quorum_count
             );
             self.timer_start = Instant::now();
-            // broadcast commit, then try commit
             if response_count >= quorum_count {
+                self.quorum_at = Some(Instant::now());
+            }
+            // broadcast commit, then try commit
+            // locks that arrive after the quorum don't commit the command again
+            if response_count >= quorum_count && !self.commits.contains_key(&sequence) {
                 info!("Quorum achieved, commiting first and sending out Commit message!");
-                self.try_commit(command_view.clone())
-                    .await
-                    .expect("Error committing command as primary");
+                let certificate = QuorumCertificate {
+                    command_view: command_view.clone(),
+                    votes: responses.clone(),
+                };
+                self.try_commit(command_view.clone()).await?;
+                self.update_highest_certificate(certificate.clone()).await?;
 
-                self.broadcast_to_others(NetworkCommand::Commit { command_view })
-                    .await;
+                let certificate = if self.byzantine == Some(Byzantine::ConflictingCommits) {
+                    // a conflicting command-view, certified by the primary's vote alone
+                    let command_view = CommandView {
+                        command: command_view.command.conflicting(),
+                        ..command_view
+                    };
+                    let signature = certificate::sign_vote(&self.keypair, &command_view);
+                    QuorumCertificate {
+                        command_view,
+                        votes: BTreeMap::from([(self.socket_address, signature)]),
+                    }
+                } else {
+                    certificate
+                };
+
+                if self.byzantine == Some(Byzantine::Equivocate) {
+                    // each half of the backups only learns of the command it was proposed
+                    let voters = self.other_voters(&certificate);
+                    self.send_to(voters, NetworkCommand::Commit { certificate })
+                        .await;
+                } else {
+                    self.broadcast_to_others(NetworkCommand::Commit { certificate })
+                        .await;
+                }
             }
             Ok(None)
         }
     }
 
-    async fn handle_client_command(&self, command: ClientCommand) -> Result<Option<String>> {
-        match command {
-            ClientCommand::Set { key, value } => {
-                self.store.write(key.into(), value.clone().into()).await?;
-                Ok(Some(value))
-            }
-            ClientCommand::Get { key } => {
-                if let Ok(Some(val)) = self.store.read(key.clone().into()).await {
-                    let value = String::from_utf8(val)?;
-                    return Ok(Some(value));
-                }
-                Ok(None)
-            }
+    /// The number of Lock votes that make up a certificate.
+    fn quorum(&self) -> usize {
+        // the literature defines quorum as a function of the adversarial threshold we want to support
+        // n > f, n > 2f, or n > 3f are the alternatives; with n > 3f any two quorums share an honest
+        // node, which doesn't vote for conflicting commands
+        let n = self.peers.len();
+        if self.byzantine_quorums {
+            n - (n - 1) / 3
+        } else {
+            n / 2 + 1
+        }
+    }
+
+    /// Apply the command of a certificate a Commit or a ViewChange carried. A quorum locked it, so it
+    /// replaces any conflicting lock this node holds, or the missing one if the node missed the proposal.
+    async fn commit_certified(&mut self, certificate: QuorumCertificate) -> Result<()> {
+        let command_view = certificate.command_view.clone();
+        if command_view.sequence > self.committed {
+            self.lock_command_view(&command_view).await?;
+            self.try_commit(command_view).await?;
+        }
+        self.update_highest_certificate(certificate).await
+    }
+
+    async fn update_highest_certificate(&mut self, certificate: QuorumCertificate) -> Result<()> {
+        let sequence = certificate.command_view.sequence;
+        if self
+            .highest_certificate
+            .as_ref()
+            .is_none_or(|highest| highest.command_view.sequence < sequence)
+        {
+            self.store
+                .write(CERTIFICATE_KEY.to_vec(), bincode::serialize(&certificate)?)
+                .await?;
+            self.highest_certificate = Some(certificate);
         }
+        Ok(())
+    }
+
+    /// Whether a read with the given consistency can be served from the local store: lease reads by a
+    /// primary whose lease didn't expire, and stale reads by any node that applied every commit it got
+    /// recently enough.
+    fn serves_locally(&self, consistency: Consistency) -> bool {
+        match (self.get_state(), consistency) {
+            (_, Consistency::Linearizable) => false,
+            (Primary, Consistency::Lease) => match self.view_change_delta_ms {
+                // backups blame the primary once their timer expires, 8 deltas after they last locked a
+                // command, and any quorum of blames includes a node of the last lock quorum, so the
+                // primary keeps half that time as a margin for the locks to arrive
+                Some(delta) => self
+                    .quorum_at
+                    .is_some_and(|at| at.elapsed() < Duration::from_millis(delta.into()) * 4),
+                // without view changes the primary never changes
+                None => true,
+            },
+            (Backup, Consistency::Lease) => false,
+            (Primary, Consistency::Stale { .. }) => true,
+            (Backup, Consistency::Stale { max_staleness_ms }) => self
+                .synced_at
+                .is_some_and(|at| at.elapsed() <= Duration::from_millis(max_staleness_ms)),
+        }
+    }
+
+    async fn handle_client_command(&self, command: ClientCommand) -> Result<CommandResult> {
+        command.apply(&self.store).await
     }
 
     async fn broadcast(&mut self, network_command: NetworkCommand) {
@@ -346,6 +841,11 @@ impl Node {
         self.sender.broadcast(other_peers, message).await;
     }
 
+    async fn send_to(&mut self, addresses: Vec<SocketAddr>, network_command: NetworkCommand) {
+        let message: Bytes = bincode::serialize(&network_command).unwrap().into();
+        self.sender.broadcast(addresses, message).await;
+    }
+
     async fn send_to_primary(&mut self, cmd: NetworkCommand) {
         let message: Bytes = bincode::serialize(&cmd).unwrap().into();
         let primary_address = *(self.get_primary(self.current_view));
@@ -354,14 +854,22 @@ impl Node {
         self.sender.send(primary_address, message).await;
     }
 
-    async fn try_commit(&mut self, command_view: CommandView) -> Result<Option<String>> {
+    /// Commit the given command-view if it matches the lock of its sequence number, and apply the
+    /// committed commands that are next in order. Rejected commands (e.g. a failed compare-and-swap)
+    /// still count as committed, they just don't change the store.
+    async fn try_commit(&mut self, command_view: CommandView) -> Result<()> {
         self.timer_start = Instant::now();
 
-        // handle command, remove command lock (Primray already commits when quorum is achieved)
-        if self.command_view_lock != command_view {
+        let sequence = command_view.sequence;
+        if sequence <= self.committed {
+            return Ok(());
+        }
+        if self.locks.get(&sequence) != Some(&command_view) {
             info!(
                 "{}: trying to commit {:?} but we had locked {:?}",
-                self.socket_address, command_view, self.command_view_lock
+                self.socket_address,
+                command_view,
+                self.locks.get(&sequence)
             );
             // we are trying to commit something that has not been locked correctly,
             // so there must have been some fault
@@ -370,23 +878,81 @@ impl Node {
             ));
         }
 
-        self.current_view = command_view.view;
-        self.command_view_lock.view += 1;
+        self.commits.insert(sequence, command_view);
+        self.apply_commits().await
+    }
 
-        self.lock_responses.clear();
-        self.clear_cmd_view_lock();
+    /// Apply the committed commands that follow the last applied one, in sequence order, removing
+    /// their locks.
+    async fn apply_commits(&mut self) -> Result<()> {
+        while let Some(command_view) = self.commits.remove(&(self.committed + 1)) {
+            let sequence = command_view.sequence;
+            // the command is applied in the same batch that records it, so a restart never applies it twice
+            let writes = vec![
+                (COMMITTED_KEY.to_vec(), Some(bincode::serialize(&sequence)?)),
+                (lock_key(sequence), None),
+            ];
+            let result = command_view.command.apply_with(&self.store, writes).await?;
 
-        self.handle_client_command(command_view.command).await
-    }
+            self.committed = sequence;
+            self.locks.remove(&sequence);
+            self.lock_responses.remove(&sequence);
 
-    fn lock_command_view(&mut self, command_view: &CommandView) {
-        if command_view.view != 0 {
-            self.command_view_lock = command_view.clone();
+            info!(
+                "{}: Committed command {}, response was {:?}",
+                self.socket_address, sequence, result
+            );
+            if let Some(reply_sender) = self.reads.remove(&sequence) {
+                if let Err(error) = reply_sender.send(result.into()) {
+                    error!("failed to send read response {:?}", error);
+                }
+            }
         }
+        // a gap means some Commit is missing, or arrived ahead of the one before it
+        if self.commits.is_empty() {
+            self.synced_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    async fn lock_command_view(&mut self, command_view: &CommandView) -> Result<()> {
+        self.store
+            .write(
+                lock_key(command_view.sequence),
+                bincode::serialize(command_view)?,
+            )
+            .await?;
+        self.locks
+            .insert(command_view.sequence, command_view.clone());
         info!(
             "{}: Locked command view {:?}",
-            self.socket_address, self.command_view_lock
+            self.socket_address, command_view
         );
+        Ok(())
+    }
+
+    /// Read the view, the last applied command, the locks and the highest certificate the node had before
+    /// a restart.
+    pub async fn restore(&mut self) -> Result<()> {
+        if let Some(view) = self.store.read(VIEW_KEY.to_vec()).await? {
+            self.current_view = bincode::deserialize(&view)?;
+        }
+        if let Some(certificate) = self.store.read(CERTIFICATE_KEY.to_vec()).await? {
+            self.highest_certificate = Some(bincode::deserialize(&certificate)?);
+        }
+        if let Some(committed) = self.store.read(COMMITTED_KEY.to_vec()).await? {
+            self.committed = bincode::deserialize(&committed)?;
+        }
+        for (_, value) in self.store.scan(LOCK_PREFIX.to_vec()).await? {
+            let command_view: CommandView = bincode::deserialize(&value)?;
+            self.locks.insert(command_view.sequence, command_view);
+        }
+        self.next_sequence = self.locks.keys().next_back().unwrap_or(&self.committed) + 1;
+        info!(
+            "{}: Restored view {}, {} applied commands and locks {:?}",
+            self.socket_address, self.current_view, self.committed, self.locks
+        );
+        Ok(())
     }
 
     pub fn get_state(&self) -> State {
@@ -396,22 +962,41 @@ impl Node {
         State::Backup
     }
 
-    fn trigger_view_change(&mut self, new_view: u128) {
+    /// Move to the given view, whose primary can be reached by clients at the given address if known.
+    async fn trigger_view_change(
+        &mut self,
+        new_view: u128,
+        primary_client_address: Option<SocketAddr>,
+    ) -> Result<()> {
         self.current_view = new_view; // update last valid view number
+        self.primary_client_address = primary_client_address;
 
         self.lock_responses.clear();
+        self.equivocations.clear();
         self.blame_messages.clear();
-        self.command_view_lock = CommandView::new();
+        self.next_sequence = self.committed + 1;
+        self.quorum_at = None;
+        // the commits waiting for the ones before them are certified, so they are kept
+        self.recovering = matches!(self.get_state(), Primary);
+        self.view_changes.clear();
+        self.reported_locks.clear();
+
+        // the reads waiting to be applied may never be, so their clients retry with the new primary
+        for (_, reply_sender) in self.reads.drain() {
+            let _ = reply_sender.send(ClientReply::Redirect(self.primary_client_address));
+        }
+
+        // the locks are kept: a command locked by a quorum may have been committed in the previous view
+        self.store
+            .write(VIEW_KEY.to_vec(), bincode::serialize(&self.current_view)?)
+            .await?;
+        Ok(())
     }
 
     fn get_primary(&self, view: u128) -> &SocketAddr {
         self.peers.get(view as usize % self.peers.len()).unwrap()
     }
 
-    fn clear_cmd_view_lock(&mut self) {
-        self.command_view_lock = CommandView::new();
-    }
-
     async fn handle_blame(
         &mut self,
         view: u128,
@@ -425,7 +1010,7 @@ impl Node {
         let _ = self.blame_messages.insert(socket_addr);
         let blame_count = self.blame_messages.len();
 
-        let highest_view_lock = self.command_view_lock.clone();
+        let highest_view_lock = self.highest_certificate.clone();
 
         // from the docs, f is the amount of omission failures we want to tolerate
         // again, f is defined from the adversarial threshold of the system
@@ -455,12 +1040,28 @@ impl Node {
             // same as if the timer expired on the node
 
             self.broadcast(NetworkCommand::ViewChange {
-                socket_addr,
+                socket_addr: self.socket_address,
                 new_view: current_view + 1,
                 highest_lock: highest_view_lock,
+                locks: self.locks.values().cloned().collect(),
+                client_address: self.client_address,
             })
             .await;
         }
         Ok(None)
     }
 }
+
+/// Keep the given lock if there is none with a higher view for its sequence number.
+fn keep_highest_lock(locks: &mut BTreeMap<u64, CommandView>, command_view: CommandView) {
+    if locks
+        .get(&command_view.sequence)
+        .is_none_or(|locked| locked.view < command_view.view)
+    {
+        locks.insert(command_view.sequence, command_view);
+    }
+}
+
+fn lock_key(sequence: u64) -> Vec<u8> {
+    [LOCK_PREFIX, &sequence.to_be_bytes()].concat()
+}
@@ -18,9 +18,17 @@ while [ $i -lt $1 ]; do
 done
 
 
+# every node needs the public keys of the others to verify their votes
+k=0; LIST_KEYS=""
+while [ $k -lt $1 ]; do
+   PORT=$((6100+$k))
+   LIST_KEYS="${LIST_KEYS} $(../../target/debug/node_lock_commit --port $PORT --public-key)"
+   k=$((k + 1))
+done
+
 while [ $j -lt $1 ]; do
    PORT=$((6100+$j))
-   RUST_LOG=INFO ../../target/debug/node_lock_commit --port $PORT --peers "$LIST_PEERS" -v $2 &
+   RUST_LOG=INFO ../../target/debug/node_lock_commit --port $PORT --peers "$LIST_PEERS" --peer-keys "${LIST_KEYS# }" -v $2 &
    
    j=$((j + 1))
 done
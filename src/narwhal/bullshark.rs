@@ -0,0 +1,41 @@
+/// This module contains the partially synchronous version of the Bullshark ordering engine: the leader
+/// of each even round is known in advance, and it's committed as soon as enough certificates of the
+/// next round link to it.
+use crate::consensus::{Committer, Ordering};
+use crate::dag::{Certificate, Dag, Round};
+use std::net::SocketAddr;
+
+pub struct Bullshark {
+    committer: Committer,
+}
+
+impl Bullshark {
+    pub fn new(peers: Vec<SocketAddr>) -> Self {
+        Self {
+            committer: Committer::new(peers),
+        }
+    }
+}
+
+/// The leaders are picked in a round robin fashion.
+fn leader(peers: &[SocketAddr], round: Round) -> SocketAddr {
+    peers[(round / 2 % peers.len() as u64) as usize]
+}
+
+impl Ordering for Bullshark {
+    fn process(&mut self, dag: &Dag, certificate: &Certificate) -> Vec<Certificate> {
+        // the certificates of odd rounds are the votes for the leader of the round before them
+        let round = certificate.round();
+        if round < 3 || round % 2 == 0 {
+            return vec![];
+        }
+        let leader_round = round - 1;
+        let peers = self.committer.peers.clone();
+        match dag.certificate(leader_round, leader(&peers, leader_round)) {
+            Some(leader_certificate) => self
+                .committer
+                .commit(dag, leader_certificate, |round| leader(&peers, round)),
+            None => vec![],
+        }
+    }
+}
@@ -0,0 +1,194 @@
+/// This module contains the interface of the engines that order the Narwhal DAG, and the commit logic
+/// they share. Every engine picks a leader certificate at some rounds and decides when a leader is
+/// committed; committing a leader also commits the earlier leaders it links to, and the causal
+/// history of each leader is then linearized in a deterministic order, so every node that commits
+/// the same leaders executes the same sequence of certificates.
+use crate::dag::{Certificate, Dag, Digest, Round};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+/// A pluggable engine that totally orders the certificates of a DAG.
+pub trait Ordering: Send {
+    /// Process a certificate that was just inserted in the DAG, and return the certificates that
+    /// are committed as a result, in the order they should be executed.
+    fn process(&mut self, dag: &Dag, certificate: &Certificate) -> Vec<Certificate>;
+}
+
+/// The commit state shared by the ordering engines.
+pub struct Committer {
+    /// All the nodes of the network, sorted so every node agrees on the leaders.
+    pub peers: Vec<SocketAddr>,
+
+    /// The round of the last committed leader. Leaders are only elected at even rounds.
+    pub last_leader_round: Round,
+
+    /// The certificates that were already committed, as part of the history of some leader.
+    committed: HashSet<Digest>,
+}
+
+impl Committer {
+    pub fn new(peers: Vec<SocketAddr>) -> Self {
+        let committed = Certificate::genesis(&peers)
+            .iter()
+            .map(|certificate| certificate.digest().clone())
+            .collect();
+        Self {
+            peers,
+            last_leader_round: 0,
+            committed,
+        }
+    }
+
+    /// The number of certificates of the round after a leader that have to link to it to commit it:
+    /// one more than the number of faulty nodes, out of a network of 3f + 1 nodes, so at least one
+    /// honest node links to it, and any quorum of the next rounds has a path to it.
+    pub fn validity(&self) -> usize {
+        (self.peers.len() - 1) / 3 + 1
+    }
+
+    /// The number of certificates of the round after the given leader that link to it.
+    pub fn support(&self, dag: &Dag, leader: &Certificate) -> usize {
+        dag.round(leader.round() + 1)
+            .iter()
+            .filter(|certificate| certificate.header.parents.contains(leader.digest()))
+            .count()
+    }
+
+    /// Commit the given leader if it has enough support, along with the uncommitted leaders of
+    /// previous rounds it links to, as elected by the given function. Return the causal history
+    /// of those leaders that was not committed yet, in execution order.
+    pub fn commit<F>(&mut self, dag: &Dag, leader: &Certificate, elect: F) -> Vec<Certificate>
+    where
+        F: Fn(Round) -> SocketAddr,
+    {
+        if leader.round() <= self.last_leader_round || self.support(dag, leader) < self.validity() {
+            return vec![];
+        }
+
+        // an earlier leader may not have had enough support when this node checked it, but if the
+        // new leader links to it, some other node could have committed it, so it has to be committed too
+        let mut leaders = vec![leader.clone()];
+        let mut current = leader;
+        let mut round = leader.round() - 2;
+        while round > self.last_leader_round {
+            if let Some(previous) = dag.certificate(round, elect(round)) {
+                if dag.linked(current, previous) {
+                    leaders.push(previous.clone());
+                    current = previous;
+                }
+            }
+            round -= 2;
+        }
+        self.last_leader_round = leader.round();
+
+        leaders
+            .iter()
+            .rev()
+            .flat_map(|leader| self.order_history(dag, leader))
+            .collect()
+    }
+
+    /// The uncommitted certificates in the causal history of the given leader, sorted by round and
+    /// author, which are marked as committed.
+    fn order_history(&mut self, dag: &Dag, leader: &Certificate) -> Vec<Certificate> {
+        let mut history = Vec::new();
+        let mut to_visit = vec![leader];
+        while let Some(certificate) = to_visit.pop() {
+            if !self.committed.insert(certificate.digest().clone()) {
+                continue;
+            }
+            history.push(certificate.clone());
+            to_visit.extend(
+                certificate
+                    .header
+                    .parents
+                    .iter()
+                    .filter_map(|parent| dag.get(parent)),
+            );
+        }
+        history.sort_by_key(|certificate| (certificate.round(), certificate.author()));
+        history
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bullshark::Bullshark;
+    use crate::dag::Header;
+    use crate::tusk::Tusk;
+    use std::collections::BTreeSet;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    /// Build a DAG where the given authors create a certificate at each round up to the given one,
+    /// linking to every certificate of the previous round, and process each certificate with
+    /// the engine. Return the digests of the committed certificates in order.
+    fn run(
+        engine: &mut dyn Ordering,
+        peers: &[SocketAddr],
+        authors: &[SocketAddr],
+        rounds: Round,
+    ) -> Vec<Digest> {
+        let mut dag = Dag::new(peers);
+        let mut committed = Vec::new();
+        for round in 1..=rounds {
+            let parents: BTreeSet<Digest> = dag
+                .round(round - 1)
+                .iter()
+                .map(|certificate| certificate.digest().clone())
+                .collect();
+            for author in authors {
+                let certificate = Certificate {
+                    header: Header::new(*author, round, vec![], parents.clone()),
+                    voters: authors.iter().cloned().collect(),
+                };
+                dag.insert(certificate.clone());
+                committed.extend(
+                    engine
+                        .process(&dag, &certificate)
+                        .iter()
+                        .map(|certificate| certificate.digest().clone()),
+                );
+            }
+        }
+        committed
+    }
+
+    #[test]
+    fn order_dag() {
+        let peers: Vec<SocketAddr> = (7780..7784).map(address).collect();
+
+        // bullshark commits the leader of round 2 with the support of round 3, and with it the
+        // round before it
+        let mut bullshark = Bullshark::new(peers.clone());
+        let committed = run(&mut bullshark, &peers, &peers, 3);
+        assert_eq!(5, committed.len());
+
+        // each leader commits the rounds before it
+        let mut bullshark = Bullshark::new(peers.clone());
+        let committed = run(&mut bullshark, &peers, &peers, 9);
+        assert_eq!(4 * 7 + 1, committed.len());
+        let unique: HashSet<&Digest> = committed.iter().collect();
+        assert_eq!(committed.len(), unique.len());
+
+        // tusk needs one more round to reveal the coin that elects the leader
+        let mut tusk = Tusk::new(peers.clone());
+        let committed = run(&mut tusk, &peers, &peers, 9);
+        assert_eq!(4 * 5 + 1, committed.len());
+        let mut tusk = Tusk::new(peers.clone());
+        let committed = run(&mut tusk, &peers, &peers, 10);
+        assert_eq!(4 * 7 + 1, committed.len());
+
+        // with a crashed node the rounds where it's the leader are not committed on their own,
+        // but the following leaders link to them so the rest of the DAG is still committed
+        let mut bullshark = Bullshark::new(peers.clone());
+        let committed = run(&mut bullshark, &peers, &peers[..3], 17);
+        assert_eq!(3 * 15 + 1, committed.len());
+        let mut tusk = Tusk::new(peers.clone());
+        let committed = run(&mut tusk, &peers, &peers[..3], 18);
+        assert_eq!(3 * 15 + 1, committed.len());
+    }
+}
@@ -0,0 +1,263 @@
+/// This module contains the data structures of the Narwhal mempool: the batches of transactions
+/// created by workers, the headers proposed by primaries at each round, the certificates that prove
+/// a quorum of nodes stored a header and its batches, and the round-based DAG they form.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+
+use lib::command::ClientCommand;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+pub type TransactionId = String;
+pub type Transaction = (TransactionId, ClientCommand);
+pub type Digest = String;
+pub type Round = u64;
+
+/// A list of client transactions sealed by the worker of a node.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Batch {
+    pub author: SocketAddr,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Batch {
+    /// Generate a hex string of a Sha256 hash of this batch.
+    pub fn digest(&self) -> Digest {
+        let mut hasher = Sha256::new();
+        hasher.update(self.author.to_string());
+        for (txid, command) in &self.transactions {
+            hasher.update(txid);
+            hasher.update(command.to_string());
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// The proposal of a primary for a round: the digests of the batches of its worker, and at least a
+/// quorum of certificates of the previous round.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub author: SocketAddr,
+    pub round: Round,
+    pub batches: Vec<Digest>,
+    pub parents: BTreeSet<Digest>,
+    pub digest: Digest,
+}
+
+impl Header {
+    pub fn new(
+        author: SocketAddr,
+        round: Round,
+        batches: Vec<Digest>,
+        parents: BTreeSet<Digest>,
+    ) -> Self {
+        let mut header = Self {
+            author,
+            round,
+            batches,
+            parents,
+            digest: "not known yet".to_string(),
+        };
+        header.digest = header.calculate_digest();
+        header
+    }
+
+    /// Generate a hex string of a Sha256 hash for the attributes in this header.
+    /// The digest field itself doesn't affect the result.
+    pub fn calculate_digest(&self) -> Digest {
+        let mut hasher = Sha256::new();
+        hasher.update(self.author.to_string());
+        hasher.update(self.round.to_string());
+        for batch in &self.batches {
+            hasher.update(batch);
+        }
+        for parent in &self.parents {
+            hasher.update(parent);
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// A header acknowledged by a quorum of nodes, which guarantees the header and its batches are
+/// available and that its author didn't propose a different header for the same round.
+/// Votes are not signed, so the certificate just lists the nodes that voted.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub header: Header,
+    pub voters: BTreeSet<SocketAddr>,
+}
+
+impl Certificate {
+    /// The certificates of round zero, one per node, which every DAG starts with.
+    pub fn genesis(peers: &[SocketAddr]) -> Vec<Self> {
+        peers
+            .iter()
+            .map(|peer| Self {
+                header: Header::new(*peer, 0, vec![], BTreeSet::new()),
+                voters: BTreeSet::new(),
+            })
+            .collect()
+    }
+
+    pub fn round(&self) -> Round {
+        self.header.round
+    }
+
+    pub fn author(&self) -> SocketAddr {
+        self.header.author
+    }
+
+    pub fn digest(&self) -> &Digest {
+        &self.header.digest
+    }
+
+    /// Returns true if the certificate has the given quorum of votes from known nodes for a
+    /// well-formed header. Genesis certificates are never sent so they are not valid.
+    pub fn is_valid(&self, peers: &[SocketAddr], quorum: usize) -> bool {
+        self.round() > 0
+            && self.header.calculate_digest() == self.header.digest
+            && peers.contains(&self.author())
+            && self.header.parents.len() >= quorum
+            && self.voters.len() >= quorum
+            && self.voters.iter().all(|voter| peers.contains(voter))
+    }
+}
+
+/// The certificates known by a node, indexed by round and author. A certificate is only inserted
+/// once all its parents are, so the DAG always contains the full causal history of its certificates.
+pub struct Dag {
+    certificates: HashMap<Digest, Certificate>,
+    rounds: BTreeMap<Round, BTreeMap<SocketAddr, Digest>>,
+}
+
+impl Dag {
+    pub fn new(peers: &[SocketAddr]) -> Self {
+        let mut dag = Self {
+            certificates: HashMap::new(),
+            rounds: BTreeMap::new(),
+        };
+        for certificate in Certificate::genesis(peers) {
+            dag.insert(certificate);
+        }
+        dag
+    }
+
+    pub fn insert(&mut self, certificate: Certificate) {
+        self.rounds
+            .entry(certificate.round())
+            .or_default()
+            .insert(certificate.author(), certificate.digest().clone());
+        self.certificates
+            .insert(certificate.digest().clone(), certificate);
+    }
+
+    pub fn get(&self, digest: &str) -> Option<&Certificate> {
+        self.certificates.get(digest)
+    }
+
+    pub fn contains(&self, digest: &str) -> bool {
+        self.certificates.contains_key(digest)
+    }
+
+    /// The certificate of the given author at the given round, if known.
+    pub fn certificate(&self, round: Round, author: SocketAddr) -> Option<&Certificate> {
+        self.rounds
+            .get(&round)
+            .and_then(|certificates| certificates.get(&author))
+            .and_then(|digest| self.get(digest))
+    }
+
+    /// The known certificates of the given round, sorted by author.
+    pub fn round(&self, round: Round) -> Vec<&Certificate> {
+        self.rounds
+            .get(&round)
+            .map(|certificates| {
+                certificates
+                    .values()
+                    .filter_map(|digest| self.get(digest))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The parents of the given header that are not in the DAG yet.
+    pub fn missing_parents(&self, header: &Header) -> Vec<Digest> {
+        header
+            .parents
+            .iter()
+            .filter(|parent| !self.contains(parent))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns true if the given parents are a quorum of distinct certificates of the given round.
+    pub fn are_valid_parents(
+        &self,
+        parents: &BTreeSet<Digest>,
+        round: Round,
+        quorum: usize,
+    ) -> bool {
+        let authors: BTreeSet<SocketAddr> = parents
+            .iter()
+            .filter_map(|parent| self.get(parent))
+            .filter(|parent| parent.round() == round)
+            .map(|parent| parent.author())
+            .collect();
+        authors.len() == parents.len() && authors.len() >= quorum
+    }
+
+    /// Returns true if there's a path of parent links from one certificate down to the other.
+    pub fn linked(&self, from: &Certificate, to: &Certificate) -> bool {
+        let mut frontier: BTreeSet<&Digest> = [from.digest()].into();
+        for _ in to.round()..from.round() {
+            frontier = frontier
+                .into_iter()
+                .filter_map(|digest| self.get(digest))
+                .flat_map(|certificate| certificate.header.parents.iter())
+                .collect();
+        }
+        frontier.contains(to.digest())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    #[test]
+    fn build_dag() {
+        let peers: Vec<SocketAddr> = (7790..7794).map(address).collect();
+        let mut dag = Dag::new(&peers);
+        assert_eq!(4, dag.round(0).len());
+
+        let genesis: Vec<Certificate> = dag.round(0).into_iter().cloned().collect();
+        let parents: BTreeSet<Digest> = genesis[..3].iter().map(|c| c.digest().clone()).collect();
+        assert!(dag.are_valid_parents(&parents, 0, 3));
+        assert!(!dag.are_valid_parents(&parents, 1, 3));
+
+        let header = Header::new(peers[0], 1, vec![], parents.clone());
+        let certificate = Certificate {
+            header,
+            voters: peers[1..].iter().cloned().collect(),
+        };
+        assert!(certificate.is_valid(&peers, 3));
+        assert!(dag.missing_parents(&certificate.header).is_empty());
+
+        let mut tampered = certificate.clone();
+        tampered.header.round = 2;
+        assert!(!tampered.is_valid(&peers, 3));
+
+        dag.insert(certificate.clone());
+        assert_eq!(Some(&certificate), dag.certificate(1, peers[0]));
+        assert!(dag.linked(&certificate, &genesis[0]));
+        assert!(!dag.linked(&certificate, &genesis[3]));
+
+        // a header of the next round is missing the certificates this node doesn't know of
+        let unknown = Header::new(peers[1], 2, vec![], ["unknown".to_string()].into());
+        assert_eq!(vec!["unknown".to_string()], dag.missing_parents(&unknown));
+    }
+}
@@ -0,0 +1,645 @@
+/// This module contains the definition of a Narwhal node and the network messages supported between
+/// nodes. The worker of the node batches client transactions and shares the batches with the rest of
+/// the nodes. At every round the primary of the node proposes a header with the digests of its new
+/// batches and links to a quorum of certificates of the previous round. Nodes acknowledge the headers
+/// whose batches and parents they have stored, and a quorum of acknowledgments forms the certificate
+/// of the header, which is added to the DAG of every node. An ordering engine then decides which
+/// parts of the DAG are committed, and their transactions are applied to the store.
+use crate::consensus::Ordering;
+use crate::dag::{Batch, Certificate, Dag, Digest, Header, Round, TransactionId};
+use crate::worker::Worker;
+use bytes::Bytes;
+use core::fmt;
+use lib::command::{ClientCommand, CommandResult};
+use lib::network::{MessageSender, SimpleSender};
+use lib::store::Store;
+use log::{debug, error, info, warn};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+use tokio::time::{interval, Duration, Instant};
+
+/// How often the node checks its batch and header timers.
+const TICK: Duration = Duration::from_millis(10);
+
+/// The maximum number of transactions of a batch.
+const BATCH_SIZE: usize = 100;
+
+/// How long a transaction waits for more transactions to fill its batch.
+const MAX_BATCH_DELAY: Duration = Duration::from_millis(20);
+
+/// How long the node waits in a round before sending its header or certificate again, in case
+/// the messages it needs to move on were lost.
+const RESEND_DELAY: Duration = Duration::from_millis(500);
+
+/// The types of messages supported by this implementation's state machine.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Message {
+    /// A batch sealed by the worker of its author.
+    NewBatch(Batch),
+    /// The header proposed by the primary of its author for its round.
+    NewHeader(Header),
+    /// An acknowledgment that the sender stored the header with the given digest and its batches.
+    Vote {
+        from: SocketAddr,
+        round: Round,
+        digest: Digest,
+    },
+    /// A header with a quorum of votes.
+    NewCertificate(Certificate),
+    /// A request for certificates this node is missing, answered with `NewCertificate` messages.
+    FetchCertificates {
+        from: SocketAddr,
+        digests: Vec<Digest>,
+    },
+    /// A request for batches this node is missing, answered with `NewBatch` messages.
+    FetchBatches {
+        from: SocketAddr,
+        digests: Vec<Digest>,
+    },
+}
+
+pub struct Node {
+    pub address: SocketAddr,
+
+    /// All the nodes of the network, including this one, sorted so every node agrees on the leaders
+    /// of the ordering engine.
+    peers: Vec<SocketAddr>,
+
+    pub sender: Box<dyn MessageSender>,
+
+    worker: Worker,
+
+    /// The digests of the batches of this node's worker that are not in a header yet.
+    digests: Vec<Digest>,
+
+    /// The round this node proposes a header for, once it has a quorum of certificates of the
+    /// previous round, and when it should propose it. Waiting a bit before proposing lets the worker
+    /// include more batches, and the certificates of the rest of the nodes arrive.
+    round: Round,
+    header_at: Instant,
+    header_delay: Duration,
+    resend_at: Instant,
+
+    /// The header of this node for the current round, if proposed, and the nodes that voted for it.
+    header: Option<Header>,
+    votes: BTreeSet<SocketAddr>,
+
+    /// The header voted for each author and round, to acknowledge at most one header per author and round.
+    voted: HashMap<(SocketAddr, Round), Digest>,
+
+    /// The certificates with all their parents known by this node.
+    dag: Dag,
+
+    /// The headers and certificates received with missing parents or batches, which were requested
+    /// to other nodes, by digest.
+    pending_headers: BTreeMap<Digest, Header>,
+    pending_certificates: BTreeMap<Digest, Certificate>,
+
+    /// The engine that decides which certificates of the DAG are committed.
+    ordering: Box<dyn Ordering>,
+
+    /// The committed certificates that are not executed yet, and the batches requested to other
+    /// nodes to execute them, with when they were last requested.
+    to_execute: VecDeque<Certificate>,
+    requested_batches: HashMap<Digest, Instant>,
+
+    store: Store,
+
+    /// The reply channels of the client commands received by this node, answered when the
+    /// command is executed.
+    pending: HashMap<TransactionId, oneshot::Sender<CommandResult>>,
+
+    /// The source of the ids of the client commands.
+    pub rng: SmallRng,
+}
+
+use Message::*;
+
+impl Node {
+    pub fn new(
+        mut peers: Vec<SocketAddr>,
+        db_path: &str,
+        address: SocketAddr,
+        ordering: Box<dyn Ordering>,
+        header_delay_ms: u64,
+    ) -> Self {
+        if !peers.contains(&address) {
+            peers.push(address);
+        }
+        peers.sort();
+
+        let header_delay = Duration::from_millis(header_delay_ms);
+        Self {
+            address,
+            dag: Dag::new(&peers),
+            peers,
+            sender: Box::new(SimpleSender::new()),
+            worker: Worker::new(address, BATCH_SIZE, MAX_BATCH_DELAY),
+            digests: Vec::new(),
+            round: 1,
+            header_at: Instant::now() + header_delay,
+            header_delay,
+            resend_at: Instant::now() + header_delay + RESEND_DELAY,
+            header: None,
+            votes: BTreeSet::new(),
+            voted: HashMap::new(),
+            pending_headers: BTreeMap::new(),
+            pending_certificates: BTreeMap::new(),
+            ordering,
+            to_execute: VecDeque::new(),
+            requested_batches: HashMap::new(),
+            store: Store::new(db_path).unwrap(),
+            pending: HashMap::new(),
+            rng: SmallRng::from_entropy(),
+        }
+    }
+
+    /// Runs the node to process network messages incoming in the given receiver.
+    pub async fn run(
+        &mut self,
+        mut network_receiver: Receiver<(Message, oneshot::Sender<String>)>,
+        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<CommandResult>)>,
+    ) {
+        let mut timer = interval(TICK);
+        loop {
+            tokio::select! {
+                biased;
+                _ = timer.tick() => self.check_timers().await,
+                Some((command, reply_sender)) = client_receiver.recv() => {
+                    info!("{}: Received client message {}", self.address, command);
+                    self.handle_client_command(command, reply_sender).await;
+                }
+                Some((message, reply_sender)) = network_receiver.recv() => {
+                    debug!("{}: Received network message {}", self.address, message);
+                    if let Err(error) = reply_sender.send("ACK".to_string()) {
+                        error!("failed to send message {:?} response {:?}", message, error);
+                    }
+                    self.handle_message(message).await;
+                }
+                else => {
+                    error!("node channels are closed");
+                }
+            }
+        }
+    }
+
+    /// Reads are served from the local store. Writes are added to the batch of the worker, and are
+    /// answered once their batch is committed and executed.
+    async fn handle_client_command(
+        &mut self,
+        command: ClientCommand,
+        reply_sender: oneshot::Sender<CommandResult>,
+    ) {
+        if !command.is_write() {
+            let result = command
+                .apply(&self.store)
+                .await
+                .unwrap_or_else(|error| Err(error.to_string()));
+            if let Err(error) = reply_sender.send(result) {
+                error!("failed to send client response {:?}", error);
+            }
+            return;
+        }
+
+        let txid = uuid::Builder::from_random_bytes(self.rng.gen())
+            .into_uuid()
+            .to_string();
+        self.pending.insert(txid.clone(), reply_sender);
+        if let Some(batch) = self.worker.add((txid, command)) {
+            self.share_batch(batch).await;
+        }
+    }
+
+    async fn handle_message(&mut self, message: Message) {
+        match message {
+            NewBatch(batch) => {
+                if self.peers.contains(&batch.author) {
+                    self.requested_batches.remove(&batch.digest());
+                    self.worker.store(batch);
+                    self.process_pending_headers().await;
+                    self.execute().await;
+                }
+            }
+            NewHeader(header) => self.handle_header(header).await,
+            Vote {
+                from,
+                round,
+                digest,
+            } => self.handle_vote(from, round, digest).await,
+            NewCertificate(certificate) => self.handle_certificate(certificate).await,
+            FetchCertificates { from, digests } => {
+                let certificates: Vec<Certificate> = digests
+                    .iter()
+                    .filter_map(|digest| self.dag.get(digest))
+                    .cloned()
+                    .collect();
+                for certificate in certificates {
+                    self.send(from, NewCertificate(certificate)).await;
+                }
+            }
+            FetchBatches { from, digests } => {
+                let batches: Vec<Batch> = digests
+                    .iter()
+                    .filter_map(|digest| self.worker.get(digest))
+                    .cloned()
+                    .collect();
+                for batch in batches {
+                    self.send(from, NewBatch(batch)).await;
+                }
+            }
+        }
+    }
+
+    /// Seal the batch of the worker if it's been waiting for too long, propose the header of the
+    /// current round if it's time to, and resend it if the node has been stuck in the round.
+    async fn check_timers(&mut self) {
+        let now = Instant::now();
+        if let Some(batch) = self.worker.seal_expired(now) {
+            self.share_batch(batch).await;
+        }
+        if self.header.is_none() && now >= self.header_at {
+            self.propose().await;
+        }
+        if self.header.is_some() && now >= self.resend_at {
+            self.resend().await;
+            self.resend_at = now + RESEND_DELAY;
+        }
+        // the requests for missing batches may have been lost too
+        if !self.to_execute.is_empty() {
+            self.execute().await;
+        }
+    }
+
+    /// Send the certificate of this node for the current round again, or its header if it's not
+    /// certified yet so the nodes whose vote was lost vote again. Headers, votes and certificates are
+    /// sent only once, so without this a lost message can leave every node short of a quorum of
+    /// certificates for the round.
+    async fn resend(&mut self) {
+        if let Some(certificate) = self.dag.certificate(self.round, self.address).cloned() {
+            debug!(
+                "{}: Resending certificate for round {}",
+                self.address, self.round
+            );
+            self.broadcast(NewCertificate(certificate)).await;
+        } else if let Some(header) = self.header.clone() {
+            debug!(
+                "{}: Resending header {} for round {}",
+                self.address, header.digest, self.round
+            );
+            self.broadcast(NewHeader(header)).await;
+        }
+    }
+
+    /// Send a sealed batch of this node's worker to the rest of the nodes, and queue it to be
+    /// included in the next header.
+    async fn share_batch(&mut self, batch: Batch) {
+        debug!(
+            "{}: Sealed batch {} with {} transactions",
+            self.address,
+            batch.digest(),
+            batch.transactions.len()
+        );
+        self.digests.push(batch.digest());
+        self.broadcast(NewBatch(batch)).await;
+    }
+
+    /// Propose a header for the current round, linking to all the known certificates of the previous one.
+    async fn propose(&mut self) {
+        let parents: BTreeSet<Digest> = self
+            .dag
+            .round(self.round - 1)
+            .iter()
+            .map(|certificate| certificate.digest().clone())
+            .collect();
+        let batches = std::mem::take(&mut self.digests);
+        let header = Header::new(self.address, self.round, batches, parents);
+        debug!(
+            "{}: Proposing header {} for round {}",
+            self.address, header.digest, self.round
+        );
+        self.header = Some(header.clone());
+        self.votes.clear();
+        self.voted
+            .insert((self.address, self.round), header.digest.clone());
+        self.broadcast(NewHeader(header.clone())).await;
+        self.handle_vote(self.address, header.round, header.digest)
+            .await;
+    }
+
+    /// Acknowledge the header if it's the first one of its author for its round and this node has
+    /// all its parents and batches. Otherwise keep it until the missing data is fetched.
+    async fn handle_header(&mut self, header: Header) {
+        if header.round == 0
+            || header.calculate_digest() != header.digest
+            || !self.peers.contains(&header.author)
+        {
+            warn!(
+                "{}: ignoring invalid header {}",
+                self.address, header.digest
+            );
+            return;
+        }
+        if let Some(voted) = self.voted.get(&(header.author, header.round)) {
+            if *voted != header.digest {
+                warn!(
+                    "{}: {} proposed more than one header for round {}",
+                    self.address, header.author, header.round
+                );
+            } else if header.author != self.address {
+                // the header was sent again, so the vote may have been lost
+                let vote = Vote {
+                    from: self.address,
+                    round: header.round,
+                    digest: header.digest,
+                };
+                self.send(header.author, vote).await;
+            }
+            return;
+        }
+
+        let missing_parents = self.dag.missing_parents(&header);
+        let missing_batches: Vec<Digest> = header
+            .batches
+            .iter()
+            .filter(|digest| !self.worker.contains(digest))
+            .cloned()
+            .collect();
+        if !missing_parents.is_empty() || !missing_batches.is_empty() {
+            // a header received again is fetched for again, in case the first request was lost
+            let author = header.author;
+            self.pending_headers.insert(header.digest.clone(), header);
+            if !missing_parents.is_empty() {
+                let fetch = FetchCertificates {
+                    from: self.address,
+                    digests: missing_parents,
+                };
+                self.send(author, fetch).await;
+            }
+            if !missing_batches.is_empty() {
+                let fetch = FetchBatches {
+                    from: self.address,
+                    digests: missing_batches,
+                };
+                self.send(author, fetch).await;
+            }
+            return;
+        }
+
+        if !self
+            .dag
+            .are_valid_parents(&header.parents, header.round - 1, self.quorum())
+        {
+            warn!(
+                "{}: ignoring header {} with invalid parents",
+                self.address, header.digest
+            );
+            return;
+        }
+
+        self.voted
+            .insert((header.author, header.round), header.digest.clone());
+        let vote = Vote {
+            from: self.address,
+            round: header.round,
+            digest: header.digest,
+        };
+        self.send(header.author, vote).await;
+    }
+
+    /// Handle the headers that were waiting for parents or batches that are now available.
+    async fn process_pending_headers(&mut self) {
+        let ready: Vec<Digest> = self
+            .pending_headers
+            .iter()
+            .filter(|(_, header)| {
+                self.dag.missing_parents(header).is_empty()
+                    && header
+                        .batches
+                        .iter()
+                        .all(|digest| self.worker.contains(digest))
+            })
+            .map(|(digest, _)| digest.clone())
+            .collect();
+        for digest in ready {
+            if let Some(header) = self.pending_headers.remove(&digest) {
+                self.handle_header(header).await;
+            }
+        }
+    }
+
+    /// Count the vote for this node's header, and share its certificate once there's a quorum.
+    async fn handle_vote(&mut self, from: SocketAddr, round: Round, digest: Digest) {
+        let header = match &self.header {
+            Some(header) if header.round == round && header.digest == digest => header.clone(),
+            _ => return,
+        };
+        if !self.peers.contains(&from) || !self.votes.insert(from) {
+            return;
+        }
+        if self.votes.len() == self.quorum() {
+            let certificate = Certificate {
+                header,
+                voters: self.votes.clone(),
+            };
+            debug!(
+                "{}: Formed certificate {} for round {}",
+                self.address,
+                certificate.digest(),
+                round
+            );
+            self.broadcast(NewCertificate(certificate.clone())).await;
+            self.handle_certificate(certificate).await;
+        }
+    }
+
+    /// Add the certificate to the DAG, or keep it until its missing parents are fetched.
+    async fn handle_certificate(&mut self, certificate: Certificate) {
+        if self.dag.contains(certificate.digest())
+            || self.pending_certificates.contains_key(certificate.digest())
+        {
+            return;
+        }
+        if !certificate.is_valid(&self.peers, self.quorum()) {
+            warn!(
+                "{}: ignoring invalid certificate {}",
+                self.address,
+                certificate.digest()
+            );
+            return;
+        }
+
+        let missing = self.dag.missing_parents(&certificate.header);
+        if !missing.is_empty() {
+            // the author of the certificate may be down, so any node that has the parents can send them
+            self.pending_certificates
+                .insert(certificate.digest().clone(), certificate);
+            let fetch = FetchCertificates {
+                from: self.address,
+                digests: missing,
+            };
+            self.broadcast(fetch).await;
+            return;
+        }
+
+        self.insert_certificate(certificate);
+        while let Some(digest) = self
+            .pending_certificates
+            .iter()
+            .find_map(|(digest, pending)| {
+                self.dag
+                    .missing_parents(&pending.header)
+                    .is_empty()
+                    .then(|| digest.clone())
+            })
+        {
+            if let Some(pending) = self.pending_certificates.remove(&digest) {
+                self.insert_certificate(pending);
+            }
+        }
+
+        self.process_pending_headers().await;
+        self.execute().await;
+    }
+
+    /// Insert a certificate with all its parents known in the DAG, pass it to the ordering engine and
+    /// move to the next round if there's a quorum of certificates for the current one.
+    fn insert_certificate(&mut self, certificate: Certificate) {
+        self.dag.insert(certificate.clone());
+        let committed = self.ordering.process(&self.dag, &certificate);
+        for certificate in &committed {
+            debug!(
+                "{}: Committed certificate {} of {} at round {}",
+                self.address,
+                certificate.digest(),
+                certificate.author(),
+                certificate.round()
+            );
+        }
+        self.to_execute.extend(committed);
+
+        while self.dag.round(self.round).len() >= self.quorum() {
+            // if this node's header wasn't certified in time, its batches go in the next one instead.
+            // Votes that arrive after this point are ignored, so the header is never certified.
+            let certified = self.dag.certificate(self.round, self.address).is_some();
+            if let Some(header) = self.header.take() {
+                if !certified {
+                    self.digests.splice(0..0, header.batches);
+                }
+            }
+            self.round += 1;
+            self.header_at = Instant::now() + self.header_delay;
+            self.resend_at = self.header_at + RESEND_DELAY;
+        }
+    }
+
+    /// Apply the transactions of the committed certificates to the store in order, and answer the
+    /// client commands received by this node. Stop at the first certificate with missing batches,
+    /// which are requested to the rest of the nodes, again if they don't arrive after `RESEND_DELAY`.
+    async fn execute(&mut self) {
+        while let Some(certificate) = self.to_execute.front() {
+            let missing: Vec<Digest> = certificate
+                .header
+                .batches
+                .iter()
+                .filter(|digest| !self.worker.contains(digest))
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                let now = Instant::now();
+                let missing: Vec<Digest> = missing
+                    .into_iter()
+                    .filter(|digest| {
+                        self.requested_batches
+                            .get(digest)
+                            .is_none_or(|requested_at| now >= *requested_at + RESEND_DELAY)
+                    })
+                    .collect();
+                for digest in &missing {
+                    self.requested_batches.insert(digest.clone(), now);
+                }
+                if !missing.is_empty() {
+                    warn!("{}: missing batches {:?} to execute", self.address, missing);
+                    let fetch = FetchBatches {
+                        from: self.address,
+                        digests: missing,
+                    };
+                    self.broadcast(fetch).await;
+                }
+                return;
+            }
+
+            let certificate = self.to_execute.pop_front().unwrap();
+            for digest in &certificate.header.batches {
+                let transactions = self.worker.get(digest).unwrap().transactions.clone();
+                for (txid, command) in transactions {
+                    let result = command
+                        .apply(&self.store)
+                        .await
+                        .unwrap_or_else(|error| Err(error.to_string()));
+                    if let Some(reply_sender) = self.pending.remove(&txid) {
+                        if let Err(error) = reply_sender.send(result) {
+                            error!("failed to send client response {:?}", error);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The number of votes needed for a certificate: all the nodes but the faulty ones, out of a
+    /// network of 3f + 1 nodes.
+    fn quorum(&self) -> usize {
+        self.peers.len() - (self.peers.len() - 1) / 3
+    }
+
+    async fn send(&mut self, to: SocketAddr, message: Message) {
+        let message: Bytes = bincode::serialize(&message).unwrap().into();
+        self.sender.send(to, message).await;
+    }
+
+    /// Send a message to every other node.
+    async fn broadcast(&mut self, message: Message) {
+        let others: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|peer| **peer != self.address)
+            .cloned()
+            .collect();
+        let message: Bytes = bincode::serialize(&message).unwrap().into();
+        self.sender.broadcast(others, message).await;
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NewBatch(batch) => write!(f, "NewBatch({}, {})", batch.digest(), batch.author),
+            NewHeader(header) => write!(
+                f,
+                "NewHeader({}, round {}, {})",
+                header.digest, header.round, header.author
+            ),
+            Vote {
+                from,
+                round,
+                digest,
+            } => write!(f, "Vote({round}, {digest}, {from})"),
+            NewCertificate(certificate) => write!(
+                f,
+                "NewCertificate({}, round {}, {})",
+                certificate.digest(),
+                certificate.round(),
+                certificate.author()
+            ),
+            FetchCertificates { from, digests } => {
+                write!(f, "FetchCertificates({digests:?}, {from})")
+            }
+            FetchBatches { from, digests } => write!(f, "FetchBatches({digests:?}, {from})"),
+        }
+    }
+}
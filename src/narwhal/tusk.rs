@@ -0,0 +1,48 @@
+/// This module contains the Tusk ordering engine: every wave of two rounds has a leader at its first
+/// round, which is only elected after the next wave starts, using a random coin. A leader is committed
+/// if enough certificates of the round after it link to it. Since the adversary can't know the leader
+/// before the DAG of the wave is built, it can't prevent it from being committed.
+use crate::consensus::{Committer, Ordering};
+use crate::dag::{Certificate, Dag, Round};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+
+pub struct Tusk {
+    committer: Committer,
+}
+
+impl Tusk {
+    pub fn new(peers: Vec<SocketAddr>) -> Self {
+        Self {
+            committer: Committer::new(peers),
+        }
+    }
+}
+
+/// The leader of the wave that starts at the given round, picked by a coin that every node computes
+/// the same way. In the protocol the coin comes from a threshold signature of the round that reveals
+/// it; here it's a hash of the round, which is unpredictable enough for benign networks.
+fn coin(peers: &[SocketAddr], round: Round) -> SocketAddr {
+    let hash = Sha256::digest(&round.to_le_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    peers[(u64::from_le_bytes(bytes) % peers.len() as u64) as usize]
+}
+
+impl Ordering for Tusk {
+    fn process(&mut self, dag: &Dag, certificate: &Certificate) -> Vec<Certificate> {
+        // the certificates of the first round of a wave reveal the coin of the previous wave
+        let round = certificate.round();
+        if round < 4 || round % 2 != 0 {
+            return vec![];
+        }
+        let leader_round = round - 2;
+        let peers = self.committer.peers.clone();
+        match dag.certificate(leader_round, coin(&peers, leader_round)) {
+            Some(leader_certificate) => self
+                .committer
+                .commit(dag, leader_certificate, |round| coin(&peers, round)),
+            None => vec![],
+        }
+    }
+}
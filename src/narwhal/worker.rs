@@ -0,0 +1,77 @@
+/// This module contains the worker of a Narwhal node: it groups the transactions received from clients
+/// into batches, and stores the batches created by every node so they are available when their
+/// certificates are committed.
+use crate::dag::{Batch, Digest, Transaction};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::time::{Duration, Instant};
+
+pub struct Worker {
+    address: SocketAddr,
+
+    /// The transactions of the batch being built, and when its first transaction was received.
+    current: Vec<Transaction>,
+    started_at: Option<Instant>,
+
+    /// A batch is sealed when it has this many transactions or when its first transaction is this old.
+    batch_size: usize,
+    max_delay: Duration,
+
+    /// The batches created by this and the rest of the nodes, by digest.
+    batches: HashMap<Digest, Batch>,
+}
+
+impl Worker {
+    pub fn new(address: SocketAddr, batch_size: usize, max_delay: Duration) -> Self {
+        Self {
+            address,
+            current: Vec::new(),
+            started_at: None,
+            batch_size,
+            max_delay,
+            batches: HashMap::new(),
+        }
+    }
+
+    /// Add a client transaction to the current batch, and return the batch if it's full.
+    pub fn add(&mut self, transaction: Transaction) -> Option<Batch> {
+        self.started_at.get_or_insert_with(Instant::now);
+        self.current.push(transaction);
+        if self.current.len() >= self.batch_size {
+            Some(self.seal())
+        } else {
+            None
+        }
+    }
+
+    /// Return the current batch if it has been waiting for more transactions for too long.
+    pub fn seal_expired(&mut self, now: Instant) -> Option<Batch> {
+        match self.started_at {
+            Some(started_at) if now >= started_at + self.max_delay => Some(self.seal()),
+            _ => None,
+        }
+    }
+
+    fn seal(&mut self) -> Batch {
+        self.started_at = None;
+        let batch = Batch {
+            author: self.address,
+            transactions: std::mem::take(&mut self.current),
+        };
+        self.store(batch.clone());
+        batch
+    }
+
+    /// Keep a batch created by this or another node.
+    pub fn store(&mut self, batch: Batch) {
+        self.batches.insert(batch.digest(), batch);
+    }
+
+    pub fn get(&self, digest: &str) -> Option<&Batch> {
+        self.batches.get(digest)
+    }
+
+    pub fn contains(&self, digest: &str) -> bool {
+        self.batches.contains_key(digest)
+    }
+}
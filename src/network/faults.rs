@@ -0,0 +1,520 @@
+/// A fault-injection layer over the network senders, to partition nodes, drop or delay the messages
+/// between them, and crash and restart them while they run. Faults are shared through a `Faults` handle:
+/// tests change them programmatically while the nodes run, and the node binaries can follow a script of
+/// timed faults. Every node sends its messages through a `FaultySender` that checks the current faults
+/// before sending each of them, wrapping either the TCP senders or the ones of a simulated network.
+///
+/// Faults are applied by the sending side, so a node's faults only affect the messages sent by the nodes
+/// sharing its `Faults` handle: when running separate processes, every node should follow the same script.
+/// Client requests, which aren't sent by nodes, are not affected.
+///
+/// Nodes started through `Faults::spawn` really stop when they crash: their tasks are aborted, losing
+/// their in-memory state, and a restart builds them again from their store.
+use super::reliable_sender::CancelHandler;
+use super::transport::{MessageSender, RequestSender};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use clap::Parser;
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+/// A change to the faults of the network. From a script, each fault is written as a line with the time
+/// in milliseconds when it's applied followed by the fault, e.g. `1000 partition 127.0.0.1:6200 127.0.0.1:6201,127.0.0.1:6202`.
+#[derive(Debug, Parser, Clone, PartialEq, Eq)]
+#[clap()]
+pub enum Fault {
+    /// Split the nodes in groups, given as comma-separated addresses: messages between nodes of different
+    /// groups are dropped. Nodes not in any group can still talk to every node.
+    Partition {
+        #[clap(required = true)]
+        groups: Vec<Group>,
+    },
+    /// Drop the messages sent from one node to another, but not the other way around.
+    Drop { from: SocketAddr, to: SocketAddr },
+    /// Delay the messages sent from one node to another by the given amount of milliseconds.
+    Delay {
+        from: SocketAddr,
+        to: SocketAddr,
+        millis: u64,
+    },
+    /// Stop dropping or delaying the messages sent from one node to another.
+    Restore { from: SocketAddr, to: SocketAddr },
+    /// Drop every message sent by or to the node. If the node was started through these faults, its
+    /// tasks are aborted too.
+    Crash { address: SocketAddr },
+    /// Deliver the messages sent by or to a crashed node again. If the node was started through these
+    /// faults, it's built again from its store, with the state it persisted before crashing.
+    Restart { address: SocketAddr },
+    /// Remove every fault, restarting the crashed nodes.
+    Heal,
+}
+
+/// A group of nodes of a partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group(pub Vec<SocketAddr>);
+
+impl FromStr for Group {
+    type Err = String;
+
+    fn from_str(group: &str) -> Result<Self, Self::Err> {
+        group
+            .split(',')
+            .map(|address| {
+                address
+                    .trim()
+                    .parse()
+                    .map_err(|e| format!("{address}: {e}"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Group)
+    }
+}
+
+/// A fault to apply once the given time passed since the script started.
+pub type ScheduledFault = (Duration, Fault);
+
+/// Builds a node from its store and spawns its tasks, returning their handles.
+pub type Start = Arc<dyn Fn() -> Vec<JoinHandle<()>> + Send + Sync>;
+
+/// How long to wait once the tasks of a crashed node finished before building it again, so its store
+/// had time to close.
+const RESTART_DELAY: Duration = Duration::from_millis(10);
+
+/// Parse a script of faults, with one fault per line preceded by the milliseconds since the start of
+/// the script when it's applied. Empty lines and lines starting with `#` are ignored.
+pub fn parse_script(script: &str) -> Result<Vec<ScheduledFault>> {
+    let mut faults = Vec::new();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let millis: u64 = words
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .with_context(|| format!("line {}: invalid time", number + 1))?;
+        let fault = Fault::try_parse_from(std::iter::once("fault").chain(words))
+            .map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+        faults.push((Duration::from_millis(millis), fault));
+    }
+    Ok(faults)
+}
+
+/// Read and parse the script of faults in the given file.
+pub fn load_script(path: &Path) -> Result<Vec<ScheduledFault>> {
+    let script = fs::read_to_string(path)
+        .with_context(|| format!("failed to read faults script {}", path.display()))?;
+    parse_script(&script)
+}
+
+/// What happens to a message sent from one node to another.
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    Deliver,
+    Drop,
+    Delay(Duration),
+}
+
+#[derive(Default)]
+struct State {
+    /// The group of each partitioned node.
+    partition: HashMap<SocketAddr, usize>,
+    dropped: HashSet<(SocketAddr, SocketAddr)>,
+    delayed: HashMap<(SocketAddr, SocketAddr), Duration>,
+    crashed: HashSet<SocketAddr>,
+}
+
+impl State {
+    fn verdict(&self, from: SocketAddr, to: SocketAddr) -> Verdict {
+        let partitioned = match (self.partition.get(&from), self.partition.get(&to)) {
+            (Some(from_group), Some(to_group)) => from_group != to_group,
+            _ => false,
+        };
+        if partitioned
+            || self.dropped.contains(&(from, to))
+            || self.crashed.contains(&from)
+            || self.crashed.contains(&to)
+        {
+            return Verdict::Drop;
+        }
+        match self.delayed.get(&(from, to)) {
+            Some(delay) => Verdict::Delay(*delay),
+            None => Verdict::Deliver,
+        }
+    }
+}
+
+/// A node started through the faults, so it can be stopped and started again.
+struct Process {
+    start: Start,
+    tasks: Vec<JoinHandle<()>>,
+    running: bool,
+    /// The number of times the node crashed, so a restart that is still waiting for the previous
+    /// tasks to finish doesn't start a node that crashed again in the meantime.
+    crashes: u64,
+}
+
+/// A handle to the faults of a network, cheap to clone and shared by the senders of every node.
+#[derive(Clone, Default)]
+pub struct Faults {
+    state: Arc<RwLock<State>>,
+    processes: Arc<Mutex<HashMap<SocketAddr, Process>>>,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply the given change to the faults of the network.
+    pub fn apply(&self, fault: &Fault) {
+        info!("Injecting fault {:?}", fault);
+        let mut state = self.state.write().unwrap();
+        match fault {
+            Fault::Partition { groups } => {
+                state.partition = groups
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, group)| group.0.iter().map(move |address| (*address, i)))
+                    .collect();
+            }
+            Fault::Drop { from, to } => {
+                state.dropped.insert((*from, *to));
+            }
+            Fault::Delay { from, to, millis } => {
+                state
+                    .delayed
+                    .insert((*from, *to), Duration::from_millis(*millis));
+            }
+            Fault::Restore { from, to } => {
+                state.dropped.remove(&(*from, *to));
+                state.delayed.remove(&(*from, *to));
+            }
+            Fault::Crash { address } => {
+                state.crashed.insert(*address);
+                self.stop(*address);
+            }
+            Fault::Restart { address } => {
+                state.crashed.remove(address);
+                self.start(*address);
+            }
+            Fault::Heal => {
+                for address in std::mem::take(&mut *state).crashed {
+                    self.start(address);
+                }
+            }
+        }
+    }
+
+    /// Run the node at the given address with the given function, which builds it from its store and
+    /// spawns its tasks. The tasks are aborted when the node crashes, and the function is called again
+    /// when it restarts.
+    pub fn spawn<F>(&self, address: SocketAddr, start: F)
+    where
+        F: Fn() -> Vec<JoinHandle<()>> + Send + Sync + 'static,
+    {
+        let start: Start = Arc::new(start);
+        let running = !self.state.read().unwrap().crashed.contains(&address);
+        let tasks = if running { start() } else { Vec::new() };
+        let process = Process {
+            start,
+            tasks,
+            running,
+            crashes: 0,
+        };
+        self.processes.lock().unwrap().insert(address, process);
+    }
+
+    /// Abort the tasks of the node at the given address, if it was started through these faults.
+    fn stop(&self, address: SocketAddr) {
+        let mut processes = self.processes.lock().unwrap();
+        if let Some(process) = processes
+            .get_mut(&address)
+            .filter(|process| process.running)
+        {
+            info!("Stopping node {}", address);
+            process.tasks.iter().for_each(JoinHandle::abort);
+            process.running = false;
+            process.crashes += 1;
+        }
+    }
+
+    /// Build again the stopped node at the given address, once the tasks of its previous run finished.
+    fn start(&self, address: SocketAddr) {
+        let mut processes = self.processes.lock().unwrap();
+        let Some(process) = processes
+            .get_mut(&address)
+            .filter(|process| !process.running)
+        else {
+            return;
+        };
+        process.running = true;
+        let stopped = std::mem::take(&mut process.tasks);
+        let crashes = process.crashes;
+
+        let processes = self.processes.clone();
+        tokio::spawn(async move {
+            // the store of the node closes once the aborted tasks drop it
+            for task in stopped {
+                let _ = task.await;
+            }
+            sleep(RESTART_DELAY).await;
+
+            let mut processes = processes.lock().unwrap();
+            let process = processes.get_mut(&address).unwrap();
+            if process.crashes == crashes {
+                info!("Restarting node {}", address);
+                process.tasks = (process.start)();
+            }
+        });
+    }
+
+    pub fn partition(&self, groups: &[&[SocketAddr]]) {
+        let groups = groups.iter().map(|group| Group(group.to_vec())).collect();
+        self.apply(&Fault::Partition { groups });
+    }
+
+    pub fn drop_link(&self, from: SocketAddr, to: SocketAddr) {
+        self.apply(&Fault::Drop { from, to });
+    }
+
+    pub fn delay_link(&self, from: SocketAddr, to: SocketAddr, delay: Duration) {
+        let millis = delay.as_millis() as u64;
+        self.apply(&Fault::Delay { from, to, millis });
+    }
+
+    pub fn restore_link(&self, from: SocketAddr, to: SocketAddr) {
+        self.apply(&Fault::Restore { from, to });
+    }
+
+    pub fn crash(&self, address: SocketAddr) {
+        self.apply(&Fault::Crash { address });
+    }
+
+    pub fn restart(&self, address: SocketAddr) {
+        self.apply(&Fault::Restart { address });
+    }
+
+    pub fn heal(&self) {
+        self.apply(&Fault::Heal);
+    }
+
+    /// Spawn a task that applies each fault of the script once its time passed since now.
+    pub fn run_script(&self, script: Vec<ScheduledFault>) -> JoinHandle<()> {
+        let faults = self.clone();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            for (at, fault) in script {
+                sleep_until(start + at).await;
+                faults.apply(&fault);
+            }
+        })
+    }
+
+    /// Wrap the sender of the node at the given address, which can be a `MessageSender` or a
+    /// `RequestSender`, so its messages are subject to these faults.
+    pub fn sender<S>(&self, address: SocketAddr, inner: S) -> FaultySender<S> {
+        FaultySender {
+            address,
+            faults: self.clone(),
+            inner,
+        }
+    }
+
+    fn verdict(&self, from: SocketAddr, to: SocketAddr) -> Verdict {
+        self.state.read().unwrap().verdict(from, to)
+    }
+}
+
+/// A sender that drops or delays the messages of a node according to the current faults of the network.
+#[derive(Clone)]
+pub struct FaultySender<S> {
+    address: SocketAddr,
+    faults: Faults,
+    inner: S,
+}
+
+#[async_trait]
+impl<S: MessageSender + Clone + 'static> MessageSender for FaultySender<S> {
+    async fn send(&mut self, address: SocketAddr, data: Bytes) {
+        match self.faults.verdict(self.address, address) {
+            Verdict::Deliver => MessageSender::send(&mut self.inner, address, data).await,
+            Verdict::Drop => debug!("dropping message from {} to {}", self.address, address),
+            Verdict::Delay(delay) => {
+                // send it later without blocking the node
+                let mut inner = self.inner.clone();
+                tokio::spawn(async move {
+                    sleep(delay).await;
+                    MessageSender::send(&mut inner, address, data).await;
+                });
+            }
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn MessageSender> {
+        Box::new(self.clone())
+    }
+}
+
+#[async_trait]
+impl<S: RequestSender + Clone + 'static> RequestSender for FaultySender<S> {
+    /// A dropped request is never answered: its handler resolves to an error, as if it was canceled.
+    async fn send(&mut self, address: SocketAddr, data: Bytes) -> CancelHandler {
+        match self.faults.verdict(self.address, address) {
+            Verdict::Deliver => RequestSender::send(&mut self.inner, address, data).await,
+            Verdict::Drop => {
+                debug!("dropping request from {} to {}", self.address, address);
+                let (_, reply_receiver) = oneshot::channel();
+                reply_receiver
+            }
+            Verdict::Delay(delay) => {
+                let mut inner = self.inner.clone();
+                let (reply_sender, reply_receiver) = oneshot::channel();
+                tokio::spawn(async move {
+                    sleep(delay).await;
+                    if let Ok(reply) = RequestSender::send(&mut inner, address, data).await.await {
+                        let _ = reply_sender.send(reply);
+                    }
+                });
+                reply_receiver
+            }
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn RequestSender> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    #[test]
+    fn apply_faults() {
+        let faults = Faults::new();
+        let (a, b, c) = (address(1), address(2), address(3));
+        assert_eq!(Verdict::Deliver, faults.verdict(a, b));
+
+        faults.partition(&[&[a], &[b, c]]);
+        assert_eq!(Verdict::Drop, faults.verdict(a, b));
+        assert_eq!(Verdict::Drop, faults.verdict(c, a));
+        assert_eq!(Verdict::Deliver, faults.verdict(b, c));
+        assert_eq!(Verdict::Deliver, faults.verdict(a, address(4)));
+
+        faults.heal();
+        faults.drop_link(a, b);
+        faults.delay_link(b, c, Duration::from_millis(50));
+        assert_eq!(Verdict::Drop, faults.verdict(a, b));
+        assert_eq!(Verdict::Deliver, faults.verdict(b, a));
+        assert_eq!(
+            Verdict::Delay(Duration::from_millis(50)),
+            faults.verdict(b, c)
+        );
+
+        faults.restore_link(a, b);
+        faults.crash(c);
+        assert_eq!(Verdict::Deliver, faults.verdict(a, b));
+        assert_eq!(Verdict::Drop, faults.verdict(b, c));
+        assert_eq!(Verdict::Drop, faults.verdict(c, a));
+
+        faults.restart(c);
+        assert_eq!(Verdict::Deliver, faults.verdict(c, a));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn crash_and_restart_nodes() {
+        let faults = Faults::new();
+        let starts = Arc::new(Mutex::new(Vec::new()));
+        let (a, b) = (address(1), address(2));
+        for node in [a, b] {
+            let starts = starts.clone();
+            faults.spawn(node, move || {
+                starts.lock().unwrap().push(node);
+                vec![tokio::spawn(std::future::pending())]
+            });
+        }
+        let task = |node| faults.processes.lock().unwrap()[&node].tasks[0].is_finished();
+
+        // a crashed node stops running until it restarts, when it's started again
+        faults.crash(a);
+        sleep(RESTART_DELAY).await;
+        assert!(task(a));
+        assert!(!task(b));
+
+        faults.restart(a);
+        faults.restart(a);
+        sleep(RESTART_DELAY * 2).await;
+        assert!(!task(a));
+        assert_eq!(vec![a, b, a], *starts.lock().unwrap());
+
+        // a node crashing again before it's started again stays stopped
+        faults.crash(b);
+        faults.restart(b);
+        faults.crash(b);
+        sleep(RESTART_DELAY * 2).await;
+        assert_eq!(vec![a, b, a], *starts.lock().unwrap());
+        faults.heal();
+        sleep(RESTART_DELAY * 2).await;
+        assert_eq!(vec![a, b, a, b], *starts.lock().unwrap());
+    }
+
+    #[test]
+    fn parse_faults_script() {
+        let script = "
+            # isolate the first node for a second
+            100 partition 127.0.0.1:1 127.0.0.1:2,127.0.0.1:3
+            1100 heal
+
+            1200 delay 127.0.0.1:1 127.0.0.1:2 300
+            1500 crash 127.0.0.1:3
+        ";
+        let faults = parse_script(script).unwrap();
+        assert_eq!(
+            vec![
+                (
+                    Duration::from_millis(100),
+                    Fault::Partition {
+                        groups: vec![Group(vec![address(1)]), Group(vec![address(2), address(3)])]
+                    }
+                ),
+                (Duration::from_millis(1100), Fault::Heal),
+                (
+                    Duration::from_millis(1200),
+                    Fault::Delay {
+                        from: address(1),
+                        to: address(2),
+                        millis: 300
+                    }
+                ),
+                (
+                    Duration::from_millis(1500),
+                    Fault::Crash {
+                        address: address(3)
+                    }
+                ),
+            ],
+            faults
+        );
+
+        assert!(parse_script("soon heal").is_err());
+        assert!(parse_script("100 partition 127.0.0.1").is_err());
+        assert!(parse_script("100 explode").is_err());
+    }
+}
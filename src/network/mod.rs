@@ -1,9 +1,13 @@
 // Copyright(C) Facebook, Inc. and its affiliates.
 mod error;
+pub mod faults;
 mod receiver;
 mod reliable_sender;
 mod simple_sender;
+pub mod simulator;
+mod transport;
 
 pub use receiver::Receiver;
 pub use reliable_sender::{CancelHandler, ReliableSender};
 pub use simple_sender::SimpleSender;
+pub use transport::{MessageReceiver, MessageSender, RequestSender};
@@ -0,0 +1,567 @@
+/// A deterministic in-process network to run several nodes in a single test. Every message goes through a
+/// scheduler that decides, with a seeded random number generator, how long it takes to be delivered and
+/// whether it's dropped, and then delivers it at that point of the (tokio) clock. The decisions only depend
+/// on the seed and on the sequence of messages sent, so running the same nodes with the same seed on a
+/// current-thread runtime with paused time (`#[tokio::test(start_paused = true)]`) replays the same
+/// schedule, and the simulated time doesn't require waiting for real timeouts.
+///
+/// For a whole run to replay, the nodes have to be deterministic as well: their random decisions use the
+/// generator returned by `SimulatedNetwork::rng`, their event loops poll their channels in a fixed order
+/// (`biased` selects), and anything they send or put in a block is iterated from ordered collections.
+use super::reliable_sender::CancelHandler;
+use super::transport::{MessageReceiver, MessageSender, RequestSender};
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{debug, info, warn};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, channel, unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use super::receiver::CHANNEL_CAPACITY;
+
+/// The receiving end of the channel where receivers forward incoming messages, with their reply channels.
+pub type RequestChannel<Request, Response> = mpsc::Receiver<(Request, oneshot::Sender<Response>)>;
+
+/// The environment variable that overrides the seed of the default configuration, to replay a failing run.
+pub const SEED_VARIABLE: &str = "SIMULATOR_SEED";
+
+/// The behavior of the simulated network.
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    /// The seed of the random decisions of the network.
+    pub seed: u64,
+    /// Every message takes a random time between these bounds to be delivered.
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    /// The probability of dropping a best-effort message, between 0 and 1. Messages sent with a
+    /// `RequestSender` and their replies are never dropped, since the TCP `ReliableSender` retransmits
+    /// them until they are acknowledged, but they are delayed as any other message.
+    pub drop_rate: f64,
+    /// If true, messages between two nodes can be delivered in a different order than they were sent.
+    /// Otherwise they keep the order, as they would on a TCP connection.
+    pub reorder: bool,
+}
+
+impl Default for SimulatorConfig {
+    /// A network with small latencies that doesn't drop or reorder messages. The seed is taken from the
+    /// `SIMULATOR_SEED` environment variable if set, or picked at random otherwise.
+    fn default() -> Self {
+        let seed = std::env::var(SEED_VARIABLE)
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        Self {
+            seed,
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(10),
+            drop_rate: 0.0,
+            reorder: false,
+        }
+    }
+}
+
+/// A message scheduled to be delivered to the node listening on an address, or a reply to be delivered
+/// to the node waiting for it.
+enum Payload {
+    Message {
+        from: SocketAddr,
+        data: Bytes,
+        reply_sender: Option<oneshot::Sender<Bytes>>,
+    },
+    Reply {
+        data: Bytes,
+        reply_sender: oneshot::Sender<Bytes>,
+    },
+}
+
+struct Event {
+    at: Instant,
+    /// The order in which events were scheduled, which breaks ties between events at the same instant.
+    sequence: u64,
+    to: SocketAddr,
+    payload: Payload,
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Event {}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Event {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.sequence).cmp(&(other.at, other.sequence))
+    }
+}
+
+/// A message delivered to a simulated receiver.
+struct Envelope {
+    from: SocketAddr,
+    data: Bytes,
+    reply_sender: Option<oneshot::Sender<Bytes>>,
+}
+
+struct State {
+    config: SimulatorConfig,
+    rng: SmallRng,
+    start: Instant,
+    sequence: u64,
+    queue: BinaryHeap<Reverse<Event>>,
+    /// The channels of the receivers listening on each address.
+    inboxes: HashMap<SocketAddr, UnboundedSender<Envelope>>,
+    /// The last delivery time of the messages between each pair of nodes, to keep them in order.
+    last_delivery: HashMap<(SocketAddr, SocketAddr), Instant>,
+    /// A log of every message sent through the network and what happened to it.
+    trace: Vec<String>,
+}
+
+impl State {
+    /// Decide when the given payload is delivered, if at all, and queue it. The decisions of the network
+    /// are the only uses of the random number generator, so they only depend on the seed and the order
+    /// of the messages.
+    fn schedule(&mut self, from: SocketAddr, to: SocketAddr, payload: Payload, droppable: bool) {
+        let min = self.config.min_latency.as_micros() as u64;
+        let max = self.config.max_latency.as_micros() as u64;
+        let latency = Duration::from_micros(self.rng.gen_range(min, max + 1));
+        let dropped = self.rng.gen::<f64>() < self.config.drop_rate && droppable;
+
+        let now = Instant::now();
+        let mut at = now + latency;
+        if !self.config.reorder {
+            let last = self.last_delivery.entry((from, to)).or_insert(at);
+            at = at.max(*last);
+            *last = at;
+        }
+
+        let kind = match payload {
+            Payload::Message { .. } => "message",
+            Payload::Reply { .. } => "reply",
+        };
+        let outcome = if dropped {
+            "dropped".to_string()
+        } else {
+            format!("delivered at {:?}", at - self.start)
+        };
+        self.trace.push(format!(
+            "{:?}: {} {} -> {}, {} bytes, {}",
+            now - self.start,
+            kind,
+            from,
+            to,
+            match &payload {
+                Payload::Message { data, .. } | Payload::Reply { data, .. } => data.len(),
+            },
+            outcome
+        ));
+        if dropped {
+            return;
+        }
+
+        self.sequence += 1;
+        self.queue.push(Reverse(Event {
+            at,
+            sequence: self.sequence,
+            to,
+            payload,
+        }));
+    }
+}
+
+/// A handle to a simulated network, cheap to clone. Creating one spawns the task that delivers its
+/// messages, so it has to be created inside a tokio runtime.
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    state: Arc<Mutex<State>>,
+    /// Wakes up the scheduler when a message is queued.
+    notify: Arc<Notify>,
+}
+
+impl SimulatedNetwork {
+    /// Start the network with the given configuration. Panics if its latency bounds are reversed or its
+    /// drop rate isn't a probability.
+    pub fn new(config: SimulatorConfig) -> Self {
+        assert!(
+            config.min_latency <= config.max_latency,
+            "the minimum latency {:?} is above the maximum {:?}",
+            config.min_latency,
+            config.max_latency
+        );
+        assert!(
+            (0.0..=1.0).contains(&config.drop_rate),
+            "the drop rate {} is not between 0 and 1",
+            config.drop_rate
+        );
+        info!(
+            "Simulating network with seed {}, set {} to replay it",
+            config.seed, SEED_VARIABLE
+        );
+        let network = Self {
+            state: Arc::new(Mutex::new(State {
+                rng: SmallRng::seed_from_u64(config.seed),
+                config,
+                start: Instant::now(),
+                sequence: 0,
+                queue: BinaryHeap::new(),
+                inboxes: HashMap::new(),
+                last_delivery: HashMap::new(),
+                trace: Vec::new(),
+            })),
+            notify: Arc::new(Notify::new()),
+        };
+
+        let scheduler = network.clone();
+        tokio::spawn(async move { scheduler.run().await });
+        network
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.state.lock().unwrap().config.seed
+    }
+
+    /// A random number generator for the node at the given address, derived from the seed of the network
+    /// so that the random decisions of the node replay with it. The nodes that make random decisions keep
+    /// their generator in an `rng` field, seeded from entropy, which the tests replace with this one.
+    pub fn rng(&self, address: SocketAddr) -> SmallRng {
+        let mut hasher = DefaultHasher::new();
+        (self.seed(), address).hash(&mut hasher);
+        SmallRng::seed_from_u64(hasher.finish())
+    }
+
+    /// The log of every message sent through the network so far, with its delivery time or whether it
+    /// was dropped.
+    pub fn trace(&self) -> Vec<String> {
+        self.state.lock().unwrap().trace.clone()
+    }
+
+    /// A sender of messages from the given address, which implements both `MessageSender` and
+    /// `RequestSender`.
+    pub fn sender(&self, address: SocketAddr) -> SimulatedSender {
+        SimulatedSender {
+            address,
+            network: self.clone(),
+        }
+    }
+
+    /// A receiver listening on the given address once it runs, and the channel where it forwards the
+    /// messages it receives, like `Receiver::new` does.
+    pub fn receiver<Request, Response>(
+        &self,
+        address: SocketAddr,
+    ) -> (
+        SimulatedReceiver<Request, Response>,
+        RequestChannel<Request, Response>,
+    ) {
+        let (sender, receiver) = channel(CHANNEL_CAPACITY);
+        let receiver_handle = SimulatedReceiver {
+            address,
+            network: self.clone(),
+            sender,
+            response: PhantomData,
+        };
+        (receiver_handle, receiver)
+    }
+
+    /// Spawn a receiver listening on the given address, and return the handle of its task, which can be
+    /// aborted to simulate a crash, and the channel where it forwards the messages it receives.
+    pub fn listen<Request, Response>(
+        &self,
+        address: SocketAddr,
+    ) -> (JoinHandle<()>, RequestChannel<Request, Response>)
+    where
+        Request: DeserializeOwned + Send + Debug + Sync + 'static,
+        Response: Serialize + Send + Debug + 'static,
+    {
+        let (receiver, channel) = self.receiver(address);
+        let handle = tokio::spawn(async move { receiver.run().await });
+        (handle, channel)
+    }
+
+    /// A sender for clients of the nodes, whose replies are delivered through the network as well.
+    pub fn client(&self) -> SimulatedSender {
+        self.sender(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+    }
+
+    fn schedule(&self, from: SocketAddr, to: SocketAddr, payload: Payload, droppable: bool) {
+        self.state
+            .lock()
+            .unwrap()
+            .schedule(from, to, payload, droppable);
+        self.notify.notify_one();
+    }
+
+    /// Deliver each queued event when its time comes.
+    async fn run(&self) {
+        loop {
+            let next = self
+                .state
+                .lock()
+                .unwrap()
+                .queue
+                .peek()
+                .map(|Reverse(event)| event.at);
+            match next {
+                Some(at) if at <= Instant::now() => self.deliver_next(),
+                Some(at) => {
+                    tokio::select! {
+                        _ = sleep_until(at) => {},
+                        _ = self.notify.notified() => {},
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+
+    fn deliver_next(&self) {
+        let mut state = self.state.lock().unwrap();
+        let event = match state.queue.pop() {
+            Some(Reverse(event)) => event,
+            None => return,
+        };
+        match event.payload {
+            Payload::Message {
+                from,
+                data,
+                reply_sender,
+            } => {
+                let envelope = Envelope {
+                    from,
+                    data,
+                    reply_sender,
+                };
+                let delivered = state
+                    .inboxes
+                    .get(&event.to)
+                    .map(|inbox| inbox.send(envelope).is_ok())
+                    .unwrap_or(false);
+                if !delivered {
+                    // as with a connection refused, the sender's reply handler is dropped
+                    debug!("no receiver listening on {}", event.to);
+                    state.inboxes.remove(&event.to);
+                }
+            }
+            Payload::Reply { data, reply_sender } => {
+                let _ = reply_sender.send(data);
+            }
+        }
+    }
+}
+
+/// A sender of messages through a simulated network.
+#[derive(Clone)]
+pub struct SimulatedSender {
+    address: SocketAddr,
+    network: SimulatedNetwork,
+}
+
+#[async_trait]
+impl MessageSender for SimulatedSender {
+    async fn send(&mut self, address: SocketAddr, data: Bytes) {
+        let payload = Payload::Message {
+            from: self.address,
+            data,
+            reply_sender: None,
+        };
+        self.network.schedule(self.address, address, payload, true);
+    }
+
+    fn clone_box(&self) -> Box<dyn MessageSender> {
+        Box::new(self.clone())
+    }
+}
+
+#[async_trait]
+impl RequestSender for SimulatedSender {
+    async fn send(&mut self, address: SocketAddr, data: Bytes) -> CancelHandler {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let payload = Payload::Message {
+            from: self.address,
+            data,
+            reply_sender: Some(reply_sender),
+        };
+        self.network.schedule(self.address, address, payload, false);
+        reply_receiver
+    }
+
+    fn clone_box(&self) -> Box<dyn RequestSender> {
+        Box::new(self.clone())
+    }
+}
+
+/// A receiver of the messages sent to an address of a simulated network. Like the TCP `Receiver` it
+/// deserializes each message, forwards it to its channel, and sends back the serialized response.
+pub struct SimulatedReceiver<Request, Response> {
+    address: SocketAddr,
+    network: SimulatedNetwork,
+    sender: mpsc::Sender<(Request, oneshot::Sender<Response>)>,
+    response: PhantomData<fn() -> Response>,
+}
+
+#[async_trait]
+impl<Request, Response> MessageReceiver for SimulatedReceiver<Request, Response>
+where
+    Request: DeserializeOwned + Send + Debug + Sync + 'static,
+    Response: Serialize + Send + Debug + 'static,
+{
+    async fn run(&self) {
+        let (inbox_sender, mut inbox): (_, UnboundedReceiver<Envelope>) = unbounded_channel();
+        self.network
+            .state
+            .lock()
+            .unwrap()
+            .inboxes
+            .insert(self.address, inbox_sender);
+        debug!("Listening on simulated {}", self.address);
+
+        while let Some(envelope) = inbox.recv().await {
+            let request: Request = match bincode::deserialize(&envelope.data) {
+                Ok(request) => request,
+                Err(error) => {
+                    warn!(
+                        "failed to deserialize message from {}: {}",
+                        envelope.from, error
+                    );
+                    continue;
+                }
+            };
+            let (reply_sender, reply_receiver) = oneshot::channel();
+            if self.sender.send((request, reply_sender)).await.is_err() {
+                return;
+            }
+
+            // wait for the response without blocking the next messages, and send it back through the network
+            let network = self.network.clone();
+            let address = self.address;
+            tokio::spawn(async move {
+                let response = match reply_receiver.await {
+                    Ok(response) => response,
+                    Err(_) => return,
+                };
+                if let Some(reply_sender) = envelope.reply_sender {
+                    let data = bincode::serialize(&response).unwrap().into();
+                    let payload = Payload::Reply { data, reply_sender };
+                    network.schedule(address, envelope.from, payload, false);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration as StdDuration;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    /// Run an echo node and send it a few messages through a lossy network, returning the messages
+    /// the node received and the trace of the network.
+    async fn run(config: SimulatorConfig) -> (Vec<String>, Vec<String>) {
+        let network = SimulatedNetwork::new(config);
+        let (receiver, mut messages) = network.receiver::<String, String>(address(1));
+        tokio::spawn(async move { receiver.run().await });
+
+        let mut sender = network.sender(address(2));
+        for i in 0..20 {
+            let message = bincode::serialize(&format!("message {i}")).unwrap();
+            MessageSender::send(&mut sender, address(1), message.into()).await;
+        }
+        let request = bincode::serialize(&"request".to_string()).unwrap();
+        let reply_handler = RequestSender::send(&mut sender, address(1), request.into()).await;
+
+        let mut received = Vec::new();
+        while let Some((message, reply_sender)) = messages.recv().await {
+            let done = message == "request";
+            reply_sender.send(format!("echo {message}")).unwrap();
+            received.push(message);
+            if done {
+                break;
+            }
+        }
+        let reply: String = bincode::deserialize(&reply_handler.await.unwrap()).unwrap();
+        assert_eq!("echo request", reply);
+        (received, network.trace())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_seed() {
+        let config = SimulatorConfig {
+            seed: 42,
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(100),
+            drop_rate: 0.3,
+            reorder: true,
+        };
+        let real_start = std::time::Instant::now();
+        let (received, trace) = run(config.clone()).await;
+
+        // the request is never dropped, but some of the other messages are and the rest are reordered
+        assert!(received.len() < 21);
+        let mut sorted = received.clone();
+        sorted.sort_by_key(|message| {
+            message
+                .strip_prefix("message ")
+                .map(|i| i.parse::<u32>().unwrap())
+                .unwrap_or(u32::MAX)
+        });
+        assert_ne!(sorted, received);
+
+        // the simulated time doesn't need to pass
+        assert!(real_start.elapsed() < StdDuration::from_millis(100));
+
+        // the same seed gives the same results, a different one doesn't
+        assert_eq!((received.clone(), trace.clone()), run(config.clone()).await);
+        let other = SimulatorConfig { seed: 7, ..config };
+        assert_ne!(trace, run(other).await.1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_order() {
+        let config = SimulatorConfig {
+            seed: 42,
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(100),
+            drop_rate: 0.0,
+            reorder: false,
+        };
+        let (received, _) = run(config).await;
+        let expected: Vec<String> = (0..20)
+            .map(|i| format!("message {i}"))
+            .chain(["request".to_string()])
+            .collect();
+        assert_eq!(expected, received);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "above the maximum")]
+    async fn reversed_latencies() {
+        SimulatedNetwork::new(SimulatorConfig {
+            min_latency: Duration::from_millis(100),
+            max_latency: Duration::from_millis(1),
+            ..Default::default()
+        });
+    }
+}
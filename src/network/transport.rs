@@ -0,0 +1,136 @@
+use super::receiver::Receiver;
+use super::reliable_sender::{CancelHandler, ReliableSender};
+use super::simple_sender::SimpleSender;
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+/// A best-effort sender of messages to other nodes, which doesn't wait for replies. Implemented over
+/// TCP by `SimpleSender`, and by the simulated network used in tests.
+#[async_trait]
+pub trait MessageSender: Send + Sync {
+    /// Try (best-effort) to send a message to a specific address.
+    async fn send(&mut self, address: SocketAddr, data: Bytes);
+
+    /// Try (best-effort) to broadcast the message to all specified addresses.
+    async fn broadcast(&mut self, addresses: Vec<SocketAddr>, data: Bytes) {
+        for address in addresses {
+            self.send(address, data.clone()).await;
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn MessageSender>;
+}
+
+impl Clone for Box<dyn MessageSender> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+#[async_trait]
+impl MessageSender for Box<dyn MessageSender> {
+    async fn send(&mut self, address: SocketAddr, data: Bytes) {
+        (**self).send(address, data).await
+    }
+
+    async fn broadcast(&mut self, addresses: Vec<SocketAddr>, data: Bytes) {
+        (**self).broadcast(addresses, data).await
+    }
+
+    fn clone_box(&self) -> Box<dyn MessageSender> {
+        (**self).clone_box()
+    }
+}
+
+/// A sender of messages to other nodes that returns a handler to wait for each reply. Implemented over
+/// TCP by `ReliableSender`, and by the simulated network used in tests.
+#[async_trait]
+pub trait RequestSender: Send + Sync {
+    /// Send a message to a specific address, and return a handler that resolves to its reply.
+    async fn send(&mut self, address: SocketAddr, data: Bytes) -> CancelHandler;
+
+    /// Send the message to all specified addresses, returning the reply handlers in the same order.
+    async fn broadcast(&mut self, addresses: &[SocketAddr], data: Bytes) -> Vec<CancelHandler> {
+        let mut handlers = Vec::new();
+        for address in addresses {
+            handlers.push(self.send(*address, data.clone()).await);
+        }
+        handlers
+    }
+
+    fn clone_box(&self) -> Box<dyn RequestSender>;
+}
+
+impl Clone for Box<dyn RequestSender> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+#[async_trait]
+impl RequestSender for Box<dyn RequestSender> {
+    async fn send(&mut self, address: SocketAddr, data: Bytes) -> CancelHandler {
+        (**self).send(address, data).await
+    }
+
+    async fn broadcast(&mut self, addresses: &[SocketAddr], data: Bytes) -> Vec<CancelHandler> {
+        (**self).broadcast(addresses, data).await
+    }
+
+    fn clone_box(&self) -> Box<dyn RequestSender> {
+        (**self).clone_box()
+    }
+}
+
+/// A listener of messages sent to an address, which forwards them to the channel returned when it
+/// was created. Implemented over TCP by `Receiver`, and by the simulated network used in tests.
+#[async_trait]
+pub trait MessageReceiver: Send + Sync {
+    /// Listen for messages until the task running it is aborted.
+    async fn run(&self);
+}
+
+#[async_trait]
+impl MessageSender for SimpleSender {
+    async fn send(&mut self, address: SocketAddr, data: Bytes) {
+        SimpleSender::send(self, address, data).await
+    }
+
+    async fn broadcast(&mut self, addresses: Vec<SocketAddr>, data: Bytes) {
+        SimpleSender::broadcast(self, addresses, data).await
+    }
+
+    fn clone_box(&self) -> Box<dyn MessageSender> {
+        Box::new(self.clone())
+    }
+}
+
+#[async_trait]
+impl RequestSender for ReliableSender {
+    async fn send(&mut self, address: SocketAddr, data: Bytes) -> CancelHandler {
+        ReliableSender::send(self, address, data).await
+    }
+
+    async fn broadcast(&mut self, addresses: &[SocketAddr], data: Bytes) -> Vec<CancelHandler> {
+        ReliableSender::broadcast(self, addresses, data).await
+    }
+
+    fn clone_box(&self) -> Box<dyn RequestSender> {
+        Box::new(self.clone())
+    }
+}
+
+#[async_trait]
+impl<Request, Response> MessageReceiver for Receiver<Request, Response>
+where
+    Request: DeserializeOwned + Send + Debug + Sync + 'static,
+    Response: Serialize + Send + Debug + 'static,
+{
+    async fn run(&self) {
+        Receiver::run(self).await
+    }
+}
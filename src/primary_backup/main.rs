@@ -1,8 +1,10 @@
-use crate::node::Node;
+use crate::node::{Byzantine, Node, ReplicationMode};
 use clap::Parser;
+use lib::network::faults::{self, Faults};
 use lib::network::Receiver;
 use log::info;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use tokio::task::JoinHandle;
 
 mod node;
@@ -28,6 +30,21 @@ struct Cli {
     /// (eg. when running several nodes in same machine)
     #[clap(short, long)]
     name: Option<String>,
+    /// A script of faults to inject in the messages this node sends, with one fault per line preceded by
+    /// the milliseconds since the node started when it's applied, e.g. `1000 crash 127.0.0.1:6200`.
+    #[clap(long, value_parser, value_name = "FILE")]
+    faults: Option<PathBuf>,
+    /// When the primary replies to writes: once all the backups acknowledge them, once a quorum of the
+    /// nodes has them, or right after applying them.
+    #[clap(long, value_enum, default_value_t = ReplicationMode::Async)]
+    replication: ReplicationMode,
+    /// Run the node with a faulty behavior, to test how the other nodes and the clients cope with it.
+    #[clap(long, value_enum, value_name = "BEHAVIOR")]
+    byzantine: Option<Byzantine>,
+    /// As a backup, reply to client writes with the address of the primary instead of forwarding them to it,
+    /// so the clients get the result of their writes.
+    #[clap(long)]
+    redirect: bool,
 }
 
 #[tokio::main(flavor = "multi_thread")]
@@ -43,29 +60,50 @@ async fn main() {
     let network_address = SocketAddr::new(cli.address, cli.network_port);
     let client_address = SocketAddr::new(cli.address, cli.client_port);
 
-    let node = if let Some(primary_address) = cli.primary {
-        info!(
-            "Replica: Running as replica on {}, waiting for commands from the primary node...",
-            network_address
-        );
+    let faults = Faults::new();
+    if let Some(path) = &cli.faults {
+        let script = faults::load_script(path).unwrap();
+        faults.run_script(script);
+    }
 
-        let db_name = &db_name(&cli, &format!("replic-{}", cli.network_port)[..]);
-        Node::backup(db_name, network_address, primary_address)
-    } else {
-        info!("Primary: Running as primary on {}.", network_address);
-        let db_name = db_name(&cli, "primary");
-        Node::primary(&db_name, network_address, network_address)
-    };
+    // the node is built again from its store each time the faults script restarts it
+    let node_faults = faults.clone();
+    faults.spawn(network_address, move || {
+        let mut node = if let Some(primary_address) = cli.primary {
+            info!(
+                "Replica: Running as replica on {}, waiting for commands from the primary node...",
+                network_address
+            );
+
+            let db_name = &db_name(&cli, &format!("replic-{}", cli.network_port)[..]);
+            Node::backup(db_name, network_address, primary_address)
+        } else {
+            info!("Primary: Running as primary on {}.", network_address);
+            let db_name = db_name(&cli, "primary");
+            Node::primary(&db_name, network_address, network_address)
+        };
 
-    let (_, network_handle, _) = spawn_node_tasks(network_address, client_address, node).await;
-    network_handle.await.unwrap();
+        node.mode = cli.replication;
+        node.byzantine = cli.byzantine;
+        node.redirect = cli.redirect;
+        node.sender = Box::new(node_faults.sender(network_address, node.sender.clone()));
+
+        let (node_handle, network_handle, client_handle) =
+            spawn_node_tasks(network_address, client_address, node);
+        vec![node_handle, network_handle, client_handle]
+    });
+
+    // keep running while the node is crashed
+    std::future::pending::<()>().await;
 }
 
-async fn spawn_node_tasks(
+fn spawn_node_tasks(
     network_address: SocketAddr,
     client_address: SocketAddr,
     mut node: Node,
 ) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
+    node.client_address = Some(client_address);
+
     // listen for peer network tcp connections
     let (network_tcp_receiver, network_channel_receiver) = Receiver::new(network_address);
     let network_handle = tokio::spawn(async move {
@@ -98,7 +136,14 @@ mod tests {
     use crate::node::{Message, State};
     use anyhow::{anyhow, Result};
     use bytes::Bytes;
-    use lib::{command::ClientCommand, network::ReliableSender};
+    use lib::{
+        cluster::{ClusterClient, NotLeader},
+        command::{ClientCommand, Consistency},
+        history::History,
+        linearizability,
+        network::simulator::{SimulatedNetwork, SimulatedSender, SimulatorConfig},
+        network::{ReliableSender, RequestSender},
+    };
     use std::fs;
     use tokio::time::Duration;
     use tokio_retry::{strategy::FixedInterval, Retry};
@@ -223,6 +268,8 @@ mod tests {
             State::Backup,
         )
         .await;
+        // the backups are promoted in the order they subscribe to the primary
+        tokio::time::sleep(Duration::from_millis(100)).await;
         run_node(
             db_path("db_test_backup5"),
             network_address_second_replica,
@@ -293,6 +340,1080 @@ mod tests {
         assert_get_msg(KEY, VALUE, client_address_second_replica, false).await;
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_set_command_to_backup_is_redirected_to_primary() {
+        let (network_address_primary, client_address_primary) = get_address_pair(BASE_PORT + 126);
+        let (network_address_replica, client_address_replica) = get_address_pair(BASE_PORT + 128);
+        let (network_address_second_replica, client_address_second_replica) =
+            get_address_pair(BASE_PORT + 130);
+
+        run_node(
+            db_path("db_test_redirect_primary"),
+            network_address_primary,
+            client_address_primary,
+            network_address_primary,
+            State::Primary,
+        )
+        .await;
+        for (name, network_address, client_address) in [
+            (
+                "db_test_redirect_backup1",
+                network_address_replica,
+                client_address_replica,
+            ),
+            (
+                "db_test_redirect_backup2",
+                network_address_second_replica,
+                client_address_second_replica,
+            ),
+        ] {
+            let mut node = Node::backup(&db_path(name), network_address, network_address_primary);
+            node.redirect = true;
+            spawn_node_tasks(network_address, client_address, node);
+        }
+
+        // a set on a replica fails, naming the primary learned from its heartbeats
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: VALUE.to_string(),
+        };
+        let reply = command.clone().send_to(client_address_replica).await;
+        assert_eq!(
+            NotLeader(Some(client_address_primary)).to_string(),
+            reply.unwrap_err().to_string()
+        );
+
+        // so a client of the cluster follows the redirect to the primary
+        let mut client = ClusterClient::new(vec![client_address_replica]);
+        let reply = client.send(command).await.unwrap();
+        assert_eq!(Some(VALUE.to_string()), reply);
+        assert_eq!(client_address_primary, client.leader());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // get value on primary
+        assert_get_msg(KEY, VALUE, client_address_primary, false).await;
+        // get value on replica to make sure it was replicated
+        assert_get_msg(KEY, VALUE, client_address_replica, false).await;
+        // get value on second replica to make sure it was replicated
+        assert_get_msg(KEY, VALUE, client_address_second_replica, false).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replicated_batch() {
+        let (network_address_primary, client_address_primary) = get_address_pair(BASE_PORT + 22);
+        let (network_address_replica, client_address_replica) = get_address_pair(BASE_PORT + 24);
+
+        run_node(
+            db_path("db_test_primary6"),
+            network_address_primary,
+            client_address_primary,
+            network_address_primary,
+            State::Primary,
+        )
+        .await;
+        run_node(
+            db_path("db_test_backup8"),
+            network_address_replica,
+            client_address_replica,
+            network_address_primary,
+            State::Backup,
+        )
+        .await;
+
+        // give the backup time to subscribe to the primary
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_set_msg("from", "10", client_address_primary).await;
+
+        // move 4 units between two keys atomically
+        let transfer = ClientCommand::Batch {
+            commands: vec![
+                ClientCommand::CompareAndSwap {
+                    key: "from".to_string(),
+                    expected: "10".to_string(),
+                    new: "6".to_string(),
+                },
+                ClientCommand::Increment {
+                    key: "to".to_string(),
+                    by: 4,
+                },
+            ],
+        };
+        let reply = transfer.clone().send_to(client_address_primary).await;
+        assert_eq!("[6, 4]", reply.unwrap().unwrap());
+
+        // the same transfer is now rejected as a whole
+        assert!(transfer.send_to(client_address_primary).await.is_err());
+
+        // give the backup time to apply the replicated commands
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_get_msg("from", "6", client_address_primary, false).await;
+        assert_get_msg("to", "4", client_address_primary, false).await;
+        assert_get_msg("from", "6", client_address_replica, false).await;
+        assert_get_msg("to", "4", client_address_replica, false).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_simulated_network() {
+        // replication messages are sent once and never retried, so the network doesn't drop them
+        let network = SimulatedNetwork::new(SimulatorConfig {
+            reorder: true,
+            ..Default::default()
+        });
+        let (network_address_primary, client_address_primary) = get_address_pair(BASE_PORT + 26);
+        let replicas = [
+            get_address_pair(BASE_PORT + 28),
+            get_address_pair(BASE_PORT + 30),
+        ];
+
+        for (i, (network_address, client_address)) in
+            [(network_address_primary, client_address_primary)]
+                .iter()
+                .chain(&replicas)
+                .enumerate()
+        {
+            let db_path = db_path(&format!("db_test_simulated{i}"));
+            let mut node = if i == 0 {
+                node::Node::primary(&db_path, *network_address, network_address_primary)
+            } else {
+                node::Node::backup(&db_path, *network_address, network_address_primary)
+            };
+            node.sender = Box::new(network.sender(*network_address));
+            node.client_address = Some(*client_address);
+            node.redirect = true;
+            let (_, network_channel) = network.listen(*network_address);
+            let (_, client_channel) = network.listen(*client_address);
+            tokio::spawn(async move {
+                node.run(network_channel, client_channel).await;
+            });
+        }
+
+        // give the backups time to subscribe to the primary
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut client = network.client();
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: "1".to_string(),
+        };
+        let reply = command.send_with(&mut client, client_address_primary).await;
+        assert_eq!(Some("1".to_string()), reply.unwrap());
+
+        // writes sent to a backup are redirected to the primary
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: "2".to_string(),
+        };
+        let mut cluster = ClusterClient::with_sender(vec![replicas[1].1], Box::new(client.clone()));
+        cluster.send(command).await.unwrap();
+
+        for (_, client_address) in replicas {
+            let retries = FixedInterval::from_millis(100).take(50);
+            Retry::spawn(retries, || {
+                let mut client = client.clone();
+                async move {
+                    let command = ClientCommand::Get {
+                        key: KEY.to_string(),
+                    };
+                    match command.send_with(&mut client, client_address).await {
+                        Ok(Some(value)) if value == "2" => Ok(()),
+                        _ => Err(()),
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("value was never replicated, seed {}", network.seed()));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_primary_timeout() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 32),
+            get_address_pair(BASE_PORT + 34),
+        ];
+        let (network_address_primary, _) = nodes[0];
+        let (network_address_replica, client_address_replica) = nodes[1];
+        spawn_simulated_nodes(&network, &faults, &nodes, "timeout", ReplicationMode::Async).await;
+
+        // the backup stops receiving heartbeats and takes over once the primary timeout expires
+        faults.crash(network_address_primary);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        let mut client = network.client();
+        let message = bincode::serialize(&Message::PrimaryAddress).unwrap();
+        let reply = RequestSender::send(&mut client, network_address_replica, message.into())
+            .await
+            .await
+            .unwrap();
+        let primary: String = bincode::deserialize(&reply).unwrap();
+        assert_eq!(network_address_replica.to_string(), primary);
+
+        // the new primary applies writes itself instead of forwarding them
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: VALUE.to_string(),
+        };
+        let reply = command.send_with(&mut client, client_address_replica).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_messages_dont_shorten_primary_timeout() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 118),
+            get_address_pair(BASE_PORT + 120),
+        ];
+        let (network_address_primary, _) = nodes[0];
+        let (network_address_replica, _) = nodes[1];
+        spawn_simulated_nodes(&network, &faults, &nodes, "busy", ReplicationMode::Async).await;
+
+        // a busy backup counts the time without heartbeats, not the messages it handled meanwhile
+        faults.crash(network_address_primary);
+        let mut client = network.client();
+        for _ in 0..30 {
+            let message = bincode::serialize(&Message::PrimaryAddress).unwrap();
+            let reply = RequestSender::send(&mut client, network_address_replica, message.into())
+                .await
+                .await
+                .unwrap();
+            let primary: String = bincode::deserialize(&reply).unwrap();
+            assert_eq!(network_address_primary.to_string(), primary);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_promoted_primary_notifies_backups() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 40),
+            get_address_pair(BASE_PORT + 42),
+            get_address_pair(BASE_PORT + 44),
+        ];
+        let primary = nodes[0].0;
+        spawn_simulated_nodes(
+            &network,
+            &faults,
+            &nodes,
+            "promotion",
+            ReplicationMode::Async,
+        )
+        .await;
+
+        faults.crash(primary);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        // both backups agree on the next peer being the new primary
+        let mut client = network.client();
+        for (network_address, _) in &nodes[1..] {
+            let message = bincode::serialize(&Message::PrimaryAddress).unwrap();
+            let reply = RequestSender::send(&mut client, *network_address, message.into())
+                .await
+                .await
+                .unwrap();
+            let reply: String = bincode::deserialize(&reply).unwrap();
+            assert_eq!(nodes[1].0.to_string(), reply);
+        }
+
+        // writes sent to the other backup are redirected to the new primary, which replicates them
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: VALUE.to_string(),
+        };
+        let mut cluster = ClusterClient::with_sender(vec![nodes[2].1], Box::new(client.clone()));
+        cluster.send(command).await.unwrap();
+        assert_eq!(nodes[1].1, cluster.leader());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for (_, client_address) in &nodes[1..] {
+            let command = ClientCommand::Get {
+                key: KEY.to_string(),
+            };
+            let reply = command.send_with(&mut client, *client_address).await;
+            assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_late_backup_catches_up() {
+        let network = SimulatedNetwork::new(SimulatorConfig {
+            reorder: true,
+            ..Default::default()
+        });
+        let (network_address_primary, client_address_primary) = get_address_pair(BASE_PORT + 46);
+        let (network_address_replica, client_address_replica) = get_address_pair(BASE_PORT + 48);
+
+        let spawn = |i: usize, network_address: SocketAddr, client_address: SocketAddr| {
+            let db_path = db_path(&format!("db_test_late{i}"));
+            let mut node = if i == 0 {
+                node::Node::primary(&db_path, network_address, network_address_primary)
+            } else {
+                node::Node::backup(&db_path, network_address, network_address_primary)
+            };
+            node.sender = Box::new(network.sender(network_address));
+            let (_, network_channel) = network.listen(network_address);
+            let (_, client_channel) = network.listen(client_address);
+            tokio::spawn(async move {
+                node.run(network_channel, client_channel).await;
+            });
+        };
+
+        // the primary accepts writes before the backup joins
+        spawn(0, network_address_primary, client_address_primary);
+        let mut client = network.client();
+        for (key, value) in [("k1", "v1"), ("k2", "v2")] {
+            let command = ClientCommand::Set {
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+            command
+                .send_with(&mut client, client_address_primary)
+                .await
+                .unwrap();
+        }
+
+        // the backup gets the snapshot and then replicates the writes that follow it, even if
+        // the network delivers them first
+        spawn(1, network_address_replica, client_address_replica);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let command = ClientCommand::Set {
+            key: "k2".to_string(),
+            value: "v3".to_string(),
+        };
+        command
+            .send_with(&mut client, client_address_primary)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for (key, value) in [("k1", "v1"), ("k2", "v3")] {
+            let command = ClientCommand::Get {
+                key: key.to_string(),
+            };
+            let reply = command.send_with(&mut client, client_address_replica).await;
+            assert_eq!(
+                Some(value.to_string()),
+                reply.unwrap(),
+                "seed {}",
+                network.seed()
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backup_recovers_lost_writes() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let (network_address_primary, client_address_primary) = get_address_pair(BASE_PORT + 50);
+        let (network_address_replica, client_address_replica) = get_address_pair(BASE_PORT + 52);
+
+        for (i, (network_address, client_address)) in [
+            (network_address_primary, client_address_primary),
+            (network_address_replica, client_address_replica),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let db_path = db_path(&format!("db_test_lost{i}"));
+            let mut node = if i == 0 {
+                node::Node::primary(&db_path, network_address, network_address_primary)
+            } else {
+                node::Node::backup(&db_path, network_address, network_address_primary)
+            };
+            let sender = network.sender(network_address);
+            node.sender = Box::new(faults.sender(network_address, sender));
+            let (_, network_channel) = network.listen(network_address);
+            let (_, client_channel) = network.listen(client_address);
+            tokio::spawn(async move {
+                node.run(network_channel, client_channel).await;
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // the second increment never reaches the backup, which notices the gap when the third
+        // one arrives, and the last one is only noticed through the heartbeats
+        let mut client = network.client();
+        for i in 0..4 {
+            if i == 1 || i == 3 {
+                faults.drop_link(network_address_primary, network_address_replica);
+            }
+            let command = ClientCommand::Increment {
+                key: KEY.to_string(),
+                by: 1,
+            };
+            command
+                .send_with(&mut client, client_address_primary)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            faults.restore_link(network_address_primary, network_address_replica);
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let command = ClientCommand::Get {
+            key: KEY.to_string(),
+        };
+        let reply = command.send_with(&mut client, client_address_replica).await;
+        assert_eq!(Some("4".to_string()), reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_async_replication_loses_writes() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 54),
+            get_address_pair(BASE_PORT + 56),
+        ];
+        spawn_simulated_nodes(&network, &faults, &nodes, "async", ReplicationMode::Async).await;
+
+        // the primary acknowledges the write without waiting for the backup, which never gets it
+        faults.drop_link(nodes[0].0, nodes[1].0);
+        let mut client = network.client();
+        let reply = set_with(&mut client, nodes[0].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+
+        // so the write is lost when the backup takes over
+        faults.crash(nodes[0].0);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let reply = get_with(&mut client, nodes[1].1).await;
+        assert_eq!(None, reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sync_replication() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 58),
+            get_address_pair(BASE_PORT + 60),
+            get_address_pair(BASE_PORT + 62),
+        ];
+        spawn_simulated_nodes(&network, &faults, &nodes, "sync", ReplicationMode::Sync).await;
+
+        // once the write is acknowledged every backup has it
+        let mut client = network.client();
+        let reply = set_with(&mut client, nodes[0].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+        for (_, client_address) in &nodes[1..] {
+            let reply = get_with(&mut client, *client_address).await;
+            assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+        }
+
+        // a single unreachable backup makes writes fail, since they may not be durable
+        faults.drop_link(nodes[0].0, nodes[2].0);
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: "other".to_string(),
+        };
+        let reply = command.send_with(&mut client, nodes[0].1).await;
+        assert!(reply.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_quorum_replication() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 64),
+            get_address_pair(BASE_PORT + 66),
+            get_address_pair(BASE_PORT + 68),
+        ];
+        spawn_simulated_nodes(&network, &faults, &nodes, "quorum", ReplicationMode::Quorum).await;
+
+        // a write acknowledged by one of the two backups is on a majority of the nodes, so it
+        // survives the primary failing before the other backup gets it
+        faults.drop_link(nodes[0].0, nodes[2].0);
+        let mut client = network.client();
+        let reply = set_with(&mut client, nodes[0].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+
+        faults.crash(nodes[0].0);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let reply = get_with(&mut client, nodes[1].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_promoted_primary_catches_up() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 112),
+            get_address_pair(BASE_PORT + 114),
+            get_address_pair(BASE_PORT + 116),
+        ];
+        spawn_simulated_nodes(
+            &network,
+            &faults,
+            &nodes,
+            "catch_up",
+            ReplicationMode::Async,
+        )
+        .await;
+
+        // the next primary in line misses a write that the other backup applies
+        faults.drop_link(nodes[0].0, nodes[1].0);
+        let mut client = network.client();
+        let reply = set_with(&mut client, nodes[0].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // once promoted it gets the write from the other backup before accepting new ones, so
+        // its writes are numbered after it and the backup doesn't discard them
+        faults.crash(nodes[0].0);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let reply = get_with(&mut client, nodes[1].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: "new".to_string(),
+        };
+        command.send_with(&mut client, nodes[1].1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let reply = get_with(&mut client, nodes[2].1).await;
+        assert_eq!(Some("new".to_string()), reply.unwrap());
+
+        // a write of the previous view is rejected
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: "stale".to_string(),
+        };
+        let replicate = Message::Replicate(0, 3, command, nodes[0].0);
+        let message = bincode::serialize(&replicate).unwrap();
+        RequestSender::send(&mut client, nodes[2].0, message.into())
+            .await
+            .await
+            .unwrap();
+        let reply = get_with(&mut client, nodes[2].1).await;
+        assert_eq!(Some("new".to_string()), reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeats_carry_membership() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 70),
+            get_address_pair(BASE_PORT + 72),
+            get_address_pair(BASE_PORT + 74),
+        ];
+
+        // the first backup misses the announcement of the second one
+        faults.drop_link(nodes[0].0, nodes[1].0);
+        spawn_simulated_nodes(
+            &network,
+            &faults,
+            &nodes,
+            "heartbeat",
+            ReplicationMode::Async,
+        )
+        .await;
+        faults.restore_link(nodes[0].0, nodes[1].0);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // but learns about it from the heartbeats, so it replicates to it once promoted
+        faults.crash(nodes[0].0);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let mut client = network.client();
+        set_with(&mut client, nodes[1].1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let reply = get_with(&mut client, nodes[2].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+
+        // a heartbeat of the previous view is ignored
+        let heartbeat = Message::Heartbeat {
+            view: 0,
+            peers: vec![nodes[0].0, nodes[2].0],
+            sequence: 0,
+            client_address: Some(nodes[0].1),
+        };
+        let message = bincode::serialize(&heartbeat).unwrap();
+        RequestSender::send(&mut client, nodes[2].0, message.into())
+            .await
+            .await
+            .unwrap();
+        let message = bincode::serialize(&Message::PrimaryAddress).unwrap();
+        let reply = RequestSender::send(&mut client, nodes[2].0, message.into())
+            .await
+            .await
+            .unwrap();
+        let primary: String = bincode::deserialize(&reply).unwrap();
+        assert_eq!(nodes[1].0.to_string(), primary);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_removed_node_stops_timer() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 136),
+            get_address_pair(BASE_PORT + 138),
+            get_address_pair(BASE_PORT + 140),
+        ];
+        spawn_simulated_nodes(&network, &faults, &nodes, "removed", ReplicationMode::Async).await;
+        let mut client = network.client();
+        let leave = Message::Leave {
+            address: nodes[2].0,
+        };
+        let message = bincode::serialize(&leave).unwrap();
+        RequestSender::send(&mut client, nodes[0].0, message.into())
+            .await
+            .await
+            .unwrap();
+
+        // the removed node stops getting heartbeats, but doesn't move on to the views of the next peers
+        tokio::time::sleep(Duration::from_millis(3000)).await;
+        let message = bincode::serialize(&Message::PrimaryAddress).unwrap();
+        let reply = RequestSender::send(&mut client, nodes[2].0, message.into())
+            .await
+            .await
+            .unwrap();
+        let primary: String = bincode::deserialize(&reply).unwrap();
+        assert_eq!(nodes[0].0.to_string(), primary);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_nodes_leave() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 76),
+            get_address_pair(BASE_PORT + 78),
+            get_address_pair(BASE_PORT + 80),
+            get_address_pair(BASE_PORT + 82),
+        ];
+        spawn_simulated_nodes(&network, &faults, &nodes, "leave", ReplicationMode::Sync).await;
+        let mut client = network.client();
+
+        // a backup leaves through another backup, which forwards the request to the primary, and
+        // stops getting the writes
+        let leave = Message::Leave {
+            address: nodes[3].0,
+        };
+        let message = bincode::serialize(&leave).unwrap();
+        RequestSender::send(&mut client, nodes[1].0, message.into())
+            .await
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        set_with(&mut client, nodes[0].1).await.unwrap();
+        let reply = get_with(&mut client, nodes[3].1).await;
+        assert_eq!(None, reply.unwrap());
+
+        // the primary leaves and the next backup takes over in the same view
+        let leave = Message::Leave {
+            address: nodes[0].0,
+        };
+        let message = bincode::serialize(&leave).unwrap();
+        RequestSender::send(&mut client, nodes[0].0, message.into())
+            .await
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for (network_address, _) in &nodes[..3] {
+            let message = bincode::serialize(&Message::PrimaryAddress).unwrap();
+            let reply = RequestSender::send(&mut client, *network_address, message.into())
+                .await
+                .await
+                .unwrap();
+            let primary: String = bincode::deserialize(&reply).unwrap();
+            assert_eq!(nodes[1].0.to_string(), primary);
+        }
+
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: "other".to_string(),
+        };
+        let reply = command.send_with(&mut client, nodes[1].1).await;
+        assert_eq!(Some("other".to_string()), reply.unwrap());
+        let reply = get_with(&mut client, nodes[2].1).await;
+        assert_eq!(Some("other".to_string()), reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unresponsive_backup_evicted() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 84),
+            get_address_pair(BASE_PORT + 86),
+            get_address_pair(BASE_PORT + 88),
+        ];
+        spawn_simulated_nodes(&network, &faults, &nodes, "evicted", ReplicationMode::Sync).await;
+
+        // a crashed backup makes synchronous writes fail until the primary evicts it
+        faults.crash(nodes[2].0);
+        let mut client = network.client();
+        assert!(set_with(&mut client, nodes[0].1).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(3500)).await;
+        let reply = set_with(&mut client, nodes[0].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+        let reply = get_with(&mut client, nodes[1].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delayed_snapshot_ignored() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let (primary_address, _) = get_address_pair(BASE_PORT + 122);
+        let (backup_address, _) = get_address_pair(BASE_PORT + 124);
+        let mut backup = Node::backup(&db_path("delayed"), backup_address, primary_address);
+        backup.sender = Box::new(network.sender(backup_address));
+
+        let snapshot = || {
+            let entries = vec![(KEY.as_bytes().to_vec(), b"first".to_vec())];
+            Message::Snapshot(entries, 1, 0)
+        };
+        backup.handle_msg(snapshot()).await.unwrap();
+        let write = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: VALUE.to_string(),
+        };
+        backup
+            .handle_msg(Message::Replicate(0, 2, write, primary_address))
+            .await
+            .unwrap();
+
+        // a snapshot sent before the last write doesn't roll it back
+        backup.handle_msg(snapshot()).await.unwrap();
+        let value = backup.store.read(KEY.into()).await.unwrap();
+        assert_eq!(Some(VALUE.into()), value);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retried_request_replicated_once() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let (primary_address, _) = get_address_pair(BASE_PORT + 132);
+        let (backup_address, _) = get_address_pair(BASE_PORT + 134);
+        let mut primary = Node::primary(&db_path("retried"), primary_address, primary_address);
+        primary.sender = Box::new(network.sender(primary_address));
+        primary.peers = vec![primary_address, backup_address];
+        let (_, mut backup) = network.listen::<Message, String>(backup_address);
+
+        let request = Message::Command(ClientCommand::Session {
+            client: 1,
+            sequence: 1,
+            command: Box::new(ClientCommand::Increment {
+                key: KEY.to_string(),
+                by: 1,
+            }),
+        });
+        for _ in 0..2 {
+            let reply = primary.handle_msg(request.clone()).await.unwrap();
+            assert_eq!(Some("1".to_string()), reply);
+        }
+
+        // the retry gets the cached reply, and only the first request reaches the backup
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (message, _) = backup.try_recv().unwrap();
+        assert!(matches!(message, Message::Replicate(0, 1, _, _)));
+        assert!(backup.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarted_backup_recovers_from_store() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 108),
+            get_address_pair(BASE_PORT + 110),
+        ];
+        spawn_simulated_nodes(
+            &network,
+            &faults,
+            &nodes,
+            "restarted",
+            ReplicationMode::Sync,
+        )
+        .await;
+        let mut client = network.client();
+        let reply = set_with(&mut client, nodes[0].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+
+        // a crashed backup stops running, so it doesn't even answer its clients
+        faults.crash(nodes[1].0);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(get_with(&mut client, nodes[1].1).await.is_err());
+
+        // once restarted it's built from its store, with the writes it applied before crashing, and
+        // subscribes to the primary again to get the ones it missed
+        faults.restart(nodes[1].0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let reply = get_with(&mut client, nodes[1].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+
+        let command = ClientCommand::Increment {
+            key: "counter".to_string(),
+            by: 1,
+        };
+        command.send_with(&mut client, nodes[0].1).await.unwrap();
+        let command = ClientCommand::Get {
+            key: "counter".to_string(),
+        };
+        let reply = command.send_with(&mut client, nodes[1].1).await;
+        assert_eq!(Some("1".to_string()), reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_equivocating_primary_diverges_backups() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 90),
+            get_address_pair(BASE_PORT + 92),
+            get_address_pair(BASE_PORT + 94),
+        ];
+        let byzantine = [(0, Byzantine::Equivocate)];
+        spawn_byzantine_nodes(
+            &network,
+            &faults,
+            &nodes,
+            "equivocate",
+            ReplicationMode::Sync,
+            &byzantine,
+        )
+        .await;
+
+        // backups apply whatever the primary replicates, so nothing catches the conflicting writes
+        // and both acknowledge them
+        let mut client = network.client();
+        let reply = set_with(&mut client, nodes[0].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+        let reply = get_with(&mut client, nodes[1].1).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+        let reply = get_with(&mut client, nodes[2].1).await;
+        assert_eq!(Some(format!("{VALUE}'")), reply.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lying_primary_address() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 96),
+            get_address_pair(BASE_PORT + 98),
+            get_address_pair(BASE_PORT + 100),
+        ];
+        let byzantine = [(2, Byzantine::LiePrimaryAddress)];
+        spawn_byzantine_nodes(
+            &network,
+            &faults,
+            &nodes,
+            "lie",
+            ReplicationMode::Sync,
+            &byzantine,
+        )
+        .await;
+
+        // replies aren't authenticated, so a client asking the lying backup believes it's the
+        // primary, and only asking the other nodes too exposes the lie
+        let mut client = network.client();
+        let mut primaries = Vec::new();
+        for (network_address, _) in &nodes {
+            let message = bincode::serialize(&Message::PrimaryAddress).unwrap();
+            let reply = RequestSender::send(&mut client, *network_address, message.into())
+                .await
+                .await
+                .unwrap();
+            let primary: String = bincode::deserialize(&reply).unwrap();
+            primaries.push(primary);
+        }
+        assert_eq!(
+            vec![
+                nodes[0].0.to_string(),
+                nodes[0].0.to_string(),
+                nodes[2].0.to_string()
+            ],
+            primaries
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_consistency_levels() {
+        let network = SimulatedNetwork::new(SimulatorConfig::default());
+        let faults = Faults::new();
+        let nodes = [
+            get_address_pair(BASE_PORT + 102),
+            get_address_pair(BASE_PORT + 104),
+            get_address_pair(BASE_PORT + 106),
+        ];
+        spawn_simulated_nodes(&network, &faults, &nodes, "reads", ReplicationMode::Async).await;
+        let mut client = network.client();
+        set_with(&mut client, nodes[0].1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // the primary serves every level, and backups only the stale reads
+        for consistency in [Consistency::Linearizable, Consistency::Lease] {
+            let reply = read_with(&mut client, nodes[0].1, consistency).await;
+            assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+            let reply = read_with(&mut client, nodes[1].1, consistency).await;
+            assert!(reply.unwrap_err().to_string().contains("not the leader"));
+        }
+        let stale = Consistency::Stale {
+            max_staleness_ms: 1000,
+        };
+        let reply = read_with(&mut client, nodes[1].1, stale).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+
+        // a cluster client follows the redirect of the backup to the primary
+        let backup_first = vec![nodes[1].1, nodes[0].1];
+        let mut cluster = ClusterClient::with_sender(backup_first, Box::new(network.client()));
+        let read = ClientCommand::Read {
+            key: KEY.to_string(),
+            consistency: Consistency::Linearizable,
+        };
+        assert_eq!(Some(VALUE.to_string()), cluster.send(read).await.unwrap());
+
+        // a backup cut off from the primary misses the next write, but still serves the value it has
+        // to the reads that tolerate its staleness
+        faults.drop_link(nodes[0].0, nodes[2].0);
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: "other".to_string(),
+        };
+        command.send_with(&mut client, nodes[0].1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let bounded = Consistency::Stale {
+            max_staleness_ms: 5000,
+        };
+        let reply = read_with(&mut client, nodes[2].1, bounded).await;
+        assert_eq!(Some(VALUE.to_string()), reply.unwrap());
+        let recent = Consistency::Stale {
+            max_staleness_ms: 200,
+        };
+        let reply = read_with(&mut client, nodes[2].1, recent).await;
+        assert!(reply.is_err());
+
+        // and the primary loses its lease, so lease reads wait for the backup like linearizable ones
+        let reply = read_with(&mut client, nodes[0].1, Consistency::Lease).await;
+        assert!(reply.is_err());
+        let reply = read_with(&mut client, nodes[1].1, stale).await;
+        assert_eq!(Some("other".to_string()), reply.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_linearizable_history() {
+        let (network_address_primary, client_address_primary) = get_address_pair(BASE_PORT + 36);
+        let (network_address_replica, client_address_replica) = get_address_pair(BASE_PORT + 38);
+
+        run_node(
+            db_path("db_test_primary_linearizable"),
+            network_address_primary,
+            client_address_primary,
+            network_address_primary,
+            State::Primary,
+        )
+        .await;
+        run_node(
+            db_path("db_test_backup_linearizable"),
+            network_address_replica,
+            client_address_replica,
+            network_address_primary,
+            State::Backup,
+        )
+        .await;
+
+        // concurrent clients of the primary should see a linearizable store
+        let history = History::new();
+        history
+            .run_clients(4, 20, &["k1", "k2"], |_| client_address_primary)
+            .await;
+
+        if let Err(violations) = linearizability::check(&history.operations()) {
+            panic!("{}", violations[0]);
+        }
+    }
+
+    /// Run the given nodes on the simulated network, the first one as primary, through the given faults
+    /// so they can be crashed and restarted.
+    async fn spawn_simulated_nodes(
+        network: &SimulatedNetwork,
+        faults: &Faults,
+        nodes: &[(SocketAddr, SocketAddr)],
+        name: &str,
+        mode: ReplicationMode,
+    ) {
+        spawn_byzantine_nodes(network, faults, nodes, name, mode, &[]).await
+    }
+
+    /// Like `spawn_simulated_nodes`, running the nodes at the given indexes with a faulty behavior.
+    async fn spawn_byzantine_nodes(
+        network: &SimulatedNetwork,
+        faults: &Faults,
+        nodes: &[(SocketAddr, SocketAddr)],
+        name: &str,
+        mode: ReplicationMode,
+        byzantine: &[(usize, Byzantine)],
+    ) {
+        let primary = nodes[0].0;
+        for (i, (network_address, client_address)) in nodes.iter().copied().enumerate() {
+            let db_path = db_path(&format!("db_test_{name}{i}"));
+            let behavior = byzantine
+                .iter()
+                .find(|(index, _)| *index == i)
+                .map(|(_, behavior)| *behavior);
+            let (network, node_faults) = (network.clone(), faults.clone());
+            faults.spawn(network_address, move || {
+                let mut node = if i == 0 {
+                    node::Node::primary(&db_path, network_address, primary)
+                } else {
+                    node::Node::backup(&db_path, network_address, primary)
+                };
+                node.mode = mode;
+                node.client_address = Some(client_address);
+                node.byzantine = behavior;
+                node.redirect = true;
+                let sender = network.sender(network_address);
+                node.sender = Box::new(node_faults.sender(network_address, sender));
+                let (network_handle, network_channel) = network.listen(network_address);
+                let (client_handle, client_channel) = network.listen(client_address);
+                let node_handle = tokio::spawn(async move {
+                    node.run(network_channel, client_channel).await;
+                });
+                vec![node_handle, network_handle, client_handle]
+            });
+            // the backups are promoted in the order they subscribe to the primary
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    async fn set_with(client: &mut SimulatedSender, address: SocketAddr) -> Result<Option<String>> {
+        let command = ClientCommand::Set {
+            key: KEY.to_string(),
+            value: VALUE.to_string(),
+        };
+        command.send_with(client, address).await
+    }
+
+    async fn get_with(client: &mut SimulatedSender, address: SocketAddr) -> Result<Option<String>> {
+        let command = ClientCommand::Get {
+            key: KEY.to_string(),
+        };
+        command.send_with(client, address).await
+    }
+
+    async fn read_with(
+        client: &mut SimulatedSender,
+        address: SocketAddr,
+        consistency: Consistency,
+    ) -> Result<Option<String>> {
+        let command = ClientCommand::Read {
+            key: KEY.to_string(),
+            consistency,
+        };
+        command.send_with(client, address).await
+    }
+
     fn db_path(suffix: &str) -> String {
         format!(".db_test/{suffix}")
     }
@@ -342,7 +1463,7 @@ mod tests {
             State::Backup => node::Node::backup(&db_path, network_address, primary),
         };
 
-        spawn_node_tasks(network_address, client_address, node).await
+        spawn_node_tasks(network_address, client_address, node)
     }
 
     fn get_address_pair(port: u16) -> (SocketAddr, SocketAddr) {
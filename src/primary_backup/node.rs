@@ -1,18 +1,31 @@
 /// This module contains an implementation nodes that can run in primary or backup mode.
-/// Every Set command to a primary node will be broadcasted reliably for the backup nodes to replicate it.
-/// We plan to add backup promotion in case of primary failure.
+/// Every write command to a primary node will be broadcasted reliably for the backup nodes to replicate it.
+/// When backups stop receiving heartbeats from the primary they move to the next view, whose primary is the
+/// next node in the list of peers. That node promotes itself and notifies the rest of the new view, then
+/// catches up with the most advanced backup and sends every backup its snapshot before accepting writes.
+/// Backups that subscribe after the primary accepted writes first install a snapshot of the primary store.
+/// Replicated writes carry a sequence number and are kept in a log, so backups apply them in order and ask
+/// the primary to resend the ones they detect missing. Nodes can leave the cluster, and the primary evicts the
+/// backups that stop answering its heartbeats. Depending on the replication mode, the primary replies
+/// to a write right away or once all or a quorum of the backups acknowledge it.
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
+use clap::ValueEnum;
 use core::fmt;
-use lib::command::{ClientCommand, CommandResult};
-use lib::{network::SimpleSender, store::Store};
+use lib::cluster::{ClientReply, NotLeader};
+use lib::command::{Changes, ClientCommand, CommandResult, Consistency, SESSION_PREFIX};
+use lib::{
+    network::{MessageSender, SimpleSender},
+    store::Store,
+};
 use log::{error, info};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::SocketAddr;
-use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
 
 /// The types of messages supported by this implementation's state machine.
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -20,24 +33,82 @@ pub enum Message {
     /// A command sent by a a client to this node.
     Command(ClientCommand),
 
-    /// A request from a peer to replicate a client write command
-    Replicate(ClientCommand, SocketAddr),
+    /// A request from the primary of the given view to replicate a client write command, with its sequence number
+    /// in the replication log
+    Replicate(usize, u64, ClientCommand, SocketAddr),
+
+    /// A backup acknowledgment that it applied the replicated writes up to the given sequence number
+    Ack { sequence: u64, address: SocketAddr },
+
+    /// A backup request to resend the writes of the replication log from the given sequence number on, also sent
+    /// by a new primary to the most advanced backup to catch up with it
+    Retransmit { from: u64, address: SocketAddr },
 
     /// A backup replica request to subcribe to a primary
     Subscribe { address: SocketAddr },
 
-    /// A primary node's heartbeat including the currently known peers this is just for illustration purposes not being used yet
-    Heartbeat,
+    /// A primary node's heartbeat with its view, the currently known peers and the sequence number of its last write,
+    /// so backups converge on the membership even if they missed a `NewReplica` message, and detect the writes they missed.
+    /// It also carries the address clients reach the primary at, so backups can redirect them to it
+    Heartbeat {
+        view: usize,
+        peers: Vec<SocketAddr>,
+        sequence: u64,
+        client_address: Option<SocketAddr>,
+    },
 
     /// A request for the actual primary, used when a new replica wants to join but doesn't know who is the current primary
     PrimaryAddress,
 
     /// A Message emitted from primary to all replicas informing that a new node was added with the current peers and the view
     NewReplica(Vec<SocketAddr>, usize),
+
+    /// The contents of the primary store, the sequence number of the last write included and the view of the
+    /// primary, sent to a backup that just subscribed so it catches up with the writes it missed before replicating
+    /// new ones, and to every backup once a new primary caught up, so they continue from the same state
+    Snapshot(Vec<(Vec<u8>, Vec<u8>)>, u64, usize),
+
+    /// A request to remove the node with the given address from the cluster, sent by the node itself or an
+    /// operator. Backups forward it to the primary
+    Leave { address: SocketAddr },
+
+    /// A Message emitted from primary to all replicas, including the removed one, informing that a node left or
+    /// was evicted with the remaining peers and the view
+    RemoveReplica(Vec<SocketAddr>, usize),
+
+    /// A message emitted by a backup promoted to primary informing all the other nodes of the current peers and the new view.
+    /// The other nodes acknowledge their last write, so the new primary catches up with the most advanced of them
+    NewView(Vec<SocketAddr>, usize),
 }
 
-const HEARTBEAT_CYCLE: usize = 2;
-const PRIMARY_TIMEOUT: usize = 10;
+/// The entries of the replication log are stored under this prefix, followed by their big-endian sequence
+/// number. It can't collide with client keys since those are valid UTF-8 strings, and the 0xff byte never
+/// appears in UTF-8.
+const LOG_PREFIX: &[u8] = b"\xfflog/";
+
+/// The sequence number of the last write applied to the store.
+const SEQUENCE_KEY: &[u8] = b"\xffsequence";
+
+/// How long the primary waits for the backups to acknowledge a write before failing the client request.
+const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a new primary waits for every backup to report its last write before catching up with the most
+/// advanced of those that did.
+const RECOVERY_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How long the primary waits for a backup to acknowledge its heartbeats before removing it from the peers.
+const EVICTION_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long after the last acknowledgment of every backup the primary serves lease reads locally. Backups
+/// only take over after hearing nothing from the primary for PRIMARY_TIMEOUT, so the rest of that time is
+/// left for the acknowledgments to arrive.
+const LEASE_DURATION: Duration = Duration::from_millis(500);
+
+/// How often the primary sends heartbeats to the backups.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(300);
+/// How long a backup waits without hearing from the primary before the next node in line takes over.
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the node checks its timers.
 const CIYLE_LENGTH: u64 = 100;
 
 /// Safe serialization helper. Logs on error.
@@ -57,7 +128,36 @@ impl fmt::Display for Message {
     }
 }
 
-#[derive(Clone)]
+/// When the primary replies to a client write, trading latency for durability.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReplicationMode {
+    /// Reply once every backup applied the write, so it survives as long as any node does.
+    Sync,
+    /// Reply once enough backups applied the write for it to be on a majority of the nodes.
+    Quorum,
+    /// Reply right after applying the write locally, so it's lost if the primary fails before the
+    /// backups get it.
+    Async,
+}
+
+/// A faulty behavior a node can be started with, to test which faults the protocol tolerates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Byzantine {
+    /// As primary, replicate each write to half of the backups and a conflicting write with the same
+    /// sequence number to the other half.
+    Equivocate,
+    /// Answer `PrimaryAddress` requests with the address of this node, whether it's the primary or not.
+    LiePrimaryAddress,
+}
+
+/// A client write the primary applied and replicated, waiting for the backups to acknowledge it.
+struct PendingReply {
+    result: CommandResult,
+    reply_sender: oneshot::Sender<ClientReply>,
+    sent_at: Instant,
+    mode: ReplicationMode,
+}
+
 /// A message handler that just forwards key/value store requests from clients to an internal rocksdb store.
 pub struct Node {
     pub state: State,
@@ -66,19 +166,65 @@ pub struct Node {
     /// peers is a vector with the addresses of the nodes that integrate the system ordered by the time that they integrate it.
     pub peers: Vec<SocketAddr>,
 
-    /// cycle represents:
-    ///     Primary: Number of seconds since the last time the node send a heartbeat to the replicas.
-    ///     Backup: Number of seconds since the last received heartbeat.
-    pub cycle: usize,
+    /// timer_start represents:
+    ///     Primary: When the node last sent a heartbeat to the replicas.
+    ///     Backup: When the node last heard from the primary.
+    timer_start: Instant,
 
     /// view number refers to the current primary on peers
     pub view: usize,
 
-    pub sender: SimpleSender,
+    pub sender: Box<dyn MessageSender>,
     address: SocketAddr,
 
+    /// the address clients send commands to this node at
+    pub client_address: Option<SocketAddr>,
+
+    /// the client address of the primary, learned from its heartbeats, where backups redirect clients
+    primary_client_address: Option<SocketAddr>,
+
+    /// whether backups redirect client writes to the primary instead of forwarding them to it
+    pub redirect: bool,
+
     /// address of the primary node, used by backups nodes to subscribe at start up
     primary_address: SocketAddr,
+
+    /// false while a backup waits for the snapshot of the primary store after subscribing
+    synced: bool,
+
+    /// sequence number of the last write applied: assigned by the primary, or replicated by a backup
+    sequence: u64,
+
+    /// replicate requests received by a backup ahead of the next sequence number, or before installing
+    /// the snapshot, applied once the writes before them are
+    pending: BTreeMap<u64, (ClientCommand, SocketAddr)>,
+
+    /// the highest sequence number a backup asked the primary to resend writes up to
+    requested: u64,
+
+    pub mode: ReplicationMode,
+
+    /// the highest sequence number acknowledged by each backup
+    acknowledged: HashMap<SocketAddr, u64>,
+
+    /// the last time the primary heard from each backup
+    last_seen: HashMap<SocketAddr, Instant>,
+
+    /// the last time each backup acknowledged a write or a heartbeat, which the lease of the primary relies on
+    acked_at: HashMap<SocketAddr, Instant>,
+
+    /// the last time a backup got a heartbeat reporting no writes it hadn't applied
+    caught_up_at: Option<Instant>,
+
+    /// the client writes waiting for acknowledgments, by sequence number
+    waiting: BTreeMap<u64, PendingReply>,
+
+    /// when this node became primary, while it's still catching up with the most advanced backup, which may
+    /// have writes of the previous primary that this node missed. Commands are rejected until then
+    recovery: Option<Instant>,
+
+    /// the faulty behavior of this node, if any
+    pub byzantine: Option<Byzantine>,
 }
 
 /// The state of a node viewed as a state-machine.
@@ -96,26 +242,56 @@ impl Node {
     pub fn primary(db_path: &str, address: SocketAddr, primary_address: SocketAddr) -> Self {
         Self {
             address,
+            client_address: None,
+            primary_client_address: None,
+            redirect: false,
             state: Primary,
             store: Store::new(db_path).unwrap(),
             view: 0,
-            cycle: 0,
+            timer_start: Instant::now(),
             peers: Vec::new(),
-            sender: SimpleSender::new(),
+            sender: Box::new(SimpleSender::new()),
             primary_address,
+            synced: true,
+            sequence: 0,
+            pending: BTreeMap::new(),
+            requested: 0,
+            mode: ReplicationMode::Async,
+            acknowledged: HashMap::new(),
+            last_seen: HashMap::new(),
+            acked_at: HashMap::new(),
+            caught_up_at: None,
+            waiting: BTreeMap::new(),
+            recovery: None,
+            byzantine: None,
         }
     }
 
     pub fn backup(db_path: &str, address: SocketAddr, primary_address: SocketAddr) -> Self {
         Self {
             address,
+            client_address: None,
+            primary_client_address: None,
+            redirect: false,
             state: Backup,
             store: Store::new(db_path).unwrap(),
             peers: Vec::new(),
-            cycle: 0,
+            timer_start: Instant::now(),
             view: 0,
-            sender: SimpleSender::new(),
+            sender: Box::new(SimpleSender::new()),
             primary_address,
+            synced: false,
+            sequence: 0,
+            pending: BTreeMap::new(),
+            requested: 0,
+            mode: ReplicationMode::Async,
+            acknowledged: HashMap::new(),
+            last_seen: HashMap::new(),
+            acked_at: HashMap::new(),
+            caught_up_at: None,
+            waiting: BTreeMap::new(),
+            recovery: None,
+            byzantine: None,
         }
     }
 }
@@ -123,17 +299,36 @@ impl Node {
 impl Node {
     async fn broadcast_to_others(&mut self, command: Message) {
         let message: Bytes = bincode::serialize(&command).unwrap().into();
-        let peers = &self.peers[self.view + 1..];
-        let other_peers: Vec<SocketAddr> = peers
-            .iter()
-            .copied()
-            .filter(|x| *x != self.address)
-            .collect();
+        let other_peers = self.backups();
 
-        // forward the command to all replicas and wait for them to respond
+        // forward the command to all replicas
         self.sender.broadcast(other_peers, message).await;
     }
 
+    /// Replicate the write to half of the backups and a conflicting one with the same sequence number
+    /// to the other half. Backups trust the primary, so they diverge.
+    async fn equivocate(&mut self, command: ClientCommand) {
+        let backups = self.backups();
+        let (first_half, second_half) = backups.split_at(backups.len() / 2);
+        info!(
+            "[{}] Equivocating, replicating {} to {:?} and a conflicting write to {:?}",
+            self.address, command, first_half, second_half
+        );
+
+        let conflicting = Replicate(
+            self.view,
+            self.sequence,
+            command.conflicting(),
+            self.address,
+        );
+        let replicate = Replicate(self.view, self.sequence, command, self.address);
+        for (addresses, message) in [(first_half, replicate), (second_half, conflicting)] {
+            if let Some(data) = serialize(&message) {
+                self.sender.broadcast(addresses.to_vec(), data).await;
+            }
+        }
+    }
+
     async fn send_primary(&mut self, command: Message) {
         let message: Bytes = bincode::serialize(&command).unwrap().into();
         self.sender.send(self.get_primary(), message).await;
@@ -142,8 +337,15 @@ impl Node {
     pub async fn run(
         &mut self,
         mut network_receiver: Receiver<(Message, oneshot::Sender<String>)>,
-        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<CommandResult>)>,
+        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<ClientReply>)>,
     ) -> JoinHandle<()> {
+        if let Err(error) = self.restore().await {
+            error!(
+                "[{}] failed to restore the replication log: {}",
+                self.address, error
+            );
+        }
+
         if self.state == Backup {
             let msg = Message::Subscribe {
                 address: self.address,
@@ -159,11 +361,21 @@ impl Node {
                 Some((command, reply_sender)) = client_receiver.recv() => {
                     info!("Received client message {}", command);
 
+                    // the reads replicated like writes are answered once every backup applied them, so
+                    // none of them can have moved on to a new primary
+                    let mode = if matches!(command, Read { .. }) {
+                        ReplicationMode::Sync
+                    } else {
+                        self.mode
+                    };
                     let message = Command(command);
 
-                    let result = self.handle_msg(message.clone()).await.map_err(|e|e.to_string());
+                    let sequence = self.sequence;
+                    let result = self.handle_msg(message.clone()).await;
 
-                    if let Err(error) = reply_sender.send(result) {
+                    if self.state == Primary && self.sequence > sequence {
+                        self.reply_when_acknowledged(result.map_err(|e|e.to_string()), reply_sender, mode);
+                    } else if let Err(error) = reply_sender.send(result.into()) {
                         error!("failed to send message {:?} response {:?}", message, error);
                     };
                 }
@@ -181,7 +393,9 @@ impl Node {
                         };
                     } else {
                         reply_sender.send("ACK".to_string()).unwrap();
-                        self.handle_msg(message.clone()).await.unwrap();
+                        if let Err(error) = self.handle_msg(message.clone()).await {
+                            error!("failed to handle message {:?}: {}", message, error);
+                        }
                     }
                 }
                 _ = self.check_timer() => ()
@@ -192,72 +406,309 @@ impl Node {
     // Checks sync betwen primary and replicas.
     async fn check_timer(&mut self) {
         match self.state {
-            // Primary waits HEARTBEAT_CYCLE * CYCLE_LENGTH miliseconds to send a new heartbeat to replicas
+            // Primary waits HEARTBEAT_INTERVAL to send a new heartbeat to replicas
             State::Primary => {
-                if self.cycle >= HEARTBEAT_CYCLE {
-                    self.broadcast_to_others(Heartbeat).await;
-                    self.cycle = 0;
-                } else {
-                    self.cycle += 1;
+                self.expire_waiting();
+                self.evict_unresponsive().await;
+                if let Err(error) = self.recover().await {
+                    error!("[{}] failed to catch up: {}", self.address, error);
+                }
+                if self.timer_start.elapsed() >= HEARTBEAT_INTERVAL {
+                    let heartbeat = Heartbeat {
+                        view: self.view,
+                        peers: self.peers.clone(),
+                        sequence: self.sequence,
+                        client_address: self.client_address,
+                    };
+                    self.broadcast_to_others(heartbeat).await;
+                    self.timer_start = Instant::now();
                 }
             }
-            // Backup waits at least PRIMARY TIMEOUT * cycle milliseconds to change view
+            // Backup waits at least PRIMARY_TIMEOUT without news from the primary to change view
             // If Backup is next in line (peers[view + 1]) and the view change then it becomes the new primary
+            // A node removed from the cluster gets no heartbeats and no longer takes part in view changes
             State::Backup => {
-                if self.cycle >= PRIMARY_TIMEOUT {
-                    if self.peers[self.view + 1] == self.address {
-                        self.state = State::Primary;
+                let removed = !self.peers.is_empty() && !self.peers.contains(&self.address);
+                if !removed && self.timer_start.elapsed() >= PRIMARY_TIMEOUT {
+                    // without a next peer in line there's no one to take over, keep waiting for the primary
+                    if self.view + 1 < self.peers.len() {
+                        self.follow_view(self.view + 1);
+                        if self.get_primary() == self.address {
+                            self.promote().await;
+                        }
                     }
-                    self.view += 1;
-                    self.cycle = 0
-                } else {
-                    self.cycle += 1;
+                    self.timer_start = Instant::now();
                 }
             }
         }
 
         tokio::time::sleep(Duration::from_millis(CIYLE_LENGTH)).await;
     }
+    /// Switch to primary of the current view and let the other nodes know, so backups that didn't
+    /// time out yet move to this view and the previous primary steps down if it comes back.
+    async fn promote(&mut self) {
+        info!("[{}] Taking over in view {}", self.address, self.view);
+        self.state = State::Primary;
+        self.synced = true;
+        self.pending.clear();
+        self.acknowledged.clear();
+        self.recovery = Some(Instant::now());
+
+        let message: Bytes = bincode::serialize(&NewView(self.peers.clone(), self.view))
+            .unwrap()
+            .into();
+        let others: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .copied()
+            .filter(|x| *x != self.address)
+            .collect();
+        self.sender.broadcast(others, message).await;
+    }
+
     /// Process each messages coming from clients and foward events to the replicas
     pub async fn handle_msg(&mut self, message: Message) -> Result<Option<String>> {
         match (self.state, message) {
-            (Primary, Command(Set { key, value })) => {
-                self.broadcast_to_others(Replicate(
-                    Set {
-                        key: key.clone(),
-                        value: value.clone(),
-                    },
-                    self.address,
-                ))
-                .await;
-                self.cycle = 0;
-                self.store
-                    .write(key.clone().into(), value.clone().into())
-                    .await?;
-
-                Ok(Some(value))
+            (_, Command(Get { key })) => self.read(key).await,
+            (
+                Primary,
+                Command(Read {
+                    key,
+                    consistency: Consistency::Stale { .. },
+                }),
+            ) => self.read(key).await,
+            // the writes of the previous primary that this node missed would be ordered after the new ones
+            (Primary, Command(_)) if self.recovery.is_some() => Err(anyhow!(
+                "the primary is catching up with the backups, try again later"
+            )),
+            (
+                Primary,
+                Command(Read {
+                    key,
+                    consistency: Consistency::Lease,
+                }),
+            ) if self.holds_lease() => self.read(key).await,
+            (
+                Backup,
+                Command(Read {
+                    key,
+                    consistency: Consistency::Stale { max_staleness_ms },
+                }),
+            ) if self
+                .caught_up_at
+                .is_some_and(|at| at.elapsed() <= Duration::from_millis(max_staleness_ms)) =>
+            {
+                self.read(key).await
             }
-            (Backup, Replicate(Set { key, value }, reply_to)) => {
-                self.cycle = 0;
-                self.store.write(key.into(), value.clone().into()).await?;
+            // linearizable reads, and lease reads without a lease, are replicated like writes
+            (Primary, Command(command)) => {
+                // a retried request gets its reply again, without logging or replicating it twice
+                if let Some(reply) = command.session_reply(&self.store).await? {
+                    return reply.map_err(|e| anyhow!(e));
+                }
+                // run locally first so rejected commands (e.g. a failed compare-and-swap) are not replicated
+                let (result, mut changes) = command.mutations(&self.store).await?;
+                if result.is_ok() {
+                    changes.extend(log_writes(self.sequence + 1, &command)?);
+                }
+                // the changes of the command are written along with its log entry and sequence number
+                if !changes.is_empty() {
+                    self.store.write_batch(changes).await?;
+                }
+                if result.is_ok() {
+                    self.sequence += 1;
+                    if self.byzantine == Some(Byzantine::Equivocate) {
+                        self.equivocate(command).await;
+                    } else {
+                        let replicate = Replicate(self.view, self.sequence, command, self.address);
+                        self.broadcast_to_others(replicate).await;
+                    }
+                    self.timer_start = Instant::now();
+                }
 
-                if let Some(data) = serialize(&value) {
-                    self.sender.send(reply_to, data).await;
+                result.map_err(|e| anyhow!(e))
+            }
+            (Backup, Replicate(view, _, _, reply_to))
+                if view < self.view || reply_to != self.primary_of(view) =>
+            {
+                // the writes of a previous primary may conflict with the ones of the current one
+                info!(
+                    "[{}] Ignoring write of view {} from {}",
+                    self.address, view, reply_to
+                );
+                Ok(None)
+            }
+            (Backup, Replicate(view, sequence, command, reply_to)) => {
+                if view > self.view {
+                    // this node missed the new view, its writes follow the snapshot of the new primary
+                    self.follow_view(view);
+                }
+                self.timer_start = Instant::now();
+                // duplicates of writes already applied are dropped
+                if sequence > self.sequence {
+                    self.pending.insert(sequence, (command, reply_to));
+                }
+                if self.synced {
+                    self.apply_pending().await?;
+                    // a write arrived ahead of the next one, which was lost or delayed
+                    if let Some(last) = self.pending.keys().next_back().copied() {
+                        if last > self.requested {
+                            self.request_missing(last).await;
+                        }
+                    }
                 }
                 Ok(None)
             }
-            (Backup, message @ Command(Set { key: _, value: _ })) => {
+            (Primary, Replicate(view, sequence, command, reply_to))
+                if self.recovery.is_some() && view == self.view =>
+            {
+                // the writes the most advanced backup resent to this new primary
+                if sequence > self.sequence {
+                    self.pending.insert(sequence, (command, reply_to));
+                }
+                self.apply_pending().await?;
+                self.recover().await?;
+                Ok(None)
+            }
+            (Backup, Snapshot(entries, sequence, view)) if view >= self.view => {
+                if view > self.view {
+                    self.follow_view(view);
+                }
+                // a delayed snapshot would roll back the writes applied after it, while the first one of
+                // a view replaces the writes of the previous primary
+                if !self.synced || sequence > self.sequence {
+                    self.install_snapshot(entries, sequence).await?;
+                }
+                self.synced = true;
+                self.apply_pending().await?;
+                Ok(None)
+            }
+            (Primary, Snapshot(entries, sequence, view))
+                if self.recovery.is_some() && view == self.view =>
+            {
+                self.install_snapshot(entries, sequence).await?;
+                self.recover().await?;
+                Ok(None)
+            }
+            (_, Snapshot(_, _, view)) => {
+                info!(
+                    "[{}] Ignoring snapshot of stale view {}",
+                    self.address, view
+                );
+                Ok(None)
+            }
+            (Primary, Ack { sequence, address }) => {
+                let acknowledged = self.acknowledged.entry(address).or_default();
+                *acknowledged = sequence.max(*acknowledged);
+                self.last_seen.insert(address, Instant::now());
+                self.acked_at.insert(address, Instant::now());
+                self.release_acknowledged();
+                self.recover().await?;
+                Ok(None)
+            }
+            (Primary, Leave { address }) => {
+                self.remove_replica(address).await?;
+                Ok(None)
+            }
+            (Backup, message @ Leave { .. }) => {
+                self.send_primary(message).await;
+                Ok(None)
+            }
+            (Backup, RemoveReplica(peers, view)) => {
+                self.view = view;
+                self.peers = peers;
+                self.timer_start = Instant::now();
+                if !self.peers.contains(&self.address) {
+                    info!("[{}] Removed from the cluster", self.address);
+                } else if self.get_primary() == self.address {
+                    // the previous primary left and this node was next in line
+                    self.promote().await;
+                }
+                Ok(None)
+            }
+            // the backups get a snapshot once the new primary caught up
+            (Primary, Retransmit { .. }) if self.recovery.is_some() => Ok(None),
+            (Primary, Retransmit { from, address }) => {
+                self.retransmit(from, address).await?;
+                Ok(None)
+            }
+            // a new primary catching up with this backup
+            (Backup, Retransmit { from, address }) if address == self.get_primary() => {
+                self.retransmit(from, address).await?;
+                Ok(None)
+            }
+            // clients find the primary through the redirect, so that they get the result of their writes
+            // and the reads a backup can't serve
+            (Backup, Command(Read { .. })) => Err(anyhow!(NotLeader(self.primary_client_address))),
+            (Backup, Command(_)) if self.redirect => {
+                Err(anyhow!(NotLeader(self.primary_client_address)))
+            }
+            (Backup, message @ Command(_)) => {
                 self.send_primary(message).await;
                 Ok(None)
             }
-            (Backup, Heartbeat) => {
-                self.cycle = 0;
+            (
+                Backup,
+                Heartbeat {
+                    view,
+                    peers,
+                    sequence,
+                    client_address,
+                },
+            ) if view >= self.view => {
+                if view > self.view {
+                    // this node missed the new view, so it waits for the snapshot of the new primary
+                    self.follow_view(view);
+                }
+                self.timer_start = Instant::now();
+                self.view = view;
+                self.peers = peers;
+                self.primary_client_address = client_address;
+                // the snapshot or the last writes were lost, or a previous retransmission was
+                if !self.synced || sequence > self.sequence {
+                    self.request_missing(sequence).await;
+                } else {
+                    self.caught_up_at = Some(Instant::now());
+                }
+
+                // acknowledge the heartbeat, so the primary knows this backup is alive
+                self.send_primary(Ack {
+                    sequence: self.sequence,
+                    address: self.address,
+                })
+                .await;
+                Ok(None)
+            }
+            (Primary, Heartbeat { view, peers, .. }) if view > self.view => {
+                info!("[{}] Stepping down in view {}", self.address, view);
+                self.follow_view(view);
+                self.peers = peers;
+                Ok(None)
+            }
+            // a heartbeat from the primary of an older view doesn't count, so backups keep
+            // counting towards electing the next one
+            (_, Heartbeat { view, .. }) => {
+                info!(
+                    "[{}] Ignoring heartbeat of stale view {}",
+                    self.address, view
+                );
                 Ok(None)
             }
             (Primary, Subscribe { address }) => {
-                self.peers.push(address);
+                // a backup that restarts subscribes again, keeping its place in the peers
+                if !self.peers.contains(&address) {
+                    self.peers.push(address);
+                }
+                self.last_seen.insert(address, Instant::now());
                 info!("Peers: {:?}", self.peers);
 
+                // the snapshot is sent before any replicate request reaches the new backup, which
+                // only happens once it's in the peers
+                let entries = self.snapshot().await?;
+                if let Some(data) = serialize(&Snapshot(entries, self.sequence, self.view)) {
+                    self.sender.send(address, data).await;
+                }
+
                 self.broadcast_to_others(Message::NewReplica(self.peers.clone(), self.view))
                     .await;
 
@@ -268,20 +719,411 @@ impl Node {
                 self.peers = peers;
                 Ok(None)
             }
-            (_, Command(Get { key })) => {
-                if let Ok(Some(val)) = self.store.read(key.clone().into()).await {
-                    let value = String::from_utf8(val)?;
-                    return Ok(Some(value));
-                }
-
+            (Backup, NewView(peers, view)) if view >= self.view => {
+                self.follow_view(view);
+                self.peers = peers;
+                self.report_last_write().await;
                 Ok(None)
             }
+            (Primary, NewView(peers, view)) if view > self.view => {
+                info!("[{}] Stepping down in view {}", self.address, view);
+                self.follow_view(view);
+                self.peers = peers;
+                self.report_last_write().await;
+                Ok(None)
+            }
+            // an announcement of a view older than the current one is ignored
+            (_, NewView(..)) => Ok(None),
+            (_, PrimaryAddress) if self.byzantine == Some(Byzantine::LiePrimaryAddress) => {
+                Ok(Some(self.address.to_string()))
+            }
             (_, PrimaryAddress) => Ok(Some(self.get_primary().to_string())),
             _ => Err(anyhow!("Unhandled command")),
         }
     }
 
+    /// Read the value of the key from the local store.
+    async fn read(&self, key: String) -> Result<Option<String>> {
+        if let Ok(Some(val)) = self.store.read(key.into()).await {
+            let value = String::from_utf8(val)?;
+            return Ok(Some(value));
+        }
+
+        Ok(None)
+    }
+
+    /// Whether every backup acknowledged the primary recently enough that none of them can have
+    /// timed out and taken over, so the primary can serve reads from its local state.
+    fn holds_lease(&self) -> bool {
+        self.backups().iter().all(|backup| {
+            self.acked_at
+                .get(backup)
+                .is_some_and(|at| at.elapsed() < LEASE_DURATION)
+        })
+    }
+
+    /// Read the sequence number of the last write applied in a previous run.
+    async fn restore(&mut self) -> Result<()> {
+        if let Some(sequence) = self.store.read(SEQUENCE_KEY.to_vec()).await? {
+            self.sequence = bincode::deserialize(&sequence)?;
+            info!("[{}] Restored sequence {}", self.address, self.sequence);
+        }
+        Ok(())
+    }
+
+    /// Apply the pending replicated writes that follow the last one applied, in order, and send
+    /// the primary their results.
+    async fn apply_pending(&mut self) -> Result<()> {
+        self.pending = self.pending.split_off(&(self.sequence + 1));
+        while let Some((command, reply_to)) = self.pending.remove(&(self.sequence + 1)) {
+            // the primary only replicates accepted commands and backups hold the same state,
+            // so the command evaluates to the same result here, and only the primary replies with it
+            let writes = log_writes(self.sequence + 1, &command)?;
+            let _ = command.apply_with(&self.store, writes).await?;
+            self.sequence += 1;
+
+            // a new primary catching up doesn't acknowledge the writes resent by a backup
+            if self.state == Backup {
+                let ack = Ack {
+                    sequence: self.sequence,
+                    address: self.address,
+                };
+                if let Some(data) = serialize(&ack) {
+                    self.sender.send(reply_to, data).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Follow the primary of the given view as a backup. The new primary may be missing some of the
+    /// writes this node applied, or have others, so this node stops applying writes until it installs
+    /// the snapshot the new primary sends once it caught up.
+    fn follow_view(&mut self, view: usize) {
+        self.state = Backup;
+        self.view = view;
+        self.timer_start = Instant::now();
+        self.synced = false;
+        self.pending.clear();
+        self.requested = 0;
+    }
+
+    /// Let the new primary know the last write this node applied, so it can catch up with it.
+    async fn report_last_write(&mut self) {
+        self.send_primary(Ack {
+            sequence: self.sequence,
+            address: self.address,
+        })
+        .await;
+    }
+
+    /// Once every backup reported its last write, or RECOVERY_TIMEOUT passed, catch up with the most
+    /// advanced backup. Then send every backup a snapshot so they continue from the state of this node,
+    /// dropping the writes of the previous primary that none of the reporting backups had, and start
+    /// accepting commands.
+    async fn recover(&mut self) -> Result<()> {
+        let Some(promoted_at) = self.recovery else {
+            return Ok(());
+        };
+        let backups = self.backups();
+        let reported = backups
+            .iter()
+            .all(|backup| self.acknowledged.contains_key(backup));
+        if !reported && promoted_at.elapsed() < RECOVERY_TIMEOUT {
+            return Ok(());
+        }
+
+        let most_advanced = backups
+            .iter()
+            .filter_map(|backup| Some((*self.acknowledged.get(backup)?, *backup)))
+            .max();
+        if let Some((sequence, backup)) =
+            most_advanced.filter(|(sequence, _)| *sequence > self.sequence)
+        {
+            info!(
+                "[{}] Catching up with {} up to write {}",
+                self.address, backup, sequence
+            );
+            let retransmit = Retransmit {
+                from: self.sequence + 1,
+                address: self.address,
+            };
+            if let Some(data) = serialize(&retransmit) {
+                self.sender.send(backup, data).await;
+            }
+            return Ok(());
+        }
+
+        info!(
+            "[{}] Caught up at write {}, accepting commands",
+            self.address, self.sequence
+        );
+        self.recovery = None;
+        let snapshot = Snapshot(self.snapshot().await?, self.sequence, self.view);
+        if let Some(data) = serialize(&snapshot) {
+            self.sender.broadcast(backups, data).await;
+        }
+        Ok(())
+    }
+
+    /// Reply to the client write with the last sequence number once enough backups acknowledge it,
+    /// according to the given replication mode.
+    fn reply_when_acknowledged(
+        &mut self,
+        result: CommandResult,
+        reply_sender: oneshot::Sender<ClientReply>,
+        mode: ReplicationMode,
+    ) {
+        self.waiting.insert(
+            self.sequence,
+            PendingReply {
+                result,
+                reply_sender,
+                sent_at: Instant::now(),
+                mode,
+            },
+        );
+        self.release_acknowledged();
+    }
+
+    /// Reply to the waiting client writes acknowledged by enough of the current backups.
+    fn release_acknowledged(&mut self) {
+        let backups = self.backups();
+        let acknowledged: Vec<u64> = self
+            .waiting
+            .iter()
+            .filter(|(sequence, pending)| {
+                let required = match pending.mode {
+                    ReplicationMode::Sync => backups.len(),
+                    // a majority of the backups and the primary
+                    ReplicationMode::Quorum => backups.len().div_ceil(2),
+                    ReplicationMode::Async => 0,
+                };
+                let acks = backups
+                    .iter()
+                    .filter(|backup| self.acknowledged.get(backup) >= Some(*sequence))
+                    .count();
+                acks >= required
+            })
+            .map(|(sequence, _)| *sequence)
+            .collect();
+
+        for sequence in acknowledged {
+            if let Some(pending) = self.waiting.remove(&sequence) {
+                if let Err(error) = pending.reply_sender.send(pending.result.into()) {
+                    error!("failed to send write response {:?}", error);
+                }
+            }
+        }
+    }
+
+    /// Fail the client writes that were not acknowledged in time. They were applied by the primary
+    /// and may still reach the backups, but the client can't count on them surviving a failure.
+    fn expire_waiting(&mut self) {
+        let expired: Vec<u64> = self
+            .waiting
+            .iter()
+            .filter(|(_, pending)| pending.sent_at.elapsed() > ACK_TIMEOUT)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+
+        for sequence in expired {
+            if let Some(pending) = self.waiting.remove(&sequence) {
+                let error = format!("write {sequence} was not acknowledged by the backups in time");
+                if let Err(error) = pending.reply_sender.send(ClientReply::Err(error)) {
+                    error!("failed to send write response {:?}", error);
+                }
+            }
+        }
+    }
+
+    /// Remove the backups the primary didn't hear from in a while.
+    async fn evict_unresponsive(&mut self) {
+        let now = Instant::now();
+        // a node that just became primary gives every backup time to acknowledge its heartbeats
+        for backup in self.backups() {
+            self.last_seen.entry(backup).or_insert(now);
+        }
+        let unresponsive: Vec<SocketAddr> = self
+            .backups()
+            .into_iter()
+            .filter(|backup| now.duration_since(self.last_seen[backup]) > EVICTION_TIMEOUT)
+            .collect();
+
+        for backup in unresponsive {
+            info!("[{}] Evicting unresponsive backup {}", self.address, backup);
+            if let Err(error) = self.remove_replica(backup).await {
+                error!("failed to evict {}: {}", backup, error);
+            }
+        }
+    }
+
+    /// Remove the given node from the peers and let every node know, including the removed one.
+    /// Only the primary and the backups of the current view can be removed, the nodes before them are
+    /// the primaries of previous views, so the view keeps pointing at the same position of the peers.
+    /// If the primary itself leaves, the next backup in line takes over in the same view.
+    async fn remove_replica(&mut self, address: SocketAddr) -> Result<()> {
+        let index = self
+            .peers
+            .iter()
+            .position(|peer| *peer == address)
+            .ok_or_else(|| anyhow!("{} is not a peer", address))?;
+        if index < self.view {
+            return Err(anyhow!("{} is not a member of view {}", address, self.view));
+        }
+        if self.peers.len() == self.view + 1 {
+            return Err(anyhow!("the last node of the cluster can't leave"));
+        }
+
+        let recipients: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .copied()
+            .filter(|peer| *peer != self.address)
+            .collect();
+        self.peers.remove(index);
+        self.acknowledged.remove(&address);
+        self.last_seen.remove(&address);
+        info!("Peers: {:?}", self.peers);
+
+        let message = RemoveReplica(self.peers.clone(), self.view);
+        if let Some(data) = serialize(&message) {
+            self.sender.broadcast(recipients, data).await;
+        }
+
+        if address == self.address {
+            info!("[{}] Left the cluster", self.address);
+            self.state = State::Backup;
+            for (sequence, pending) in std::mem::take(&mut self.waiting) {
+                let error = format!("primary left before write {sequence} was acknowledged");
+                if let Err(error) = pending.reply_sender.send(ClientReply::Err(error)) {
+                    error!("failed to send write response {:?}", error);
+                }
+            }
+        } else {
+            // writes waiting for the removed backup can now complete
+            self.release_acknowledged();
+        }
+        Ok(())
+    }
+
+    /// The backups the primary replicates to.
+    fn backups(&self) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .skip(self.view + 1)
+            .copied()
+            .filter(|x| *x != self.address)
+            .collect()
+    }
+
+    /// Ask the primary to resend the writes after the last one applied, up to the given one.
+    async fn request_missing(&mut self, sequence: u64) {
+        info!(
+            "[{}] Missing writes {} to {}, asking the primary to resend them",
+            self.address,
+            self.sequence + 1,
+            sequence
+        );
+        self.requested = sequence;
+        // there's no write 0 in the log, so a backup still waiting for the snapshot gets it again
+        let from = if self.synced { self.sequence + 1 } else { 0 };
+        self.send_primary(Retransmit {
+            from,
+            address: self.address,
+        })
+        .await;
+    }
+
+    /// Resend the writes of the log from the given sequence number on to a backup, or a snapshot
+    /// if the log doesn't have them all.
+    async fn retransmit(&mut self, from: u64, address: SocketAddr) -> Result<()> {
+        let mut messages = Vec::new();
+        for sequence in from..=self.sequence {
+            match self.store.read(log_key(sequence)).await? {
+                Some(command) => {
+                    let command = bincode::deserialize(&command)?;
+                    messages.push(Replicate(self.view, sequence, command, self.address));
+                }
+                // a node that installed a snapshot has no log entries before it
+                None => {
+                    messages = vec![Snapshot(self.snapshot().await?, self.sequence, self.view)];
+                    break;
+                }
+            }
+        }
+
+        for message in messages {
+            if let Some(data) = serialize(&message) {
+                self.sender.send(address, data).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// The client key/values of the store, along with the client sessions so duplicate requests are
+    /// still detected after a failover.
+    async fn snapshot(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = self.store.scan(Vec::new()).await?;
+        entries.retain(|(key, _)| !is_internal(key) || key.starts_with(SESSION_PREFIX));
+        Ok(entries)
+    }
+
+    /// Replace the client key/values of the store with the given snapshot of the primary store,
+    /// removing the keys a restarted backup holds that the primary no longer has.
+    async fn install_snapshot(
+        &mut self,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        sequence: u64,
+    ) -> Result<()> {
+        info!(
+            "[{}] Installing snapshot with {} keys up to sequence {}",
+            self.address,
+            entries.len(),
+            sequence
+        );
+        let keys: HashSet<&Vec<u8>> = entries.iter().map(|(key, _)| key).collect();
+        let mut changes: Vec<_> = self
+            .snapshot()
+            .await?
+            .into_iter()
+            .filter(|(key, _)| !keys.contains(key))
+            .map(|(key, _)| (key, None))
+            .collect();
+        changes.extend(entries.into_iter().map(|(key, value)| (key, Some(value))));
+        changes.push((SEQUENCE_KEY.to_vec(), Some(bincode::serialize(&sequence)?)));
+
+        self.store.write_batch(changes).await?;
+        self.sequence = sequence;
+        Ok(())
+    }
+
     fn get_primary(&self) -> SocketAddr {
-        self.peers[self.view]
+        self.primary_of(self.view)
+    }
+
+    fn primary_of(&self, view: usize) -> SocketAddr {
+        // a backup that didn't get the peer list from the primary yet only knows the address it subscribed to
+        self.peers
+            .get(view)
+            .copied()
+            .unwrap_or(self.primary_address)
     }
 }
+
+fn log_key(sequence: u64) -> Vec<u8> {
+    [LOG_PREFIX, &sequence.to_be_bytes()].concat()
+}
+
+/// The writes that append the command with the given sequence number to the replication log, to be
+/// applied in the same batch as the changes of the command.
+fn log_writes(sequence: u64, command: &ClientCommand) -> Result<Changes> {
+    Ok(vec![
+        (log_key(sequence), Some(bincode::serialize(command)?)),
+        (SEQUENCE_KEY.to_vec(), Some(bincode::serialize(&sequence)?)),
+    ])
+}
+
+fn is_internal(key: &[u8]) -> bool {
+    key.first() == Some(&0xff)
+}
@@ -0,0 +1,344 @@
+use crate::node::Node;
+/// This module is a binary that runs a Raft node: it listens for TCP connections from clients and
+/// peers and forwards incoming messages to the node state machine.
+use clap::Parser;
+use lib::network::Receiver;
+use log::info;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::task::JoinHandle;
+
+mod node;
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct Cli {
+    /// The client port of the node where to send txs.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 6100)]
+    client_port: u16,
+    /// The network port where other nodes sends msg.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 6200)]
+    network_port: u16,
+    /// Node Address
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    address: IpAddr,
+    /// The network addresses of all the nodes in the cluster, including this one.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "ADDR",
+        use_value_delimiter = true,
+        value_delimiter = ' '
+    )]
+    peers: Vec<SocketAddr>,
+    /// Base election timeout in milliseconds, the actual timeout is randomized between this value and twice it.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 300)]
+    election_timeout_ms: u64,
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    let cli = Cli::parse();
+
+    simple_logger::SimpleLogger::new()
+        .env()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    let network_address = SocketAddr::new(cli.address, cli.network_port);
+    let client_address = SocketAddr::new(cli.address, cli.client_port);
+
+    let mut peers = cli.peers;
+    if !peers.contains(&network_address) {
+        peers.push(network_address);
+    }
+
+    info!(
+        "Node: Running on {}, client requests on {}, peers {:?}",
+        network_address, client_address, peers
+    );
+
+    let node = Node::new(
+        peers,
+        &format!(".db_raft_{}", network_address.port()),
+        network_address,
+        cli.election_timeout_ms,
+    );
+
+    let (_, network_handle, _) = spawn_node_tasks(network_address, client_address, node).await;
+    network_handle.await.unwrap();
+}
+
+async fn spawn_node_tasks(
+    network_address: SocketAddr,
+    client_address: SocketAddr,
+    mut node: Node,
+) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
+    // listen for peer network tcp connections
+    let (network_tcp_receiver, network_channel_receiver) = Receiver::new(network_address);
+    let network_handle = tokio::spawn(async move {
+        network_tcp_receiver.run().await;
+    });
+
+    // listen for client command tcp connections
+    let (client_tcp_receiver, client_channel_receiver) = Receiver::new(client_address);
+    let client_handle = tokio::spawn(async move {
+        client_tcp_receiver.run().await;
+    });
+
+    let node_handle = tokio::spawn(async move {
+        node.run(network_channel_receiver, client_channel_receiver)
+            .await;
+    });
+
+    (node_handle, network_handle, client_handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Message;
+    use anyhow::{anyhow, Result};
+    use bytes::Bytes;
+    use lib::command::{ClientCommand, CommandResult};
+    use lib::network::simulator::{SimulatedNetwork, SimulatorConfig};
+    use lib::network::ReliableSender;
+    use lib::testing::{self, NodeHandles};
+    use std::fs;
+    use tokio_retry::strategy::FixedInterval;
+    use tokio_retry::Retry;
+
+    // since logger is meant to be initialized once and tests run in parallel,
+    // run this before anything because otherwise it errors out
+    #[ctor::ctor]
+    fn init() {
+        simple_logger::SimpleLogger::new().env().init().unwrap();
+
+        fs::remove_dir_all(db_path("")).unwrap_or_default();
+    }
+
+    fn db_path(suffix: &str) -> String {
+        format!(".db_test_raft/{suffix}")
+    }
+
+    /// Start a cluster with one node per port pair starting at the given port, and return
+    /// the network addresses, client addresses and task handles of each node.
+    async fn start_cluster(
+        base_port: u16,
+        size: u16,
+    ) -> (Vec<SocketAddr>, Vec<SocketAddr>, Vec<NodeHandles>) {
+        let (network_addresses, client_addresses) = testing::local_addresses(base_port, size);
+
+        let mut handles = Vec::new();
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let node = Node::new(
+                network_addresses.clone(),
+                &db_path(&network_address.port().to_string()),
+                *network_address,
+                100,
+            );
+            handles.push(spawn_node_tasks(*network_address, *client_address, node).await);
+        }
+        (network_addresses, client_addresses, handles)
+    }
+
+    async fn leader_address(address: SocketAddr) -> Result<Option<SocketAddr>> {
+        let mut sender = ReliableSender::new();
+        let message: Bytes = bincode::serialize(&Message::LeaderAddress)?.into();
+        let response = sender.send(address, message).await.await?;
+        let response: CommandResult = bincode::deserialize(&response)?;
+        match response.map_err(|e| anyhow!(e))? {
+            Some(leader) => Ok(Some(leader.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Wait until the node at the given address knows of a leader other than the excluded one.
+    async fn eventually_leader(address: SocketAddr, excluded: Option<SocketAddr>) -> SocketAddr {
+        let retries = FixedInterval::from_millis(100).take(100);
+        Retry::spawn(retries, || async {
+            match leader_address(address).await {
+                Ok(Some(leader)) if Some(leader) != excluded => Ok(leader),
+                _ => Err(()),
+            }
+        })
+        .await
+        .expect("no leader was elected")
+    }
+
+    /// Send a command to the given address, retrying while there's no leader to commit it.
+    async fn send_eventually(command: ClientCommand, address: SocketAddr) -> Option<String> {
+        let retries = FixedInterval::from_millis(100).take(100);
+        Retry::spawn(retries, || async {
+            command.clone().send_to(address).await.map_err(|_| ())
+        })
+        .await
+        .expect("command was never committed")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn single_node() {
+        let (network_addresses, client_addresses, _) = start_cluster(7100, 1).await;
+
+        let leader = eventually_leader(network_addresses[0], None).await;
+        assert_eq!(network_addresses[0], leader);
+
+        let reply = send_eventually(
+            ClientCommand::Get {
+                key: "k1".to_string(),
+            },
+            client_addresses[0],
+        )
+        .await;
+        assert!(reply.is_none());
+
+        let reply = send_eventually(
+            ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            },
+            client_addresses[0],
+        )
+        .await;
+        assert_eq!(Some("v1".to_string()), reply);
+
+        let reply = send_eventually(
+            ClientCommand::Get {
+                key: "k1".to_string(),
+            },
+            client_addresses[0],
+        )
+        .await;
+        assert_eq!(Some("v1".to_string()), reply);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replicated_commands() {
+        let (network_addresses, client_addresses, _) = start_cluster(7110, 3).await;
+
+        // all nodes agree on the elected leader
+        let leader = eventually_leader(network_addresses[0], None).await;
+        for address in &network_addresses {
+            assert_eq!(leader, eventually_leader(*address, None).await);
+        }
+
+        // commands sent to any node are forwarded to the leader
+        for (i, client_address) in client_addresses.iter().enumerate() {
+            let reply = send_eventually(
+                ClientCommand::Increment {
+                    key: "counter".to_string(),
+                    by: 1,
+                },
+                *client_address,
+            )
+            .await;
+            assert_eq!(Some((i + 1).to_string()), reply);
+        }
+
+        // reads go through the log, so every node returns the committed value
+        for client_address in &client_addresses {
+            let reply = send_eventually(
+                ClientCommand::Get {
+                    key: "counter".to_string(),
+                },
+                *client_address,
+            )
+            .await;
+            assert_eq!(Some("3".to_string()), reply);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn leader_crash() {
+        let (network_addresses, client_addresses, handles) = start_cluster(7120, 3).await;
+
+        let leader = eventually_leader(network_addresses[0], None).await;
+        let reply = send_eventually(
+            ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            },
+            client_addresses[0],
+        )
+        .await;
+        assert_eq!(Some("v1".to_string()), reply);
+
+        // kill the leader
+        let leader_position = network_addresses
+            .iter()
+            .position(|address| *address == leader)
+            .unwrap();
+        let (node_handle, network_handle, client_handle) = &handles[leader_position];
+        node_handle.abort();
+        network_handle.abort();
+        client_handle.abort();
+
+        // the remaining nodes elect a new leader that keeps the committed value
+        let survivor = (leader_position + 1) % network_addresses.len();
+        let new_leader = eventually_leader(network_addresses[survivor], Some(leader)).await;
+        assert_ne!(leader, new_leader);
+
+        let reply = send_eventually(
+            ClientCommand::Get {
+                key: "k1".to_string(),
+            },
+            client_addresses[survivor],
+        )
+        .await;
+        assert_eq!(Some("v1".to_string()), reply);
+
+        let reply = send_eventually(
+            ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v2".to_string(),
+            },
+            client_addresses[survivor],
+        )
+        .await;
+        assert_eq!(Some("v2".to_string()), reply);
+    }
+
+    /// Run a cluster on a lossy simulated network with the given seed, send a command to each node and
+    /// check that every node applies them. Returns the trace of the network.
+    async fn run_simulated(seed: Option<u64>, name: &str) -> Vec<String> {
+        let default = SimulatorConfig::default();
+        let network = SimulatedNetwork::new(SimulatorConfig {
+            seed: seed.unwrap_or(default.seed),
+            drop_rate: 0.05,
+            reorder: true,
+            ..default
+        });
+        let (network_addresses, client_addresses) = testing::local_addresses(7130, 3);
+
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let mut node = Node::new(
+                network_addresses.clone(),
+                &db_path(&format!("{name}_{}", network_address.port())),
+                *network_address,
+                100,
+            );
+            node.sender = Box::new(network.sender(*network_address));
+            node.forwarder = Box::new(network.sender(*network_address));
+            node.rng = network.rng(*network_address);
+            let (_, network_channel) = network.listen(*network_address);
+            let (_, client_channel) = network.listen(*client_address);
+            tokio::spawn(async move { node.run(network_channel, client_channel).await });
+        }
+
+        // commands sent to any node are committed despite lost and reordered messages
+        testing::check_simulated_writes(&network, &client_addresses).await;
+        network.trace()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simulated_network() {
+        run_simulated(None, "sim").await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_seed() {
+        // the elections and the messages of a run only depend on the seed
+        let trace = run_simulated(Some(42), "replay_first").await;
+        assert_eq!(trace, run_simulated(Some(42), "replay_second").await);
+    }
+}
@@ -0,0 +1,872 @@
+/// This module contains the Raft state machine: leader election with randomized timeouts, log
+/// replication through AppendEntries and the application of committed commands to the key/value store.
+/// Both reads and writes go through the log, so every reply reflects a committed state.
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use core::fmt;
+use lib::command::{ClientCommand, CommandResult};
+use lib::network::{MessageSender, ReliableSender, RequestSender, SimpleSender};
+use lib::store::Store;
+use log::{debug, error, info};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+
+/// The types of messages supported by this implementation's state machine.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Message {
+    /// Sent by a candidate to ask its peers for their vote in the given term.
+    RequestVote {
+        term: u64,
+        candidate: SocketAddr,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+
+    /// A peer's answer to a vote request.
+    Vote {
+        term: u64,
+        from: SocketAddr,
+        granted: bool,
+    },
+
+    /// Sent by the leader to replicate its log. With no entries it works as a heartbeat.
+    AppendEntries {
+        term: u64,
+        leader: SocketAddr,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+
+    /// A follower's answer to AppendEntries. On success `match_index` is the index of the last entry
+    /// known to match the leader's log, on failure it's a hint of where the logs may start to match.
+    AppendEntriesResponse {
+        term: u64,
+        from: SocketAddr,
+        success: bool,
+        match_index: u64,
+    },
+
+    /// A client command forwarded by a follower. The leader replies once it's committed and applied.
+    Forward(ClientCommand),
+
+    /// A request for the address of the current leader, as known by the receiving node.
+    LeaderAddress,
+}
+
+/// An entry of the replicated log. Leaders append an entry without command (a no-op) when they are
+/// elected, so entries from previous terms get committed without waiting for new client requests.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub term: u64,
+    pub command: Option<ClientCommand>,
+}
+
+/// The state of a node viewed as a state-machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// The key under which the current term and vote are persisted, prefixed with a byte that can't start a
+/// UTF-8 client key.
+const TERM_KEY: &[u8] = b"\xffterm";
+/// The prefix of the keys under which the log entries are persisted, followed by their index.
+const LOG_PREFIX: &[u8] = b"\xfflog/";
+/// The key under which the index of the last applied entry is persisted, along with its changes.
+const LAST_APPLIED_KEY: &[u8] = b"\xffapplied";
+
+/// How long a follower waits for the leader before forwarded commands are failed.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the node checks its timers.
+const TICK_MS: u64 = 10;
+
+use Message::*;
+use State::*;
+
+pub struct Node {
+    /// The ip+port this node is listening on for peer messages.
+    pub address: SocketAddr,
+
+    /// The network addresses of all the nodes in the cluster, including this one.
+    pub peers: Vec<SocketAddr>,
+
+    /// The key/value store committed commands are applied to.
+    pub store: Store,
+
+    pub sender: Box<dyn MessageSender>,
+
+    /// The sender used to forward client commands to the leader and wait for their replies.
+    pub forwarder: Box<dyn RequestSender>,
+
+    pub state: State,
+
+    /// Latest term this node has seen.
+    pub current_term: u64,
+
+    /// The candidate that received this node's vote in the current term, if any.
+    pub voted_for: Option<SocketAddr>,
+
+    /// The replicated log. The entry at position `i` has index `i + 1`.
+    pub log: Vec<Entry>,
+
+    /// Index of the highest log entry known to be committed.
+    pub commit_index: u64,
+
+    /// Index of the highest log entry applied to the store.
+    pub last_applied: u64,
+
+    /// The leader of the current term, if known. Used to forward client commands.
+    pub leader: Option<SocketAddr>,
+
+    /// Candidate: the peers that granted their vote in the current term.
+    votes: HashSet<SocketAddr>,
+
+    /// Leader: for each peer, the index of the next log entry to send to it.
+    next_index: HashMap<SocketAddr, u64>,
+
+    /// Leader: for each peer, the index of the highest log entry known to be replicated on it.
+    match_index: HashMap<SocketAddr, u64>,
+
+    /// Leader: the client replies waiting for the entry at the given index to be applied,
+    /// along with the term in which the entry was appended.
+    pending: BTreeMap<u64, (u64, oneshot::Sender<CommandResult>)>,
+
+    /// Base election timeout, in milliseconds. Each election timeout is picked at random
+    /// between this value and twice this value, and leaders send heartbeats every third of it.
+    election_timeout_ms: u64,
+    election_timeout: Duration,
+    timer_start: Instant,
+
+    /// The source of the randomized election timeouts.
+    pub rng: SmallRng,
+}
+
+impl Node {
+    pub fn new(
+        peers: Vec<SocketAddr>,
+        db_path: &str,
+        address: SocketAddr,
+        election_timeout_ms: u64,
+    ) -> Self {
+        Self {
+            address,
+            peers,
+            store: Store::new(db_path).unwrap(),
+            sender: Box::new(SimpleSender::new()),
+            forwarder: Box::new(ReliableSender::new()),
+            state: Follower,
+            current_term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            leader: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            pending: BTreeMap::new(),
+            election_timeout_ms,
+            election_timeout: Duration::from_millis(election_timeout_ms),
+            timer_start: Instant::now(),
+            rng: SmallRng::from_entropy(),
+        }
+    }
+
+    /// Runs the node to process network messages incoming in the given receiver
+    pub async fn run(
+        &mut self,
+        mut network_receiver: Receiver<(Message, oneshot::Sender<CommandResult>)>,
+        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<CommandResult>)>,
+    ) {
+        if let Err(error) = self.restore().await {
+            error!(
+                "{}: failed to restore the term, vote and log: {}",
+                self.address, error
+            );
+        }
+        self.reset_election_timer();
+        loop {
+            tokio::select! {
+                biased;
+                Some((command, reply_sender)) = client_receiver.recv() => {
+                    info!("{}: Received client message {}", self.address, command);
+                    self.handle_client_command(command, reply_sender).await;
+                }
+                Some((message, reply_sender)) = network_receiver.recv() => {
+                    debug!("{}: Received network message {}", self.address, message);
+                    match message {
+                        // the reply to forwarded commands is sent once they are applied
+                        Forward(command) => self.handle_client_command(command, reply_sender).await,
+                        LeaderAddress => {
+                            let leader = self.leader.map(|leader| leader.to_string());
+                            if let Err(error) = reply_sender.send(Ok(leader)) {
+                                error!("failed to send leader address {:?}", error);
+                            }
+                        }
+                        message => {
+                            let _ = reply_sender.send(Ok(None));
+                            if let Err(error) = self.handle_message(message).await {
+                                error!("{}: failed to handle message: {}", self.address, error);
+                            }
+                        }
+                    }
+                }
+                _ = self.check_timer() => ()
+            }
+        }
+    }
+
+    /// Starts an election if the leader didn't send anything during the election timeout,
+    /// or sends heartbeats if this node is the leader.
+    async fn check_timer(&mut self) {
+        match self.state {
+            Leader => {
+                if self.timer_start.elapsed() >= self.heartbeat_interval() {
+                    self.timer_start = Instant::now();
+                    self.broadcast_append_entries().await;
+                }
+            }
+            Follower | Candidate => {
+                if self.timer_start.elapsed() >= self.election_timeout {
+                    self.start_election().await;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(TICK_MS)).await;
+    }
+
+    /// Leaders append client commands to their log and reply once they are applied.
+    /// Followers forward them to the leader and relay its reply.
+    pub async fn handle_client_command(
+        &mut self,
+        command: ClientCommand,
+        reply_sender: oneshot::Sender<CommandResult>,
+    ) {
+        match (self.state, self.leader) {
+            (Leader, _) => {
+                let index = self.last_log_index() + 1;
+                let entry = Entry {
+                    term: self.current_term,
+                    command: Some(command),
+                };
+                if let Err(error) = self.write_log(index, vec![entry]).await {
+                    let _ = reply_sender.send(Err(error.to_string()));
+                    return;
+                }
+                self.pending
+                    .insert(index, (self.current_term, reply_sender));
+
+                self.broadcast_append_entries().await;
+                // in a single node cluster the entry is committed right away
+                self.advance_commit_index().await;
+            }
+            (_, Some(leader)) => {
+                let forwarder = self.forwarder.clone();
+                tokio::spawn(async move {
+                    let result = forward(forwarder, leader, command).await;
+                    if let Err(error) = reply_sender.send(result) {
+                        error!("failed to send forwarded command response {:?}", error);
+                    }
+                });
+            }
+            (_, None) => {
+                let result = Err("no leader elected yet, try again later".to_string());
+                if let Err(error) = reply_sender.send(result) {
+                    error!("failed to send command response {:?}", error);
+                }
+            }
+        }
+    }
+
+    /// Process the Raft messages coming from peers.
+    pub async fn handle_message(&mut self, message: Message) -> Result<()> {
+        match message {
+            RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > self.current_term {
+                    self.step_down(term).await?;
+                }
+
+                // only vote for candidates whose log is at least as up-to-date as ours
+                let log_ok = last_log_term > self.last_log_term()
+                    || (last_log_term == self.last_log_term()
+                        && last_log_index >= self.last_log_index());
+                let granted = term == self.current_term
+                    && log_ok
+                    && (self.voted_for.is_none() || self.voted_for == Some(candidate));
+
+                if granted {
+                    // the vote must survive a restart, so the node never votes twice in a term
+                    self.voted_for = Some(candidate);
+                    self.persist_term().await?;
+                    self.reset_election_timer();
+                }
+
+                let vote = Vote {
+                    term: self.current_term,
+                    from: self.address,
+                    granted,
+                };
+                self.send(candidate, &vote).await;
+            }
+            Vote {
+                term,
+                from,
+                granted,
+            } => {
+                if term > self.current_term {
+                    self.step_down(term).await?;
+                } else if self.state == Candidate && term == self.current_term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader().await;
+                    }
+                }
+            }
+            AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.current_term {
+                    let response = AppendEntriesResponse {
+                        term: self.current_term,
+                        from: self.address,
+                        success: false,
+                        match_index: 0,
+                    };
+                    self.send(leader, &response).await;
+                    return Ok(());
+                }
+
+                if term > self.current_term {
+                    self.step_down(term).await?;
+                }
+                // a candidate that hears from the leader of its term goes back to follower
+                self.state = Follower;
+                self.leader = Some(leader);
+                self.reset_election_timer();
+
+                let response = match self
+                    .append_entries(prev_log_index, prev_log_term, entries, leader_commit)
+                    .await?
+                {
+                    Ok(match_index) => AppendEntriesResponse {
+                        term: self.current_term,
+                        from: self.address,
+                        success: true,
+                        match_index,
+                    },
+                    Err(hint) => AppendEntriesResponse {
+                        term: self.current_term,
+                        from: self.address,
+                        success: false,
+                        match_index: hint,
+                    },
+                };
+                self.apply_committed().await?;
+                self.send(leader, &response).await;
+            }
+            AppendEntriesResponse {
+                term,
+                from,
+                success,
+                match_index,
+            } => {
+                if term > self.current_term {
+                    self.step_down(term).await?;
+                } else if self.state == Leader && term == self.current_term {
+                    if success {
+                        let matched = self.match_index.entry(from).or_insert(0);
+                        *matched = (*matched).max(match_index);
+                        self.next_index.insert(from, *matched + 1);
+                        self.advance_commit_index().await;
+                    } else {
+                        // retry from the follower's hint, which is never past the previous attempt
+                        let next = self.next_index.get(&from).copied().unwrap_or(1);
+                        self.next_index
+                            .insert(from, (match_index + 1).min(next.saturating_sub(1)).max(1));
+                        self.send_append_entries(from).await;
+                    }
+                }
+            }
+            Forward(_) | LeaderAddress => return Err(anyhow!("Unhandled command")),
+        }
+        Ok(())
+    }
+
+    /// Follower side of AppendEntries: check that the log contains the leader's previous entry,
+    /// append the new entries (dropping any conflicting suffix) and follow the leader's commit index.
+    /// Returns the index of the last entry matching the leader's log, or a hint of where the leader
+    /// should retry from if the previous entry doesn't match.
+    async fn append_entries(
+        &mut self,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<Result<u64, u64>> {
+        if prev_log_index > self.last_log_index() {
+            return Ok(Err(self.last_log_index()));
+        }
+        if self.term_at(prev_log_index) != prev_log_term {
+            // the whole conflicting term will be overwritten, skip past it in one go
+            let conflict_term = self.term_at(prev_log_index);
+            let mut hint = prev_log_index - 1;
+            while hint > self.commit_index && self.term_at(hint) == conflict_term {
+                hint -= 1;
+            }
+            return Ok(Err(hint));
+        }
+
+        // the entries in this request are known to match, entries past them might not
+        let match_index = prev_log_index + entries.len() as u64;
+        let matching = entries
+            .iter()
+            .enumerate()
+            .take_while(|(offset, entry)| {
+                let index = prev_log_index + 1 + *offset as u64;
+                index <= self.last_log_index() && self.term_at(index) == entry.term
+            })
+            .count();
+        if matching < entries.len() {
+            // committed entries never conflict, so this only drops uncommitted ones
+            let index = prev_log_index + 1 + matching as u64;
+            self.write_log(index, entries[matching..].to_vec()).await?;
+        }
+
+        // a stale request may carry an older commit index, which never moves the commit index back
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(match_index).max(self.commit_index);
+        }
+        Ok(Ok(match_index))
+    }
+
+    /// Replace the entries of the log from the given index on with the given entries, in memory and in
+    /// the store, so the node never forgets entries it acknowledged.
+    async fn write_log(&mut self, index: u64, entries: Vec<Entry>) -> Result<()> {
+        let previous_last = self.last_log_index();
+        self.log.truncate(index as usize - 1);
+        let mut batch = Vec::new();
+        for entry in entries {
+            batch.push((
+                log_key(self.last_log_index() + 1),
+                Some(bincode::serialize(&entry)?),
+            ));
+            self.log.push(entry);
+        }
+        for stale in self.last_log_index() + 1..=previous_last {
+            batch.push((log_key(stale), None));
+        }
+        self.store.write_batch(batch).await?;
+        Ok(())
+    }
+
+    /// Persist the current term and vote, which must be known after a restart before answering peers.
+    async fn persist_term(&self) -> Result<()> {
+        let state = bincode::serialize(&(self.current_term, self.voted_for))?;
+        self.store.write(TERM_KEY.to_vec(), state).await?;
+        Ok(())
+    }
+
+    /// Read the term, the vote, the log and the last applied entry the node had before a restart.
+    pub async fn restore(&mut self) -> Result<()> {
+        if let Some(state) = self.store.read(TERM_KEY.to_vec()).await? {
+            (self.current_term, self.voted_for) = bincode::deserialize(&state)?;
+        }
+        // the keys end with the big-endian index, so the scan returns the entries in order
+        for (_, value) in self.store.scan(LOG_PREFIX.to_vec()).await? {
+            self.log.push(bincode::deserialize(&value)?);
+        }
+        if let Some(applied) = self.store.read(LAST_APPLIED_KEY.to_vec()).await? {
+            self.last_applied = bincode::deserialize(&applied)?;
+        }
+        self.commit_index = self.last_applied;
+        info!(
+            "{}: Restored term {}, {} log entries and {} applied entries",
+            self.address,
+            self.current_term,
+            self.log.len(),
+            self.last_applied
+        );
+        Ok(())
+    }
+
+    async fn start_election(&mut self) {
+        self.state = Candidate;
+        self.current_term += 1;
+        self.voted_for = Some(self.address);
+        self.leader = None;
+        self.votes = HashSet::from([self.address]);
+        self.reset_election_timer();
+        if let Err(error) = self.persist_term().await {
+            error!("{}: failed to persist the term: {}", self.address, error);
+            return;
+        }
+        info!(
+            "{}: Election timeout, starting election for term {}",
+            self.address, self.current_term
+        );
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader().await;
+            return;
+        }
+
+        let request = RequestVote {
+            term: self.current_term,
+            candidate: self.address,
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+        };
+        self.broadcast_to_others(&request).await;
+    }
+
+    async fn become_leader(&mut self) {
+        info!(
+            "{}: Elected leader for term {}",
+            self.address, self.current_term
+        );
+        self.state = Leader;
+        self.leader = Some(self.address);
+
+        let next_index = self.last_log_index() + 1;
+        self.next_index = self.peers.iter().map(|peer| (*peer, next_index)).collect();
+        self.match_index = self.peers.iter().map(|peer| (*peer, 0)).collect();
+
+        // commit a no-op in the new term so previous entries get committed too
+        let entry = Entry {
+            term: self.current_term,
+            command: None,
+        };
+        if let Err(error) = self.write_log(next_index, vec![entry]).await {
+            error!("{}: failed to append the no-op: {}", self.address, error);
+        }
+
+        self.timer_start = Instant::now();
+        self.broadcast_append_entries().await;
+        self.advance_commit_index().await;
+    }
+
+    /// Move to a newer term as a follower. Pending client commands are failed since this node
+    /// can no longer guarantee they will be committed.
+    async fn step_down(&mut self, term: u64) -> Result<()> {
+        if self.state == Leader {
+            info!("{}: Stepping down as leader", self.address);
+        }
+        self.current_term = term;
+        self.state = Follower;
+        self.voted_for = None;
+        self.leader = None;
+        self.reset_election_timer();
+
+        for (_, (_, reply_sender)) in std::mem::take(&mut self.pending) {
+            let _ = reply_sender.send(Err(
+                "leadership lost before the command was committed".to_string()
+            ));
+        }
+        self.persist_term().await
+    }
+
+    /// Leader: commit the highest entry of the current term replicated on a quorum of nodes.
+    async fn advance_commit_index(&mut self) {
+        if self.state != Leader {
+            return;
+        }
+        self.match_index.insert(self.address, self.last_log_index());
+
+        let mut replicated: Vec<u64> = self
+            .peers
+            .iter()
+            .map(|peer| self.match_index.get(peer).copied().unwrap_or(0))
+            .collect();
+        replicated.sort_unstable_by(|a, b| b.cmp(a));
+        let quorum_index = replicated[self.quorum() - 1];
+
+        // entries from previous terms are only committed indirectly, see section 5.4.2 of the paper
+        if quorum_index > self.commit_index && self.term_at(quorum_index) == self.current_term {
+            self.commit_index = quorum_index;
+            if let Err(error) = self.apply_committed().await {
+                error!(
+                    "{}: failed to apply committed entries: {}",
+                    self.address, error
+                );
+            }
+        }
+    }
+
+    /// Apply the committed entries not yet applied to the store, and reply to the clients waiting for them.
+    async fn apply_committed(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = self.log[index as usize - 1].clone();
+
+            // the index is written with the changes of the command, so a restart never applies it twice
+            let applied = (LAST_APPLIED_KEY.to_vec(), Some(bincode::serialize(&index)?));
+            let result = match &entry.command {
+                Some(command) => command.apply_with(&self.store, vec![applied]).await?,
+                None => {
+                    self.store.write_batch(vec![applied]).await?;
+                    Ok(None)
+                }
+            };
+            self.last_applied = index;
+
+            if let Some((term, reply_sender)) = self.pending.remove(&self.last_applied) {
+                // the entry the client sent may have been replaced by one from another leader
+                let result = if term == entry.term {
+                    result
+                } else {
+                    Err("leadership lost before the command was committed".to_string())
+                };
+                if let Err(error) = reply_sender.send(result) {
+                    error!("failed to send command response {:?}", error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn broadcast_append_entries(&mut self) {
+        let others: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .copied()
+            .filter(|peer| *peer != self.address)
+            .collect();
+        for peer in others {
+            self.send_append_entries(peer).await;
+        }
+    }
+
+    /// Send the given peer every entry starting from the next one it's expected to need.
+    async fn send_append_entries(&mut self, peer: SocketAddr) {
+        let next_index = self.next_index.get(&peer).copied().unwrap_or(1).max(1);
+        let prev_log_index = next_index - 1;
+        let message = AppendEntries {
+            term: self.current_term,
+            leader: self.address,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries: self.log[prev_log_index as usize..].to_vec(),
+            leader_commit: self.commit_index,
+        };
+        self.send(peer, &message).await;
+    }
+
+    async fn send(&mut self, address: SocketAddr, message: &Message) {
+        if let Some(data) = serialize(message) {
+            self.sender.send(address, data).await;
+        }
+    }
+
+    async fn broadcast_to_others(&mut self, message: &Message) {
+        if let Some(data) = serialize(message) {
+            let other_peers: Vec<SocketAddr> = self
+                .peers
+                .iter()
+                .copied()
+                .filter(|x| *x != self.address)
+                .collect();
+            self.sender.broadcast(other_peers, data).await;
+        }
+    }
+
+    fn reset_election_timer(&mut self) {
+        let timeout = self
+            .rng
+            .gen_range(self.election_timeout_ms, 2 * self.election_timeout_ms + 1);
+        self.election_timeout = Duration::from_millis(timeout);
+        self.timer_start = Instant::now();
+    }
+
+    fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.election_timeout_ms / 3)
+    }
+
+    fn quorum(&self) -> usize {
+        self.peers.len() / 2 + 1
+    }
+
+    fn last_log_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.term_at(self.last_log_index())
+    }
+
+    /// The term of the entry at the given index, 0 for the (empty) entry before the first one.
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            index => self.log[index as usize - 1].term,
+        }
+    }
+}
+
+/// The store key of the log entry at the given index.
+fn log_key(index: u64) -> Vec<u8> {
+    [LOG_PREFIX, &index.to_be_bytes()].concat()
+}
+
+/// Send a client command to the leader and wait for its reply.
+async fn forward(
+    mut sender: Box<dyn RequestSender>,
+    leader: SocketAddr,
+    command: ClientCommand,
+) -> CommandResult {
+    let message: Bytes = bincode::serialize(&Forward(command))
+        .map_err(|e| e.to_string())?
+        .into();
+    let reply_handler = sender.send(leader, message).await;
+
+    match tokio::time::timeout(FORWARD_TIMEOUT, reply_handler).await {
+        Ok(Ok(response)) => bincode::deserialize(&response).map_err(|e| e.to_string())?,
+        Ok(Err(error)) => Err(error.to_string()),
+        Err(_) => Err(format!("timed out waiting for leader {leader}")),
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Safe serialization helper. Logs on error.
+fn serialize<T: Serialize + fmt::Debug>(message: &T) -> Option<Bytes> {
+    match bincode::serialize(message) {
+        Ok(data) => Some(data.into()),
+        Err(err) => {
+            error!("failed to serialize message {:?}, error: {}", message, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn entry(term: u64, value: &str) -> Entry {
+        Entry {
+            term,
+            command: Some(ClientCommand::Set {
+                key: "k".to_string(),
+                value: value.to_string(),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn append_entries_log_matching() {
+        let path = ".db_test_raft_append_entries";
+        fs::remove_dir_all(path).unwrap_or_default();
+        let address: SocketAddr = "127.0.0.1:7190".parse().unwrap();
+        let mut node = Node::new(vec![address], path, address, 100);
+
+        // entries are appended when the previous one matches
+        let result = node
+            .append_entries(0, 0, vec![entry(1, "a"), entry(1, "b")], 1)
+            .await
+            .unwrap();
+        assert_eq!(Ok(2), result);
+        assert_eq!(1, node.commit_index);
+
+        // missing previous entry: the leader should retry from the end of our log
+        assert_eq!(
+            Err(2),
+            node.append_entries(4, 1, vec![entry(2, "x")], 1)
+                .await
+                .unwrap()
+        );
+
+        // a conflicting entry from a newer leader replaces the uncommitted suffix
+        let result = node
+            .append_entries(1, 1, vec![entry(2, "c")], 1)
+            .await
+            .unwrap();
+        assert_eq!(Ok(2), result);
+        assert_eq!(vec![entry(1, "a"), entry(2, "c")], node.log);
+
+        // a stale, shorter request doesn't drop entries that match
+        let result = node
+            .append_entries(0, 0, vec![entry(1, "a")], 2)
+            .await
+            .unwrap();
+        assert_eq!(Ok(1), result);
+        assert_eq!(2, node.log.len());
+        assert_eq!(1, node.commit_index);
+
+        // previous entry with a different term is rejected
+        assert_eq!(Err(1), node.append_entries(2, 1, vec![], 2).await.unwrap());
+
+        // a delayed request only matching the start of the log doesn't move the commit index back
+        node.commit_index = 2;
+        assert_eq!(
+            Ok(1),
+            node.append_entries(0, 0, vec![entry(1, "a")], 3)
+                .await
+                .unwrap()
+        );
+        assert_eq!(2, node.commit_index);
+        node.commit_index = 1;
+
+        node.apply_committed().await.unwrap();
+        assert_eq!(1, node.last_applied);
+        let value = node.store.read("k".into()).await.unwrap();
+        assert_eq!(Some("a".into()), value);
+
+        // the log and the last applied entry survive a restart
+        let mut restarted = Node::new(vec![address], path, address, 100);
+        restarted.restore().await.unwrap();
+        assert_eq!(vec![entry(1, "a"), entry(2, "c")], restarted.log);
+        assert_eq!(1, restarted.last_applied);
+    }
+
+    #[tokio::test]
+    async fn restart_keeps_vote() {
+        let path = ".db_test_raft_restart_keeps_vote";
+        fs::remove_dir_all(path).unwrap_or_default();
+        let address: SocketAddr = "127.0.0.1:7192".parse().unwrap();
+        let candidate: SocketAddr = "127.0.0.1:7194".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:7196".parse().unwrap();
+        let peers = vec![address, candidate, other];
+        let mut node = Node::new(peers.clone(), path, address, 100);
+
+        let request = |candidate| RequestVote {
+            term: 1,
+            candidate,
+            last_log_index: 0,
+            last_log_term: 0,
+        };
+        node.handle_message(request(candidate)).await.unwrap();
+        assert_eq!(Some(candidate), node.voted_for);
+
+        // after a restart the node doesn't vote for another candidate in the same term
+        let mut restarted = Node::new(peers, path, address, 100);
+        restarted.restore().await.unwrap();
+        assert_eq!(1, restarted.current_term);
+        restarted.handle_message(request(other)).await.unwrap();
+        assert_eq!(Some(candidate), restarted.voted_for);
+    }
+}
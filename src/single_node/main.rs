@@ -59,6 +59,8 @@ async fn spawn_node_tasks(
 mod tests {
     use super::*;
     use lib::command::ClientCommand;
+    use lib::history::History;
+    use lib::linearizability;
     use std::fs;
     use tokio::time::{sleep, Duration};
 
@@ -119,4 +121,86 @@ mod tests {
         assert!(reply.is_some());
         assert_eq!("v2".to_string(), reply.unwrap());
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_write_commands() {
+        fs::remove_dir_all(".db_single_node_write_commands").unwrap_or_default();
+
+        let address: SocketAddr = "127.0.0.1:6183".parse().unwrap();
+        let node = Node {
+            store: lib::store::Store::new(".db_single_node_write_commands").unwrap(),
+        };
+
+        spawn_node_tasks(address, node).await;
+
+        sleep(Duration::from_millis(10)).await;
+
+        let reply = ClientCommand::Increment {
+            key: "counter".to_string(),
+            by: 3,
+        }
+        .send_to(address)
+        .await
+        .unwrap();
+        assert_eq!(Some("3".to_string()), reply);
+
+        // a compare-and-swap with a stale expected value is rejected
+        let reply = ClientCommand::CompareAndSwap {
+            key: "counter".to_string(),
+            expected: "0".to_string(),
+            new: "10".to_string(),
+        }
+        .send_to(address)
+        .await;
+        assert!(reply.is_err());
+
+        let reply = ClientCommand::CompareAndSwap {
+            key: "counter".to_string(),
+            expected: "3".to_string(),
+            new: "10".to_string(),
+        }
+        .send_to(address)
+        .await
+        .unwrap();
+        assert_eq!(Some("10".to_string()), reply);
+
+        let reply = ClientCommand::Delete {
+            key: "counter".to_string(),
+        }
+        .send_to(address)
+        .await
+        .unwrap();
+        assert!(reply.is_none());
+
+        let reply = ClientCommand::Get {
+            key: "counter".to_string(),
+        }
+        .send_to(address)
+        .await
+        .unwrap();
+        assert!(reply.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_linearizable_history() {
+        fs::remove_dir_all(".db_single_node_linearizable").unwrap_or_default();
+
+        let address: SocketAddr = "127.0.0.1:6184".parse().unwrap();
+        let node = Node {
+            store: lib::store::Store::new(".db_single_node_linearizable").unwrap(),
+        };
+
+        spawn_node_tasks(address, node).await;
+
+        sleep(Duration::from_millis(10)).await;
+
+        let history = History::new();
+        history.run_clients(4, 20, &["k1", "k2"], |_| address).await;
+
+        let operations = history.operations();
+        assert_eq!(80, operations.len());
+        if let Err(violations) = linearizability::check(&operations) {
+            panic!("{}", violations[0]);
+        }
+    }
 }
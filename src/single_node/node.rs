@@ -1,6 +1,6 @@
 /// This module contains an implementation of a single node.
 /// The node keeps a state, wich could be updated by tcp requests.
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use lib::command::CommandResult;
 use lib::{command::ClientCommand, store::Store};
 use log::error;
@@ -38,22 +38,6 @@ impl Node {
 
     /// Process each messages coming from clients
     pub async fn handle_msg(&mut self, message: ClientCommand) -> Result<Option<String>> {
-        match message {
-            ClientCommand::Set { key, value } => {
-                self.store
-                    .write(key.clone().into(), value.clone().into())
-                    .await?;
-
-                Ok(Some(value))
-            }
-            ClientCommand::Get { key } => {
-                if let Ok(Some(val)) = self.store.read(key.clone().into()).await {
-                    let value = String::from_utf8(val)?;
-                    return Ok(Some(value));
-                }
-
-                Ok(None)
-            }
-        }
+        message.apply(&self.store).await?.map_err(|e| anyhow!(e))
     }
 }
@@ -10,6 +10,11 @@ type Value = Vec<u8>;
 pub enum StoreCommand {
     Write(Key, Value),
     Read(Key),
+    Delete(Key),
+    /// Atomically write (`Some`) or delete (`None`) several keys.
+    WriteBatch(Vec<(Key, Option<Value>)>),
+    /// Read all the key/values whose key starts with the given prefix, ordered by key.
+    Scan(Key),
 }
 
 /// (sender, command) pair used to interact with the task that manages the store.
@@ -31,6 +36,21 @@ impl Store {
                 let response = match command {
                     StoreCommand::Write(key, value) => db.put(key, &value).and(Ok(Some(value))),
                     StoreCommand::Read(key) => db.get(key),
+                    StoreCommand::Delete(key) => db.delete(key).and(Ok(None)),
+                    StoreCommand::WriteBatch(entries) => {
+                        let mut batch = rocksdb::WriteBatch::default();
+                        for (key, value) in entries {
+                            match value {
+                                Some(value) => batch.put(key, value),
+                                None => batch.delete(key),
+                            }
+                        }
+                        db.write(batch).and(Ok(None))
+                    }
+                    // the entries are serialized to fit in the single value reply of the store channel
+                    StoreCommand::Scan(prefix) => scan(&db, &prefix).map(|entries| {
+                        Some(bincode::serialize(&entries).expect("failed to serialize entries"))
+                    }),
                 };
 
                 // convert internal rocksdb error to anyhow before returning
@@ -52,6 +72,21 @@ impl Store {
         self.send(StoreCommand::Read(key)).await
     }
 
+    pub async fn delete(&self, key: Key) -> Result<Option<Value>> {
+        self.send(StoreCommand::Delete(key)).await
+    }
+
+    pub async fn write_batch(&self, entries: Vec<(Key, Option<Value>)>) -> Result<Option<Value>> {
+        self.send(StoreCommand::WriteBatch(entries)).await
+    }
+
+    pub async fn scan(&self, prefix: Key) -> Result<Vec<(Key, Value)>> {
+        match self.send(StoreCommand::Scan(prefix)).await? {
+            Some(data) => Ok(bincode::deserialize(&data)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
     async fn send(&self, command: StoreCommand) -> Result<Option<Value>> {
         let (sender, receiver) = oneshot::channel();
 
@@ -66,6 +101,20 @@ impl Store {
     }
 }
 
+/// Collect the key/values of the database whose key starts with the given prefix.
+fn scan(db: &rocksdb::DB, prefix: &[u8]) -> Result<Vec<(Key, Value)>, rocksdb::Error> {
+    let mut entries = Vec::new();
+    let mode = rocksdb::IteratorMode::From(prefix, rocksdb::Direction::Forward);
+    for item in db.iterator(mode) {
+        let (key, value) = item?;
+        if !key.starts_with(prefix) {
+            break;
+        }
+        entries.push((key.to_vec(), value.to_vec()));
+    }
+    Ok(entries)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,6 +149,66 @@ mod tests {
         assert_eq!(read_value.unwrap(), value);
     }
 
+    #[tokio::test]
+    async fn delete_value() {
+        // Create new store.
+        let path = ".db_test_delete_value";
+        let _ = fs::remove_dir_all(path);
+        let store = Store::new(path).unwrap();
+
+        // Write value to the store.
+        let key = vec![0u8, 1u8, 2u8, 3u8];
+        let value = vec![4u8, 5u8, 6u8, 7u8];
+        store.write(key.clone(), value).await.unwrap();
+
+        // Delete it and make sure it's gone.
+        let result = store.delete(key.clone()).await;
+        assert!(result.is_ok());
+        let result = store.read(key).await;
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn write_batch() {
+        // Create new store.
+        let path = ".db_test_write_batch";
+        let _ = fs::remove_dir_all(path);
+        let store = Store::new(path).unwrap();
+
+        let key1 = vec![0u8, 1u8];
+        let key2 = vec![2u8, 3u8];
+        let value = vec![4u8, 5u8];
+        store.write(key2.clone(), value.clone()).await.unwrap();
+
+        // Write one key and delete the other in a single batch.
+        let entries = vec![(key1.clone(), Some(value.clone())), (key2.clone(), None)];
+        assert!(store.write_batch(entries).await.is_ok());
+
+        assert_eq!(Some(value), store.read(key1).await.unwrap());
+        assert!(store.read(key2).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn scan_prefix() {
+        // Create new store.
+        let path = ".db_test_scan_prefix";
+        let _ = fs::remove_dir_all(path);
+        let store = Store::new(path).unwrap();
+
+        let value = vec![4u8, 5u8];
+        for key in [vec![1u8, 2u8], vec![1u8, 1u8], vec![0u8, 1u8], vec![2u8]] {
+            store.write(key, value.clone()).await.unwrap();
+        }
+
+        // Only the keys with the prefix are returned, in order.
+        let entries = store.scan(vec![1u8]).await.unwrap();
+        let keys: Vec<Key> = entries.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(vec![vec![1u8, 1u8], vec![1u8, 2u8]], keys);
+
+        assert!(store.scan(vec![3u8]).await.unwrap().is_empty());
+    }
+
     #[tokio::test]
     async fn read_unknown_key() {
         // Create new store.
@@ -0,0 +1,176 @@
+use crate::node::Node;
+/// This module is a binary that runs a Streamlet node: it listens for TCP connections from clients and
+/// peers and forwards incoming messages to the node state machine.
+use clap::Parser;
+use lib::network::Receiver;
+use log::info;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::task::JoinHandle;
+
+mod ledger;
+mod node;
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct Cli {
+    /// The client port of the node where to send txs.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 6100)]
+    client_port: u16,
+    /// The network port where other nodes sends msg.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 6200)]
+    network_port: u16,
+    /// Node Address
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    address: IpAddr,
+    /// The network addresses of all the nodes in the network, including this one.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "ADDR",
+        use_value_delimiter = true,
+        value_delimiter = ' '
+    )]
+    peers: Vec<SocketAddr>,
+    /// The duration of each epoch in milliseconds. It should be at least twice the expected network delay.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 500)]
+    epoch_ms: u64,
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    let cli = Cli::parse();
+
+    simple_logger::SimpleLogger::new()
+        .env()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    let network_address = SocketAddr::new(cli.address, cli.network_port);
+    let client_address = SocketAddr::new(cli.address, cli.client_port);
+
+    info!(
+        "Node: Running on {}, client requests on {}, peers {:?}",
+        network_address, client_address, cli.peers
+    );
+
+    let node = Node::new(cli.peers, network_address, cli.epoch_ms);
+
+    let (_, network_handle, _) = spawn_node_tasks(network_address, client_address, node).await;
+    network_handle.await.unwrap();
+}
+
+async fn spawn_node_tasks(
+    network_address: SocketAddr,
+    client_address: SocketAddr,
+    mut node: Node,
+) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
+    // listen for peer network tcp connections
+    let (network_tcp_receiver, network_channel_receiver) = Receiver::new(network_address);
+    let network_handle = tokio::spawn(async move {
+        network_tcp_receiver.run().await;
+    });
+
+    // listen for client command tcp connections
+    let (client_tcp_receiver, client_channel_receiver) = Receiver::new(client_address);
+    let client_handle = tokio::spawn(async move {
+        client_tcp_receiver.run().await;
+    });
+
+    let node_handle = tokio::spawn(async move {
+        node.run(network_channel_receiver, client_channel_receiver)
+            .await;
+    });
+
+    (node_handle, network_handle, client_handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::network::simulator::{SimulatedNetwork, SimulatorConfig};
+    use lib::testing::{self, NodeHandles};
+    use tokio::time::Duration;
+
+    // since logger is meant to be initialized once and tests run in parallel,
+    // run this before anything because otherwise it errors out
+    #[ctor::ctor]
+    fn init() {
+        simple_logger::SimpleLogger::new().env().init().unwrap();
+    }
+
+    /// Start a network with one node per port pair starting at the given port, and return
+    /// the client addresses and task handles of each node.
+    async fn start_network(base_port: u16, size: u16) -> (Vec<SocketAddr>, Vec<NodeHandles>) {
+        let (network_addresses, client_addresses) = testing::local_addresses(base_port, size);
+
+        let mut handles = Vec::new();
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let node = Node::new(network_addresses.clone(), *network_address, 100);
+            handles.push(spawn_node_tasks(*network_address, *client_address, node).await);
+        }
+        (client_addresses, handles)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn single_node() {
+        let (client_addresses, _) = start_network(7400, 1).await;
+        testing::check_single_node(client_addresses[0]).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replicated_commands() {
+        let (client_addresses, _) = start_network(7410, 3).await;
+
+        // commands sent to any node are answered once final, and every node finalizes the same chain
+        testing::check_replicated_increments(&client_addresses).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn crashed_node() {
+        let (client_addresses, handles) = start_network(7420, 4).await;
+
+        // one out of four nodes can crash: the rest are still a notarization quorum, and the
+        // epochs of the remaining leaders are consecutive often enough to finalize blocks
+        testing::check_crashed_node(&client_addresses, &handles).await;
+    }
+
+    /// Run a cluster on a simulated network with the given seed, send a command to each node and check
+    /// that every node finalizes them. Returns the trace of the network.
+    async fn run_simulated(seed: Option<u64>) -> Vec<String> {
+        // nodes don't echo the messages they receive, so a lost vote can leave them with different
+        // longest notarized chains and stall the protocol: only delays and reordering are simulated
+        let default = SimulatorConfig::default();
+        let network = SimulatedNetwork::new(SimulatorConfig {
+            seed: seed.unwrap_or(default.seed),
+            reorder: true,
+            ..default
+        });
+        let (network_addresses, client_addresses) = testing::local_addresses(7490, 4);
+
+        for (network_address, client_address) in network_addresses.iter().zip(&client_addresses) {
+            let mut node = Node::new(network_addresses.clone(), *network_address, 100);
+            node.sender = Box::new(network.sender(*network_address));
+            node.rng = network.rng(*network_address);
+            node.created_at = Duration::ZERO;
+            let (_, network_channel) = network.listen(*network_address);
+            let (_, client_channel) = network.listen(*client_address);
+            tokio::spawn(async move { node.run(network_channel, client_channel).await });
+        }
+
+        // commands are finalized despite reordered messages
+        testing::check_simulated_writes(&network, &client_addresses).await;
+        network.trace()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simulated_network() {
+        run_simulated(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_seed() {
+        let trace = run_simulated(Some(42)).await;
+        assert_eq!(trace, run_simulated(Some(42)).await);
+    }
+}
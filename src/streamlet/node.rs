@@ -0,0 +1,495 @@
+/// This module contains the definition of a Streamlet node and the network messages supported between nodes.
+/// Time is divided in epochs of a fixed duration, each with a designated leader that proposes a block
+/// extending the longest notarized chain it knows of. Nodes vote for the first valid proposal of each
+/// epoch; a block with votes from two thirds of the nodes is notarized, and when a notarized chain
+/// contains three blocks of consecutive epochs, the chain up to the second of them is final.
+use crate::ledger::{Block, Ledger, Transaction, TransactionId};
+use bytes::Bytes;
+use core::fmt;
+use lib::command::{ClientCommand, CommandResult};
+use lib::network::{MessageSender, SimpleSender};
+use log::{debug, error, info, warn};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+use tokio::time::{interval, Duration, Instant};
+
+/// How often the node checks if a new epoch started.
+const TICK: Duration = Duration::from_millis(10);
+
+/// The types of messages supported by this implementation's state machine.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Message {
+    /// A client transaction received by a peer, to be included in a future proposal.
+    Command(TransactionId, ClientCommand),
+    /// A block proposed by the leader of its epoch.
+    Propose(Block),
+    /// A vote for a proposed block. The block is included so nodes that missed the proposal can
+    /// still count the votes towards its notarization.
+    Vote { from: SocketAddr, block: Block },
+}
+
+pub struct Node {
+    pub address: SocketAddr,
+
+    /// All the nodes of the network, including this one, sorted so every node agrees on the
+    /// leader of each epoch.
+    peers: Vec<SocketAddr>,
+
+    /// The duration of each epoch. Epochs are numbered from the unix epoch, so nodes with synchronized
+    /// clocks agree on the current epoch without exchanging messages.
+    epoch_duration: Duration,
+
+    /// The wall-clock time when the node was created and the tokio instant it corresponds to. The
+    /// current time is measured with the tokio clock from that point, so epochs also advance when
+    /// the clock is paused and driven by a simulated network, which also sets the wall-clock time
+    /// so the epochs replay with its seed.
+    pub created_at: Duration,
+    created_instant: Instant,
+
+    pub sender: Box<dyn MessageSender>,
+
+    /// The pool of pending transactions, to be included in this node's proposals.
+    mempool: BTreeMap<TransactionId, ClientCommand>,
+
+    /// The reply channels of the client commands received by this node, answered when the
+    /// command is finalized.
+    pending: HashMap<TransactionId, oneshot::Sender<CommandResult>>,
+
+    /// The known blocks that are not final yet, by hash.
+    blocks: BTreeMap<String, Block>,
+
+    /// The nodes that voted for each block, by block hash.
+    votes: HashMap<String, HashSet<SocketAddr>>,
+
+    /// The hashes of the blocks that got a quorum of votes.
+    notarized: BTreeSet<String>,
+
+    /// The last epoch this node voted in, to vote at most once per epoch.
+    voted_epoch: u64,
+
+    /// The last epoch this node proposed a block in.
+    proposed_epoch: u64,
+
+    /// The chain of finalized blocks.
+    ledger: Ledger,
+
+    /// The source of the ids of the client commands.
+    pub rng: SmallRng,
+}
+
+use Message::*;
+
+impl Node {
+    pub fn new(mut peers: Vec<SocketAddr>, address: SocketAddr, epoch_ms: u64) -> Self {
+        if !peers.contains(&address) {
+            peers.push(address);
+        }
+        peers.sort();
+
+        Self {
+            address,
+            peers,
+            epoch_duration: Duration::from_millis(epoch_ms),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("system clock is before the unix epoch"),
+            created_instant: Instant::now(),
+            sender: Box::new(SimpleSender::new()),
+            mempool: BTreeMap::new(),
+            pending: HashMap::new(),
+            blocks: BTreeMap::new(),
+            votes: HashMap::new(),
+            notarized: BTreeSet::new(),
+            voted_epoch: 0,
+            proposed_epoch: 0,
+            ledger: Ledger::new(),
+            rng: SmallRng::from_entropy(),
+        }
+    }
+
+    /// Runs the node to process network messages incoming in the given receiver, and proposes a
+    /// block at the start of every epoch this node is the leader of.
+    pub async fn run(
+        &mut self,
+        mut network_receiver: Receiver<(Message, oneshot::Sender<String>)>,
+        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<CommandResult>)>,
+    ) {
+        let mut timer = interval(TICK);
+
+        loop {
+            tokio::select! {
+                biased;
+                _ = timer.tick() => {
+                    let epoch = self.current_epoch();
+                    if epoch > self.proposed_epoch && self.leader(epoch) == self.address {
+                        self.propose(epoch).await;
+                    }
+                }
+                Some((command, reply_sender)) = client_receiver.recv() => {
+                    info!("{}: Received client message {}", self.address, command);
+                    self.handle_client_command(command, reply_sender).await;
+                }
+                Some((message, reply_sender)) = network_receiver.recv() => {
+                    debug!("{}: Received network message {}", self.address, message);
+                    if let Err(error) = reply_sender.send("ACK".to_string()) {
+                        error!("failed to send message {:?} response {:?}", message, error);
+                    }
+                    self.handle_message(message).await;
+                }
+                else => {
+                    error!("node channels are closed");
+                }
+            }
+        }
+    }
+
+    /// Reads are served from the finalized ledger. Writes are shared with the rest of the nodes so
+    /// any leader can propose them, and are answered once their block is final.
+    async fn handle_client_command(
+        &mut self,
+        command: ClientCommand,
+        reply_sender: oneshot::Sender<CommandResult>,
+    ) {
+        if let ClientCommand::Get { key } = &command {
+            if let Err(error) = reply_sender.send(Ok(self.ledger.get(key))) {
+                error!("failed to send client response {:?}", error);
+            }
+            return;
+        }
+        // a retry of a request the ledger already ran gets its first reply
+        if let Some(reply) = self.ledger.reply(&command) {
+            if let Err(error) = reply_sender.send(reply) {
+                error!("failed to send client response {:?}", error);
+            }
+            return;
+        }
+
+        // the requests of a client session are identified by the client, so retries are deduplicated;
+        // the id of other commands is drawn here
+        let txid = match &command {
+            ClientCommand::Session {
+                client, sequence, ..
+            } => format!("{client}/{sequence}"),
+            _ => uuid::Builder::from_random_bytes(self.rng.gen())
+                .into_uuid()
+                .to_string(),
+        };
+        self.mempool.insert(txid.clone(), command.clone());
+        self.pending.insert(txid.clone(), reply_sender);
+        self.broadcast(Command(txid, command)).await;
+    }
+
+    async fn handle_message(&mut self, message: Message) {
+        match message {
+            Command(txid, command) => {
+                if !self.ledger.contains(&txid) {
+                    self.mempool.insert(txid, command);
+                }
+            }
+            Propose(block) => self.handle_proposal(block).await,
+            Vote { from, block } => self.handle_vote(from, block),
+        }
+    }
+
+    /// Build a block extending the longest notarized chain with the pending transactions that are not
+    /// already in it, and send it to every node.
+    async fn propose(&mut self, epoch: u64) {
+        self.proposed_epoch = epoch;
+
+        let parent = self.longest_notarized_tip().clone();
+        let included: HashSet<&TransactionId> = self
+            .chain(&parent.hash)
+            .iter()
+            .flat_map(|block| block.data.iter().map(|(txid, _)| txid))
+            .collect();
+        let transactions: Vec<Transaction> = self
+            .mempool
+            .iter()
+            .filter(|(txid, _)| !included.contains(txid))
+            .map(|(txid, command)| (txid.clone(), command.clone()))
+            .collect();
+
+        let block = Block::new(epoch, self.address, &parent, transactions);
+        info!(
+            "{}: Proposing block {} at height {} for epoch {}",
+            self.address, block.hash, block.height, epoch
+        );
+        self.broadcast(Propose(block.clone())).await;
+        self.handle_proposal(block).await;
+    }
+
+    /// Vote for the given block if it's the first proposal of the current epoch leader and it extends
+    /// one of the longest notarized chains known by this node.
+    async fn handle_proposal(&mut self, block: Block) {
+        let epoch = self.current_epoch();
+        if block.epoch != epoch || block.proposer != self.leader(epoch) {
+            warn!(
+                "{}: ignoring proposal for epoch {} from {}",
+                self.address, block.epoch, block.proposer
+            );
+            return;
+        }
+        if self.voted_epoch >= epoch {
+            debug!("{}: already voted in epoch {}", self.address, epoch);
+            return;
+        }
+
+        let tip = self.longest_notarized_tip();
+        let extends_longest = match self.block(&block.previous_hash) {
+            Some(parent) => {
+                parent.height == tip.height
+                    && self.is_notarized_chain(&parent.hash)
+                    && block.extends(parent)
+            }
+            None => false,
+        };
+        if !block.is_valid() || !extends_longest {
+            warn!(
+                "{}: proposal {} doesn't extend a longest notarized chain",
+                self.address, block.hash
+            );
+            return;
+        }
+
+        self.voted_epoch = epoch;
+        let vote = Vote {
+            from: self.address,
+            block: block.clone(),
+        };
+        self.broadcast(vote).await;
+        self.handle_vote(self.address, block);
+    }
+
+    /// Count the vote for the given block, notarizing it once it reaches a quorum and finalizing
+    /// any blocks that become final as a result.
+    fn handle_vote(&mut self, from: SocketAddr, block: Block) {
+        if !self.peers.contains(&from) || !block.is_valid() {
+            warn!("{}: ignoring invalid vote from {}", self.address, from);
+            return;
+        }
+        if block.height <= self.ledger.tip().height {
+            debug!("{}: ignoring vote for a stale block", self.address);
+            return;
+        }
+
+        let hash = block.hash.clone();
+        self.blocks.entry(hash.clone()).or_insert(block);
+        let votes = self.votes.entry(hash.clone()).or_default();
+        votes.insert(from);
+
+        if votes.len() >= self.quorum() && self.notarized.insert(hash.clone()) {
+            info!("{}: Block {} notarized", self.address, hash);
+            self.try_finalize();
+        }
+    }
+
+    /// Look for a notarized chain ending in three blocks of consecutive epochs, and finalize the chain
+    /// up to the second one of them.
+    fn try_finalize(&mut self) {
+        let finalized = self
+            .notarized
+            .iter()
+            .filter_map(|hash| self.blocks.get(hash))
+            .filter(|block| self.is_notarized_chain(&block.hash))
+            .find_map(|block| {
+                let parent = self.blocks.get(&block.previous_hash)?;
+                let grandparent = self.block(&parent.previous_hash)?;
+                if grandparent.epoch + 1 == parent.epoch && parent.epoch + 1 == block.epoch {
+                    Some(parent.hash.clone())
+                } else {
+                    None
+                }
+            });
+
+        if let Some(hash) = finalized {
+            self.finalize(&hash);
+        }
+    }
+
+    /// Append to the ledger the chain of blocks ending in the given one, answer the client commands
+    /// included in them and forget the blocks that can no longer become final.
+    fn finalize(&mut self, hash: &str) {
+        let mut chain: Vec<Block> = self.chain(hash).into_iter().cloned().collect();
+        chain.reverse();
+
+        for block in chain {
+            info!(
+                "{}: Finalized block {} at height {}",
+                self.address, block.hash, block.height
+            );
+            let results = match self.ledger.extend(block) {
+                Ok(results) => results,
+                Err(error) => {
+                    error!("{}: {}", self.address, error);
+                    return;
+                }
+            };
+
+            for (txid, result) in results {
+                self.mempool.remove(&txid);
+                if let Some(reply_sender) = self.pending.remove(&txid) {
+                    if let Err(error) = reply_sender.send(result) {
+                        error!("failed to send client response {:?}", error);
+                    }
+                }
+            }
+        }
+
+        let height = self.ledger.tip().height;
+        let stale: Vec<String> = self
+            .blocks
+            .values()
+            .filter(|block| block.height <= height)
+            .map(|block| block.hash.clone())
+            .collect();
+        for hash in stale {
+            self.blocks.remove(&hash);
+            self.votes.remove(&hash);
+            self.notarized.remove(&hash);
+        }
+    }
+
+    /// The block with the given hash, if it's the latest finalized block or a known pending one.
+    fn block(&self, hash: &str) -> Option<&Block> {
+        if self.ledger.tip().hash == hash {
+            Some(self.ledger.tip())
+        } else {
+            self.blocks.get(hash)
+        }
+    }
+
+    /// The pending blocks from the given one back to the latest finalized block, excluding the latter.
+    fn chain(&self, hash: &str) -> Vec<&Block> {
+        let mut chain = Vec::new();
+        let mut current = self.blocks.get(hash);
+        while let Some(block) = current {
+            chain.push(block);
+            current = self.blocks.get(&block.previous_hash);
+        }
+        chain
+    }
+
+    /// Returns true if the given block and all its ancestors are notarized.
+    fn is_notarized_chain(&self, hash: &str) -> bool {
+        let mut hash = hash;
+        loop {
+            if self.ledger.tip().hash == hash {
+                return true;
+            }
+            match self.blocks.get(hash) {
+                Some(block) if self.notarized.contains(hash) => hash = &block.previous_hash,
+                _ => return false,
+            }
+        }
+    }
+
+    /// The last block of the longest notarized chain known by this node. Ties are broken arbitrarily.
+    fn longest_notarized_tip(&self) -> &Block {
+        self.notarized
+            .iter()
+            .filter(|hash| self.is_notarized_chain(hash))
+            .filter_map(|hash| self.blocks.get(hash))
+            .max_by_key(|block| block.height)
+            .unwrap_or_else(|| self.ledger.tip())
+    }
+
+    fn current_epoch(&self) -> u64 {
+        let now = self.created_at + self.created_instant.elapsed();
+        (now.as_millis() / self.epoch_duration.as_millis()) as u64
+    }
+
+    /// The leader of each epoch is picked in a round robin fashion.
+    fn leader(&self, epoch: u64) -> SocketAddr {
+        self.peers[(epoch % self.peers.len() as u64) as usize]
+    }
+
+    /// The amount of votes needed to notarize a block: two thirds of the nodes, rounded up.
+    fn quorum(&self) -> usize {
+        (2 * self.peers.len()).div_ceil(3)
+    }
+
+    /// Send a message to every other node.
+    async fn broadcast(&mut self, message: Message) {
+        let others: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|peer| **peer != self.address)
+            .cloned()
+            .collect();
+        let message: Bytes = bincode::serialize(&message).unwrap().into();
+        self.sender.broadcast(others, message).await;
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command(txid, command) => write!(f, "Command({txid}, {command})"),
+            Propose(block) => write!(f, "Propose({}, epoch {})", block.hash, block.epoch),
+            Vote { from, block } => write!(f, "Vote({}, {from})", block.hash),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    #[tokio::test]
+    async fn finalize_consecutive_epochs() {
+        let peers: Vec<SocketAddr> = (7490..7494).map(address).collect();
+        let mut node = Node::new(peers.clone(), peers[0], 100);
+
+        let command = ClientCommand::Set {
+            key: "k".to_string(),
+            value: "v".to_string(),
+        };
+        let b1 = Block::new(
+            1,
+            peers[1],
+            &Block::genesis(),
+            vec![("tx1".to_string(), command)],
+        );
+        let b2 = Block::new(3, peers[3], &b1, vec![]);
+        let b3 = Block::new(4, peers[0], &b2, vec![]);
+        let b4 = Block::new(5, peers[1], &b3, vec![]);
+
+        let vote_quorum = |node: &mut Node, block: &Block| {
+            for peer in &peers[..3] {
+                node.handle_vote(*peer, block.clone());
+            }
+        };
+
+        // a single vote doesn't notarize a block
+        node.handle_vote(peers[0], b1.clone());
+        assert!(!node.notarized.contains(&b1.hash));
+        vote_quorum(&mut node, &b1);
+        assert!(node.notarized.contains(&b1.hash));
+        assert_eq!(b1.hash, node.longest_notarized_tip().hash);
+
+        // notarized out of order: b3 doesn't count as a notarized chain until b2 is
+        vote_quorum(&mut node, &b3);
+        assert_eq!(b1.hash, node.longest_notarized_tip().hash);
+        vote_quorum(&mut node, &b2);
+        assert_eq!(b3.hash, node.longest_notarized_tip().hash);
+
+        // epochs 1, 3 and 4 are not consecutive, nothing is final
+        assert_eq!(0, node.ledger.tip().height);
+
+        // epochs 3, 4 and 5 are, which finalizes the chain up to b3
+        vote_quorum(&mut node, &b4);
+        assert_eq!(b3.hash, node.ledger.tip().hash);
+        assert_eq!(Some("v".to_string()), node.ledger.get("k"));
+        assert_eq!(vec![&b4], node.chain(&b4.hash));
+    }
+}
@@ -0,0 +1,274 @@
+/// This module contains the blocks proposed by Tendermint proposers, each one with the hash of its parent,
+/// and the ledger of decided blocks used as the commit log of the key/value store.
+use std::fmt::Display;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use anyhow::{bail, Result};
+use lib::command::{ClientCommand, CommandResult, KeyValues, Sessions};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub type TransactionId = String;
+pub type Transaction = (TransactionId, ClientCommand);
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub proposer: SocketAddr,
+    pub height: u64,
+    pub hash: String,
+    pub previous_hash: String,
+    pub data: Vec<Transaction>,
+}
+
+impl Block {
+    /// Create a block that extends the given parent.
+    pub fn new(proposer: SocketAddr, parent: &Block, transactions: Vec<Transaction>) -> Self {
+        let mut block = Self {
+            proposer,
+            height: parent.height + 1,
+            hash: "not known yet".to_string(),
+            previous_hash: parent.hash.clone(),
+            data: transactions,
+        };
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    /// Generate a hex string of a Sha256 hash for the attributes in this block.
+    /// The hash field itself doesn't affect the result.
+    pub fn calculate_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.proposer.to_string());
+        hasher.update(self.height.to_string());
+        hasher.update(&self.previous_hash);
+        for (txid, cmd) in &self.data {
+            hasher.update(txid);
+            hasher.update(cmd.to_string());
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    /// Create a genesis block, which is expected to be the first block of any valid ledger.
+    /// It's considered decided by every node from the start.
+    pub fn genesis() -> Self {
+        let mut block = Self {
+            proposer: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            height: 0,
+            hash: "temporary".to_string(),
+            previous_hash: "genesis".to_string(),
+            data: vec![],
+        };
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    /// Returns if this is a valid block: if its hash attribute matches the result of hashing the block data.
+    pub fn is_valid(&self) -> bool {
+        if self.calculate_hash() != self.hash {
+            warn!("block has invalid hash {}", self.hash);
+            return false;
+        }
+        true
+    }
+
+    /// Returns true if the given block is the parent of this one.
+    pub fn extends(&self, other: &Block) -> bool {
+        if self.previous_hash != other.hash {
+            warn!(
+                "block has wrong previous hash {}, expected {}",
+                self.previous_hash, other.hash
+            );
+            return false;
+        }
+        if self.height != other.height + 1 {
+            warn!(
+                "block has wrong height {}, expected {}",
+                self.height,
+                other.height + 1
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// The chain of decided blocks. Viewed as the commit log of the key/value store, the store state
+/// is the result of replaying, from the oldest block, every command in the ledger; the state is kept
+/// up to date as blocks are appended so reads don't need to replay the whole chain.
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    pub blocks: Vec<Block>,
+    state: KeyValues,
+    /// The last request of each client session run by the ledger, so retries are only run once.
+    sessions: Sessions,
+}
+
+impl Ledger {
+    /// Creates a new ledger with a genesis block in it.
+    pub fn new() -> Self {
+        Self {
+            blocks: vec![Block::genesis()],
+            state: KeyValues::new(),
+            sessions: Sessions::default(),
+        }
+    }
+
+    /// The latest decided block.
+    pub fn tip(&self) -> &Block {
+        self.blocks.last().unwrap()
+    }
+
+    /// Return the current value of the given key.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.get(key).cloned().flatten()
+    }
+
+    /// The reply to the given command if it's a session request the ledger already ran, so a retry is
+    /// answered without ordering it again.
+    pub fn reply(&self, command: &ClientCommand) -> Option<CommandResult> {
+        self.sessions.reply(command)
+    }
+
+    /// Returns true if there's a transaction with the given id commited in some block of this ledger.
+    pub fn contains(&self, txid: &str) -> bool {
+        self.blocks.iter().rev().any(|block| {
+            block
+                .data
+                .iter()
+                .any(|(stored_txid, _)| stored_txid == txid)
+        })
+    }
+
+    /// Append the given decided block to the ledger and run its commands, returning the result of
+    /// each of them. Rejected commands (e.g. a failed compare-and-swap) leave the state untouched, and
+    /// so do session requests that were already run, which get their first reply again.
+    /// Fails if the block is an invalid extension of this ledger.
+    pub fn extend(&mut self, block: Block) -> Result<Vec<(TransactionId, CommandResult)>> {
+        if !block.is_valid() || !block.extends(self.tip()) {
+            bail!("block {:?} is not a valid extension of the ledger", block);
+        }
+
+        let results = block
+            .data
+            .iter()
+            .map(|(txid, cmd)| (txid.clone(), self.sessions.execute(cmd, &mut self.state)))
+            .collect();
+        self.blocks.push(block);
+        Ok(results)
+    }
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for Ledger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Ledger {{ length: {}, latest: {:?}  }}",
+            self.blocks.len(),
+            self.tip()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposer() -> SocketAddr {
+        "127.0.0.1:6200".parse().unwrap()
+    }
+
+    fn transaction(txid: &str, command: ClientCommand) -> Transaction {
+        (txid.to_string(), command)
+    }
+
+    #[test]
+    fn extend_ledger() {
+        let mut ledger = Ledger::new();
+        let block = Block::new(
+            proposer(),
+            ledger.tip(),
+            vec![
+                transaction(
+                    "tx1",
+                    ClientCommand::Set {
+                        key: "a".to_string(),
+                        value: "1".to_string(),
+                    },
+                ),
+                transaction(
+                    "tx2",
+                    ClientCommand::CompareAndSwap {
+                        key: "a".to_string(),
+                        expected: "0".to_string(),
+                        new: "2".to_string(),
+                    },
+                ),
+            ],
+        );
+        let results = ledger.extend(block.clone()).unwrap();
+        assert_eq!(2, results.len());
+        assert_eq!(("tx1".to_string(), Ok(Some("1".to_string()))), results[0]);
+        assert!(results[1].1.is_err());
+        assert_eq!(Some("1".to_string()), ledger.get("a"));
+        assert_eq!(&block, ledger.tip());
+
+        // the same block can't be appended twice
+        assert!(ledger.extend(block.clone()).is_err());
+
+        // nor a block that doesn't extend the latest one
+        let fork = Block::new(proposer(), &Block::genesis(), vec![]);
+        assert!(ledger.extend(fork).is_err());
+
+        // nor a block with a tampered hash
+        let mut tampered = Block::new(proposer(), &block, vec![]);
+        tampered.data.push(transaction(
+            "tx3",
+            ClientCommand::Delete {
+                key: "a".to_string(),
+            },
+        ));
+        assert!(ledger.extend(tampered).is_err());
+
+        let next = Block::new(proposer(), &block, vec![]);
+        assert!(ledger.extend(next).is_ok());
+        assert_eq!(3, ledger.blocks.len());
+    }
+    #[test]
+    fn run_session_requests_once() {
+        let mut ledger = Ledger::new();
+        let request = ClientCommand::Session {
+            client: 1,
+            sequence: 1,
+            command: Box::new(ClientCommand::Increment {
+                key: "counter".to_string(),
+                by: 1,
+            }),
+        };
+        assert_eq!(None, ledger.reply(&request));
+
+        // a request decided twice, e.g. retried through another node before the first one was
+        // decided, is only run once
+        let block = Block::new(
+            proposer(),
+            ledger.tip(),
+            vec![transaction("1/1", request.clone())],
+        );
+        ledger.extend(block.clone()).unwrap();
+        let retry = Block::new(
+            proposer(),
+            &block,
+            vec![transaction("1/1", request.clone())],
+        );
+        let results = ledger.extend(retry).unwrap();
+        assert_eq!(("1/1".to_string(), Ok(Some("1".to_string()))), results[0]);
+        assert_eq!(Some("1".to_string()), ledger.get("counter"));
+        assert_eq!(Some(Ok(Some("1".to_string()))), ledger.reply(&request));
+    }
+}
@@ -0,0 +1,868 @@
+/// This module contains the definition of a Tendermint node and the network messages supported between nodes.
+/// The implementation follows the algorithm of "The latest gossip on BFT consensus": for each height, nodes
+/// go through rounds where a proposer proposes a block, and the rest of the nodes prevote and precommit it.
+/// A block is decided when it gets precommits from more than two thirds of the nodes in some round.
+/// Nodes lock on the blocks they precommit, which keeps decisions safe across rounds.
+use crate::ledger::{Block, Ledger, TransactionId};
+use bytes::Bytes;
+use core::fmt;
+use lib::command::{ClientCommand, CommandResult};
+use lib::network::{MessageSender, SimpleSender};
+use log::{debug, error, info, warn};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::time::{sleep, Duration};
+
+/// How long to wait after deciding a block before starting the next height, so the mempool can fill up
+/// instead of producing empty blocks back to back.
+const COMMIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The types of messages supported by this implementation's state machine.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Message {
+    /// A client transaction received by a peer, to be included in a future proposal.
+    Command(TransactionId, ClientCommand),
+    /// A block proposed by the proposer of a round. The valid round is the round the block got a
+    /// quorum of prevotes in, when re-proposing a block from a previous round.
+    Proposal {
+        from: SocketAddr,
+        height: u64,
+        round: u64,
+        block: Block,
+        valid_round: Option<u64>,
+    },
+    /// A prevote for the block with the given hash, or for nil if there's no hash.
+    Prevote {
+        from: SocketAddr,
+        height: u64,
+        round: u64,
+        hash: Option<String>,
+    },
+    /// A precommit for the block with the given hash, or for nil if there's no hash.
+    Precommit {
+        from: SocketAddr,
+        height: u64,
+        round: u64,
+        hash: Option<String>,
+    },
+}
+
+/// The steps of a round. Nodes wait in `NewHeight` between deciding a block and starting the next height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Step {
+    NewHeight,
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+/// The timeouts that can be scheduled for a given height and round.
+#[derive(Debug, Clone, Copy)]
+enum Timeout {
+    Propose,
+    Prevote,
+    Precommit,
+    Commit,
+    /// Sends again the messages of this node in the round, repeated until the round ends.
+    Resend,
+}
+
+/// The votes of a single kind cast in each round of the current height: the hash each node voted for,
+/// by round and node.
+type Votes = BTreeMap<u64, BTreeMap<SocketAddr, Option<String>>>;
+
+pub struct Node {
+    pub address: SocketAddr,
+
+    /// All the nodes of the network, including this one, sorted so every node agrees on the
+    /// proposer of each round.
+    peers: Vec<SocketAddr>,
+
+    pub sender: Box<dyn MessageSender>,
+
+    /// The base timeout of each step, which grows with every round of the same height.
+    timeout: Duration,
+    timeout_sender: Sender<(Timeout, u64, u64)>,
+    timeout_receiver: Receiver<(Timeout, u64, u64)>,
+
+    /// The pool of pending transactions, to be included in this node's proposals.
+    mempool: BTreeMap<TransactionId, ClientCommand>,
+
+    /// The reply channels of the client commands received by this node, answered when the
+    /// command is decided.
+    pending: HashMap<TransactionId, oneshot::Sender<CommandResult>>,
+
+    /// The chain of decided blocks.
+    ledger: Ledger,
+
+    height: u64,
+    round: u64,
+    step: Step,
+
+    /// The last block this node precommitted, and the round it did so. The node will only prevote for
+    /// other blocks if it sees a quorum of prevotes for them in a later round.
+    locked: Option<(u64, Block)>,
+
+    /// The last block that got a quorum of prevotes, and the round it got them. If this node is the
+    /// proposer of a later round it proposes this block again.
+    valid: Option<(u64, Block)>,
+
+    /// The messages received for the current height.
+    proposals: BTreeMap<u64, (Block, Option<u64>)>,
+    prevotes: Votes,
+    precommits: Votes,
+
+    /// Messages for later heights, processed once this node gets there.
+    future: Vec<Message>,
+
+    /// The proposal and precommits that decided each height, sent to the nodes that fall behind so
+    /// they can decide it too.
+    commits: HashMap<u64, Vec<Message>>,
+
+    /// Rules that only apply the first time their condition is met in the current round.
+    prevote_timeout_scheduled: bool,
+    precommit_timeout_scheduled: bool,
+    valid_updated: bool,
+
+    /// The source of the ids of the client commands.
+    pub rng: SmallRng,
+}
+
+use Message::*;
+
+impl Node {
+    pub fn new(mut peers: Vec<SocketAddr>, address: SocketAddr, timeout_ms: u64) -> Self {
+        if !peers.contains(&address) {
+            peers.push(address);
+        }
+        peers.sort();
+
+        let (timeout_sender, timeout_receiver) = channel(100);
+        let ledger = Ledger::new();
+
+        Self {
+            address,
+            peers,
+            sender: Box::new(SimpleSender::new()),
+            timeout: Duration::from_millis(timeout_ms),
+            timeout_sender,
+            timeout_receiver,
+            mempool: BTreeMap::new(),
+            pending: HashMap::new(),
+            height: ledger.tip().height + 1,
+            ledger,
+            round: 0,
+            step: Step::NewHeight,
+            locked: None,
+            valid: None,
+            proposals: BTreeMap::new(),
+            prevotes: BTreeMap::new(),
+            precommits: BTreeMap::new(),
+            future: Vec::new(),
+            commits: HashMap::new(),
+            prevote_timeout_scheduled: false,
+            precommit_timeout_scheduled: false,
+            valid_updated: false,
+            rng: SmallRng::from_entropy(),
+        }
+    }
+
+    /// Runs the node to process network messages incoming in the given receiver.
+    pub async fn run(
+        &mut self,
+        mut network_receiver: Receiver<(Message, oneshot::Sender<String>)>,
+        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<CommandResult>)>,
+    ) {
+        self.start_round(0).await;
+        self.apply_rules().await;
+
+        loop {
+            tokio::select! {
+                biased;
+                Some((timeout, height, round)) = self.timeout_receiver.recv() => {
+                    self.handle_timeout(timeout, height, round).await;
+                }
+                Some((command, reply_sender)) = client_receiver.recv() => {
+                    info!("{}: Received client message {}", self.address, command);
+                    self.handle_client_command(command, reply_sender).await;
+                }
+                Some((message, reply_sender)) = network_receiver.recv() => {
+                    debug!("{}: Received network message {}", self.address, message);
+                    if let Err(error) = reply_sender.send("ACK".to_string()) {
+                        error!("failed to send message {:?} response {:?}", message, error);
+                    }
+                    self.handle_message(message).await;
+                }
+                else => {
+                    error!("node channels are closed");
+                }
+            }
+        }
+    }
+
+    /// Reads are served from the decided ledger. Writes are shared with the rest of the nodes so
+    /// any proposer can include them, and are answered once their block is decided.
+    async fn handle_client_command(
+        &mut self,
+        command: ClientCommand,
+        reply_sender: oneshot::Sender<CommandResult>,
+    ) {
+        if let ClientCommand::Get { key } = &command {
+            if let Err(error) = reply_sender.send(Ok(self.ledger.get(key))) {
+                error!("failed to send client response {:?}", error);
+            }
+            return;
+        }
+        // a retry of a request the ledger already ran gets its first reply
+        if let Some(reply) = self.ledger.reply(&command) {
+            if let Err(error) = reply_sender.send(reply) {
+                error!("failed to send client response {:?}", error);
+            }
+            return;
+        }
+
+        // the requests of a client session are identified by the client, so retries are deduplicated;
+        // the id of other commands is drawn here
+        let txid = match &command {
+            ClientCommand::Session {
+                client, sequence, ..
+            } => format!("{client}/{sequence}"),
+            _ => uuid::Builder::from_random_bytes(self.rng.gen())
+                .into_uuid()
+                .to_string(),
+        };
+        self.mempool.insert(txid.clone(), command.clone());
+        self.pending.insert(txid.clone(), reply_sender);
+        self.broadcast(Command(txid, command)).await;
+    }
+
+    async fn handle_message(&mut self, message: Message) {
+        if let Command(txid, command) = message {
+            if !self.ledger.contains(&txid) {
+                self.mempool.insert(txid, command);
+            }
+            return;
+        }
+
+        let (from, height) = match &message {
+            Proposal { from, height, .. }
+            | Prevote { from, height, .. }
+            | Precommit { from, height, .. } => (*from, *height),
+            Command(..) => unreachable!(),
+        };
+        if height > self.height {
+            self.future.push(message);
+        } else if height == self.height {
+            self.record(message);
+            self.apply_rules().await;
+        } else if matches!(message, Prevote { .. }) {
+            // the sender is behind. Commits are relayed with their original senders, so only prevotes,
+            // which are never part of a commit, are answered to avoid nodes bouncing commits back and forth
+            self.send_commit(from, height).await;
+        }
+    }
+
+    /// Send the messages that decided the given height to a node that is still trying to decide it.
+    async fn send_commit(&mut self, to: SocketAddr, height: u64) {
+        if let Some(messages) = self.commits.get(&height) {
+            debug!(
+                "{}: Sending commit of height {} to {}",
+                self.address, height, to
+            );
+            for message in messages {
+                let message: Bytes = bincode::serialize(message).unwrap().into();
+                self.sender.send(to, message).await;
+            }
+        }
+    }
+
+    /// Store a consensus message of the current height. Only the first proposal of the round
+    /// proposer and the first vote of each node in each round are kept.
+    fn record(&mut self, message: Message) {
+        match message {
+            Proposal {
+                from,
+                round,
+                block,
+                valid_round,
+                ..
+            } => {
+                if from != self.proposer(self.height, round) {
+                    warn!("{}: ignoring proposal from {}", self.address, from);
+                    return;
+                }
+                self.proposals.entry(round).or_insert((block, valid_round));
+            }
+            Prevote {
+                from, round, hash, ..
+            } if self.peers.contains(&from) => {
+                let votes = self.prevotes.entry(round).or_default();
+                votes.entry(from).or_insert(hash);
+            }
+            Precommit {
+                from, round, hash, ..
+            } if self.peers.contains(&from) => {
+                let votes = self.precommits.entry(round).or_default();
+                votes.entry(from).or_insert(hash);
+            }
+            message => warn!("{}: ignoring message {}", self.address, message),
+        }
+    }
+
+    async fn start_round(&mut self, round: u64) {
+        debug!(
+            "{}: Starting round {} of height {}",
+            self.address, round, self.height
+        );
+        self.round = round;
+        self.step = Step::Propose;
+        self.prevote_timeout_scheduled = false;
+        self.precommit_timeout_scheduled = false;
+        self.valid_updated = false;
+        self.schedule(Timeout::Resend, round);
+
+        if self.proposer(self.height, round) == self.address {
+            let (valid_round, block) = match &self.valid {
+                Some((valid_round, block)) => (Some(*valid_round), block.clone()),
+                None => (None, self.new_block()),
+            };
+            info!(
+                "{}: Proposing block {} for height {} round {}",
+                self.address, block.hash, self.height, round
+            );
+            let proposal = Proposal {
+                from: self.address,
+                height: self.height,
+                round,
+                block,
+                valid_round,
+            };
+            self.send(proposal).await;
+        } else {
+            self.schedule(Timeout::Propose, round);
+        }
+    }
+
+    /// Check the conditions of every rule of the algorithm against the messages received so far, and
+    /// apply the ones that are met until there are no more changes.
+    async fn apply_rules(&mut self) {
+        while self.apply_next_rule().await {}
+    }
+
+    /// Apply the first rule whose condition is met, if any. Returns true if a rule was applied.
+    async fn apply_next_rule(&mut self) -> bool {
+        // a quorum of precommits for the proposal of any round decides it. This also applies while
+        // waiting to start the next height, so a node that fell behind can decide the heights it
+        // missed one after the other
+        let decided = self.proposals.iter().find_map(|(round, (block, _))| {
+            let hash = Some(block.hash.clone());
+            (self.count(&self.precommits, *round, &hash) >= self.quorum() && self.is_valid(block))
+                .then(|| (*round, block.clone()))
+        });
+        if let Some((round, block)) = decided {
+            self.decide(round, block);
+            return true;
+        }
+
+        if self.step == Step::NewHeight {
+            return false;
+        }
+
+        let round = self.round;
+        let proposal = self.proposals.get(&round).cloned();
+
+        if let Some((block, valid_round)) = &proposal {
+            let hash = Some(block.hash.clone());
+
+            // a new block, or a block re-proposed with a quorum of prevotes from a previous round
+            if self.step == Step::Propose {
+                let can_vote = match valid_round {
+                    None => Some(match &self.locked {
+                        None => true,
+                        Some((_, locked)) => locked == block,
+                    }),
+                    Some(valid_round)
+                        if *valid_round < round
+                            && self.count(&self.prevotes, *valid_round, &hash) >= self.quorum() =>
+                    {
+                        Some(match &self.locked {
+                            None => true,
+                            Some((locked_round, locked)) => {
+                                locked_round <= valid_round || locked == block
+                            }
+                        })
+                    }
+                    _ => None,
+                };
+                if let Some(can_vote) = can_vote {
+                    let vote = if can_vote && self.is_valid(block) {
+                        hash.clone()
+                    } else {
+                        None
+                    };
+                    self.prevote(vote).await;
+                    return true;
+                }
+            }
+
+            // a quorum of prevotes for the proposal: lock it and precommit it
+            if self.step >= Step::Prevote
+                && !self.valid_updated
+                && self.count(&self.prevotes, round, &hash) >= self.quorum()
+                && self.is_valid(block)
+            {
+                self.valid_updated = true;
+                if self.step == Step::Prevote {
+                    self.locked = Some((round, block.clone()));
+                    self.precommit(hash.clone()).await;
+                }
+                self.valid = Some((round, block.clone()));
+                return true;
+            }
+        }
+
+        if self.step == Step::Prevote {
+            if self.count(&self.prevotes, round, &None) >= self.quorum() {
+                self.precommit(None).await;
+                return true;
+            }
+            if !self.prevote_timeout_scheduled && self.total(&self.prevotes, round) >= self.quorum()
+            {
+                self.prevote_timeout_scheduled = true;
+                self.schedule(Timeout::Prevote, round);
+                return true;
+            }
+        }
+
+        if !self.precommit_timeout_scheduled && self.total(&self.precommits, round) >= self.quorum()
+        {
+            self.precommit_timeout_scheduled = true;
+            self.schedule(Timeout::Precommit, round);
+            return true;
+        }
+
+        // enough nodes are in a later round that at least one of them is correct: skip to it
+        let later_round = self
+            .proposals
+            .keys()
+            .chain(self.prevotes.keys())
+            .chain(self.precommits.keys())
+            .filter(|later| **later > round)
+            .find(|later| self.senders(**later).len() > self.max_faulty())
+            .cloned();
+        if let Some(later_round) = later_round {
+            self.start_round(later_round).await;
+            return true;
+        }
+
+        false
+    }
+
+    async fn handle_timeout(&mut self, timeout: Timeout, height: u64, round: u64) {
+        if height != self.height || (round != self.round && !matches!(timeout, Timeout::Commit)) {
+            return;
+        }
+        debug!(
+            "{}: {:?} timeout for height {} round {}",
+            self.address, timeout, height, round
+        );
+
+        match (timeout, self.step) {
+            (Timeout::Propose, Step::Propose) => self.prevote(None).await,
+            (Timeout::Prevote, Step::Prevote) => self.precommit(None).await,
+            (Timeout::Precommit, step) if step != Step::NewHeight => {
+                self.start_round(round + 1).await
+            }
+            (Timeout::Commit, Step::NewHeight) => self.start_round(0).await,
+            (Timeout::Resend, step) if step != Step::NewHeight => {
+                self.resend().await;
+                self.schedule(Timeout::Resend, round);
+                return;
+            }
+            _ => return,
+        }
+        self.apply_rules().await;
+    }
+
+    /// Send the proposal and votes of this node in the current round again. Messages are sent once, so
+    /// without this a lost vote could leave the nodes waiting for each other, and a node that fell
+    /// behind could miss the commit the others send back when they get its prevote.
+    async fn resend(&mut self) {
+        let (height, round) = (self.height, self.round);
+        let mut messages = Vec::new();
+        if self.proposer(height, round) == self.address {
+            if let Some((block, valid_round)) = self.proposals.get(&round) {
+                messages.push(Proposal {
+                    from: self.address,
+                    height,
+                    round,
+                    block: block.clone(),
+                    valid_round: *valid_round,
+                });
+            }
+        }
+        if let Some(hash) = self
+            .prevotes
+            .get(&round)
+            .and_then(|votes| votes.get(&self.address))
+        {
+            messages.push(Prevote {
+                from: self.address,
+                height,
+                round,
+                hash: hash.clone(),
+            });
+        }
+        if let Some(hash) = self
+            .precommits
+            .get(&round)
+            .and_then(|votes| votes.get(&self.address))
+        {
+            messages.push(Precommit {
+                from: self.address,
+                height,
+                round,
+                hash: hash.clone(),
+            });
+        }
+
+        debug!(
+            "{}: Resending {} messages of height {} round {}",
+            self.address,
+            messages.len(),
+            height,
+            round
+        );
+        for message in messages {
+            self.broadcast(message).await;
+        }
+    }
+
+    /// Append the given block, decided in the given round, to the ledger, answer the client commands
+    /// included in it and move on to the next height.
+    fn decide(&mut self, round: u64, block: Block) {
+        info!(
+            "{}: Decided block {} at height {}",
+            self.address, block.hash, block.height
+        );
+        let mut commit = vec![Proposal {
+            from: self.proposer(self.height, round),
+            height: self.height,
+            round,
+            block: block.clone(),
+            valid_round: self.proposals[&round].1,
+        }];
+        for (from, hash) in self.precommits.get(&round).into_iter().flatten() {
+            if *hash == Some(block.hash.clone()) {
+                commit.push(Precommit {
+                    from: *from,
+                    height: self.height,
+                    round,
+                    hash: hash.clone(),
+                });
+            }
+        }
+
+        match self.ledger.extend(block) {
+            Ok(results) => {
+                for (txid, result) in results {
+                    self.mempool.remove(&txid);
+                    if let Some(reply_sender) = self.pending.remove(&txid) {
+                        if let Err(error) = reply_sender.send(result) {
+                            error!("failed to send client response {:?}", error);
+                        }
+                    }
+                }
+            }
+            Err(error) => {
+                error!("{}: {}", self.address, error);
+                return;
+            }
+        }
+
+        self.commits.insert(self.height, commit);
+        self.height = self.ledger.tip().height + 1;
+        self.round = 0;
+        self.step = Step::NewHeight;
+        self.locked = None;
+        self.valid = None;
+        self.proposals.clear();
+        self.prevotes.clear();
+        self.precommits.clear();
+
+        let future = std::mem::take(&mut self.future);
+        for message in future {
+            match &message {
+                Proposal { height, .. } | Prevote { height, .. } | Precommit { height, .. }
+                    if *height > self.height =>
+                {
+                    self.future.push(message)
+                }
+                Proposal { height, .. } | Prevote { height, .. } | Precommit { height, .. }
+                    if *height == self.height =>
+                {
+                    self.record(message)
+                }
+                _ => {}
+            }
+        }
+
+        self.schedule(Timeout::Commit, 0);
+    }
+
+    async fn prevote(&mut self, hash: Option<String>) {
+        self.step = Step::Prevote;
+        let prevote = Prevote {
+            from: self.address,
+            height: self.height,
+            round: self.round,
+            hash,
+        };
+        self.send(prevote).await;
+    }
+
+    async fn precommit(&mut self, hash: Option<String>) {
+        self.step = Step::Precommit;
+        let precommit = Precommit {
+            from: self.address,
+            height: self.height,
+            round: self.round,
+            hash,
+        };
+        self.send(precommit).await;
+    }
+
+    /// Build a block extending the ledger with the pending transactions.
+    fn new_block(&self) -> Block {
+        let transactions = self
+            .mempool
+            .iter()
+            .map(|(txid, command)| (txid.clone(), command.clone()))
+            .collect();
+        Block::new(self.address, self.ledger.tip(), transactions)
+    }
+
+    /// Returns true if the given block can be decided at the current height.
+    fn is_valid(&self, block: &Block) -> bool {
+        let mut txids = HashSet::new();
+        block.is_valid()
+            && block.extends(self.ledger.tip())
+            && block
+                .data
+                .iter()
+                .all(|(txid, _)| txids.insert(txid) && !self.ledger.contains(txid))
+    }
+
+    /// Schedule the given timeout for the current height and the given round. Step timeouts grow
+    /// with each round so eventually they are long enough for the network to reach an agreement.
+    fn schedule(&self, timeout: Timeout, round: u64) {
+        let duration = match timeout {
+            Timeout::Commit => COMMIT_TIMEOUT,
+            _ => self.timeout + self.timeout * round as u32 / 2,
+        };
+        let sender = self.timeout_sender.clone();
+        let height = self.height;
+        tokio::spawn(async move {
+            sleep(duration).await;
+            let _ = sender.send((timeout, height, round)).await;
+        });
+    }
+
+    /// The number of votes for the given hash in the given round.
+    fn count(&self, votes: &Votes, round: u64, hash: &Option<String>) -> usize {
+        votes
+            .get(&round)
+            .map(|votes| votes.values().filter(|vote| *vote == hash).count())
+            .unwrap_or(0)
+    }
+
+    /// The number of votes in the given round, regardless of what they are for.
+    fn total(&self, votes: &Votes, round: u64) -> usize {
+        votes.get(&round).map(BTreeMap::len).unwrap_or(0)
+    }
+
+    /// The nodes that sent a message for the given round.
+    fn senders(&self, round: u64) -> HashSet<SocketAddr> {
+        let mut senders: HashSet<SocketAddr> = HashSet::new();
+        if self.proposals.contains_key(&round) {
+            senders.insert(self.proposer(self.height, round));
+        }
+        for votes in [&self.prevotes, &self.precommits] {
+            if let Some(votes) = votes.get(&round) {
+                senders.extend(votes.keys());
+            }
+        }
+        senders
+    }
+
+    /// The proposer of each round is picked in a round robin fashion.
+    fn proposer(&self, height: u64, round: u64) -> SocketAddr {
+        self.peers[((height + round) % self.peers.len() as u64) as usize]
+    }
+
+    /// The maximum number of faulty nodes tolerated by the network size.
+    fn max_faulty(&self) -> usize {
+        (self.peers.len() - 1) / 3
+    }
+
+    /// The number of votes needed to make progress: all the nodes but the faulty ones.
+    fn quorum(&self) -> usize {
+        self.peers.len() - self.max_faulty()
+    }
+
+    /// Send a consensus message to every other node and record it as received from this node.
+    async fn send(&mut self, message: Message) {
+        self.broadcast(message.clone()).await;
+        self.record(message);
+    }
+
+    /// Send a message to every other node.
+    async fn broadcast(&mut self, message: Message) {
+        let others: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|peer| **peer != self.address)
+            .cloned()
+            .collect();
+        let message: Bytes = bincode::serialize(&message).unwrap().into();
+        self.sender.broadcast(others, message).await;
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command(txid, command) => write!(f, "Command({txid}, {command})"),
+            Proposal {
+                from,
+                height,
+                round,
+                block,
+                ..
+            } => write!(f, "Proposal({height}, {round}, {}, {from})", block.hash),
+            Prevote {
+                from,
+                height,
+                round,
+                hash,
+            } => write!(f, "Prevote({height}, {round}, {hash:?}, {from})"),
+            Precommit {
+                from,
+                height,
+                round,
+                hash,
+            } => write!(f, "Precommit({height}, {round}, {hash:?}, {from})"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    fn vote(from: SocketAddr, round: u64, hash: Option<String>, precommit: bool) -> Message {
+        if precommit {
+            Precommit {
+                from,
+                height: 1,
+                round,
+                hash,
+            }
+        } else {
+            Prevote {
+                from,
+                height: 1,
+                round,
+                hash,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn locked_value() {
+        let peers: Vec<SocketAddr> = (7590..7594).map(address).collect();
+        let mut node = Node::new(peers.clone(), peers[0], 1000);
+        node.start_round(0).await;
+
+        // the proposer of height 1 round 0 proposes a block, the node prevotes it
+        let block = Block::new(peers[1], node.ledger.tip(), vec![]);
+        let hash = Some(block.hash.clone());
+        node.handle_message(Proposal {
+            from: peers[1],
+            height: 1,
+            round: 0,
+            block: block.clone(),
+            valid_round: None,
+        })
+        .await;
+        assert_eq!(Step::Prevote, node.step);
+        assert_eq!(Some(&hash), node.prevotes[&0].get(&peers[0]));
+
+        // with a quorum of prevotes the node locks on the block and precommits it
+        for peer in &peers[1..3] {
+            node.handle_message(vote(*peer, 0, hash.clone(), false))
+                .await;
+        }
+        assert_eq!(Step::Precommit, node.step);
+        assert_eq!(Some((0, block.clone())), node.locked);
+        assert_eq!(Some(&hash), node.precommits[&0].get(&peers[0]));
+
+        // the rest of the nodes precommit nil, so the round times out without a decision
+        for peer in &peers[1..] {
+            node.handle_message(vote(*peer, 0, None, true)).await;
+        }
+        assert!(node.precommit_timeout_scheduled);
+        node.handle_timeout(Timeout::Precommit, 1, 0).await;
+        assert_eq!(1, node.round);
+        assert_eq!(Step::Propose, node.step);
+
+        // while locked, the node prevotes nil for a different block
+        let other = Block::new(peers[2], node.ledger.tip(), vec![]);
+        node.handle_message(Proposal {
+            from: peers[2],
+            height: 1,
+            round: 1,
+            block: other,
+            valid_round: None,
+        })
+        .await;
+        assert_eq!(Some(&None), node.prevotes[&1].get(&peers[0]));
+
+        // enough nodes moving on to a later round make the node skip to it
+        for peer in &peers[1..3] {
+            node.handle_message(vote(*peer, 5, None, false)).await;
+        }
+        assert_eq!(5, node.round);
+
+        // the proposer of the new round re-proposes the block, which got a quorum of prevotes in
+        // the round the node locked it, so the node prevotes it
+        node.handle_message(Proposal {
+            from: peers[2],
+            height: 1,
+            round: 5,
+            block: block.clone(),
+            valid_round: Some(0),
+        })
+        .await;
+        assert_eq!(Some(&hash), node.prevotes[&5].get(&peers[0]));
+
+        // a quorum of precommits for the block decides it
+        for peer in &peers[1..] {
+            node.handle_message(vote(*peer, 5, hash.clone(), true))
+                .await;
+        }
+        assert_eq!(Step::NewHeight, node.step);
+        assert_eq!(block, *node.ledger.tip());
+        assert_eq!(2, node.height);
+        assert!(node.locked.is_none());
+    }
+}
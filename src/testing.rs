@@ -0,0 +1,161 @@
+/// This module contains the scaffolding shared by the tests of the consensus binaries: the addresses of
+/// local clusters, and the checks every protocol passes on a cluster of local nodes or on a simulated
+/// network. Each binary starts its own nodes and keeps its protocol-specific assertions.
+use crate::command::ClientCommand;
+use crate::network::simulator::SimulatedNetwork;
+use crate::network::{ReliableSender, RequestSender};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+
+/// The time between the attempts of the checks that wait for the nodes to apply a command.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The tasks running a node: the node itself, and its network and client listeners.
+pub type NodeHandles = (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>);
+
+/// The network and client addresses of the given number of local nodes, each using a pair of
+/// consecutive ports from the given one on.
+pub fn local_addresses(base_port: u16, size: u16) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    let address = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    (0..size)
+        .map(|i| (address(base_port + 2 * i), address(base_port + 2 * i + 1)))
+        .unzip()
+}
+
+/// Send a Get of the key through the given sender until the node replies with the expected value.
+/// Returns false if it doesn't after the given number of attempts.
+pub async fn get_eventually(
+    sender: &mut dyn RequestSender,
+    address: SocketAddr,
+    key: &str,
+    expected: &str,
+    attempts: usize,
+) -> bool {
+    for _ in 0..attempts {
+        let command = ClientCommand::Get {
+            key: key.to_string(),
+        };
+        match command.send_with(sender, address).await {
+            Ok(Some(value)) if value == expected => return true,
+            _ => sleep(RETRY_DELAY).await,
+        }
+    }
+    false
+}
+
+/// Wait until the value of the given key at the given node is the expected one.
+pub async fn eventually_get(address: SocketAddr, key: &str, expected: &str) {
+    let mut sender = ReliableSender::new();
+    assert!(
+        get_eventually(&mut sender, address, key, expected, 50).await,
+        "{key} was never {expected} at {address}"
+    );
+}
+
+/// Check that a single node answers a write once applied, and reads it back.
+pub async fn check_single_node(client_address: SocketAddr) {
+    let reply = ClientCommand::Set {
+        key: "k1".to_string(),
+        value: "v1".to_string(),
+    }
+    .send_to(client_address)
+    .await
+    .unwrap();
+    assert_eq!(Some("v1".to_string()), reply);
+
+    let reply = ClientCommand::Get {
+        key: "k1".to_string(),
+    }
+    .send_to(client_address)
+    .await
+    .unwrap();
+    assert_eq!(Some("v1".to_string()), reply);
+}
+
+/// Send an increment to each node in turn, answered once applied, and check that every node
+/// applies all of them.
+pub async fn check_replicated_increments(client_addresses: &[SocketAddr]) {
+    for (i, client_address) in client_addresses.iter().enumerate() {
+        let reply = ClientCommand::Increment {
+            key: "counter".to_string(),
+            by: 1,
+        }
+        .send_to(*client_address)
+        .await
+        .unwrap();
+        assert_eq!(Some((i + 1).to_string()), reply);
+    }
+
+    let count = client_addresses.len().to_string();
+    for client_address in client_addresses {
+        eventually_get(*client_address, "counter", &count).await;
+    }
+}
+
+/// Crash the last node, then check that the others still apply a batch sent to the first one.
+pub async fn check_crashed_node(client_addresses: &[SocketAddr], handles: &[NodeHandles]) {
+    let (node_handle, network_handle, client_handle) = handles.last().unwrap();
+    node_handle.abort();
+    network_handle.abort();
+    client_handle.abort();
+
+    let reply = ClientCommand::Batch {
+        commands: vec![
+            ClientCommand::Set {
+                key: "k1".to_string(),
+                value: "v1".to_string(),
+            },
+            ClientCommand::Increment {
+                key: "k2".to_string(),
+                by: 5,
+            },
+        ],
+    }
+    .send_to(client_addresses[0])
+    .await
+    .unwrap();
+    assert_eq!(Some("[v1, 5]".to_string()), reply);
+
+    for client_address in &client_addresses[..client_addresses.len() - 1] {
+        eventually_get(*client_address, "k1", "v1").await;
+        eventually_get(*client_address, "k2", "5").await;
+    }
+}
+
+/// Send a write to each node of the simulated network, retrying while it can't be applied, and check
+/// that every node eventually applies all of them. Failures name the seed of the network, to replay it.
+pub async fn check_simulated_writes(network: &SimulatedNetwork, client_addresses: &[SocketAddr]) {
+    let mut client = network.client();
+    for (i, client_address) in client_addresses.iter().enumerate() {
+        let command = ClientCommand::Set {
+            key: format!("k{i}"),
+            value: format!("v{i}"),
+        };
+        let reply = timeout(Duration::from_secs(60), async {
+            loop {
+                match command
+                    .clone()
+                    .send_with(&mut client, *client_address)
+                    .await
+                {
+                    Ok(reply) => break reply,
+                    Err(_) => sleep(RETRY_DELAY).await,
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("command never answered, seed {}", network.seed()));
+        assert_eq!(Some(format!("v{i}")), reply, "seed {}", network.seed());
+    }
+
+    let last = client_addresses.len() - 1;
+    let (key, value) = (format!("k{last}"), format!("v{last}"));
+    for client_address in client_addresses {
+        assert!(
+            get_eventually(&mut client, *client_address, &key, &value, 50).await,
+            "value was never applied at {client_address}, seed {}",
+            network.seed()
+        );
+    }
+}
@@ -0,0 +1,315 @@
+/// This module contains the coordinator side of two-phase commit. The coordinator receives client
+/// commands, splits them by shard into a distributed transaction and drives it through the prepare
+/// and commit phases. Decisions are logged to the coordinator's store before they are sent out, so
+/// in-doubt transactions can be completed after a coordinator crash.
+use crate::message::{Decision, Message, Reply, TransactionId};
+use anyhow::Result;
+use bytes::Bytes;
+use futures::future::join_all;
+use lib::command::{format_batch_results, ClientCommand, CommandResult};
+use lib::network::{ReliableSender, RequestSender};
+use lib::store::Store;
+use log::{error, info, warn};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout, Duration};
+
+/// The entries of the decision log are stored under this prefix.
+const DECISION_PREFIX: &str = "decision/";
+
+/// How long to wait before resending a decision that a participant failed to process.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How long a client waits for the participants to acknowledge the decision. Past this the client
+/// gets its reply anyway, and the decision keeps being delivered in the background.
+const PHASE_TWO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// An entry of the decision log. It is written before sending the prepare messages and updated
+/// once the decision is made; it is removed when all participants have acknowledged the decision.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    pub participants: Vec<SocketAddr>,
+    pub decision: Option<Decision>,
+}
+
+#[derive(Clone)]
+pub struct Coordinator {
+    pub address: SocketAddr,
+    pub store: Store,
+
+    /// The participants owning each shard of the key space.
+    participants: Vec<SocketAddr>,
+    /// How long to wait for the votes of the participants before aborting.
+    prepare_timeout: Duration,
+    pub sender: Box<dyn RequestSender>,
+
+    /// The source of the transaction ids.
+    pub rng: SmallRng,
+}
+
+impl Coordinator {
+    pub fn new(
+        db_path: &str,
+        address: SocketAddr,
+        participants: Vec<SocketAddr>,
+        prepare_timeout_ms: u64,
+    ) -> Self {
+        Self {
+            address,
+            store: Store::new(db_path).unwrap(),
+            participants,
+            prepare_timeout: Duration::from_millis(prepare_timeout_ms),
+            sender: Box::new(ReliableSender::new()),
+            rng: SmallRng::from_entropy(),
+        }
+    }
+
+    /// Runs the coordinator to process the client commands incoming in the given receiver.
+    /// Each command runs as a separate transaction, so commands on unrelated keys don't wait for
+    /// each other.
+    pub async fn run(
+        &mut self,
+        mut client_receiver: Receiver<(ClientCommand, oneshot::Sender<CommandResult>)>,
+    ) {
+        if let Err(error) = self.recover().await {
+            error!(
+                "{}: failed to recover the decision log: {}",
+                self.address, error
+            );
+        }
+
+        while let Some((command, reply_sender)) = client_receiver.recv().await {
+            info!("{}: Received client command {}", self.address, command);
+            // the id is drawn before spawning the transaction, so ids follow the order of the commands
+            let txid = uuid::Builder::from_random_bytes(self.rng.gen())
+                .into_uuid()
+                .to_string();
+            let mut coordinator = self.clone();
+            tokio::spawn(async move {
+                let result = coordinator
+                    .execute(txid, command)
+                    .await
+                    .unwrap_or_else(|error| {
+                        error!("{}: transaction failed: {}", coordinator.address, error);
+                        Err(error.to_string())
+                    });
+                if let Err(error) = reply_sender.send(result) {
+                    error!("failed to send client response {:?}", error);
+                }
+            });
+        }
+    }
+
+    /// Complete the transactions left in the log by a previous run. Transactions with no logged
+    /// decision may have participants waiting for it, so they are aborted: since the client never got
+    /// a reply for them, and no participant could have been told to commit, this is always safe.
+    async fn recover(&mut self) -> Result<()> {
+        let entries = self.store.scan(DECISION_PREFIX.into()).await?;
+        for (key, value) in entries {
+            let txid = String::from_utf8(key[DECISION_PREFIX.len()..].to_vec())?;
+            let mut record: TransactionRecord = bincode::deserialize(&value)?;
+            if record.decision.is_none() {
+                record.decision = Some(Decision::Abort);
+                self.log(&txid, &record).await?;
+            }
+            info!(
+                "{}: Recovered transaction {} with decision {:?}",
+                self.address, txid, record.decision
+            );
+
+            let mut coordinator = self.clone();
+            tokio::spawn(async move {
+                if let Err(error) = coordinator.complete(&txid, &record).await {
+                    error!(
+                        "{}: failed to complete {}: {}",
+                        coordinator.address, txid, error
+                    );
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Run the given command as a distributed transaction with the given id over the participants
+    /// owning its keys.
+    async fn execute(
+        &mut self,
+        txid: TransactionId,
+        command: ClientCommand,
+    ) -> Result<CommandResult> {
+        // each attempt at a session request runs as a new transaction, and the participants answer the
+        // retries of a committed one from their session table
+        let (session, command) = match command {
+            ClientCommand::Session {
+                client,
+                sequence,
+                command,
+            } => (Some((client, sequence)), *command),
+            command => (None, command),
+        };
+        let (commands, is_batch) = match command {
+            ClientCommand::Batch { commands } => (commands, true),
+            command => (vec![command], false),
+        };
+
+        // group the commands by participant, remembering their position to put the results back together
+        let mut shards: BTreeMap<SocketAddr, Vec<(usize, ClientCommand)>> = BTreeMap::new();
+        for (index, command) in commands.into_iter().enumerate() {
+            let mut owners: Vec<SocketAddr> =
+                command.keys().iter().map(|key| self.owner(key)).collect();
+            owners.dedup();
+            if owners.len() != 1 {
+                return Ok(Err(
+                    "nested batches must only involve keys of a single shard".to_string(),
+                ));
+            }
+            shards.entry(owners[0]).or_default().push((index, command));
+        }
+
+        let mut record = TransactionRecord {
+            participants: shards.keys().cloned().collect(),
+            decision: None,
+        };
+        self.log(&txid, &record).await?;
+
+        let outcome = self.prepare(&txid, session, &shards).await;
+
+        let decision = if outcome.is_ok() {
+            Decision::Commit
+        } else {
+            Decision::Abort
+        };
+        // once the decision is logged the transaction will eventually complete, even if the coordinator crashes
+        record.decision = Some(decision);
+        self.log(&txid, &record).await?;
+        info!(
+            "{}: Transaction {} decision {:?}",
+            self.address, txid, decision
+        );
+
+        let mut coordinator = self.clone();
+        let completion = tokio::spawn(async move { coordinator.complete(&txid, &record).await });
+        match timeout(PHASE_TWO_TIMEOUT, completion).await {
+            Ok(Ok(Err(error))) => error!(
+                "{}: failed to complete transaction: {}",
+                self.address, error
+            ),
+            Ok(_) => {}
+            Err(_) => warn!(
+                "{}: transaction decision not yet acknowledged",
+                self.address
+            ),
+        }
+
+        let result = outcome
+            .map(|results| {
+                if is_batch {
+                    Some(format_batch_results(results))
+                } else {
+                    results.into_iter().next().flatten()
+                }
+            })
+            .map_err(|reason| format!("transaction aborted: {reason}"));
+        Ok(result)
+    }
+
+    /// First phase: send the commands of each participant and collect their votes. Returns the
+    /// results of every command in their original order if all participants voted to commit.
+    async fn prepare(
+        &mut self,
+        txid: &TransactionId,
+        session: Option<(u64, u64)>,
+        shards: &BTreeMap<SocketAddr, Vec<(usize, ClientCommand)>>,
+    ) -> Result<Vec<Option<String>>, String> {
+        let mut handlers = Vec::new();
+        for (participant, commands) in shards {
+            let message = Message::Prepare {
+                txid: txid.clone(),
+                commands: commands
+                    .iter()
+                    .map(|(_, command)| command.clone())
+                    .collect(),
+                session,
+            };
+            let message: Bytes = bincode::serialize(&message).unwrap().into();
+            let handler = self.sender.send(*participant, message).await;
+            handlers.push(timeout(self.prepare_timeout, handler));
+        }
+
+        let mut results = vec![None; shards.values().map(Vec::len).sum()];
+        for (votes, commands) in join_all(handlers).await.into_iter().zip(shards.values()) {
+            let votes = match votes {
+                Ok(Ok(reply)) => match bincode::deserialize(&reply) {
+                    Ok(Reply::Vote(votes)) => votes?,
+                    Ok(reply) => return Err(format!("unexpected reply {reply:?}")),
+                    Err(error) => return Err(error.to_string()),
+                },
+                Ok(Err(error)) => return Err(error.to_string()),
+                Err(_) => return Err("timed out waiting for votes".to_string()),
+            };
+            for ((index, _), result) in commands.iter().zip(votes) {
+                results[*index] = result;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Second phase: deliver the logged decision to every participant of the transaction until all of
+    /// them acknowledge it, then forget the transaction.
+    async fn complete(&mut self, txid: &TransactionId, record: &TransactionRecord) -> Result<()> {
+        let message = match record.decision {
+            Some(Decision::Commit) => Message::Commit { txid: txid.clone() },
+            _ => Message::Abort { txid: txid.clone() },
+        };
+        let message: Bytes = bincode::serialize(&message)?.into();
+
+        let mut handlers = Vec::new();
+        for participant in &record.participants {
+            let mut sender = self.sender.clone();
+            let message = message.clone();
+            let participant = *participant;
+            handlers.push(async move {
+                // the sender retries until the participant is reachable, but the participant may still fail
+                // to apply the decision, in which case it has to be sent again
+                loop {
+                    let handler = sender.send(participant, message.clone()).await;
+                    match handler.await.map(|reply| bincode::deserialize(&reply)) {
+                        Ok(Ok(Reply::Ack)) => break,
+                        reply => {
+                            warn!("{}: decision not acknowledged: {:?}", participant, reply);
+                            sleep(RETRY_DELAY).await;
+                        }
+                    }
+                }
+            });
+        }
+        join_all(handlers).await;
+
+        self.store.delete(decision_key(txid)).await?;
+        Ok(())
+    }
+
+    pub async fn log(&self, txid: &TransactionId, record: &TransactionRecord) -> Result<()> {
+        self.store
+            .write(decision_key(txid), bincode::serialize(record)?)
+            .await?;
+        Ok(())
+    }
+
+    /// The participant that owns the given key.
+    fn owner(&self, key: &str) -> SocketAddr {
+        let hash = Sha256::digest(key.as_bytes());
+        let hash = u64::from_be_bytes(hash[..8].try_into().unwrap());
+        self.participants[(hash % self.participants.len() as u64) as usize]
+    }
+}
+
+pub fn decision_key(txid: &str) -> Vec<u8> {
+    format!("{DECISION_PREFIX}{txid}").into_bytes()
+}
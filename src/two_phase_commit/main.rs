@@ -0,0 +1,348 @@
+use crate::coordinator::Coordinator;
+use crate::participant::Participant;
+/// This module is a binary that runs either the coordinator or one of the participants of a sharded
+/// key/value store with transactions implemented by two-phase commit.
+use clap::Parser;
+use lib::network::Receiver;
+use log::info;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::task::JoinHandle;
+
+mod coordinator;
+mod message;
+mod participant;
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct Cli {
+    /// The client port of the coordinator where to send txs.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 6100)]
+    client_port: u16,
+    /// The network port where participants receive coordinator messages.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 6200)]
+    network_port: u16,
+    /// Node Address
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    address: IpAddr,
+    /// The network addresses of the participants, one per shard. If set the node runs as the
+    /// coordinator, otherwise as a participant.
+    #[clap(
+        long,
+        value_parser,
+        value_name = "ADDR",
+        use_value_delimiter = true,
+        value_delimiter = ' '
+    )]
+    participants: Vec<SocketAddr>,
+    /// How long the coordinator waits for the participant votes before aborting a transaction.
+    #[clap(long, value_parser, value_name = "UINT", default_value_t = 1000)]
+    prepare_timeout_ms: u64,
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    let cli = Cli::parse();
+
+    simple_logger::SimpleLogger::new()
+        .env()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    if cli.participants.is_empty() {
+        let address = SocketAddr::new(cli.address, cli.network_port);
+        info!("Participant: Running on {}", address);
+
+        let participant = Participant::new(&format!(".db_2pc_{}", address.port()), address);
+        let (_, network_handle) = spawn_participant_tasks(address, participant).await;
+        network_handle.await.unwrap();
+    } else {
+        let address = SocketAddr::new(cli.address, cli.client_port);
+        info!(
+            "Coordinator: Running on {}, participants {:?}",
+            address, cli.participants
+        );
+
+        let coordinator = Coordinator::new(
+            &format!(".db_2pc_coordinator_{}", address.port()),
+            address,
+            cli.participants,
+            cli.prepare_timeout_ms,
+        );
+        let (_, client_handle) = spawn_coordinator_tasks(address, coordinator).await;
+        client_handle.await.unwrap();
+    }
+}
+
+async fn spawn_coordinator_tasks(
+    client_address: SocketAddr,
+    mut coordinator: Coordinator,
+) -> (JoinHandle<()>, JoinHandle<()>) {
+    // listen for client command tcp connections
+    let (client_tcp_receiver, client_channel_receiver) = Receiver::new(client_address);
+    let client_handle = tokio::spawn(async move {
+        client_tcp_receiver.run().await;
+    });
+
+    let coordinator_handle = tokio::spawn(async move {
+        coordinator.run(client_channel_receiver).await;
+    });
+
+    (coordinator_handle, client_handle)
+}
+
+async fn spawn_participant_tasks(
+    network_address: SocketAddr,
+    mut participant: Participant,
+) -> (JoinHandle<()>, JoinHandle<()>) {
+    // listen for coordinator tcp connections
+    let (network_tcp_receiver, network_channel_receiver) = Receiver::new(network_address);
+    let network_handle = tokio::spawn(async move {
+        network_tcp_receiver.run().await;
+    });
+
+    let participant_handle = tokio::spawn(async move {
+        participant.run(network_channel_receiver).await;
+    });
+
+    (participant_handle, network_handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinator::{decision_key, TransactionRecord};
+    use crate::message::{Decision, Message, Reply};
+    use bytes::Bytes;
+    use lib::command::ClientCommand;
+    use lib::network::simulator::{SimulatedNetwork, SimulatorConfig};
+    use lib::network::ReliableSender;
+    use std::fs;
+    use tokio::time::{sleep, Duration};
+
+    #[ctor::ctor]
+    fn init() {
+        simple_logger::SimpleLogger::new().env().init().unwrap();
+
+        fs::remove_dir_all(db_path("")).unwrap_or_default();
+    }
+
+    fn db_path(suffix: &str) -> String {
+        format!(".db_test_2pc/{suffix}")
+    }
+
+    fn set(key: &str, value: &str) -> ClientCommand {
+        ClientCommand::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn get(key: &str) -> ClientCommand {
+        ClientCommand::Get {
+            key: key.to_string(),
+        }
+    }
+
+    /// Start the given number of participants on consecutive ports after the given one, and return
+    /// their addresses.
+    async fn start_participants(base_port: u16, count: u16) -> Vec<SocketAddr> {
+        let mut addresses = Vec::new();
+        for i in 1..=count {
+            let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), base_port + i);
+            let participant = Participant::new(&db_path(&address.port().to_string()), address);
+            spawn_participant_tasks(address, participant).await;
+            addresses.push(address);
+        }
+        addresses
+    }
+
+    /// Start a coordinator on the given port.
+    async fn start_coordinator(port: u16, participants: Vec<SocketAddr>) -> SocketAddr {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        let coordinator =
+            Coordinator::new(&db_path(&port.to_string()), address, participants, 1000);
+        spawn_coordinator_tasks(address, coordinator).await;
+        sleep(Duration::from_millis(10)).await;
+        address
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_across_shards() {
+        let participants = start_participants(7300, 3).await;
+        let coordinator = start_coordinator(7300, participants).await;
+
+        let batch = ClientCommand::Batch {
+            commands: vec![
+                set("a", "1"),
+                set("b", "2"),
+                set("c", "3"),
+                set("d", "4"),
+                ClientCommand::Increment {
+                    key: "a".to_string(),
+                    by: 10,
+                },
+                get("e"),
+            ],
+        };
+        let reply = batch.send_to(coordinator).await.unwrap();
+        assert_eq!(Some("[1, 2, 3, 4, 11, null]".to_string()), reply);
+
+        for (key, value) in [("a", "11"), ("b", "2"), ("c", "3"), ("d", "4")] {
+            let reply = get(key).send_to(coordinator).await.unwrap();
+            assert_eq!(Some(value.to_string()), reply);
+        }
+
+        let reply = set("b", "20").send_to(coordinator).await.unwrap();
+        assert_eq!(Some("20".to_string()), reply);
+        let reply = get("b").send_to(coordinator).await.unwrap();
+        assert_eq!(Some("20".to_string()), reply);
+    }
+
+    /// Run a coordinator and its participants on a simulated network with the given seed, storing their
+    /// data under the given name, and check that a batch over every shard commits. Returns the trace of
+    /// the network.
+    async fn run_simulated(seed: Option<u64>, name: &str) -> Vec<String> {
+        // the coordinator waits for a reply to every request, so requests are never dropped but are
+        // delayed and reordered
+        let default = SimulatorConfig::default();
+        let network = SimulatedNetwork::new(SimulatorConfig {
+            seed: seed.unwrap_or(default.seed),
+            reorder: true,
+            ..default
+        });
+
+        let mut participants = Vec::new();
+        for i in 1..=3 {
+            let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 7324 + i);
+            let mut participant =
+                Participant::new(&db_path(&format!("{name}_{}", address.port())), address);
+            let (_, network_channel) = network.listen(address);
+            tokio::spawn(async move { participant.run(network_channel).await });
+            participants.push(address);
+        }
+
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 7324);
+        let mut coordinator = Coordinator::new(
+            &db_path(&format!("{name}_7324")),
+            address,
+            participants,
+            1000,
+        );
+        coordinator.sender = Box::new(network.sender(address));
+        coordinator.rng = network.rng(address);
+        let (_, client_channel) = network.listen(address);
+        tokio::spawn(async move { coordinator.run(client_channel).await });
+
+        let mut client = network.client();
+        let batch = ClientCommand::Batch {
+            commands: vec![set("a", "1"), set("b", "2"), set("c", "3"), set("d", "4")],
+        };
+        let reply = batch.send_with(&mut client, address).await.unwrap();
+        assert_eq!(
+            Some("[1, 2, 3, 4]".to_string()),
+            reply,
+            "seed {}",
+            network.seed()
+        );
+
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")] {
+            let reply = get(key).send_with(&mut client, address).await.unwrap();
+            assert_eq!(Some(value.to_string()), reply, "seed {}", network.seed());
+        }
+        network.trace()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_simulated_network() {
+        run_simulated(None, "sim").await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_seed() {
+        let trace = run_simulated(Some(42), "replay_a").await;
+        assert_eq!(trace, run_simulated(Some(42), "replay_b").await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_abort_on_rejected_command() {
+        let participants = start_participants(7310, 3).await;
+        let coordinator = start_coordinator(7310, participants).await;
+
+        set("a", "1").send_to(coordinator).await.unwrap();
+
+        // the compare-and-swap fails on one shard so none of the writes is applied
+        let batch = ClientCommand::Batch {
+            commands: vec![
+                set("b", "2"),
+                set("c", "3"),
+                ClientCommand::CompareAndSwap {
+                    key: "a".to_string(),
+                    expected: "0".to_string(),
+                    new: "2".to_string(),
+                },
+            ],
+        };
+        assert!(batch.send_to(coordinator).await.is_err());
+
+        assert_eq!(None, get("b").send_to(coordinator).await.unwrap());
+        assert_eq!(None, get("c").send_to(coordinator).await.unwrap());
+        assert_eq!(
+            Some("1".to_string()),
+            get("a").send_to(coordinator).await.unwrap()
+        );
+
+        // the locks were released, so the keys can be written again
+        let reply = set("b", "2").send_to(coordinator).await.unwrap();
+        assert_eq!(Some("2".to_string()), reply);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_coordinator_recovery() {
+        let participants = start_participants(7320, 1).await;
+        let participant = participants[0];
+
+        // prepare two transactions as if the coordinator crashed after logging the decision of
+        // the first one, but before deciding the second one
+        let mut sender = ReliableSender::new();
+        for (txid, key) in [("tx1", "a"), ("tx2", "b")] {
+            let message = Message::Prepare {
+                txid: txid.to_string(),
+                commands: vec![set(key, "1")],
+                session: None,
+            };
+            let message: Bytes = bincode::serialize(&message).unwrap().into();
+            let reply = sender.send(participant, message).await.await.unwrap();
+            let reply: Reply = bincode::deserialize(&reply).unwrap();
+            assert!(matches!(reply, Reply::Vote(Ok(_))));
+        }
+
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 7320);
+        let coordinator = Coordinator::new(&db_path("7320"), address, participants.clone(), 1000);
+        for (txid, decision) in [("tx1", Some(Decision::Commit)), ("tx2", None)] {
+            let record = TransactionRecord {
+                participants: participants.clone(),
+                decision,
+            };
+            coordinator.log(&txid.to_string(), &record).await.unwrap();
+        }
+        let store = coordinator.store.clone();
+        spawn_coordinator_tasks(address, coordinator).await;
+
+        // the decided transaction is committed and the undecided one aborted
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            Some("1".to_string()),
+            get("a").send_to(address).await.unwrap()
+        );
+        assert_eq!(None, get("b").send_to(address).await.unwrap());
+
+        // the aborted transaction no longer holds its lock
+        let reply = set("b", "2").send_to(address).await.unwrap();
+        assert_eq!(Some("2".to_string()), reply);
+
+        // and the log was cleaned up
+        assert!(store.read(decision_key("tx1")).await.unwrap().is_none());
+        assert!(store.read(decision_key("tx2")).await.unwrap().is_none());
+    }
+}
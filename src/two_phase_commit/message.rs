@@ -0,0 +1,49 @@
+use std::fmt;
+
+use lib::command::ClientCommand;
+use serde::{Deserialize, Serialize};
+
+pub type TransactionId = String;
+
+/// The messages sent by the coordinator to the participants.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Message {
+    /// First phase: ask the participant to stage the given commands and lock their keys.
+    /// The participant answers with a `Reply::Vote`.
+    Prepare {
+        txid: TransactionId,
+        commands: Vec<ClientCommand>,
+        /// The client and sequence number of the request, if it was sent in a client session, so a
+        /// retried request is only committed once.
+        session: Option<(u64, u64)>,
+    },
+    /// Second phase: make the staged writes of the transaction visible and release its locks.
+    Commit { txid: TransactionId },
+    /// Second phase: drop the staged writes of the transaction and release its locks.
+    Abort { txid: TransactionId },
+}
+
+/// The responses of the participants to the coordinator messages.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// A participant's vote on a prepared transaction: the result of each of its commands if it
+    /// can commit, or the reason why it can't.
+    Vote(Result<Vec<Option<String>>, String>),
+    /// Acknowledges a commit or abort decision.
+    Ack,
+    /// The participant failed to process the message, e.g. because of a store error.
+    Failed(String),
+}
+
+/// The outcome of a transaction, as recorded in the coordinator's decision log.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
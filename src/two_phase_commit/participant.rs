@@ -0,0 +1,422 @@
+/// This module contains the participant side of two-phase commit. Participants own a shard of the
+/// key/value store: on prepare they evaluate the commands of a transaction against their store, stage
+/// the resulting writes and lock the keys involved until the coordinator's decision arrives.
+use crate::message::{Message, Reply, TransactionId};
+use anyhow::Result;
+use lib::command::{KeyValues, SESSION_PREFIX};
+use lib::store::Store;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+
+/// Staged transactions are stored under this prefix. It can't collide with client keys since those are
+/// valid UTF-8 strings, and the 0xff byte never appears in UTF-8.
+const STAGED_PREFIX: &[u8] = b"\xffstaged/";
+
+/// A prepared transaction waiting for the coordinator's decision.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StagedTransaction {
+    /// The keys locked by the transaction.
+    keys: Vec<String>,
+    /// The writes to apply on commit, `None` meaning the key is deleted.
+    writes: Vec<(String, Option<String>)>,
+    /// The result of each command, kept to answer duplicate prepare messages.
+    results: Vec<Option<String>>,
+    /// The client and sequence number of the request, if it was sent in a client session.
+    session: Option<(u64, u64)>,
+}
+
+pub struct Participant {
+    pub address: SocketAddr,
+    pub store: Store,
+
+    /// The prepared transactions, also persisted in the store so they survive restarts.
+    staged: HashMap<TransactionId, StagedTransaction>,
+
+    /// The keys held by prepared transactions. A transaction that needs a locked key votes to abort
+    /// instead of waiting, so transactions can't deadlock.
+    locks: HashMap<String, TransactionId>,
+
+    /// The last committed request of each client session and the results of its commands, also
+    /// persisted in the store, to answer the retries of the request without running it again.
+    sessions: HashMap<u64, (u64, Vec<Option<String>>)>,
+}
+
+use Message::*;
+
+impl Participant {
+    pub fn new(db_path: &str, address: SocketAddr) -> Self {
+        Self {
+            address,
+            store: Store::new(db_path).unwrap(),
+            staged: HashMap::new(),
+            locks: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Runs the participant to process the coordinator messages incoming in the given receiver.
+    pub async fn run(&mut self, mut network_receiver: Receiver<(Message, oneshot::Sender<Reply>)>) {
+        if let Err(error) = self.restore().await {
+            error!(
+                "{}: failed to restore staged transactions: {}",
+                self.address, error
+            );
+        }
+
+        while let Some((message, reply_sender)) = network_receiver.recv().await {
+            info!("{}: Received network message {}", self.address, message);
+            let reply = self
+                .handle_message(message.clone())
+                .await
+                .unwrap_or_else(|error| {
+                    error!("{}: failed to handle message: {}", self.address, error);
+                    Reply::Failed(error.to_string())
+                });
+            if let Err(error) = reply_sender.send(reply) {
+                error!("failed to send message {:?} response {:?}", message, error);
+            }
+        }
+    }
+
+    /// Reload the transactions that were prepared before a restart and lock their keys again:
+    /// they remain in doubt until the coordinator sends its decision. The client sessions are
+    /// reloaded too.
+    async fn restore(&mut self) -> Result<()> {
+        for (key, value) in self.store.scan(SESSION_PREFIX.to_vec()).await? {
+            let client = u64::from_be_bytes(key[SESSION_PREFIX.len()..].try_into()?);
+            self.sessions.insert(client, bincode::deserialize(&value)?);
+        }
+        for (key, value) in self.store.scan(STAGED_PREFIX.to_vec()).await? {
+            let txid = String::from_utf8(key[STAGED_PREFIX.len()..].to_vec())?;
+            let staged: StagedTransaction = bincode::deserialize(&value)?;
+            info!("{}: Restored in-doubt transaction {}", self.address, txid);
+            self.lock(&txid, &staged);
+            self.staged.insert(txid, staged);
+        }
+        Ok(())
+    }
+
+    pub async fn handle_message(&mut self, message: Message) -> Result<Reply> {
+        match message {
+            Prepare {
+                txid,
+                commands,
+                session,
+            } => self.prepare(txid, commands, session).await,
+            Commit { txid } => {
+                if let Some(staged) = self.staged.get(&txid) {
+                    // apply the writes and forget the transaction in a single atomic write
+                    let mut entries: Vec<(Vec<u8>, Option<Vec<u8>>)> = staged
+                        .writes
+                        .iter()
+                        .map(|(key, value)| {
+                            (
+                                key.clone().into_bytes(),
+                                value.clone().map(String::into_bytes),
+                            )
+                        })
+                        .collect();
+                    entries.push((staged_key(&txid), None));
+                    let session = staged
+                        .session
+                        .map(|(client, sequence)| (client, (sequence, staged.results.clone())));
+                    if let Some((client, entry)) = &session {
+                        entries.push((session_key(*client), Some(bincode::serialize(entry)?)));
+                    }
+                    self.store.write_batch(entries).await?;
+
+                    self.sessions.extend(session);
+                    self.release(&txid);
+                }
+                // an unknown transaction was already committed
+                Ok(Reply::Ack)
+            }
+            Abort { txid } => {
+                if self.staged.contains_key(&txid) {
+                    self.store.delete(staged_key(&txid)).await?;
+                    self.release(&txid);
+                }
+                // an unknown transaction was never prepared or was already aborted
+                Ok(Reply::Ack)
+            }
+        }
+    }
+
+    /// Evaluate the commands against the current store and, if they all succeed and none of their keys
+    /// is locked, persist the resulting writes and lock the keys before voting to commit.
+    async fn prepare(
+        &mut self,
+        txid: TransactionId,
+        commands: Vec<lib::command::ClientCommand>,
+        session: Option<(u64, u64)>,
+    ) -> Result<Reply> {
+        // the coordinator may retransmit its messages
+        if let Some(staged) = self.staged.get(&txid) {
+            return Ok(Reply::Vote(Ok(staged.results.clone())));
+        }
+        if let Some(vote) =
+            session.and_then(|(client, sequence)| self.session_vote(client, sequence))
+        {
+            return Ok(Reply::Vote(vote));
+        }
+
+        let mut keys: Vec<String> = commands
+            .iter()
+            .flat_map(|command| command.keys())
+            .map(String::from)
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        if let Some(key) = keys.iter().find(|key| self.locks.contains_key(*key)) {
+            return Ok(Reply::Vote(Err(format!(
+                "key {key} is locked by another transaction"
+            ))));
+        }
+
+        let mut state = KeyValues::new();
+        for key in &keys {
+            let value = match self.store.read(key.clone().into_bytes()).await? {
+                Some(value) => Some(String::from_utf8(value)?),
+                None => None,
+            };
+            state.insert(key.clone(), value);
+        }
+
+        let mut updated = state.clone();
+        let mut results = Vec::new();
+        for command in &commands {
+            match command.execute(&mut updated) {
+                Ok(result) => results.push(result),
+                Err(error) => return Ok(Reply::Vote(Err(error))),
+            }
+        }
+
+        let writes = updated
+            .into_iter()
+            .filter(|(key, value)| state.get(key) != Some(value))
+            .collect();
+        let staged = StagedTransaction {
+            keys,
+            writes,
+            results: results.clone(),
+            session,
+        };
+
+        // the vote is a promise to commit if asked to, so it has to survive a restart
+        self.store
+            .write(staged_key(&txid), bincode::serialize(&staged)?)
+            .await?;
+        self.lock(&txid, &staged);
+        self.staged.insert(txid, staged);
+
+        Ok(Reply::Vote(Ok(results)))
+    }
+
+    /// The vote on a retry of the given session request, if it doesn't run as a new transaction: the
+    /// results of the request if it was committed, or an error for an older request or for one whose
+    /// previous attempt is still in doubt, since that attempt may commit.
+    fn session_vote(
+        &self,
+        client: u64,
+        sequence: u64,
+    ) -> Option<Result<Vec<Option<String>>, String>> {
+        if self
+            .staged
+            .values()
+            .any(|staged| staged.session == Some((client, sequence)))
+        {
+            return Some(Err(format!(
+                "request {sequence} of client {client} is in progress"
+            )));
+        }
+        let (last, results) = self.sessions.get(&client)?;
+        if sequence == *last {
+            Some(Ok(results.clone()))
+        } else if sequence < *last {
+            Some(Err(format!(
+                "request {sequence} of client {client} was already answered"
+            )))
+        } else {
+            None
+        }
+    }
+
+    fn lock(&mut self, txid: &TransactionId, staged: &StagedTransaction) {
+        for key in &staged.keys {
+            self.locks.insert(key.clone(), txid.clone());
+        }
+    }
+
+    fn release(&mut self, txid: &TransactionId) {
+        if let Some(staged) = self.staged.remove(txid) {
+            for key in staged.keys {
+                self.locks.remove(&key);
+            }
+        }
+    }
+}
+
+fn staged_key(txid: &str) -> Vec<u8> {
+    [STAGED_PREFIX, txid.as_bytes()].concat()
+}
+
+fn session_key(client: u64) -> Vec<u8> {
+    [SESSION_PREFIX, &client.to_be_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::command::ClientCommand;
+    use std::fs;
+
+    fn set(key: &str, value: &str) -> ClientCommand {
+        ClientCommand::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn prepare_commit_abort() {
+        let path = ".db_test_2pc_participant";
+        fs::remove_dir_all(path).unwrap_or_default();
+        let address: SocketAddr = "127.0.0.1:7290".parse().unwrap();
+        let mut participant = Participant::new(path, address);
+
+        let prepare = Prepare {
+            txid: "tx1".to_string(),
+            commands: vec![set("a", "1"), set("b", "2")],
+            session: None,
+        };
+        let reply = participant.handle_message(prepare.clone()).await.unwrap();
+        assert_eq!(
+            Reply::Vote(Ok(vec![Some("1".to_string()), Some("2".to_string())])),
+            reply
+        );
+
+        // writes are staged, not visible yet
+        assert!(participant.store.read("a".into()).await.unwrap().is_none());
+
+        // duplicate prepares get the same vote
+        assert_eq!(reply, participant.handle_message(prepare).await.unwrap());
+
+        // the keys are locked until the decision arrives
+        let conflicting = Prepare {
+            txid: "tx2".to_string(),
+            commands: vec![set("b", "3")],
+            session: None,
+        };
+        let reply = participant.handle_message(conflicting).await.unwrap();
+        assert!(matches!(reply, Reply::Vote(Err(_))));
+
+        // staged transactions survive a restart
+        let mut restarted = Participant::new(path, address);
+        restarted.restore().await.unwrap();
+        assert!(restarted.locks.contains_key("a"));
+
+        let commit = Commit {
+            txid: "tx1".to_string(),
+        };
+        assert_eq!(Reply::Ack, restarted.handle_message(commit).await.unwrap());
+        assert_eq!(
+            Some("1".into()),
+            restarted.store.read("a".into()).await.unwrap()
+        );
+        assert!(restarted.locks.is_empty());
+        assert!(restarted
+            .store
+            .scan(STAGED_PREFIX.to_vec())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // a rejected command votes to abort
+        let prepare = Prepare {
+            txid: "tx3".to_string(),
+            commands: vec![
+                set("c", "1"),
+                ClientCommand::CompareAndSwap {
+                    key: "a".to_string(),
+                    expected: "0".to_string(),
+                    new: "2".to_string(),
+                },
+            ],
+            session: None,
+        };
+        let reply = restarted.handle_message(prepare).await.unwrap();
+        assert!(matches!(reply, Reply::Vote(Err(_))));
+
+        // abort drops the staged writes
+        let prepare = Prepare {
+            txid: "tx4".to_string(),
+            commands: vec![set("c", "1")],
+            session: None,
+        };
+        restarted.handle_message(prepare).await.unwrap();
+        let abort = Abort {
+            txid: "tx4".to_string(),
+        };
+        assert_eq!(Reply::Ack, restarted.handle_message(abort).await.unwrap());
+        assert!(restarted.store.read("c".into()).await.unwrap().is_none());
+        assert!(restarted.locks.is_empty());
+    }
+    #[tokio::test]
+    async fn commit_session_requests_once() {
+        let path = ".db_test_2pc_participant_sessions";
+        fs::remove_dir_all(path).unwrap_or_default();
+        let address: SocketAddr = "127.0.0.1:7291".parse().unwrap();
+        let mut participant = Participant::new(path, address);
+
+        // each attempt at the request is a separate transaction
+        let attempt = |txid: &str, sequence| Prepare {
+            txid: txid.to_string(),
+            commands: vec![ClientCommand::Increment {
+                key: "counter".to_string(),
+                by: 1,
+            }],
+            session: Some((1, sequence)),
+        };
+        let one = Reply::Vote(Ok(vec![Some("1".to_string())]));
+        assert_eq!(
+            one,
+            participant.handle_message(attempt("tx1", 1)).await.unwrap()
+        );
+
+        // a retry can't run while the first attempt is in doubt
+        let reply = participant.handle_message(attempt("tx2", 1)).await.unwrap();
+        assert!(matches!(reply, Reply::Vote(Err(_))));
+
+        // once the first attempt is committed, the retries get its results without running it again,
+        // even after a restart
+        let commit = Commit {
+            txid: "tx1".to_string(),
+        };
+        participant.handle_message(commit).await.unwrap();
+        drop(participant);
+        let mut restarted = Participant::new(path, address);
+        restarted.restore().await.unwrap();
+        assert_eq!(
+            one,
+            restarted.handle_message(attempt("tx3", 1)).await.unwrap()
+        );
+        assert!(restarted.staged.is_empty());
+        assert_eq!(
+            Some("1".as_bytes().to_vec()),
+            restarted.store.read("counter".into()).await.unwrap()
+        );
+
+        // older requests are no longer answered, and newer ones run as usual
+        let reply = restarted.handle_message(attempt("tx4", 0)).await.unwrap();
+        assert!(matches!(reply, Reply::Vote(Err(_))));
+        assert_eq!(
+            Reply::Vote(Ok(vec![Some("2".to_string())])),
+            restarted.handle_message(attempt("tx5", 2)).await.unwrap()
+        );
+    }
+}
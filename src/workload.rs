@@ -0,0 +1,319 @@
+/// A workload generator that drives concurrent clients against the nodes of any of the key/value stores
+/// of this workbench for a fixed time, and reports the throughput and latency percentiles of the commands,
+/// to compare the different replication strategies under the same load.
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use lib::command::ClientCommand;
+use lib::network::ReliableSender;
+use log::info;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct Cli {
+    /// The client addresses of the nodes where to send commands, separated by commas. Clients are assigned
+    /// to them in turns.
+    #[clap(long, value_delimiter = ',', default_value = "127.0.0.1:6100")]
+    nodes: Vec<SocketAddr>,
+
+    /// The number of concurrent clients. Each one waits for the reply to a command before sending the next.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 4)]
+    clients: usize,
+
+    /// How long to run the workload, in seconds.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 10)]
+    duration: u64,
+
+    /// The maximum number of commands per second sent by all clients together. Unlimited if not set.
+    #[clap(short, long, value_parser = parse_rate, value_name = "FLOAT")]
+    rate: Option<f64>,
+
+    /// The fraction of commands that are reads, from 0 to 1. The rest are sets.
+    #[clap(long, value_parser = parse_ratio, value_name = "FLOAT", default_value_t = 0.5)]
+    read_ratio: f64,
+
+    /// The number of different keys the commands use.
+    #[clap(short, long, value_parser, value_name = "UINT", default_value_t = 100)]
+    keys: usize,
+
+    /// How keys are picked for each command.
+    #[clap(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    /// The exponent of the zipfian distribution, the higher the more commands go to the first keys.
+    #[clap(long, value_parser, value_name = "FLOAT", default_value_t = 0.99)]
+    zipf_exponent: f64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Distribution {
+    Uniform,
+    Zipfian,
+}
+
+fn parse_ratio(ratio: &str) -> Result<f64> {
+    let ratio: f64 = ratio.parse()?;
+    if (0.0..=1.0).contains(&ratio) {
+        Ok(ratio)
+    } else {
+        Err(anyhow!("ratio must be between 0 and 1"))
+    }
+}
+
+/// Rates whose period between commands can't be a `Duration` of at least a nanosecond are rejected.
+fn parse_rate(rate: &str) -> Result<f64> {
+    let rate: f64 = rate.parse()?;
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(anyhow!("rate must be a positive number"));
+    }
+    match Duration::try_from_secs_f64(1.0 / rate) {
+        Ok(period) if period >= Duration::from_nanos(1) => Ok(rate),
+        _ => Err(anyhow!(
+            "rate must be at most one command per nanosecond and at least one every {} seconds",
+            u64::MAX
+        )),
+    }
+}
+
+/// Picks the index of the key of each command.
+enum KeyPicker {
+    Uniform(usize),
+    /// The cumulative probabilities of the keys, the one of key `i` proportional to `1 / (i + 1)^s`.
+    Zipfian(Vec<f64>),
+}
+
+impl KeyPicker {
+    fn new(keys: usize, distribution: Distribution, exponent: f64) -> Self {
+        match distribution {
+            Distribution::Uniform => Self::Uniform(keys),
+            Distribution::Zipfian => {
+                let weights: Vec<f64> = (1..=keys).map(|i| (i as f64).powf(-exponent)).collect();
+                let total: f64 = weights.iter().sum();
+                let cumulative = weights
+                    .iter()
+                    .scan(0.0, |sum, weight| {
+                        *sum += weight / total;
+                        Some(*sum)
+                    })
+                    .collect();
+                Self::Zipfian(cumulative)
+            }
+        }
+    }
+
+    fn pick(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            Self::Uniform(keys) => rng.gen_range(0, *keys),
+            Self::Zipfian(cumulative) => {
+                let sample: f64 = rng.gen();
+                cumulative
+                    .partition_point(|probability| *probability < sample)
+                    .min(cumulative.len() - 1)
+            }
+        }
+    }
+}
+
+/// The latencies of the commands that succeeded and the number of the ones that failed.
+#[derive(Default)]
+struct Stats {
+    reads: Vec<Duration>,
+    writes: Vec<Duration>,
+    errors: usize,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.reads.extend(other.reads);
+        self.writes.extend(other.writes);
+        self.errors += other.errors;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    simple_logger::SimpleLogger::new().env().init()?;
+
+    if cli.nodes.is_empty() || cli.clients == 0 || cli.keys == 0 {
+        return Err(anyhow!("at least one node, client and key are needed"));
+    }
+
+    info!(
+        "Running {} clients against {:?} for {}s",
+        cli.clients, cli.nodes, cli.duration
+    );
+
+    let picker = Arc::new(KeyPicker::new(
+        cli.keys,
+        cli.distribution,
+        cli.zipf_exponent,
+    ));
+    // each client sends at most its share of the rate
+    let period = cli
+        .rate
+        .map(|rate| {
+            Duration::try_from_secs_f64(cli.clients as f64 / rate)
+                .map(|period| period.max(Duration::from_nanos(1)))
+                .map_err(|_| anyhow!("rate is too low for {} clients", cli.clients))
+        })
+        .transpose()?;
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(cli.duration);
+
+    let handles: Vec<_> = (0..cli.clients)
+        .map(|client| {
+            let address = cli.nodes[client % cli.nodes.len()];
+            let picker = picker.clone();
+            let read_ratio = cli.read_ratio;
+            tokio::spawn(async move {
+                run_client(client, address, &picker, read_ratio, period, deadline).await
+            })
+        })
+        .collect();
+
+    let mut stats = Stats::default();
+    for handle in handles {
+        stats.merge(handle.await?);
+    }
+    report(&stats, start.elapsed());
+    Ok(())
+}
+
+/// Send commands to the given address until the deadline and return their stats.
+async fn run_client(
+    client: usize,
+    address: SocketAddr,
+    picker: &KeyPicker,
+    read_ratio: f64,
+    period: Option<Duration>,
+    deadline: Instant,
+) -> Stats {
+    let mut sender = ReliableSender::new();
+    let mut rng = SmallRng::from_entropy();
+    let mut interval = period.map(|period| {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    let mut stats = Stats::default();
+
+    for i in 0.. {
+        if let Some(interval) = &mut interval {
+            interval.tick().await;
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+
+        let key = format!("key{}", picker.pick(&mut rng));
+        let command = if rng.gen::<f64>() < read_ratio {
+            ClientCommand::Get { key }
+        } else {
+            ClientCommand::Set {
+                key,
+                value: format!("{client}-{i}"),
+            }
+        };
+        let is_write = command.is_write();
+
+        let sent = Instant::now();
+        // commands still pending at the deadline are not counted
+        let result = match time::timeout_at(deadline, command.send_with(&mut sender, address)).await
+        {
+            Ok(result) => result,
+            Err(_) => break,
+        };
+        match result {
+            Ok(_) if is_write => stats.writes.push(sent.elapsed()),
+            Ok(_) => stats.reads.push(sent.elapsed()),
+            Err(_) => stats.errors += 1,
+        }
+    }
+    stats
+}
+
+fn report(stats: &Stats, elapsed: Duration) {
+    let completed = stats.reads.len() + stats.writes.len();
+    println!(
+        "{} commands in {:.2}s: {:.1} commands/s, {} errors",
+        completed,
+        elapsed.as_secs_f64(),
+        completed as f64 / elapsed.as_secs_f64(),
+        stats.errors
+    );
+
+    let all: Vec<Duration> = stats.reads.iter().chain(&stats.writes).copied().collect();
+    for (name, latencies) in [
+        ("reads", &stats.reads),
+        ("writes", &stats.writes),
+        ("all", &all),
+    ] {
+        let mut latencies = latencies.clone();
+        latencies.sort();
+        if latencies.is_empty() {
+            continue;
+        }
+        println!(
+            "{:>6}: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            name,
+            percentile(&latencies, 0.5),
+            percentile(&latencies, 0.9),
+            percentile(&latencies, 0.99),
+            latencies[latencies.len() - 1]
+        );
+    }
+}
+
+/// The latency under which the given fraction of the sorted latencies fall.
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    let index = (fraction * sorted.len() as f64).ceil() as usize;
+    sorted[index.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_keys() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut counts = [0; 10];
+        let picker = KeyPicker::new(10, Distribution::Zipfian, 0.99);
+        for _ in 0..10_000 {
+            counts[picker.pick(&mut rng)] += 1;
+        }
+        // the first key is picked about ten times more often than the last one
+        assert!(counts[0] > 5 * counts[9]);
+        assert!(counts.iter().all(|count| *count > 0));
+
+        let picker = KeyPicker::new(10, Distribution::Uniform, 0.99);
+        let mut counts = [0; 10];
+        for _ in 0..10_000 {
+            counts[picker.pick(&mut rng)] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 800 && *count < 1200));
+    }
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(2.5, parse_rate("2.5").unwrap());
+        for rate in ["0", "-10", "inf", "NaN", "fast", "1e-300", "1e20"] {
+            assert!(parse_rate(rate).is_err(), "{rate}");
+        }
+        assert!(Cli::try_parse_from(["workload", "--rate", "0"]).is_err());
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(Duration::from_millis(50), percentile(&latencies, 0.5));
+        assert_eq!(Duration::from_millis(99), percentile(&latencies, 0.99));
+        assert_eq!(Duration::from_millis(1), percentile(&latencies, 0.0));
+    }
+}